@@ -3,22 +3,26 @@
 //! This module implements an iterative correction loop that allows the model
 //! to call tools and refine its answer over multiple iterations.
 
-use crate::parser::{parse_response, ModelResponse};
-use crate::tools::{Shell, Tool, ToolExecutor, ToolResult};
+use crate::parser::{parse_response_with_tags, ModelResponse};
+use crate::sampling::{default_thinking_tags, ThinkingTags};
+use crate::tools::{Shell, Tool, ToolExecutor, ToolResult, ToolSource};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 
 /// Maximum iterations for the agentic loop to prevent infinite loops
 pub const MAX_ITERATIONS: usize = 3;
 
 /// A message in the conversation context
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: MessageRole,
     pub content: String,
 }
 
 /// Role of a message in the conversation
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MessageRole {
     System,
     User,
@@ -130,6 +134,21 @@ impl Context {
     pub fn shell(&self) -> Shell {
         self.shell
     }
+
+    /// Messages so far, oldest first
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+}
+
+/// Write the conversation to `path` as a JSON array of messages, for debugging
+///
+/// Tool calls appear as the assistant messages that made them, each followed
+/// by its `tool_result` message.
+pub fn write_trace(path: &Path, context: &Context) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(context.messages())
+        .map_err(|e| format!("Failed to serialize trace: {}", e))?;
+    std::fs::write(path, json).map_err(|e| format!("Failed to write trace: {}", e))
 }
 
 /// Result of the agentic correction process
@@ -141,6 +160,8 @@ pub struct AgentResult {
     pub iterations: usize,
     /// Whether tools were used
     pub tools_used: bool,
+    /// The full conversation, ending with the model's final response
+    pub context: Context,
 }
 
 /// Execute the agentic correction loop
@@ -162,9 +183,36 @@ pub fn agentic_correct<F>(
     input: &str,
     shell: Shell,
     error: Option<&str>,
+    generate_fn: F,
+) -> AgentResult
+where
+    F: FnMut(&str) -> String,
+{
+    let executor = ToolExecutor::new(shell);
+    agentic_correct_with(
+        input,
+        shell,
+        error,
+        &executor,
+        &default_thinking_tags(),
+        generate_fn,
+    )
+}
+
+/// Like `agentic_correct`, answering tool calls from `tools`
+///
+/// Pass a `MockToolSource` to replay recorded tool results. Thinking blocks
+/// are recognized by `thinking_tags`, normally the config's.
+pub fn agentic_correct_with<S, F>(
+    input: &str,
+    shell: Shell,
+    error: Option<&str>,
+    tools: &S,
+    thinking_tags: &[ThinkingTags],
     mut generate_fn: F,
 ) -> AgentResult
 where
+    S: ToolSource,
     F: FnMut(&str) -> String,
 {
     let mut context = Context::new(shell);
@@ -174,20 +222,19 @@ where
         context.add_error(err);
     }
 
-    let executor = ToolExecutor::new(shell);
     let mut tools_used = false;
 
     for iteration in 0..MAX_ITERATIONS {
         let prompt = context.build_prompt();
         let response = generate_fn(&prompt);
 
-        match parse_response(&response) {
+        match parse_response_with_tags(&response, thinking_tags) {
             ModelResponse::ToolCall { name, args } => {
                 tools_used = true;
 
                 // Execute the tool
                 if let Some(tool) = create_tool(&name, &args) {
-                    let result = executor.execute(&tool);
+                    let result = tools.execute(&tool);
                     context.add_assistant(&response);
                     context.add_tool_result(&name, &result);
                 } else {
@@ -200,10 +247,12 @@ where
                 }
             }
             ModelResponse::FinalAnswer(answer) => {
+                context.add_assistant(&response);
                 return AgentResult {
                     command: answer,
                     iterations: iteration + 1,
                     tools_used,
+                    context,
                 };
             }
         }
@@ -214,6 +263,7 @@ where
         command: fallback_correction(input),
         iterations: MAX_ITERATIONS,
         tools_used,
+        context,
     }
 }
 
@@ -248,6 +298,10 @@ fn create_tool(name: &str, args: &HashMap<String, String>) -> Option<Tool> {
                 command: command.clone(),
             })
         }
+        "file_exists" => {
+            let path = args.get("path")?;
+            Some(Tool::FileExists { path: path.clone() })
+        }
         _ => None,
     }
 }
@@ -392,6 +446,20 @@ mod tests {
         assert_eq!(tool.unwrap().name(), "man_page");
     }
 
+    #[test]
+    fn test_create_tool_file_exists() {
+        let mut args = HashMap::new();
+        args.insert("path".to_string(), "Cargo.toml".to_string());
+
+        assert_eq!(
+            create_tool("file_exists", &args),
+            Some(Tool::FileExists {
+                path: "Cargo.toml".to_string()
+            })
+        );
+        assert_eq!(create_tool("file_exists", &HashMap::new()), None);
+    }
+
     #[test]
     fn test_create_tool_unknown() {
         let args = HashMap::new();
@@ -500,6 +568,45 @@ mod tests {
         assert!(result.tools_used);
     }
 
+    #[test]
+    fn test_write_trace_two_iterations() {
+        let mut call_count = 0;
+        let result = agentic_correct("gti status", Shell::Bash, None, |_| {
+            call_count += 1;
+            if call_count == 1 {
+                r#"<tool_call>{"name": "get_env_var", "args": {"name": "HOME"}}</tool_call>"#
+                    .to_string()
+            } else {
+                "<answer>git status</answer>".to_string()
+            }
+        });
+        assert_eq!(result.iterations, 2);
+
+        let path =
+            std::env::temp_dir().join(format!("fix-agent-trace-{}.json", std::process::id()));
+        write_trace(&path, &result.context).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let messages: Vec<Message> = serde_json::from_str(&contents).unwrap();
+        assert_eq!(messages.len(), 5);
+        let roles: Vec<MessageRole> = messages.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(
+            roles,
+            vec![
+                MessageRole::System,
+                MessageRole::User,
+                MessageRole::Assistant,
+                MessageRole::ToolResult,
+                MessageRole::Assistant,
+            ]
+        );
+        assert!(messages[2].content.contains("get_env_var"));
+        assert!(messages[3].content.starts_with("[get_env_var]"));
+        assert_eq!(messages[4].content, "<answer>git status</answer>");
+        assert!(contents.contains("\"role\": \"tool_result\""));
+    }
+
     // ===== Fallback Tests =====
 
     #[test]
@@ -0,0 +1,321 @@
+//! Timing corrections for `fix --benchmark`
+//!
+//! A fixed set of typos is corrected a number of times with the model loaded
+//! once, and the per-correction latencies are summarized as percentiles so
+//! runs can be compared across versions. `fix --profile-startup` times the
+//! phases of a single correction instead, to show where a slow first run
+//! spends its time.
+
+use std::time::{Duration, Instant};
+
+/// Typos every benchmark run corrects, covering short and long commands
+pub const SAMPLE_TYPOS: &[&str] = &[
+    "gti status",
+    "sl -la",
+    "dokcer ps -a",
+    "cd..",
+    "grpe -rn TODO src",
+    "pyhton3 --version",
+    "npm isntall --save-dev typescript",
+    "git comit -m 'wip'",
+];
+
+/// Latency percentiles over a set of runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+/// Value at percentile `p` (0-100) of `sorted`, by the nearest-rank method
+///
+/// Returns zero for an empty slice.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Summarize latencies in any order; None when there are none
+pub fn latency_stats(latencies: &[Duration]) -> Option<LatencyStats> {
+    let mut sorted = latencies.to_vec();
+    sorted.sort();
+
+    Some(LatencyStats {
+        min: *sorted.first()?,
+        median: percentile(&sorted, 50.0),
+        p95: percentile(&sorted, 95.0),
+        max: *sorted.last()?,
+    })
+}
+
+/// Outcome of a benchmark run
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkReport {
+    /// Number of corrections timed
+    pub runs: usize,
+    pub latency: LatencyStats,
+    /// Tokens in all corrections together
+    pub tokens: usize,
+    /// Time spent in corrections, excluding setup
+    pub total: Duration,
+}
+
+impl BenchmarkReport {
+    /// Output tokens per second of correction time
+    pub fn tokens_per_sec(&self) -> f64 {
+        let secs = self.total.as_secs_f64();
+        if secs > 0.0 {
+            self.tokens as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Correct every sample `rounds` times, timing each call of `infer`
+///
+/// `infer` returns how many tokens its correction took. The first error
+/// stops the run.
+pub fn run_benchmark<F>(
+    samples: &[&str],
+    rounds: usize,
+    mut infer: F,
+) -> Result<BenchmarkReport, String>
+where
+    F: FnMut(&str) -> Result<usize, String>,
+{
+    let mut latencies = Vec::with_capacity(samples.len() * rounds);
+    let mut tokens = 0;
+
+    for _ in 0..rounds {
+        for sample in samples {
+            let start = Instant::now();
+            tokens += infer(sample)?;
+            latencies.push(start.elapsed());
+        }
+    }
+
+    let latency = latency_stats(&latencies).ok_or("Nothing to benchmark")?;
+    Ok(BenchmarkReport {
+        runs: latencies.len(),
+        latency,
+        tokens,
+        total: latencies.iter().sum(),
+    })
+}
+
+/// Render a report for the terminal
+pub fn format_report(report: &BenchmarkReport) -> String {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    format!(
+        "{} corrections\n  min    {:>8.1} ms\n  median {:>8.1} ms\n  p95    {:>8.1} ms\n  max    {:>8.1} ms\n  {:.1} tokens/sec\n",
+        report.runs,
+        ms(report.latency.min),
+        ms(report.latency.median),
+        ms(report.latency.p95),
+        ms(report.latency.max),
+        report.tokens_per_sec()
+    )
+}
+
+/// Steps of a single correction, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StartupPhase {
+    ConfigLoad,
+    ModelResolution,
+    BackendInit,
+    ModelLoad,
+    ContextCreation,
+    Generation,
+}
+
+impl StartupPhase {
+    /// Every phase, in order
+    pub const ALL: [StartupPhase; 6] = [
+        StartupPhase::ConfigLoad,
+        StartupPhase::ModelResolution,
+        StartupPhase::BackendInit,
+        StartupPhase::ModelLoad,
+        StartupPhase::ContextCreation,
+        StartupPhase::Generation,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            StartupPhase::ConfigLoad => "config load",
+            StartupPhase::ModelResolution => "model resolution",
+            StartupPhase::BackendInit => "backend init",
+            StartupPhase::ModelLoad => "model load",
+            StartupPhase::ContextCreation => "context creation",
+            StartupPhase::Generation => "generation",
+        }
+    }
+}
+
+/// Time spent in each startup phase of one run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StartupProfile {
+    phases: Vec<(StartupPhase, Duration)>,
+}
+
+impl StartupProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `step`, adding its duration to `phase`
+    pub fn time<T>(&mut self, phase: StartupPhase, step: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = step();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    /// Add `elapsed` to `phase`
+    pub fn record(&mut self, phase: StartupPhase, elapsed: Duration) {
+        match self.phases.iter_mut().find(|(p, _)| *p == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Timed phases in the order they run, whatever order they were recorded in
+    pub fn phases(&self) -> Vec<(StartupPhase, Duration)> {
+        let mut phases = self.phases.clone();
+        phases.sort_by_key(|(phase, _)| *phase);
+        phases
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, elapsed)| *elapsed).sum()
+    }
+}
+
+/// Render a startup profile for the terminal
+pub fn format_profile(profile: &StartupProfile) -> String {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let mut out = String::from("Startup profile\n");
+    for (phase, elapsed) in profile.phases() {
+        out.push_str(&format!("  {:<17}{:>8.1} ms\n", phase.label(), ms(elapsed)));
+    }
+    out.push_str(&format!(
+        "  {:<17}{:>8.1} ms\n",
+        "total",
+        ms(profile.total())
+    ));
+    out
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|&ms| Duration::from_millis(ms)).collect()
+    }
+
+    #[test]
+    fn test_latency_stats_percentiles() {
+        // 1..=20 ms, shuffled
+        let latencies = millis(&[
+            7, 20, 3, 15, 1, 12, 9, 18, 5, 11, 2, 16, 14, 4, 19, 8, 13, 6, 17, 10,
+        ]);
+        let stats = latency_stats(&latencies).unwrap();
+
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.median, Duration::from_millis(10));
+        assert_eq!(stats.p95, Duration::from_millis(19));
+        assert_eq!(stats.max, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_percentile_small_samples() {
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+
+        let one = millis(&[42]);
+        assert_eq!(percentile(&one, 0.0), Duration::from_millis(42));
+        assert_eq!(percentile(&one, 95.0), Duration::from_millis(42));
+
+        let three = millis(&[10, 20, 30]);
+        assert_eq!(percentile(&three, 50.0), Duration::from_millis(20));
+        assert_eq!(percentile(&three, 95.0), Duration::from_millis(30));
+        assert_eq!(latency_stats(&[]), None);
+    }
+
+    #[test]
+    fn test_run_benchmark_with_mock_inference() {
+        let mut seen = Vec::new();
+        let report = run_benchmark(&["gti status", "sl"], 3, |sample| {
+            seen.push(sample.to_string());
+            Ok(sample.len())
+        })
+        .unwrap();
+
+        assert_eq!(report.runs, 6);
+        assert_eq!(report.tokens, 3 * ("gti status".len() + "sl".len()));
+        assert_eq!(seen.len(), 6);
+        assert_eq!(&seen[..2], ["gti status", "sl"]);
+        assert!(report.latency.min <= report.latency.median);
+        assert!(report.latency.p95 <= report.latency.max);
+    }
+
+    #[test]
+    fn test_run_benchmark_stops_on_error() {
+        let mut calls = 0;
+        let result = run_benchmark(SAMPLE_TYPOS, 2, |_| {
+            calls += 1;
+            Err("Decode failed".to_string())
+        });
+
+        assert_eq!(result, Err("Decode failed".to_string()));
+        assert_eq!(calls, 1);
+        assert!(run_benchmark(&[], 5, |_| Ok(1)).is_err());
+    }
+
+    #[test]
+    fn test_tokens_per_sec() {
+        let latency = latency_stats(&millis(&[500, 1500])).unwrap();
+        let report = BenchmarkReport {
+            runs: 2,
+            latency,
+            tokens: 40,
+            total: Duration::from_secs(2),
+        };
+        assert!((report.tokens_per_sec() - 20.0).abs() < 1e-9);
+        assert!(format_report(&report).contains("20.0 tokens/sec"));
+    }
+
+    #[test]
+    fn test_startup_profile_reports_phases_in_order() {
+        let mut profile = StartupProfile::new();
+        // Recorded out of order, with generation split over two steps
+        for (i, phase) in StartupPhase::ALL.iter().rev().enumerate() {
+            profile.record(*phase, Duration::from_millis(10 * (i as u64 + 1)));
+        }
+        profile.record(StartupPhase::Generation, Duration::from_millis(5));
+        assert_eq!(profile.time(StartupPhase::ConfigLoad, || 7), 7);
+
+        let phases = profile.phases();
+        let order: Vec<_> = phases.iter().map(|(phase, _)| *phase).collect();
+        assert_eq!(order, StartupPhase::ALL);
+        assert_eq!(phases[5].1, Duration::from_millis(15));
+        assert!(phases[0].1 >= Duration::from_millis(60));
+        assert!(profile.total() >= Duration::from_millis(215));
+
+        let report = format_profile(&profile);
+        let positions: Vec<_> = StartupPhase::ALL
+            .iter()
+            .map(|phase| report.find(phase.label()).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(report.contains("  generation           15.0 ms\n"));
+        assert!(report.contains("total"));
+    }
+}
@@ -4,41 +4,74 @@
 //! Uses daemon mode by default on Unix to keep the model loaded for fast inference.
 //! Example: `fix "gti status"` → `git status`
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use fix_lib::benchmark::{StartupPhase, StartupProfile};
+use fix_lib::doctor::{self, Check, CheckOutcome};
+use fix_lib::history::parse_history;
+use fix_lib::parser::{command_from_thinking, sequence_complete};
+use fix_lib::progress::{self, ColorMode};
+#[cfg(unix)]
+use fix_lib::prompt_cache::PromptCache;
+use fix_lib::sampling::{
+    generate, is_truncated, rank_candidates, retry_if_truncated, sequence_confidence,
+    token_probability, Candidate, GenerationState, SamplingPreset, Step,
+};
+use fix_lib::tools::{PowerShellFlavor, Shell, ToolExecutor};
 use fix_lib::{
-    build_prompt, config_path, detect_shell, download_model, find_model_path, get_model_path,
-    list_models, load_config, save_config, suppress_llama_logs, validate_model_exists,
+    append_correction_record, benchmark, build_prompt_from_template, check_prompt_fits,
+    clean_correction, command_in_path, config_path, confirm, detect_shell, download_model,
+    enable_utf8_console, find_model_path, find_or_download_model_url, get_model_path, init_proxy,
+    interactive_default, is_interactive, list_models, load_config, log_debug, log_info, model_dir,
+    parse_since, prepend_env_assignments, preserves_command_word, reattach_args, safety,
+    save_config, should_confirm_download, split_env_assignments, suppress_llama_logs,
+    validate_model_exists, Config, CorrectionRecord, PromptFormat, CHAT_TEMPLATE_KEY,
+    FLAGS_ONLY_INSTRUCTION,
 };
 use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
 use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::token::data::LlamaTokenData;
 use llama_cpp_2::token::data_array::LlamaTokenDataArray;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 // Unix-specific imports for daemon mode
 #[cfg(unix)]
+use fix_lib::context::ReusableContext;
+#[cfg(unix)]
+use fix_lib::daemon;
+#[cfg(unix)]
 use fix_lib::stderr_redirect;
 #[cfg(unix)]
+use fix_lib::{resolve_daemon_executable, send_to_daemon, DAEMON_PROTOCOL_VERSION};
+#[cfg(unix)]
 use serde::{Deserialize, Serialize};
 #[cfg(unix)]
 use std::fs;
 #[cfg(unix)]
-use std::io::{BufRead, BufReader, Write};
+use std::io::Write;
 #[cfg(unix)]
 use std::os::unix::net::{UnixListener, UnixStream};
 #[cfg(unix)]
-use std::sync::atomic::{AtomicBool, Ordering};
-#[cfg(unix)]
-use std::sync::{Arc, Mutex};
-#[cfg(unix)]
-use std::time::{Duration, Instant};
+use std::time::Duration;
+
+/// Known typo used by `--doctor` to verify inference end to end
+const DOCTOR_INPUT: &str = "gti status";
+
+/// Expected correction of `DOCTOR_INPUT`
+const DOCTOR_EXPECTED: &str = "git status";
 
 /// Idle timeout before daemon auto-shuts down (1 hour)
 #[cfg(unix)]
 const IDLE_TIMEOUT_SECS: u64 = 3600;
 
+/// Longest a client waits for the daemon's correction
+#[cfg(unix)]
+const DAEMON_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Socket path for daemon communication
 #[cfg(unix)]
 fn socket_path() -> PathBuf {
@@ -75,10 +108,106 @@ struct Args {
     #[arg(short, long)]
     model: Option<PathBuf>,
 
+    /// Directory to find and download models in (overrides $FIX_MODEL_DIR)
+    #[arg(long, value_name = "PATH")]
+    model_dir: Option<PathBuf>,
+
+    /// Download a GGUF model from any URL (cached locally) and use it
+    #[arg(long, conflicts_with = "model")]
+    model_url: Option<String>,
+
+    /// Proxy for model downloads (overrides $HTTPS_PROXY and $HTTP_PROXY)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// HuggingFace branch, tag or commit to download models from (default: main)
+    #[arg(long, value_name = "SHA|TAG")]
+    revision: Option<String>,
+
     /// Number of GPU layers to offload (default: all)
     #[arg(long, default_value = "99")]
     gpu_layers: u32,
 
+    /// Sampling preset: greedy (deterministic), balanced or creative
+    #[arg(long, value_enum, default_value_t = SamplingPreset::Greedy)]
+    sampling: SamplingPreset,
+
+    /// Keep multi-line corrections (backslash continuations, heredocs)
+    #[arg(long)]
+    multiline: bool,
+
+    /// Only correct the command word, keeping the arguments as typed
+    #[arg(long, conflicts_with = "multiline")]
+    preserve_args: bool,
+
+    /// Only correct the flags, keeping the command word and other arguments
+    #[arg(long, conflicts_with = "preserve_args")]
+    flags_only: bool,
+
+    /// Correct the last entries of a bash, zsh or fish history file
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["command", "json", "suggestions", "interactive"]
+    )]
+    history_file: Option<PathBuf>,
+
+    /// Number of history entries to correct with --history-file
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 10,
+        requires = "history_file"
+    )]
+    last: usize,
+
+    /// Time N rounds of sample corrections with the model loaded once
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["command", "history_file"],
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    benchmark: Option<u16>,
+
+    /// Correct the command without the daemon and print how long each startup phase took
+    #[arg(
+        long,
+        conflicts_with_all = ["history_file", "benchmark", "json", "suggestions", "interactive"]
+    )]
+    profile_startup: bool,
+
+    /// Read the command from stdin and print only its correction, for editor integrations
+    ///
+    /// Exactly one line goes to stdout: the correction, or an empty line when
+    /// there is none. All messages go to stderr. Exit status: 0 corrected,
+    /// 1 nothing to correct or no correction, 2 refused (denylisted input or
+    /// unsafe correction), 3 error.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "command", "json", "suggestions", "interactive", "multiline",
+            "history_file", "benchmark", "profile_startup"
+        ]
+    )]
+    filter: bool,
+
+    /// Print the correction as JSON with the model's confidence
+    #[arg(long)]
+    json: bool,
+
+    /// Append each correction to a file as a JSON line, e.g. to build a dataset
+    #[arg(long, value_name = "PATH")]
+    save_output: Option<PathBuf>,
+
+    /// Generate up to N corrections, ranked by confidence
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    suggestions: Option<u16>,
+
+    /// Offer to run the correction in the shell it was made for
+    #[arg(short, long, conflicts_with_all = ["json", "suggestions"])]
+    interactive: bool,
+
     /// Show model loading and inference logs
     #[arg(short, long)]
     verbose: bool,
@@ -87,6 +216,10 @@ struct Args {
     #[arg(long)]
     list_models: bool,
 
+    /// With --list-models, only show models updated within this window (e.g. 7d, 24h, 2w)
+    #[arg(long, value_name = "DURATION", value_parser = parse_since, requires = "list_models")]
+    since: Option<chrono::Duration>,
+
     /// Download and set a model as default
     #[arg(long)]
     use_model: Option<String>,
@@ -95,10 +228,34 @@ struct Args {
     #[arg(long)]
     update: bool,
 
+    /// Download a missing model without asking
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Color progress output: auto (only when interactive), always or never
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Keep the partial .tmp file when a download fails, for debugging
+    #[arg(long)]
+    keep_partial: bool,
+
     /// Show current configuration
     #[arg(long)]
     show_config: bool,
 
+    /// Print a tab-completion script for fix's flags and exit
+    #[arg(long, value_enum, value_name = "SHELL")]
+    completions: Option<clap_complete::Shell>,
+
+    /// Print the system prompt template in effect, placeholders intact
+    #[arg(long)]
+    print_prompt_template: bool,
+
+    /// Check the install end to end and report each problem found
+    #[arg(long)]
+    doctor: bool,
+
     /// Stop the daemon and unload model from memory (Unix only)
     #[arg(long)]
     stop: bool,
@@ -107,10 +264,26 @@ struct Args {
     #[arg(long)]
     status: bool,
 
+    /// Check that the daemon answers; exits non-zero if it doesn't (Unix only)
+    #[arg(long)]
+    ping: bool,
+
     /// Run in direct mode (no daemon, load model each time)
     #[arg(long)]
     direct: bool,
 
+    /// Leave the Windows console's code page alone instead of switching to UTF-8
+    #[arg(long)]
+    no_utf8_console: bool,
+
+    /// Correct through the daemon, stopping it afterwards if this run started it (Unix)
+    #[arg(long, conflicts_with = "direct")]
+    once: bool,
+
+    /// Permanently enable or disable the background daemon
+    #[arg(long, value_name = "on|off", value_parser = ["on", "off"])]
+    set_daemon: Option<String>,
+
     /// Run as daemon (internal use, Unix only)
     #[arg(long, hide = true)]
     daemon: bool,
@@ -120,9 +293,18 @@ struct Args {
 #[cfg(unix)]
 #[derive(Serialize, Deserialize, Debug)]
 struct DaemonRequest {
+    /// `DAEMON_PROTOCOL_VERSION` of the client
+    #[serde(default)]
+    protocol_version: u32,
     command: String,
     shell: String,
     error: Option<String>,
+    #[serde(default)]
+    sampling: SamplingPreset,
+    #[serde(default)]
+    multiline: bool,
+    #[serde(default)]
+    flags_only: bool,
     verbose: bool,
 }
 
@@ -130,9 +312,22 @@ struct DaemonRequest {
 #[cfg(unix)]
 #[derive(Serialize, Deserialize, Debug)]
 struct DaemonResponse {
+    /// `DAEMON_PROTOCOL_VERSION` of the daemon; 0 from daemons that predate it
+    #[serde(default)]
+    protocol_version: u32,
     success: bool,
     output: String,
     error: Option<String>,
+    #[serde(default)]
+    confidence: Option<f32>,
+}
+
+/// A corrected command and how sure the model was of it
+#[derive(Clone)]
+struct Correction {
+    command: String,
+    /// Confidence in [0, 1], see `sequence_confidence`
+    confidence: Option<f32>,
 }
 
 /// Check if daemon is running
@@ -160,8 +355,10 @@ fn is_daemon_running() -> bool {
 
 /// Start daemon in background
 #[cfg(unix)]
-fn start_daemon(model_path: &PathBuf, gpu_layers: u32) -> Result<(), String> {
-    let exe = std::env::current_exe().map_err(|e| format!("Failed to get executable: {}", e))?;
+fn start_daemon(model_path: &PathBuf, gpu_layers: u32, config: &Config) -> Result<(), String> {
+    let current =
+        std::env::current_exe().map_err(|e| format!("Failed to get executable: {}", e))?;
+    let exe = resolve_daemon_executable(&current, "fix", config.bin_dir.as_deref())?;
 
     let child = std::process::Command::new(&exe)
         .arg("--daemon")
@@ -213,47 +410,96 @@ fn stop_daemon() -> Result<(), String> {
     Ok(())
 }
 
-/// Send request to daemon
-#[cfg(unix)]
-fn send_to_daemon(request: &DaemonRequest) -> Result<DaemonResponse, String> {
-    let mut stream =
-        UnixStream::connect(socket_path()).map_err(|e| format!("Failed to connect: {}", e))?;
+/// Pick the prompt format from the config override or the model's chat template
+fn prompt_format_for(model: &LlamaModel, configured: Option<PromptFormat>) -> PromptFormat {
+    let template = model.meta_val_str(CHAT_TEMPLATE_KEY).ok();
+    PromptFormat::resolve(configured, template.as_deref())
+}
 
-    stream
-        .set_read_timeout(Some(Duration::from_secs(30)))
-        .map_err(|e| format!("Failed to set timeout: {}", e))?;
+/// Create an inference context sized for a single correction
+fn new_context<'a>(
+    model: &'a LlamaModel,
+    backend: &LlamaBackend,
+) -> Result<LlamaContext<'a>, String> {
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(std::num::NonZeroU32::new(CONTEXT_TOKENS as u32))
+        .with_n_batch(CONTEXT_TOKENS as u32);
+    model
+        .new_context(backend, ctx_params)
+        .map_err(|e| format!("Failed to create context: {}", e))
+}
 
-    let request_json =
-        serde_json::to_string(request).map_err(|e| format!("Failed to serialize: {}", e))?;
+/// Context size in tokens, shared by the prompt and the generated correction
+const CONTEXT_TOKENS: usize = 512;
 
-    writeln!(stream, "{}", request_json).map_err(|e| format!("Failed to send: {}", e))?;
+/// Tokens generated for a correction before checking whether it was cut off
+const MAX_TOKENS: usize = 128;
 
-    let mut reader = BufReader::new(stream);
-    let mut response_line = String::new();
-    reader
-        .read_line(&mut response_line)
-        .map_err(|e| format!("Failed to read response: {}", e))?;
+/// Prompt for `command`, which must already have its env assignments split off
+///
+/// With `flags_only` the system message also tells the model to leave
+/// everything but the flags alone.
+fn inference_prompt(
+    format: PromptFormat,
+    command: &str,
+    shell: &str,
+    error: Option<&str>,
+    config: &Config,
+    flags_only: bool,
+) -> String {
+    let template = if flags_only {
+        format!(
+            "{} {}",
+            config.system_prompt_template(),
+            FLAGS_ONLY_INSTRUCTION
+        )
+    } else {
+        config.system_prompt_template().to_string()
+    };
+    build_prompt_from_template(format, &template, shell, command, error, config.thinking)
+}
 
-    serde_json::from_str(&response_line).map_err(|e| format!("Failed to parse response: {}", e))
+/// Key for a daemon request in the prompt cache
+///
+/// Besides the prompt, the correction depends on the sampling preset, on
+/// `multiline` and on env assignments kept out of the prompt, so those are
+/// part of the key too. `flags_only` changes the prompt itself.
+#[cfg(unix)]
+fn prompt_cache_key(format: PromptFormat, request: &DaemonRequest, config: &Config) -> String {
+    let (assignments, command) = split_env_assignments(&request.command);
+    let prompt = inference_prompt(
+        format,
+        command,
+        &request.shell,
+        request.error.as_deref(),
+        config,
+        request.flags_only,
+    );
+    format!(
+        "{:?}\n{}\n{}\n{}",
+        request.sampling, request.multiline, assignments, prompt
+    )
 }
 
-/// Run inference with loaded model
+/// Run inference with loaded model in a fresh or freshly reset context
+#[allow(clippy::too_many_arguments)]
 fn run_inference(
+    ctx: &mut LlamaContext,
     model: &LlamaModel,
-    backend: &LlamaBackend,
     command: &str,
     shell: &str,
     error: Option<&str>,
+    config: &Config,
+    sampling: SamplingPreset,
+    multiline: bool,
+    flags_only: bool,
     verbose: bool,
-) -> Result<String, String> {
-    let ctx_params = LlamaContextParams::default()
-        .with_n_ctx(std::num::NonZeroU32::new(512))
-        .with_n_batch(512);
-    let mut ctx = model
-        .new_context(backend, ctx_params)
-        .map_err(|e| format!("Failed to create context: {}", e))?;
+) -> Result<Correction, String> {
+    // Correct only the command itself; leading `NAME=value` assignments are kept as typed
+    let (assignments, command) = split_env_assignments(command);
 
-    let prompt = build_prompt(shell, command, error);
+    let format = prompt_format_for(model, config.prompt_format);
+    let prompt = inference_prompt(format, command, shell, error, config, flags_only);
 
     if verbose {
         eprintln!("Prompt length: {} chars", prompt.len());
@@ -262,91 +508,110 @@ fn run_inference(
     let tokens = model
         .str_to_token(&prompt, llama_cpp_2::model::AddBos::Always)
         .map_err(|e| format!("Tokenization failed: {}", e))?;
+    check_prompt_fits(tokens.len(), CONTEXT_TOKENS)?;
 
-    let mut batch = LlamaBatch::new(512, 1);
-    for (i, token) in tokens.iter().enumerate() {
-        let is_last = i == tokens.len() - 1;
-        batch
-            .add(*token, i as i32, &[0], is_last)
-            .map_err(|e| format!("Batch add failed: {}", e))?;
-    }
+    let eos_token = model.token_eos();
+    let sampler = sampling.sampler();
+    // A truncated correction is retried once with whatever room the context has left
+    let retry_max_tokens = CONTEXT_TOKENS.saturating_sub(tokens.len());
+
+    let (mut output, thinking, probabilities) =
+        retry_if_truncated(MAX_TOKENS, retry_max_tokens, |max_tokens| {
+            // A retry starts over from the prompt
+            ctx.clear_kv_cache();
+
+            let mut batch = LlamaBatch::new(CONTEXT_TOKENS, 1);
+            for (i, token) in tokens.iter().enumerate() {
+                let is_last = i == tokens.len() - 1;
+                batch
+                    .add(*token, i as i32, &[0], is_last)
+                    .map_err(|e| format!("Batch add failed: {}", e))?;
+            }
 
-    ctx.decode(&mut batch)
-        .map_err(|e| format!("Decode failed: {}", e))?;
+            ctx.decode(&mut batch)
+                .map_err(|e| format!("Decode failed: {}", e))?;
 
-    let mut output = String::new();
-    let max_tokens = 128;
-    let eos_token = model.token_eos();
-    let mut cur_pos = tokens.len() as i32;
-    let mut in_thinking = false;
-    let mut after_thinking = false;
-    let mut should_break = false;
+            let mut state = GenerationState::with_tags(&config.thinking_tags);
+            let mut probabilities = Vec::new();
 
-    for _ in 0..max_tokens {
-        let candidates = ctx.candidates();
-        let mut candidates_data = LlamaTokenDataArray::from_iter(candidates, false);
-        let new_token = candidates_data.sample_token_greedy();
+            let generated = generate(max_tokens, |i| {
+                let cur_pos = (tokens.len() + i) as i32;
+                let candidates: Vec<LlamaTokenData> = ctx.candidates().collect();
+                let mut candidates_data =
+                    LlamaTokenDataArray::from_iter(candidates.iter().copied(), false);
+                candidates_data.apply_sampler(&sampler);
+                let new_token = candidates_data
+                    .selected_token()
+                    .ok_or_else(|| "Sampler did not select a token".to_string())?;
 
-        if new_token == eos_token {
-            break;
-        }
+                if new_token == eos_token {
+                    return Ok(Step::Stop);
+                }
 
-        if let Ok(piece) = model.token_to_str(new_token, llama_cpp_2::model::Special::Tokenize) {
-            if piece.contains("<|im_end|>") || piece.contains("<|im_start|>") {
-                break;
-            }
+                if let Ok(piece) =
+                    model.token_to_str(new_token, llama_cpp_2::model::Special::Tokenize)
+                {
+                    if format.stop_markers().iter().any(|m| piece.contains(m)) {
+                        return Ok(Step::Stop);
+                    }
+
+                    if state.push(&piece) {
+                        probabilities.extend(token_probability(&candidates, new_token));
 
-            if piece.contains("<think>") {
-                in_thinking = true;
-            } else if piece.contains("</think>") {
-                in_thinking = false;
-                after_thinking = true;
-            } else if !in_thinking {
-                if after_thinking && piece.trim().is_empty() {
-                    // Skip
-                } else {
-                    after_thinking = false;
-                    output.push_str(&piece);
-
-                    let trimmed = output.trim();
-                    if !trimmed.is_empty() && trimmed.contains('\n') {
-                        should_break = true;
+                        if !multiline && sequence_complete(state.output()) {
+                            return Ok(Step::Stop);
+                        }
                     }
                 }
+
+                batch.clear();
+                batch
+                    .add(new_token, cur_pos, &[0], true)
+                    .map_err(|e| format!("Batch add failed: {}", e))?;
+                ctx.decode(&mut batch)
+                    .map_err(|e| format!("Decode failed: {}", e))?;
+
+                Ok(Step::Continue)
+            })?;
+
+            if verbose {
+                eprintln!("Prompt tokens: {}", tokens.len());
+                eprintln!(
+                    "Generated tokens: {} ({:?})",
+                    generated.decoded, generated.stop
+                );
             }
-        }
 
-        if should_break {
-            break;
-        }
+            let (output, thinking) = state.into_parts();
+            let truncated = is_truncated(generated.stop, &output);
+            if verbose && truncated {
+                eprintln!("Output was cut off at {} tokens", max_tokens);
+            }
+            Ok(((output, thinking, probabilities), truncated))
+        })?;
 
-        batch.clear();
-        batch
-            .add(new_token, cur_pos, &[0], true)
-            .map_err(|e| format!("Batch add failed: {}", e))?;
-        cur_pos += 1;
-        ctx.decode(&mut batch)
-            .map_err(|e| format!("Decode failed: {}", e))?;
+    // The model sometimes answers inside the think block and stops there
+    if output.trim().is_empty() {
+        if let Some(command) = command_from_thinking(&thinking, command_in_path) {
+            output = command;
+        }
     }
 
-    // Clean output
-    let result = output.trim();
-    let result = result
-        .strip_prefix("command >")
-        .or_else(|| result.strip_prefix("command>"))
-        .or_else(|| result.strip_prefix("command 2>&1"))
-        .or_else(|| result.strip_prefix("Command:"))
-        .unwrap_or(result)
-        .trim();
-
-    let result = result.lines().next().unwrap_or(result).trim();
+    let result = clean_correction(command, &output, multiline, config);
 
-    Ok(result.to_string())
+    Ok(Correction {
+        command: prepend_env_assignments(assignments, &result),
+        confidence: sequence_confidence(&probabilities),
+    })
 }
 
 /// Run daemon mode (Unix only)
 #[cfg(unix)]
-fn run_daemon(model_path: PathBuf, gpu_layers: u32) -> Result<(), Box<dyn std::error::Error>> {
+fn run_daemon(
+    model_path: PathBuf,
+    gpu_layers: u32,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
     let _ = fs::remove_file(socket_path());
 
     suppress_llama_logs();
@@ -357,83 +622,78 @@ fn run_daemon(model_path: PathBuf, gpu_layers: u32) -> Result<(), Box<dyn std::e
         .map_err(|e| format!("Failed to load model: {}", e))?;
 
     let listener = UnixListener::bind(socket_path())?;
-    listener.set_nonblocking(true)?;
-
-    let last_activity = Arc::new(Mutex::new(Instant::now()));
-    let should_stop = Arc::new(AtomicBool::new(false));
-
-    loop {
-        {
-            let last = last_activity.lock().unwrap();
-            if last.elapsed() > Duration::from_secs(IDLE_TIMEOUT_SECS) {
-                break;
-            }
-        }
-
-        if should_stop.load(Ordering::Relaxed) {
-            break;
-        }
-
-        match listener.accept() {
-            Ok((mut stream, _)) => {
-                *last_activity.lock().unwrap() = Instant::now();
-
-                let mut reader = BufReader::new(&stream);
-                let mut line = String::new();
-                if reader.read_line(&mut line).is_err() {
-                    continue;
-                }
-
-                if line.contains("\"stop\"") {
-                    should_stop.store(true, Ordering::Relaxed);
-                    let response = DaemonResponse {
-                        success: true,
-                        output: "Daemon stopping".to_string(),
-                        error: None,
-                    };
-                    let _ = writeln!(stream, "{}", serde_json::to_string(&response).unwrap());
-                    break;
-                }
-
-                let request: Result<DaemonRequest, _> = serde_json::from_str(&line);
-                let response = match request {
-                    Ok(req) => {
-                        match run_inference(
+    let idle_timeout = Duration::from_secs(IDLE_TIMEOUT_SECS);
+    let mut contexts = ReusableContext::new();
+    let mut corrections: PromptCache<Correction> = PromptCache::new(config.prompt_cache_size);
+    let format = prompt_format_for(&model, config.prompt_format);
+
+    let shutdown = daemon::serve(
+        &listener,
+        idle_timeout,
+        config.ping_keeps_daemon_alive,
+        Some(&model_path),
+        |line| {
+            let request: Result<DaemonRequest, _> = serde_json::from_str(line);
+            let response = match request {
+                Ok(req) => {
+                    let key = prompt_cache_key(format, &req, config);
+                    let infer = |ctx: &mut LlamaContext| {
+                        run_inference(
+                            ctx,
                             &model,
-                            &backend,
                             &req.command,
                             &req.shell,
                             req.error.as_deref(),
+                            config,
+                            req.sampling,
+                            req.multiline,
+                            req.flags_only,
                             req.verbose,
-                        ) {
-                            Ok(output) => DaemonResponse {
-                                success: true,
-                                output,
-                                error: None,
-                            },
-                            Err(e) => DaemonResponse {
-                                success: false,
-                                output: String::new(),
-                                error: Some(e),
-                            },
-                        }
+                        )
+                    };
+                    let result = if let Some(correction) = corrections.get(&key) {
+                        log_debug!("Answering from the prompt cache");
+                        Ok(correction.clone())
+                    } else if config.reuse_daemon_context {
+                        contexts.run(|| new_context(&model, &backend), infer)
+                    } else {
+                        new_context(&model, &backend).and_then(|mut ctx| infer(&mut ctx))
+                    };
+                    if let Ok(ref correction) = result {
+                        corrections.insert(key, correction.clone());
                     }
-                    Err(e) => DaemonResponse {
-                        success: false,
-                        output: String::new(),
-                        error: Some(format!("Invalid request: {}", e)),
-                    },
-                };
-
-                let _ = writeln!(stream, "{}", serde_json::to_string(&response).unwrap());
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                std::thread::sleep(Duration::from_millis(50));
-            }
-            Err(_) => {
-                std::thread::sleep(Duration::from_millis(100));
-            }
-        }
+                    match result {
+                        Ok(correction) => DaemonResponse {
+                            protocol_version: DAEMON_PROTOCOL_VERSION,
+                            success: true,
+                            output: correction.command,
+                            error: None,
+                            confidence: correction.confidence,
+                        },
+                        Err(e) => DaemonResponse {
+                            protocol_version: DAEMON_PROTOCOL_VERSION,
+                            success: false,
+                            output: String::new(),
+                            error: Some(e),
+                            confidence: None,
+                        },
+                    }
+                }
+                Err(e) => DaemonResponse {
+                    protocol_version: DAEMON_PROTOCOL_VERSION,
+                    success: false,
+                    output: String::new(),
+                    error: Some(format!("Invalid request: {}", e)),
+                    confidence: None,
+                },
+            };
+
+            serde_json::to_string(&response).unwrap()
+        },
+    )?;
+
+    if shutdown == daemon::Shutdown::ModelChanged {
+        eprintln!("fix daemon: model file changed, shutting down");
     }
 
     let _ = fs::remove_file(socket_path());
@@ -442,15 +702,20 @@ fn run_daemon(model_path: PathBuf, gpu_layers: u32) -> Result<(), Box<dyn std::e
     Ok(())
 }
 
-/// Run in direct mode (no daemon)
+/// Run in direct mode (no daemon), one correction per sampling preset
+#[allow(clippy::too_many_arguments)]
 fn run_direct(
     command: &str,
     shell: &str,
     error: Option<&str>,
     model_path: PathBuf,
     gpu_layers: u32,
+    config: &Config,
+    samplings: &[SamplingPreset],
+    multiline: bool,
+    flags_only: bool,
     verbose: bool,
-) -> Result<String, Box<dyn std::error::Error>> {
+) -> Result<Vec<Correction>, Box<dyn std::error::Error>> {
     if !verbose {
         suppress_llama_logs();
     }
@@ -467,7 +732,14 @@ fn run_direct(
     let model = LlamaModel::load_from_file(&backend, &model_path, &model_params)
         .map_err(|e| format!("Failed to load model: {}", e))?;
 
-    let result = run_inference(&model, &backend, command, shell, error, verbose)?;
+    let mut result = Vec::new();
+    for &sampling in samplings {
+        let mut ctx = new_context(&model, &backend)?;
+        result.push(run_inference(
+            &mut ctx, &model, command, shell, error, config, sampling, multiline, flags_only,
+            verbose,
+        )?);
+    }
 
     #[cfg(unix)]
     if let Some(saved) = saved_stderr {
@@ -477,17 +749,534 @@ fn run_direct(
     Ok(result)
 }
 
+/// Correct each history entry with one loaded model
+///
+/// Returns `(original, correction)` for the entries that changed; entries
+/// the model left alone, or whose correction would be refused as unsafe,
+/// are skipped. An entry that fails to correct is reported on stderr and
+/// skipped too.
+#[allow(clippy::too_many_arguments)]
+fn run_history(
+    entries: &[String],
+    shell: &str,
+    model_path: PathBuf,
+    gpu_layers: u32,
+    config: &Config,
+    sampling: SamplingPreset,
+    verbose: bool,
+) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    if !verbose {
+        suppress_llama_logs();
+    }
+
+    let backend = LlamaBackend::init()?;
+    let model_params = LlamaModelParams::default().with_n_gpu_layers(gpu_layers);
+    let model = LlamaModel::load_from_file(&backend, &model_path, &model_params)
+        .map_err(|e| format!("Failed to load model: {}", e))?;
+
+    let mut corrected = Vec::new();
+    for entry in entries {
+        let mut ctx = new_context(&model, &backend)?;
+        // One entry failing, say by not fitting the context, doesn't stop the rest
+        let correction = match run_inference(
+            &mut ctx, &model, entry, shell, None, config, sampling, false, false, verbose,
+        ) {
+            Ok(correction) => correction.command,
+            Err(e) => {
+                eprintln!("Could not correct '{}': {}", entry, e);
+                continue;
+            }
+        };
+
+        let unsafe_correction = safety::introduced_destructive_pattern(
+            entry,
+            &correction,
+            &config.destructive_patterns,
+        )
+        .is_some();
+        if !correction.is_empty() && correction != *entry && !unsafe_correction {
+            corrected.push((entry.clone(), correction));
+        }
+    }
+
+    Ok(corrected)
+}
+
+/// Time `rounds` passes over `benchmark::SAMPLE_TYPOS` with one loaded model
+///
+/// Tokens are counted from each correction as the model would tokenize it.
+fn run_benchmark_rounds(
+    rounds: usize,
+    shell: &str,
+    model_path: PathBuf,
+    gpu_layers: u32,
+    config: &Config,
+    sampling: SamplingPreset,
+) -> Result<benchmark::BenchmarkReport, Box<dyn std::error::Error>> {
+    suppress_llama_logs();
+
+    let backend = LlamaBackend::init()?;
+    let model_params = LlamaModelParams::default().with_n_gpu_layers(gpu_layers);
+    let model = LlamaModel::load_from_file(&backend, &model_path, &model_params)
+        .map_err(|e| format!("Failed to load model: {}", e))?;
+    let mut ctx = new_context(&model, &backend)?;
+
+    let report = benchmark::run_benchmark(benchmark::SAMPLE_TYPOS, rounds, |sample| {
+        let correction = run_inference(
+            &mut ctx, &model, sample, shell, None, config, sampling, false, false, false,
+        )?;
+        model
+            .str_to_token(&correction.command, llama_cpp_2::model::AddBos::Never)
+            .map(|tokens| tokens.len())
+            .map_err(|e| format!("Tokenization failed: {}", e))
+    })?;
+    Ok(report)
+}
+
+/// Correct `command` in direct mode, timing each startup phase into `profile`
+#[allow(clippy::too_many_arguments)]
+fn run_profiled(
+    profile: &mut StartupProfile,
+    command: &str,
+    shell: &str,
+    error: Option<&str>,
+    model_path: PathBuf,
+    gpu_layers: u32,
+    config: &Config,
+    sampling: SamplingPreset,
+) -> Result<Correction, Box<dyn std::error::Error>> {
+    suppress_llama_logs();
+
+    let backend = profile.time(StartupPhase::BackendInit, LlamaBackend::init)?;
+    let model_params = LlamaModelParams::default().with_n_gpu_layers(gpu_layers);
+    let model = profile
+        .time(StartupPhase::ModelLoad, || {
+            LlamaModel::load_from_file(&backend, &model_path, &model_params)
+        })
+        .map_err(|e| format!("Failed to load model: {}", e))?;
+    let mut ctx = profile.time(StartupPhase::ContextCreation, || {
+        new_context(&model, &backend)
+    })?;
+
+    let correction = profile.time(StartupPhase::Generation, || {
+        run_inference(
+            &mut ctx, &model, command, shell, error, config, sampling, false, false, false,
+        )
+    })?;
+    Ok(correction)
+}
+
+/// Correct `command` once per sampling preset, directly or through the daemon
+fn correct_command(
+    args: &Args,
+    command: &str,
+    shell: &str,
+    model_path: PathBuf,
+    config: &Config,
+    samplings: &[SamplingPreset],
+) -> Result<Vec<Correction>, Box<dyn std::error::Error>> {
+    // Direct mode (always on Windows, or when explicitly requested)
+    #[cfg(not(unix))]
+    let use_direct = true;
+    #[cfg(unix)]
+    let use_direct = !args.once && config.use_direct_mode(args.direct);
+    log_info!(
+        "Model {}, {} mode",
+        model_path.display(),
+        if use_direct { "direct" } else { "daemon" }
+    );
+
+    #[cfg(unix)]
+    if !use_direct {
+        // A daemon that was already running may be serving other sessions
+        let spawned = args.once && !is_daemon_running();
+        let corrections = correct_with_daemon(args, command, shell, &model_path, config, samplings);
+        if spawned {
+            log_debug!("Stopping the daemon started for --once");
+            stop_daemon()?;
+        }
+        return corrections;
+    }
+
+    run_direct(
+        command,
+        shell,
+        args.error.as_deref(),
+        model_path,
+        args.gpu_layers,
+        config,
+        samplings,
+        args.multiline,
+        args.flags_only,
+        args.verbose,
+    )
+}
+
+/// Send one request per sampling preset to the daemon, starting it if needed
+#[cfg(unix)]
+fn correct_with_daemon(
+    args: &Args,
+    command: &str,
+    shell: &str,
+    model_path: &PathBuf,
+    config: &Config,
+    samplings: &[SamplingPreset],
+) -> Result<Vec<Correction>, Box<dyn std::error::Error>> {
+    if !is_daemon_running() {
+        log_debug!("No daemon running, starting one");
+        start_daemon(model_path, args.gpu_layers, config)?;
+    }
+
+    let mut corrections = Vec::new();
+    for &sampling in samplings {
+        let request = DaemonRequest {
+            protocol_version: DAEMON_PROTOCOL_VERSION,
+            command: command.to_string(),
+            shell: shell.to_string(),
+            error: args.error.clone(),
+            sampling,
+            multiline: args.multiline,
+            flags_only: args.flags_only,
+            verbose: args.verbose,
+        };
+
+        let response: DaemonResponse =
+            send_to_daemon(&socket_path(), &request, DAEMON_READ_TIMEOUT, || {
+                if args.verbose {
+                    eprintln!("Restarting daemon left over from another fix version");
+                }
+                stop_daemon()?;
+                start_daemon(model_path, args.gpu_layers, config)
+            })?;
+
+        if !response.success {
+            return Err(response
+                .error
+                .unwrap_or_else(|| "Unknown error".to_string())
+                .into());
+        }
+        corrections.push(Correction {
+            command: response.output,
+            confidence: response.confidence,
+        });
+    }
+    Ok(corrections)
+}
+
+/// `--filter` exit status: the correction was written
+const FILTER_CORRECTED: i32 = 0;
+/// `--filter` exit status: no input, or the model couldn't correct it
+const FILTER_NO_CORRECTION: i32 = 1;
+/// `--filter` exit status: denylisted input or an unsafe correction
+const FILTER_REFUSED: i32 = 2;
+/// `--filter` exit status: the correction failed (missing model, daemon error)
+const FILTER_FAILED: i32 = 3;
+
+/// Run `--filter`: correct the command on stdin, writing exactly one line
+///
+/// Returns the exit status; stdout gets the correction or an empty line,
+/// and every message goes to stderr.
+fn run_filter(args: &Args, model_dir: &Path, config: &Config) -> i32 {
+    let (status, correction) = match filter_correction(args, model_dir, config) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            (FILTER_FAILED, String::new())
+        }
+    };
+    println!("{}", correction);
+    status
+}
+
+/// Exit status and output line for `--filter`
+fn filter_correction(
+    args: &Args,
+    model_dir: &Path,
+    config: &Config,
+) -> Result<(i32, String), Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+    let command = input.trim();
+    if command.is_empty() {
+        eprintln!("No command on stdin");
+        return Ok((FILTER_NO_CORRECTION, String::new()));
+    }
+    if let Some(pattern) = safety::denied_input(command, &config.input_denylist)? {
+        eprintln!(
+            "Not processing input matching input_denylist pattern '{}'",
+            pattern
+        );
+        return Ok((FILTER_REFUSED, String::new()));
+    }
+
+    let shell = args.shell.clone().unwrap_or_else(detect_shell);
+    let model_path = resolve_model_path(args, model_dir, config)?;
+    let mut corrections =
+        correct_command(args, command, &shell, model_path, config, &[args.sampling])?;
+    if args.flags_only {
+        corrections.retain(|c| preserves_command_word(command, &c.command));
+    }
+    if args.preserve_args {
+        preserve_args(command, &mut corrections);
+    }
+
+    // Only ever one line, even if the model rambled past the command
+    let correction = corrections
+        .first()
+        .and_then(|c| c.command.lines().map(str::trim).find(|l| !l.is_empty()))
+        .unwrap_or_default()
+        .to_string();
+    if correction.is_empty() {
+        eprintln!("Could not correct command");
+        return Ok((FILTER_NO_CORRECTION, String::new()));
+    }
+    if let Some(pattern) =
+        safety::introduced_destructive_pattern(command, &correction, &config.destructive_patterns)
+    {
+        eprintln!(
+            "Refusing correction '{}': it adds '{}', which the original command didn't use",
+            correction, pattern
+        );
+        return Ok((FILTER_REFUSED, String::new()));
+    }
+    Ok((FILTER_CORRECTED, correction))
+}
+
+/// Find the model to run: `--model-url`, `--model`, or the configured default
+fn resolve_model_path(args: &Args, model_dir: &Path, config: &Config) -> Result<PathBuf, String> {
+    match args.model_url {
+        Some(ref url) => find_or_download_model_url(model_dir, url, args.update, args.keep_partial),
+        None => find_model_path(
+            model_dir,
+            args.model.clone(),
+            config,
+            config.model_revision(args.revision.as_deref()),
+            args.update,
+            should_confirm_download(is_interactive(), args.yes),
+            args.keep_partial,
+        ),
+    }
+}
+
+/// Report whether llama.cpp can offload layers to a GPU
+fn check_gpu() -> CheckOutcome {
+    suppress_llama_logs();
+    let backend =
+        LlamaBackend::init().map_err(|e| format!("Failed to initialize backend: {}", e))?;
+    if backend.supports_gpu_offload() {
+        Ok("GPU offload supported".to_string())
+    } else {
+        Ok("no GPU offload, running on CPU".to_string())
+    }
+}
+
+/// Run one known correction through the model
+fn check_known_correction(model_path: &Path, gpu_layers: u32, config: &Config) -> CheckOutcome {
+    if !model_path.exists() {
+        return Err("model not downloaded".to_string());
+    }
+
+    let result = run_direct(
+        DOCTOR_INPUT,
+        "bash",
+        None,
+        model_path.to_path_buf(),
+        gpu_layers,
+        config,
+        &[SamplingPreset::Greedy],
+        false,
+        false,
+        false,
+    )
+    .map_err(|e| e.to_string())?
+    .into_iter()
+    .next()
+    .map(|correction| correction.command)
+    .unwrap_or_default();
+
+    if result == DOCTOR_EXPECTED {
+        Ok(format!("{} → {}", DOCTOR_INPUT, result))
+    } else {
+        Err(format!(
+            "expected '{}' for '{}', got '{}'",
+            DOCTOR_EXPECTED, DOCTOR_INPUT, result
+        ))
+    }
+}
+
+/// Put the user's arguments back on every correction, for `--preserve-args`
+fn preserve_args(command: &str, corrections: &mut [Correction]) {
+    for correction in corrections {
+        correction.command = reattach_args(command, &correction.command);
+    }
+}
+
+/// Print the single correction, or the ranked list when `--suggestions` is set
+///
+/// Returns the correction printed, or the top one of the list, which is what
+/// the user saw; unsafe candidates dropped from the list never are.
+fn print_corrections(
+    command: &str,
+    corrections: Vec<Correction>,
+    config: &Config,
+    ranked: bool,
+    verbose: bool,
+    json: bool,
+) -> Option<String> {
+    if !ranked {
+        let correction = corrections.into_iter().next()?;
+        print_correction(command, &correction, config, verbose, json);
+        return Some(correction.command);
+    }
+
+    // Unsafe suggestions are dropped rather than refusing the whole list
+    let candidates = corrections
+        .into_iter()
+        .filter(|c| {
+            safety::introduced_destructive_pattern(
+                command,
+                &c.command,
+                &config.destructive_patterns,
+            )
+            .is_none()
+        })
+        .map(|c| Candidate {
+            correction: c.command,
+            confidence: c.confidence,
+        })
+        .collect();
+    let candidates = rank_candidates(candidates);
+
+    if candidates.is_empty() {
+        eprintln!("Could not correct command");
+        std::process::exit(1);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string(&candidates).unwrap());
+    } else {
+        for candidate in &candidates {
+            match (verbose, candidate.confidence) {
+                (true, Some(confidence)) => {
+                    println!("{}  ({:.2})", candidate.correction, confidence)
+                }
+                _ => println!("{}", candidate.correction),
+            }
+        }
+    }
+    candidates.into_iter().next().map(|c| c.correction)
+}
+
+/// Ask whether to run `correction` and run it in `shell`, exiting with its status
+fn offer_to_run(shell: &str, correction: &str) {
+    if !confirm("Run it? [Y/n] ") {
+        return;
+    }
+
+    let argv = match Shell::parse(shell) {
+        Some(Shell::PowerShell) => {
+            let flavor = ToolExecutor::new(Shell::PowerShell)
+                .powershell_flavor()
+                .unwrap_or(PowerShellFlavor::Core);
+            Shell::PowerShell.invocation(correction, flavor)
+        }
+        Some(parsed) => parsed.invocation(correction, PowerShellFlavor::Core),
+        // Other POSIX shells (sh, dash, ksh) all take -c
+        None => vec![shell.to_string(), "-c".to_string(), correction.to_string()],
+    };
+
+    match std::process::Command::new(&argv[0])
+        .args(&argv[1..])
+        .status()
+    {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("Failed to run '{}': {}", argv[0], e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print the correction, or exit non-zero if there is none or it is unsafe
+///
+/// With `verbose` the model's confidence goes to stderr; with `json` the
+/// correction and confidence are printed together as a JSON object.
+fn print_correction(
+    command: &str,
+    correction: &Correction,
+    config: &Config,
+    verbose: bool,
+    json: bool,
+) {
+    let Correction {
+        command: correction,
+        confidence,
+    } = correction;
+
+    if correction.is_empty() {
+        eprintln!("Could not correct command");
+        std::process::exit(1);
+    }
+
+    if let Some(pattern) =
+        safety::introduced_destructive_pattern(command, correction, &config.destructive_patterns)
+    {
+        eprintln!(
+            "Refusing correction '{}': it adds '{}', which the original command didn't use",
+            correction, pattern
+        );
+        std::process::exit(2);
+    }
+
+    if verbose {
+        if let Some(confidence) = confidence {
+            eprintln!("Confidence: {:.2}", confidence);
+        }
+    }
+
+    if json {
+        let json = serde_json::json!({
+            "command": correction,
+            "confidence": confidence,
+        });
+        println!("{}", json);
+    } else {
+        println!("{}", correction);
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let mut config = load_config();
+
+    if let Some(shell) = args.completions {
+        clap_complete::generate(shell, &mut Args::command(), "fix", &mut std::io::stdout());
+        return Ok(());
+    }
+
+    let interactive = is_interactive();
+    progress::set_color(interactive_default(args.color.explicit(), interactive));
+
+    let mut profile = StartupProfile::new();
+    let mut config = profile.time(StartupPhase::ConfigLoad, load_config);
+    if config.use_utf8_console(args.no_utf8_console) {
+        enable_utf8_console();
+    }
+    let model_dir = model_dir(args.model_dir.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    init_proxy(args.proxy.as_deref());
+    if let Ok(path) = config_path() {
+        log_debug!("Config file: {}", path.display());
+    }
 
     // Handle daemon mode (internal, Unix only)
     #[cfg(unix)]
     if args.daemon {
         let model_path = args
             .model
-            .unwrap_or_else(|| get_model_path(&config.default_model));
-        return run_daemon(model_path, args.gpu_layers);
+            .unwrap_or_else(|| get_model_path(&model_dir, &config.default_model));
+        return run_daemon(model_path, args.gpu_layers, &config);
     }
 
     // Handle --stop flag (Unix only)
@@ -509,7 +1298,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         #[cfg(unix)]
         {
             if is_daemon_running() {
-                println!("Daemon: running");
+                match daemon::ping(&socket_path()) {
+                    Ok(()) => println!("Daemon: running"),
+                    Err(e) => println!("Daemon: running, not responding ({})", e),
+                }
                 println!("Socket: {}", socket_path().display());
                 println!("PID file: {}", pid_path().display());
             } else {
@@ -523,17 +1315,62 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // Handle --ping flag (Unix only)
+    if args.ping {
+        #[cfg(unix)]
+        {
+            if let Err(e) = daemon::ping(&socket_path()) {
+                eprintln!("Daemon: not responding ({})", e);
+                std::process::exit(1);
+            }
+            println!("Daemon: responding");
+        }
+        #[cfg(not(unix))]
+        {
+            println!("Daemon mode is not supported on Windows");
+        }
+        return Ok(());
+    }
+
+    if args.doctor {
+        let model_path = get_model_path(&model_dir, &config.default_model);
+        let mut checks = doctor::standard_checks(
+            &model_dir,
+            &config.default_model,
+            config.model_revision(args.revision.as_deref()),
+        );
+        checks.push(Check::new("GPU", check_gpu));
+        checks.push(Check::new("Known correction", || {
+            check_known_correction(&model_path, args.gpu_layers, &config)
+        }));
+
+        println!("Running diagnostics...");
+        if !doctor::run_checks(&checks, &mut std::io::stdout()) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     // Handle management commands
     if args.list_models {
-        list_models(&config)?;
+        list_models(
+            &config,
+            config.model_revision(args.revision.as_deref()),
+            args.since,
+        )?;
+        return Ok(());
+    }
+
+    if args.print_prompt_template {
+        println!("{}", config.system_prompt_template());
         return Ok(());
     }
 
     if args.show_config {
-        let model_path = get_model_path(&config.default_model);
+        let model_path = get_model_path(&model_dir, &config.default_model);
         println!("Configuration:");
         println!("  Default model: {}", config.default_model);
-        println!("  Config path: {}", config_path().display());
+        println!("  Config path: {}", config_path()?.display());
         if model_path.exists() {
             println!("  Model path: {}", model_path.display());
         } else {
@@ -541,6 +1378,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         #[cfg(unix)]
         {
+            println!("  Daemon enabled: {}", config.use_daemon);
             println!("  Daemon running: {}", is_daemon_running());
             println!("  Socket: {}", socket_path().display());
         }
@@ -551,10 +1389,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    if let Some(ref state) = args.set_daemon {
+        config.use_daemon = state == "on";
+        save_config(&config)?;
+
+        #[cfg(unix)]
+        if !config.use_daemon && is_daemon_running() {
+            stop_daemon()?;
+        }
+
+        if config.use_daemon {
+            eprintln!("✓ Daemon enabled");
+        } else {
+            eprintln!("✓ Daemon disabled, models will load on each run");
+        }
+        return Ok(());
+    }
+
     if let Some(ref model_name) = args.use_model {
         eprintln!("Checking model availability...");
-        validate_model_exists(model_name)?;
-        download_model(model_name)?;
+        let revision = config.model_revision(args.revision.as_deref()).to_string();
+        validate_model_exists(model_name, &revision)?;
+        download_model(&model_dir, model_name, &revision, args.keep_partial)?;
         config.default_model = model_name.clone();
         save_config(&config)?;
         eprintln!("✓ Default model set to: {}", model_name);
@@ -567,90 +1423,142 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // Correct recent history entries with one loaded model
+    if let Some(ref history_file) = args.history_file {
+        let contents = std::fs::read(history_file)
+            .map_err(|e| format!("Failed to read {}: {}", history_file.display(), e))?;
+        let entries = parse_history(&String::from_utf8_lossy(&contents));
+        let mut recent = Vec::new();
+        for entry in &entries[entries.len().saturating_sub(args.last)..] {
+            if safety::denied_input(entry, &config.input_denylist)?.is_none() {
+                recent.push(entry.clone());
+            }
+        }
+
+        let model_path = resolve_model_path(&args, &model_dir, &config)?;
+        let shell = args.shell.clone().unwrap_or_else(detect_shell);
+        let corrected = run_history(
+            &recent,
+            &shell,
+            model_path,
+            args.gpu_layers,
+            &config,
+            args.sampling,
+            args.verbose,
+        )?;
+
+        for (original, correction) in corrected {
+            println!("{} -> {}", original, correction);
+        }
+        return Ok(());
+    }
+
+    if let Some(rounds) = args.benchmark {
+        let model_path = resolve_model_path(&args, &model_dir, &config)?;
+        let shell = args.shell.clone().unwrap_or_else(detect_shell);
+        let report = run_benchmark_rounds(
+            rounds.into(),
+            &shell,
+            model_path,
+            args.gpu_layers,
+            &config,
+            args.sampling,
+        )?;
+        print!("{}", benchmark::format_report(&report));
+        return Ok(());
+    }
+
+    if args.filter {
+        std::process::exit(run_filter(&args, &model_dir, &config));
+    }
+
     // For inference, command is required
     if args.command.is_empty() {
         eprintln!("Usage: fix <command>");
         eprintln!("       fix --list-models");
         eprintln!("       fix --use-model <name>");
         eprintln!("       fix --show-config");
+        eprintln!("       fix --doctor");
         #[cfg(unix)]
         {
             eprintln!("       fix --stop          # Unload model from memory");
             eprintln!("       fix --status        # Show daemon status");
             eprintln!("       fix --direct <cmd>  # Run without daemon");
         }
+        eprintln!("       fix --history-file <path> --last <n>");
         std::process::exit(1);
     }
 
     let command = args.command.join(" ");
-    let shell = args.shell.unwrap_or_else(detect_shell);
+    if let Some(pattern) = safety::denied_input(&command, &config.input_denylist)? {
+        eprintln!(
+            "Not processing input matching input_denylist pattern '{}'",
+            pattern
+        );
+        std::process::exit(1);
+    }
+    let shell = args.shell.clone().unwrap_or_else(detect_shell);
 
     if args.verbose {
         eprintln!("Shell: {}", shell);
         eprintln!("Command: {}", command);
     }
 
-    // Find or download model
-    let model_path = find_model_path(args.model, &config, args.update)?;
-
-    // Direct mode (always on Windows, or when explicitly requested)
-    #[cfg(not(unix))]
-    let use_direct = true;
-    #[cfg(unix)]
-    let use_direct = args.direct;
+    let model_path = profile.time(StartupPhase::ModelResolution, || {
+        resolve_model_path(&args, &model_dir, &config)
+    })?;
 
-    if use_direct {
-        let result = run_direct(
+    if args.profile_startup {
+        let correction = run_profiled(
+            &mut profile,
             &command,
             &shell,
             args.error.as_deref(),
             model_path,
             args.gpu_layers,
-            args.verbose,
+            &config,
+            args.sampling,
         )?;
-
-        if !result.is_empty() {
-            println!("{}", result);
-        } else {
-            eprintln!("Could not correct command");
-            std::process::exit(1);
-        }
+        println!("{}", correction.command);
+        eprint!("{}", benchmark::format_profile(&profile));
         return Ok(());
     }
 
-    // Daemon mode (Unix only, default)
-    #[cfg(unix)]
-    {
-        if !is_daemon_running() {
-            start_daemon(&model_path, args.gpu_layers)?;
-        }
-
-        let request = DaemonRequest {
-            command: command.clone(),
-            shell,
-            error: args.error,
-            verbose: args.verbose,
-        };
-
-        let response = send_to_daemon(&request)?;
+    let samplings = args
+        .sampling
+        .for_suggestions(args.suggestions.map_or(1, usize::from));
+    let mut corrections = correct_command(&args, &command, &shell, model_path, &config, &samplings)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
 
-        if response.success {
-            if !response.output.is_empty() {
-                println!("{}", response.output);
-            } else {
-                eprintln!("Could not correct command");
-                std::process::exit(1);
-            }
-        } else {
+    if args.flags_only {
+        corrections.retain(|c| preserves_command_word(&command, &c.command));
+        if corrections.is_empty() {
             eprintln!(
-                "Error: {}",
-                response
-                    .error
-                    .unwrap_or_else(|| "Unknown error".to_string())
+                "Could not correct command: the model changed the command word, not just flags"
             );
             std::process::exit(1);
         }
     }
+    if args.preserve_args {
+        preserve_args(&command, &mut corrections);
+    }
+    let shown = print_corrections(
+        &command,
+        corrections,
+        &config,
+        args.suggestions.is_some(),
+        args.verbose,
+        args.json,
+    );
+    if let (Some(path), Some(correction)) = (&args.save_output, &shown) {
+        append_correction_record(path, &CorrectionRecord::new(&command, correction, &shell))?;
+    }
+    if let (true, Some(correction)) = (args.interactive, shown) {
+        offer_to_run(&shell, &correction);
+    }
 
     Ok(())
 }
@@ -6,15 +6,35 @@
 //! Note: Daemon mode is only available on Unix systems. On Windows, direct mode
 //! is always used.
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use fix_lib::agent::{agentic_correct_with, write_trace};
+#[cfg(unix)]
+use fix_lib::daemon;
+use fix_lib::parser::{command_from_thinking, strip_prompt_marker};
+use fix_lib::sampling::{GenerationState, SamplingPreset, ThinkingTags};
 #[cfg(unix)]
 use fix_lib::stderr_redirect;
 use fix_lib::{
-    cache, config_path, detect_shell, discovery, download_model, find_or_download_model,
-    get_model_path, load_config, progress::ProgressSpinner, save_config, suppress_llama_logs,
-    tools::Shell, tools::Tool, tools::ToolExecutor, validate_model_exists, WIT_DEFAULT_MODEL,
+    cache, check_model_file, check_prompt_fits, command_in_path, config_path, detect_shell,
+    discovery, download_model, enable_utf8_console, escape_special_tokens, expand_abbreviation,
+    find_or_download_model, find_or_download_model_url, get_model_path, init_proxy,
+    interactive_default, is_interactive, levenshtein_distance, load_config, log_debug, log_info,
+    merge_minimal_edit, model_dir, prefer_original, prepend_env_assignments,
+    progress::{self, ColorMode, ProgressSpinner},
+    resolve_daemon_executable, safety, save_config, should_confirm_download, split_command_args,
+    split_env_assignments, suppress_llama_logs,
+    tools::not_found_error,
+    tools::output_limit,
+    tools::Shell,
+    tools::Tool,
+    tools::ToolExecutor,
+    unescape_special_tokens, validate_model_exists, Config, PromptFormat, CHAT_TEMPLATE_KEY,
+    TYPO_MAX_DISTANCE, WIT_DEFAULT_MODEL,
 };
+#[cfg(unix)]
+use fix_lib::{send_to_daemon, DAEMON_PROTOCOL_VERSION};
 use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
@@ -25,21 +45,22 @@ use serde::{Deserialize, Serialize};
 #[cfg(unix)]
 use std::fs;
 #[cfg(unix)]
-use std::io::{BufRead, BufReader, Write};
+use std::io::Write;
 #[cfg(unix)]
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
 use std::path::PathBuf;
-#[cfg(unix)]
-use std::sync::atomic::{AtomicBool, Ordering};
-#[cfg(unix)]
-use std::sync::{Arc, Mutex};
-#[cfg(unix)]
-use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Idle timeout before daemon auto-shuts down (1 hour)
 #[cfg(unix)]
 const IDLE_TIMEOUT_SECS: u64 = 3600;
 
+/// Longest a client waits for the daemon's diagnosis, tool calls included
+#[cfg(unix)]
+const DAEMON_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
 /// Socket path for daemon communication
 #[cfg(unix)]
 fn socket_path() -> PathBuf {
@@ -68,14 +89,54 @@ struct Args {
     #[arg(short, long)]
     shell: Option<String>,
 
+    /// Error message from the failed command (optional)
+    #[arg(short, long, conflicts_with = "explain_only")]
+    error: Option<String>,
+
     /// Path to a local GGUF model file (overrides default)
     #[arg(short, long)]
     model: Option<PathBuf>,
 
+    /// Directory to find and download models in (overrides $FIX_MODEL_DIR)
+    #[arg(long, value_name = "PATH")]
+    model_dir: Option<PathBuf>,
+
+    /// Download a GGUF model from any URL (cached locally) and use it
+    #[arg(long, conflicts_with = "model")]
+    model_url: Option<String>,
+
+    /// Proxy for model downloads (overrides $HTTPS_PROXY and $HTTP_PROXY)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// HuggingFace branch, tag or commit to download models from (default: main)
+    #[arg(long, value_name = "SHA|TAG")]
+    revision: Option<String>,
+
+    /// Force re-download of the wit model, stopping the daemon so it reloads
+    #[arg(long, conflicts_with = "model")]
+    update: bool,
+
+    /// Download a missing model without asking
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Color progress output: auto (only when interactive), always or never
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Keep the partial .tmp file when a download fails, for debugging
+    #[arg(long)]
+    keep_partial: bool,
+
     /// Number of GPU layers to offload (default: all)
     #[arg(long, default_value = "99")]
     gpu_layers: u32,
 
+    /// Sampling preset: greedy (deterministic), balanced or creative
+    #[arg(long, value_enum, default_value_t = SamplingPreset::Greedy)]
+    sampling: SamplingPreset,
+
     /// Show verbose output
     #[arg(short, long)]
     verbose: bool,
@@ -88,10 +149,22 @@ struct Args {
     #[arg(long)]
     show_config: bool,
 
+    /// Print a tab-completion script for wit's flags and exit
+    #[arg(long, value_enum, value_name = "SHELL")]
+    completions: Option<clap_complete::Shell>,
+
     /// Refresh the tool discovery cache
     #[arg(long)]
     refresh_tools: bool,
 
+    /// With --refresh-tools, keep cached tools the new scan doesn't find
+    #[arg(long, requires = "refresh_tools")]
+    append_only: bool,
+
+    /// List the tools in the discovery cache
+    #[arg(long)]
+    list_tools: bool,
+
     /// Download and set wit model as default
     #[arg(long)]
     use_model: Option<String>,
@@ -104,21 +177,88 @@ struct Args {
     #[arg(long)]
     status: bool,
 
+    /// Check that the daemon answers; exits non-zero if it doesn't
+    #[arg(long)]
+    ping: bool,
+
     /// Run in direct mode (no daemon, load model each time)
     #[arg(long)]
     direct: bool,
 
+    /// Leave the Windows console's code page alone instead of switching to UTF-8
+    #[arg(long)]
+    no_utf8_console: bool,
+
+    /// Permanently enable or disable the background daemon
+    #[arg(long, value_name = "on|off", value_parser = ["on", "off"])]
+    set_daemon: Option<String>,
+
+    /// Extra tool result to include in the prompt, as NAME=OUTPUT (repeatable)
+    #[arg(long = "extra-tool-result", value_name = "NAME=OUTPUT", value_parser = parse_tool_result)]
+    extra_tool_results: Vec<(String, String)>,
+
+    /// Print the prompt that would be sent to the model and exit
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Correct with the agent's tool-calling loop and write the conversation
+    /// to PATH as JSON, for debugging (runs in direct mode)
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["dry_run", "explain_only", "nl"])]
+    trace: Option<PathBuf>,
+
+    /// Describe what the command does in one line instead of correcting it
+    #[arg(long)]
+    explain_only: bool,
+
+    /// Treat the input as a description to write a command for, e.g.
+    /// "list all pdf files modified today"
+    #[arg(long, visible_alias = "generate", conflicts_with_all = ["explain_only", "error"])]
+    nl: bool,
+
+    /// For git commands, show the model the repository's branch and remote names
+    #[arg(long)]
+    git_context: bool,
+
+    /// Run at most N tools, keeping the most useful ones, to bound latency
+    #[arg(long, value_name = "N")]
+    max_tools: Option<usize>,
+
     /// Run as daemon (internal use)
     #[arg(long, hide = true)]
     daemon: bool,
 }
 
+/// Parse a `NAME=OUTPUT` pair for `--extra-tool-result`
+fn parse_tool_result(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, output)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), output.to_string()))
+        }
+        _ => Err(format!("expected NAME=OUTPUT, got '{}'", s)),
+    }
+}
+
 /// Request sent to daemon
 #[cfg(unix)]
 #[derive(Serialize, Deserialize, Debug)]
 struct DaemonRequest {
+    /// `DAEMON_PROTOCOL_VERSION` of the client
+    #[serde(default)]
+    protocol_version: u32,
     command: String,
     shell: String,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    sampling: SamplingPreset,
+    #[serde(default)]
+    extra_tool_results: Vec<(String, String)>,
+    #[serde(default)]
+    explain_only: bool,
+    #[serde(default)]
+    natural_language: bool,
+    #[serde(default)]
+    max_tools: Option<usize>,
     verbose: bool,
 }
 
@@ -126,6 +266,9 @@ struct DaemonRequest {
 #[cfg(unix)]
 #[derive(Serialize, Deserialize, Debug)]
 struct DaemonResponse {
+    /// `DAEMON_PROTOCOL_VERSION` of the daemon; 0 from daemons that predate it
+    #[serde(default)]
+    protocol_version: u32,
     success: bool,
     output: String,
     error: Option<String>,
@@ -158,10 +301,39 @@ fn is_daemon_running() -> bool {
     false
 }
 
+/// Describe the priority tools within `timeout`, then scan the rest in a
+/// detached `wit --refresh-tools` that outlives this correction
+fn first_run_discovery(config: &Config, timeout: Duration) {
+    let quick = discovery::discover_priority_tools(timeout);
+    log_debug!("First run: described {} priority tools", quick.tools.len());
+    if let Err(e) = cache::save_cache(&quick) {
+        log_debug!("Not saving first-run tools: {}", e);
+        return;
+    }
+
+    let refresh = std::env::current_exe()
+        .map_err(|e| e.to_string())
+        .and_then(|current| resolve_daemon_executable(&current, "wit", config.bin_dir.as_deref()))
+        .and_then(|exe| {
+            std::process::Command::new(exe)
+                .args(["--refresh-tools", "--append-only", "--quiet"])
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .spawn()
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = refresh {
+        log_debug!("Background tool refresh not started: {}", e);
+    }
+}
+
 /// Start daemon in background
 #[cfg(unix)]
-fn start_daemon(model_path: &PathBuf, gpu_layers: u32) -> Result<(), String> {
-    let exe = std::env::current_exe().map_err(|e| format!("Failed to get executable: {}", e))?;
+fn start_daemon(model_path: &PathBuf, gpu_layers: u32, config: &Config) -> Result<(), String> {
+    let current =
+        std::env::current_exe().map_err(|e| format!("Failed to get executable: {}", e))?;
+    let exe = resolve_daemon_executable(&current, "wit", config.bin_dir.as_deref())?;
 
     let child = std::process::Command::new(&exe)
         .arg("--daemon")
@@ -218,32 +390,73 @@ fn stop_daemon() -> Result<(), String> {
     Ok(())
 }
 
-/// Send request to daemon
-#[cfg(unix)]
-fn send_to_daemon(request: &DaemonRequest) -> Result<DaemonResponse, String> {
-    let mut stream =
-        UnixStream::connect(socket_path()).map_err(|e| format!("Failed to connect: {}", e))?;
-
-    stream
-        .set_read_timeout(Some(Duration::from_secs(60)))
-        .map_err(|e| format!("Failed to set timeout: {}", e))?;
-
-    let request_json =
-        serde_json::to_string(request).map_err(|e| format!("Failed to serialize: {}", e))?;
+/// Find the wit model, from `model_url` if given, downloading it if missing or forced
+fn download_wit_model(
+    model_dir: &Path,
+    model_url: Option<&str>,
+    revision: &str,
+    force_download: bool,
+    confirm: bool,
+    keep_partial: bool,
+) -> Result<PathBuf, String> {
+    match model_url {
+        Some(url) => find_or_download_model_url(model_dir, url, force_download, keep_partial),
+        None => find_or_download_model(
+            model_dir,
+            WIT_DEFAULT_MODEL,
+            revision,
+            force_download,
+            confirm,
+            keep_partial,
+        ),
+    }
+}
 
-    writeln!(stream, "{}", request_json).map_err(|e| format!("Failed to send: {}", e))?;
+/// Render the cached tools as a sorted, aligned list headed by the cache age
+fn format_tool_list(tools_cache: &cache::ToolsCache) -> String {
+    let age = tools_cache
+        .age()
+        .map(|age| format!("updated {} ago", format_age(age)))
+        .unwrap_or_else(|_| "update time unknown".to_string());
+    let mut out = format!("Cached tools ({}, {}):\n", tools_cache.tools.len(), age);
+
+    let mut names: Vec<&String> = tools_cache.tools.keys().collect();
+    names.sort();
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+
+    for name in names {
+        let desc = &tools_cache.tools[name].desc;
+        let line = format!("  {:width$}  {}", name, desc, width = width);
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
 
-    let mut reader = BufReader::new(stream);
-    let mut response_line = String::new();
-    reader
-        .read_line(&mut response_line)
-        .map_err(|e| format!("Failed to read response: {}", e))?;
+    out
+}
 
-    serde_json::from_str(&response_line).map_err(|e| format!("Failed to parse response: {}", e))
+/// Format a duration in its largest whole unit (e.g. "3h")
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
 }
 
 /// Analyze input command and determine which tools to run
-fn select_tools_for_input(input: &str, shell: Shell) -> Vec<Tool> {
+///
+/// `command_exists` reports whether a word resolves to an installed command.
+fn select_tools_for_input<F>(
+    input: &str,
+    shell: Shell,
+    error: Option<&str>,
+    command_exists: F,
+) -> Vec<Tool>
+where
+    F: Fn(&str) -> bool,
+{
     let mut tools = Vec::new();
     let words: Vec<&str> = input.split_whitespace().collect();
 
@@ -252,6 +465,8 @@ fn select_tools_for_input(input: &str, shell: Shell) -> Vec<Tool> {
     }
 
     let first_word = words[0];
+    // The shell already told us the first word isn't a command
+    let not_found = error.is_some_and(is_command_not_found);
 
     // Always check if the first word might be a typo of a real command
     tools.push(Tool::ListSimilar {
@@ -259,7 +474,7 @@ fn select_tools_for_input(input: &str, shell: Shell) -> Vec<Tool> {
     });
 
     // Try to find the binary for common corrections
-    if first_word.len() >= 2 {
+    if first_word.len() >= 2 || not_found {
         tools.push(Tool::WhichBinary {
             command: first_word.to_string(),
         });
@@ -290,75 +505,254 @@ fn select_tools_for_input(input: &str, shell: Shell) -> Vec<Tool> {
         });
     }
 
+    // Build tools fail outside their project; say whether its file is here
+    if let Some(marker) = project_marker(first_word) {
+        tools.push(Tool::FileExists {
+            path: marker.to_string(),
+        });
+    }
+
+    // A command that exists as typed may still have a flag typo (`git comit`),
+    // so give the model its help output to correct against
+    if words.len() > 1 && !not_found && command_exists(first_word) {
+        let help = Tool::HelpOutput {
+            command: first_word.to_string(),
+        };
+        if !tools.contains(&help) {
+            tools.push(help);
+        }
+    }
+
     tools
 }
 
-/// Simple Levenshtein distance for typo detection
-fn levenshtein_distance(a: &str, b: &str) -> usize {
-    let a_chars: Vec<char> = a.chars().collect();
-    let b_chars: Vec<char> = b.chars().collect();
-    let a_len = a_chars.len();
-    let b_len = b_chars.len();
-
-    if a_len == 0 {
-        return b_len;
+/// The file a build tool needs in the current directory, like `Cargo.toml` for cargo
+fn project_marker(command: &str) -> Option<&'static str> {
+    match command {
+        "cargo" => Some("Cargo.toml"),
+        "npm" | "yarn" | "pnpm" => Some("package.json"),
+        "make" => Some("Makefile"),
+        _ => None,
     }
-    if b_len == 0 {
-        return a_len;
+}
+
+/// Whether `input` is plainly a valid command, so the tools can be skipped
+///
+/// The first word must exist as typed, and nothing else may hint at a typo:
+/// no unbalanced quotes, dangling operator or letter typed three times in a
+/// row. Bare words after the command could be mistyped subcommands and flags
+/// could be mistyped flags (`ls --colr`), which only its help output would
+/// reveal, so inputs with either never count.
+fn obviously_valid<F>(input: &str, command_exists: F) -> bool
+where
+    F: Fn(&str) -> bool,
+{
+    let mut words = input.split_whitespace();
+    let Some(first_word) = words.next() else {
+        return false;
+    };
+
+    let unbalanced_quotes = ['\'', '"']
+        .iter()
+        .any(|quote| input.matches(*quote).count() % 2 != 0);
+    let dangling_operator = ["|", "&&", "||", "\\"]
+        .iter()
+        .any(|op| input.trim_end().ends_with(op));
+    let tripled_letter = input
+        .as_bytes()
+        .windows(3)
+        .any(|w| w[0].is_ascii_alphabetic() && w[0] == w[1] && w[1] == w[2]);
+    // Paths, numbers and quoted arguments can't be subcommands or flags
+    let only_arguments = words.all(|word| {
+        !word.starts_with('-')
+            && (word.starts_with(['/', '.', '~', '$', '\'', '"'])
+                || word.contains(['/', '.', '='])
+                || word.chars().all(|c| c.is_ascii_digit()))
+    });
+
+    !unbalanced_quotes
+        && !dangling_operator
+        && !tripled_letter
+        && only_arguments
+        && command_exists(first_word)
+}
+
+/// Whether a shell error says the command itself doesn't exist
+///
+/// Covers the POSIX shells' "command not found", fish's "Unknown command"
+/// and cmd/PowerShell's "is not recognized".
+fn is_command_not_found(error: &str) -> bool {
+    let error = error.to_lowercase();
+    ["command not found", "unknown command", "is not recognized"]
+        .iter()
+        .any(|pattern| error.contains(pattern))
+}
+
+/// Rank of a tool when `--max-tools` cuts the selection; lower is kept first
+///
+/// Typo candidates and binary lookups answer the common case cheaply; help
+/// and man pages are slower and mostly matter for flag typos.
+fn tool_priority(tool: &Tool) -> u8 {
+    match tool {
+        Tool::ListSimilar { .. } => 0,
+        Tool::WhichBinary { .. } => 1,
+        Tool::HelpOutput { .. } => 2,
+        Tool::GetEnvVar { .. } => 3,
+        Tool::ManPage { .. } => 4,
+        Tool::FileExists { .. } => 5,
     }
+}
 
-    let mut matrix = vec![vec![0usize; b_len + 1]; a_len + 1];
+/// Keep the `max` highest-priority tools, in their original order
+fn limit_tools(tools: Vec<Tool>, max: Option<usize>) -> Vec<Tool> {
+    let max = match max {
+        Some(max) if max < tools.len() => max,
+        _ => return tools,
+    };
+
+    let mut ranked: Vec<(usize, Tool)> = tools.into_iter().enumerate().collect();
+    ranked.sort_by_key(|(i, tool)| (tool_priority(tool), *i));
+    ranked.truncate(max);
+    ranked.sort_by_key(|(i, _)| *i);
+    ranked.into_iter().map(|(_, tool)| tool).collect()
+}
 
-    for (i, row) in matrix.iter_mut().enumerate().take(a_len + 1) {
-        row[0] = i;
+/// Subcommand names listed in help output
+///
+/// Picks indented lines of the form `  name   description`, as printed by
+/// git, docker and cargo (`build, b    Compile...` yields `build`).
+fn subcommands_from_help(help: &str) -> Vec<&str> {
+    help.lines()
+        .filter(|line| line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let trimmed = line.trim_start();
+            let (name, rest) = trimmed.split_once(char::is_whitespace)?;
+            let name = name.trim_end_matches(',');
+            let is_name = name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                && name.starts_with(|c: char| c.is_ascii_lowercase());
+            // Descriptions are separated by a run of spaces (or an alias list)
+            let has_description = rest.starts_with(' ') || trimmed.contains(", ");
+            (is_name && has_description).then_some(name)
+        })
+        .collect()
+}
+
+/// Subcommands from `help` within typo distance of `subcommand`, closest first
+///
+/// Empty when `subcommand` is itself a listed subcommand.
+fn similar_subcommands<'a>(subcommand: &str, help: &'a str) -> Vec<&'a str> {
+    let known = subcommands_from_help(help);
+    if known.contains(&subcommand) {
+        return Vec::new();
     }
-    #[allow(clippy::needless_range_loop)]
-    for j in 0..=b_len {
-        matrix[0][j] = j;
+
+    let mut similar: Vec<(usize, &str)> = known
+        .into_iter()
+        .map(|name| (levenshtein_distance(subcommand, name), name))
+        .filter(|(distance, _)| *distance <= TYPO_MAX_DISTANCE)
+        .collect();
+    similar.sort();
+    similar.dedup();
+    similar.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Whether `--git-context` applies to `input`; only git commands get it
+fn wants_git_context(input: &str, enabled: bool) -> bool {
+    let (_, command) = split_env_assignments(input);
+    enabled && split_command_args(command).0 == "git"
+}
+
+/// Build wit prompt with tool results in the training format
+fn build_wit_prompt(
+    format: PromptFormat,
+    shell: &str,
+    input: &str,
+    error: Option<&str>,
+    tool_results: &[(String, String)],
+) -> String {
+    let hint = Shell::parse(shell).map_or("", |shell| shell.prompt_hint());
+    let mut system = format!(
+        "You are a shell command assistant for {}. Use the provided tool results to generate the correct command. If the input is already correct, output it unchanged.",
+        shell
+    );
+    if !hint.is_empty() {
+        system.push(' ');
+        system.push_str(hint);
     }
+    system.push_str(" /no_think");
 
-    for i in 1..=a_len {
-        for j in 1..=b_len {
-            let cost = if a_chars[i - 1] == b_chars[j - 1] {
-                0
-            } else {
-                1
-            };
-            matrix[i][j] = std::cmp::min(
-                std::cmp::min(matrix[i - 1][j] + 1, matrix[i][j - 1] + 1),
-                matrix[i - 1][j - 1] + cost,
-            );
+    let mut user = format!("Input: {}\n", escape_special_tokens(input));
+    if let Some(error) = error {
+        user.push_str(&format!("Error: {}\n", escape_special_tokens(error.trim())));
+    }
+    user.push('\n');
+    if !tool_results.is_empty() {
+        user.push_str("Tool results:\n");
+        for (tool_call, result) in tool_results {
+            user.push_str(&format!("- {}: {}\n", tool_call, result));
         }
     }
 
-    matrix[a_len][b_len]
+    format.render(&system, &user)
 }
 
-/// Build wit prompt with tool results in the training format
-fn build_wit_prompt(shell: &str, input: &str, tool_results: &[(String, String)]) -> String {
-    let mut prompt = String::new();
-
-    prompt.push_str("<|im_start|>system\n");
-    prompt.push_str(&format!(
-        "You are a shell command assistant for {}. Use the provided tool results to generate the correct command. /no_think",
+/// Build the `--explain-only` prompt, which asks for a description instead of a fix
+///
+/// The input is taken to be correct; tool results are included so the model
+/// can lean on help output when describing flags.
+fn build_explain_only_prompt(
+    format: PromptFormat,
+    shell: &str,
+    input: &str,
+    tool_results: &[(String, String)],
+) -> String {
+    let system = format!(
+        "You are a shell command assistant for {}. The input is a correct command; do not correct or rewrite it. Describe what it does in one line of plain English. /no_think",
         shell
-    ));
-    prompt.push_str("<|im_end|>\n");
-
-    prompt.push_str("<|im_start|>user\n");
-    prompt.push_str(&format!("Input: {}\n\n", input));
+    );
 
+    let mut user = format!("Input: {}\n\n", escape_special_tokens(input));
     if !tool_results.is_empty() {
-        prompt.push_str("Tool results:\n");
+        user.push_str("Tool results:\n");
         for (tool_call, result) in tool_results {
-            prompt.push_str(&format!("- {}: {}\n", tool_call, result));
+            user.push_str(&format!("- {}: {}\n", tool_call, result));
         }
     }
-    prompt.push_str("<|im_end|>\n");
 
-    prompt.push_str("<|im_start|>assistant\n");
+    format.render(&system, &user)
+}
+
+/// Build the `--nl` prompt, which asks for a command written from a description
+///
+/// There is no typo to fix, so no tools run; only injected results are included.
+fn build_generate_prompt(
+    format: PromptFormat,
+    shell: &str,
+    description: &str,
+    tool_results: &[(String, String)],
+) -> String {
+    let hint = Shell::parse(shell).map_or("", |shell| shell.prompt_hint());
+    let mut system = format!(
+        "You are a shell command assistant for {}. The input describes a task in plain English; it is not a command to correct. Write the single command that does it.",
+        shell
+    );
+    if !hint.is_empty() {
+        system.push(' ');
+        system.push_str(hint);
+    }
+    system.push_str(" /no_think");
+
+    let mut user = format!("Task: {}\n\n", escape_special_tokens(description));
+    if !tool_results.is_empty() {
+        user.push_str("Tool results:\n");
+        for (tool_call, result) in tool_results {
+            user.push_str(&format!("- {}: {}\n", tool_call, result));
+        }
+    }
 
-    prompt
+    format.render(&system, &user)
 }
 
 /// Format tool call for display
@@ -369,75 +763,381 @@ fn format_tool_call(tool: &Tool) -> String {
         Tool::HelpOutput { command } => format!("help_output({})", command),
         Tool::GetEnvVar { name } => format!("get_env_var({})", name),
         Tool::ManPage { command } => format!("man_page({})", command),
+        Tool::FileExists { path } => format!("file_exists({})", path),
     }
 }
 
-/// Run inference with loaded model
-fn run_inference(
-    model: &LlamaModel,
-    backend: &LlamaBackend,
+/// Run the tools selected for the input in parallel and collect their results
+///
+/// `extra_tool_results` (from `--extra-tool-result`) are appended as-is.
+#[allow(clippy::too_many_arguments)]
+fn gather_tool_results(
     command: &str,
+    shell: Shell,
+    error: Option<&str>,
+    executor: &Arc<ToolExecutor>,
+    extra_tool_results: &[(String, String)],
+    max_tools: Option<usize>,
+    config: &Config,
+    tools_cache: &cache::ToolsCache,
+) -> Vec<(String, String)> {
+    // A command counts as known if discovery cached it or it's on PATH now
+    let command_exists = |cmd: &str| {
+        tools_cache.tools.contains_key(cmd)
+            || executor
+                .execute(&Tool::WhichBinary {
+                    command: cmd.to_string(),
+                })
+                .success
+    };
+    let tools_to_run = limit_tools(
+        select_tools_for_input(command, shell, error, command_exists),
+        max_tools,
+    );
+
+    // Run tools in parallel, with a cap on concurrent subprocesses
+    let results = executor.execute_all(&tools_to_run);
+    let mut tool_results: Vec<(String, String)> = tools_to_run
+        .iter()
+        .zip(results)
+        .filter(|(_, result)| result.success && !result.output.is_empty())
+        .map(|(tool, result)| {
+            let limit = output_limit(&config.tool_output_limits, tool.name());
+            (format_tool_call(tool), limit.apply(&result.output))
+        })
+        .collect();
+
+    // In `git commmit`, the typo is in the subcommand: list the close matches
+    // from the command's help so the model doesn't have to spot them
+    let words: Vec<&str> = command.split_whitespace().collect();
+    if let [program, subcommand, ..] = words[..] {
+        let help_tool = Tool::HelpOutput {
+            command: program.to_string(),
+        };
+        // Under --max-tools this counts as one more tool unless its help already ran
+        let within_limit = max_tools.is_none_or(|max| tools_to_run.len() < max)
+            || tools_to_run.contains(&help_tool);
+        if within_limit && !subcommand.starts_with('-') && command_exists(program) {
+            let help = executor.execute(&help_tool);
+            let similar = similar_subcommands(subcommand, &help.output);
+            if help.success && !similar.is_empty() {
+                tool_results.push((
+                    format!("similar_subcommands({} {})", program, subcommand),
+                    similar.join(", "),
+                ));
+            }
+        }
+    }
+
+    // `k get pods` is more likely `kubectl get pods` than a typo of some
+    // other one-letter command
+    if let Some(expansion) = expand_abbreviation(command, &config.abbreviations, command_exists) {
+        let program = words.first().copied().unwrap_or_default();
+        tool_results.push((
+            format!("expand_abbreviation({})", program),
+            expansion.to_string(),
+        ));
+    }
+
+    // Context passed in by hooks or tests goes after the tools we ran
+    tool_results.extend_from_slice(extra_tool_results);
+    tool_results
+}
+
+/// A request's prompt, plus what the cleanup after generation needs
+struct PreparedRequest<'a> {
+    /// Leading `NAME=value` assignments, kept as typed
+    assignments: &'a str,
+    /// The command without its assignments
+    command: &'a str,
+    executor: Arc<ToolExecutor>,
+    /// Tool results the prompt was built with
+    tool_results: Vec<(String, String)>,
+    prompt: String,
+}
+
+/// Run the tools for `command` and build its prompt, all for `shell_str`
+///
+/// Everything shell-specific is derived from `shell_str` here, per request,
+/// so one daemon can serve requests from different shells.
+#[allow(clippy::too_many_arguments)]
+fn prepare_request<'a>(
+    command: &'a str,
     shell_str: &str,
+    error: Option<&str>,
+    config: &Config,
+    format: PromptFormat,
+    extra_tool_results: &[(String, String)],
+    explain_only: bool,
+    natural_language: bool,
+    max_tools: Option<usize>,
     verbose: bool,
-) -> Result<String, String> {
+) -> PreparedRequest<'a> {
     let shell = Shell::parse(shell_str).unwrap_or(Shell::Bash);
 
-    // Execute tools in parallel
-    let tools_to_run = select_tools_for_input(command, shell);
+    // Correct only the command itself; leading `NAME=value` assignments are kept as typed
+    let (assignments, command) = split_env_assignments(command);
 
-    // Parallel tool execution using thread::scope
-    let tool_results: Vec<(String, String)> = std::thread::scope(|s| {
-        // Spawn a thread for each tool
-        let handles: Vec<_> = tools_to_run
-            .iter()
-            .map(|tool| {
-                s.spawn(move || {
-                    let executor = ToolExecutor::new(shell);
-                    let result = executor.execute(tool);
-                    if result.success && !result.output.is_empty() {
-                        let tool_call = format_tool_call(tool);
-                        let output = if result.output.len() > 200 {
-                            format!("{}...", &result.output[..200])
-                        } else {
-                            result.output
-                        };
-                        Some((tool_call, output))
-                    } else {
-                        None
-                    }
-                })
-            })
-            .collect();
-
-        // Collect results, filtering out None values
-        handles
-            .into_iter()
-            .filter_map(|h| h.join().ok().flatten())
-            .collect()
-    });
+    let executor = Arc::new(ToolExecutor::from_config(shell, config));
+    let tools_cache = cache::load_or_create_cache();
+    // An error from the shell says the command failed, however it looks
+    let valid = !explain_only
+        && error.is_none()
+        && obviously_valid(command, |cmd| {
+            tools_cache.tools.contains_key(cmd) || command_in_path(cmd)
+        });
+    let tool_results = if natural_language {
+        if verbose {
+            eprintln!("Input is a description, skipping tools");
+        }
+        extra_tool_results.to_vec()
+    } else if valid {
+        if verbose {
+            eprintln!("Command looks valid, skipping tools");
+        }
+        extra_tool_results.to_vec()
+    } else {
+        gather_tool_results(
+            command,
+            shell,
+            error,
+            &executor,
+            extra_tool_results,
+            max_tools,
+            config,
+            &tools_cache,
+        )
+    };
 
     if verbose {
         eprintln!("Tool results (parallel): {:?}", tool_results);
     }
 
-    // Build prompt
-    let prompt = build_wit_prompt(shell_str, command, &tool_results);
+    let prompt = if explain_only {
+        build_explain_only_prompt(format, shell_str, command, &tool_results)
+    } else if natural_language {
+        build_generate_prompt(format, shell_str, command, &tool_results)
+    } else {
+        build_wit_prompt(format, shell_str, command, error, &tool_results)
+    };
+
+    PreparedRequest {
+        assignments,
+        command,
+        executor,
+        tool_results,
+        prompt,
+    }
+}
+
+/// Whether a lookup of `cmd` ran and found nothing, the only proof it's missing
+fn confirmed_missing(executor: &ToolExecutor, cmd: &str) -> bool {
+    let lookup = executor.execute(&Tool::WhichBinary {
+        command: cmd.to_string(),
+    });
+    lookup.error == Some(not_found_error(cmd))
+}
+
+/// Whether the model answered with `input` unchanged although its command doesn't exist
+///
+/// "Unchanged" is the right answer for a valid command only; when
+/// `command_missing` confirms there is no such command, the model missed the
+/// typo. A lookup that couldn't tell, such as one that timed out, must not
+/// count as missing.
+fn repeats_invalid_input<F>(input: &str, output: &str, command_missing: F) -> bool
+where
+    F: Fn(&str) -> bool,
+{
+    let (word, _) = split_command_args(input);
+    !word.is_empty() && output.trim() == input.trim() && command_missing(word)
+}
+
+/// `tool_results` plus a note that the first word of `command` isn't a command
+fn with_invalid_command_note(
+    tool_results: &[(String, String)],
+    command: &str,
+) -> Vec<(String, String)> {
+    let (word, _) = split_command_args(command);
+    let mut results = tool_results.to_vec();
+    results.push((
+        format!("which_binary({})", word),
+        format!(
+            "not found. '{}' is not an installed command, so the input is wrong as typed and must be changed",
+            word
+        ),
+    ));
+    results
+}
+
+/// Answer `prompt` with `generate`, retrying once with `retry_prompt` when the
+/// answer repeats a `command` that doesn't exist
+///
+/// A retry that fails, say because the longer prompt doesn't fit, leaves the
+/// first answer standing.
+fn answer_with_retry<R, F, G>(
+    command: &str,
+    prompt: &str,
+    retry_prompt: R,
+    command_missing: F,
+    mut generate: G,
+) -> Result<String, String>
+where
+    R: FnOnce() -> String,
+    F: Fn(&str) -> bool,
+    G: FnMut(&str) -> Result<String, String>,
+{
+    let answer = generate(prompt)?;
+    if !repeats_invalid_input(command, &answer, command_missing) {
+        return Ok(answer);
+    }
+
+    log_debug!("Model repeated an invalid command, retrying");
+    match generate(&retry_prompt()) {
+        Ok(retried) => Ok(retried),
+        Err(e) => {
+            log_debug!("Retry failed, keeping the first answer: {}", e);
+            Ok(answer)
+        }
+    }
+}
+
+/// Context size in tokens, shared by the prompt with its tool results and the answer
+const CONTEXT_TOKENS: usize = 1024;
+
+/// Run inference with loaded model
+#[allow(clippy::too_many_arguments)]
+fn run_inference(
+    model: &LlamaModel,
+    backend: &LlamaBackend,
+    command: &str,
+    shell_str: &str,
+    error: Option<&str>,
+    config: &Config,
+    sampling: SamplingPreset,
+    extra_tool_results: &[(String, String)],
+    explain_only: bool,
+    natural_language: bool,
+    max_tools: Option<usize>,
+    verbose: bool,
+) -> Result<String, String> {
+    let format = model_prompt_format(model, config);
+    let prepared = prepare_request(
+        command,
+        shell_str,
+        error,
+        config,
+        format,
+        extra_tool_results,
+        explain_only,
+        natural_language,
+        max_tools,
+        verbose,
+    );
+    answer_prepared(
+        model,
+        backend,
+        prepared,
+        shell_str,
+        error,
+        config,
+        format,
+        sampling,
+        explain_only,
+        natural_language,
+    )
+}
+
+/// Prompt format for `model`: the configured one, or detected from its chat template
+fn model_prompt_format(model: &LlamaModel, config: &Config) -> PromptFormat {
+    let template = model.meta_val_str(CHAT_TEMPLATE_KEY).ok();
+    PromptFormat::resolve(config.prompt_format, template.as_deref())
+}
 
-    // Create context
+/// Create an inference context of `CONTEXT_TOKENS` tokens
+fn new_context<'a>(
+    model: &'a LlamaModel,
+    backend: &LlamaBackend,
+) -> Result<LlamaContext<'a>, String> {
     let ctx_params = LlamaContextParams::default()
-        .with_n_ctx(std::num::NonZeroU32::new(1024))
-        .with_n_batch(512);
-    let mut ctx = model
+        .with_n_ctx(std::num::NonZeroU32::new(CONTEXT_TOKENS as u32))
+        .with_n_batch(CONTEXT_TOKENS as u32);
+    model
         .new_context(backend, ctx_params)
-        .map_err(|e| format!("Failed to create context: {}", e))?;
+        .map_err(|e| format!("Failed to create context: {}", e))
+}
+
+/// Correct `command` with the agent's tool-calling loop, writing the whole
+/// conversation to `trace`
+///
+/// Unlike `run_inference`, the model asks for tools itself, one per turn.
+#[allow(clippy::too_many_arguments)]
+fn run_traced(
+    model: &LlamaModel,
+    backend: &LlamaBackend,
+    command: &str,
+    shell_str: &str,
+    error: Option<&str>,
+    config: &Config,
+    sampling: SamplingPreset,
+    trace: &Path,
+) -> Result<String, String> {
+    let shell = Shell::parse(shell_str).unwrap_or(Shell::Bash);
+    let executor = ToolExecutor::from_config(shell, config);
+    let format = model_prompt_format(model, config);
+    let mut ctx = new_context(model, backend)?;
+
+    let result = agentic_correct_with(
+        command,
+        shell,
+        error,
+        &executor,
+        &config.thinking_tags,
+        |prompt| match generate_text(
+            model,
+            &mut ctx,
+            prompt,
+            format,
+            sampling,
+            &config.thinking_tags,
+        ) {
+            Ok((output, _)) => output,
+            Err(e) => {
+                log_debug!("Agent turn failed: {}", e);
+                String::new()
+            }
+        },
+    );
+    log_debug!(
+        "Agent finished after {} iterations, tools used: {}",
+        result.iterations,
+        result.tools_used
+    );
+
+    write_trace(trace, &result.context)?;
+    Ok(result.command)
+}
+
+/// Generate the model's answer to `prompt`, split into the answer and its thinking
+///
+/// Each prompt starts from an empty context.
+fn generate_text(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    prompt: &str,
+    format: PromptFormat,
+    sampling: SamplingPreset,
+    thinking_tags: &[ThinkingTags],
+) -> Result<(String, String), String> {
+    ctx.clear_kv_cache();
 
     // Tokenize
     let tokens = model
-        .str_to_token(&prompt, llama_cpp_2::model::AddBos::Always)
+        .str_to_token(prompt, llama_cpp_2::model::AddBos::Always)
         .map_err(|e| format!("Tokenization failed: {}", e))?;
+    check_prompt_fits(tokens.len(), CONTEXT_TOKENS)?;
 
     // Create batch
-    let mut batch = LlamaBatch::new(1024, 1);
+    let mut batch = LlamaBatch::new(CONTEXT_TOKENS, 1);
     for (i, token) in tokens.iter().enumerate() {
         let is_last = i == tokens.len() - 1;
         batch
@@ -450,43 +1150,30 @@ fn run_inference(
         .map_err(|e| format!("Decode failed: {}", e))?;
 
     // Generate
-    let mut output = String::new();
     let max_tokens = 256;
     let eos_token = model.token_eos();
-    let mut cur_pos = tokens.len() as i32;
-    let mut in_thinking = false;
-    let mut after_thinking = false;
+    let mut state = GenerationState::with_tags(thinking_tags);
+    let sampler = sampling.sampler();
 
-    for _ in 0..max_tokens {
+    for (cur_pos, _) in (tokens.len() as i32..).zip(0..max_tokens) {
         let candidates = ctx.candidates();
         let mut candidates_data = LlamaTokenDataArray::from_iter(candidates, false);
-        let new_token = candidates_data.sample_token_greedy();
+        candidates_data.apply_sampler(&sampler);
+        let new_token = candidates_data
+            .selected_token()
+            .ok_or_else(|| "Sampler did not select a token".to_string())?;
 
         if new_token == eos_token {
             break;
         }
 
         if let Ok(piece) = model.token_to_str(new_token, llama_cpp_2::model::Special::Tokenize) {
-            if piece.contains("<|im_end|>") || piece.contains("<|im_start|>") {
+            if format.stop_markers().iter().any(|m| piece.contains(m)) {
                 break;
             }
 
-            if piece.contains("<think>") {
-                in_thinking = true;
-            } else if piece.contains("</think>") {
-                in_thinking = false;
-                after_thinking = true;
-            } else if !in_thinking {
-                if after_thinking && piece.trim().is_empty() {
-                    // Skip
-                } else {
-                    after_thinking = false;
-                    output.push_str(&piece);
-
-                    if !output.trim().is_empty() && output.trim().lines().count() > 10 {
-                        break;
-                    }
-                }
+            if state.push(&piece) && state.output().trim().lines().count() > 10 {
+                break;
             }
         }
 
@@ -494,25 +1181,168 @@ fn run_inference(
         batch
             .add(new_token, cur_pos, &[0], true)
             .map_err(|e| format!("Batch add failed: {}", e))?;
-        cur_pos += 1;
         ctx.decode(&mut batch)
             .map_err(|e| format!("Decode failed: {}", e))?;
     }
 
-    // Clean output
-    let result = output.trim();
-    let result = result
-        .strip_prefix("|")
-        .or_else(|| result.strip_prefix("| "))
-        .unwrap_or(result)
-        .trim();
+    Ok(state.into_parts())
+}
+
+/// Generate the answer to a prepared request and clean it up
+#[allow(clippy::too_many_arguments)]
+fn answer_prepared(
+    model: &LlamaModel,
+    backend: &LlamaBackend,
+    prepared: PreparedRequest,
+    shell_str: &str,
+    error: Option<&str>,
+    config: &Config,
+    format: PromptFormat,
+    sampling: SamplingPreset,
+    explain_only: bool,
+    natural_language: bool,
+) -> Result<String, String> {
+    let PreparedRequest {
+        assignments,
+        command,
+        executor,
+        tool_results,
+        prompt,
+    } = prepared;
+
+    let mut ctx = new_context(model, backend)?;
+
+    let command_exists = |cmd: &str| {
+        executor
+            .execute(&Tool::WhichBinary {
+                command: cmd.to_string(),
+            })
+            .success
+    };
+
+    // Answer a prompt and clean up the output; each prompt starts from an empty context
+    let mut generate = |prompt: &str| -> Result<String, String> {
+        let (mut output, thinking) = generate_text(
+            model,
+            &mut ctx,
+            prompt,
+            format,
+            sampling,
+            &config.thinking_tags,
+        )?;
 
-    Ok(result.to_string())
+        // A description is used as written; the cleanup below is for commands
+        if explain_only {
+            let explanation = output.lines().map(str::trim).find(|l| !l.is_empty());
+            return Ok(explanation.unwrap_or_default().to_string());
+        }
+
+        // The model sometimes answers inside the think block and stops there
+        if output.trim().is_empty() {
+            if let Some(command) = command_from_thinking(&thinking, command_exists) {
+                output = command;
+            }
+        }
+
+        // Clean output, putting back special tokens the command contained
+        let output = unescape_special_tokens(&output);
+        let result = output.trim();
+        let result = result
+            .strip_prefix("|")
+            .or_else(|| result.strip_prefix("| "))
+            .unwrap_or(result)
+            .trim();
+        Ok(strip_prompt_marker(result).to_string())
+    };
+
+    if explain_only {
+        return generate(&prompt);
+    }
+
+    // A written command has no typed input to stay close to
+    if natural_language {
+        return Ok(prepend_env_assignments(assignments, &generate(&prompt)?));
+    }
+
+    let retry_prompt = || {
+        let tool_results = with_invalid_command_note(&tool_results, command);
+        build_wit_prompt(format, shell_str, command, error, &tool_results)
+    };
+    let command_missing = |cmd: &str| confirmed_missing(&executor, cmd);
+    let result = answer_with_retry(command, &prompt, retry_prompt, command_missing, generate)?;
+
+    let result = if prefer_original(command, &result, command_exists) {
+        command
+    } else {
+        &result
+    };
+
+    let result = if config.minimal_edit {
+        merge_minimal_edit(command, result)
+    } else {
+        result.to_string()
+    };
+
+    Ok(prepend_env_assignments(assignments, &result))
+}
+
+/// Answer one daemon request line
+///
+/// The request's tools run and its prompt is built here, for the request's
+/// own shell; `answer` then generates from the prepared request.
+#[cfg(unix)]
+fn handle_daemon_request<F>(line: &str, config: &Config, format: PromptFormat, answer: F) -> String
+where
+    F: FnOnce(&DaemonRequest, PreparedRequest) -> Result<String, String>,
+{
+    let request: Result<DaemonRequest, _> = serde_json::from_str(line);
+    let response = match request {
+        Ok(req) => {
+            let prepared = prepare_request(
+                &req.command,
+                &req.shell,
+                req.error.as_deref(),
+                config,
+                format,
+                &req.extra_tool_results,
+                req.explain_only,
+                req.natural_language,
+                req.max_tools,
+                req.verbose,
+            );
+            match answer(&req, prepared) {
+                Ok(output) => DaemonResponse {
+                    protocol_version: DAEMON_PROTOCOL_VERSION,
+                    success: true,
+                    output,
+                    error: None,
+                },
+                Err(e) => DaemonResponse {
+                    protocol_version: DAEMON_PROTOCOL_VERSION,
+                    success: false,
+                    output: String::new(),
+                    error: Some(e),
+                },
+            }
+        }
+        Err(e) => DaemonResponse {
+            protocol_version: DAEMON_PROTOCOL_VERSION,
+            success: false,
+            output: String::new(),
+            error: Some(format!("Invalid request: {}", e)),
+        },
+    };
+
+    serde_json::to_string(&response).unwrap()
 }
 
 /// Run daemon mode
 #[cfg(unix)]
-fn run_daemon(model_path: PathBuf, gpu_layers: u32) -> Result<(), Box<dyn std::error::Error>> {
+fn run_daemon(
+    model_path: PathBuf,
+    gpu_layers: u32,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Remove stale socket
     let _ = fs::remove_file(socket_path());
 
@@ -527,87 +1357,38 @@ fn run_daemon(model_path: PathBuf, gpu_layers: u32) -> Result<(), Box<dyn std::e
 
     // Create socket
     let listener = UnixListener::bind(socket_path())?;
-    listener.set_nonblocking(true)?;
-
-    let last_activity = Arc::new(Mutex::new(Instant::now()));
-    let should_stop = Arc::new(AtomicBool::new(false));
-
-    // Main loop
-    loop {
-        // Check idle timeout
-        {
-            let last = last_activity.lock().unwrap();
-            if last.elapsed() > Duration::from_secs(IDLE_TIMEOUT_SECS) {
-                eprintln!("wit daemon: idle timeout, shutting down");
-                break;
-            }
-        }
-
-        // Check stop flag
-        if should_stop.load(Ordering::Relaxed) {
-            break;
-        }
-
-        // Accept connection (non-blocking)
-        match listener.accept() {
-            Ok((mut stream, _)) => {
-                // Update activity
-                *last_activity.lock().unwrap() = Instant::now();
-
-                // Read request
-                let mut reader = BufReader::new(&stream);
-                let mut line = String::new();
-                if reader.read_line(&mut line).is_err() {
-                    continue;
-                }
-
-                // Check for stop command
-                if line.contains("\"stop\"") {
-                    should_stop.store(true, Ordering::Relaxed);
-                    let response = DaemonResponse {
-                        success: true,
-                        output: "Daemon stopping".to_string(),
-                        error: None,
-                    };
-                    let _ = writeln!(stream, "{}", serde_json::to_string(&response).unwrap());
-                    break;
-                }
+    let idle_timeout = Duration::from_secs(IDLE_TIMEOUT_SECS);
+    let format = model_prompt_format(&model, config);
+
+    let shutdown = daemon::serve(
+        &listener,
+        idle_timeout,
+        config.ping_keeps_daemon_alive,
+        Some(&model_path),
+        |line| {
+            handle_daemon_request(line, config, format, |req, prepared| {
+                answer_prepared(
+                    &model,
+                    &backend,
+                    prepared,
+                    &req.shell,
+                    req.error.as_deref(),
+                    config,
+                    format,
+                    req.sampling,
+                    req.explain_only,
+                    req.natural_language,
+                )
+            })
+        },
+    )?;
 
-                // Parse request
-                let request: Result<DaemonRequest, _> = serde_json::from_str(&line);
-                let response = match request {
-                    Ok(req) => {
-                        match run_inference(&model, &backend, &req.command, &req.shell, req.verbose)
-                        {
-                            Ok(output) => DaemonResponse {
-                                success: true,
-                                output,
-                                error: None,
-                            },
-                            Err(e) => DaemonResponse {
-                                success: false,
-                                output: String::new(),
-                                error: Some(e),
-                            },
-                        }
-                    }
-                    Err(e) => DaemonResponse {
-                        success: false,
-                        output: String::new(),
-                        error: Some(format!("Invalid request: {}", e)),
-                    },
-                };
-
-                let _ = writeln!(stream, "{}", serde_json::to_string(&response).unwrap());
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // No connection, sleep briefly
-                std::thread::sleep(Duration::from_millis(50));
-            }
-            Err(_) => {
-                std::thread::sleep(Duration::from_millis(100));
-            }
+    match shutdown {
+        daemon::Shutdown::IdleTimeout => eprintln!("wit daemon: idle timeout, shutting down"),
+        daemon::Shutdown::ModelChanged => {
+            eprintln!("wit daemon: model file changed, shutting down")
         }
+        daemon::Shutdown::Stopped => {}
     }
 
     // Cleanup
@@ -618,13 +1399,22 @@ fn run_daemon(model_path: PathBuf, gpu_layers: u32) -> Result<(), Box<dyn std::e
 }
 
 /// Run in direct mode (no daemon)
+#[allow(clippy::too_many_arguments)]
 fn run_direct(
     command: &str,
     shell_str: &str,
+    error: Option<&str>,
     model_path: PathBuf,
     gpu_layers: u32,
+    config: &Config,
+    sampling: SamplingPreset,
+    extra_tool_results: &[(String, String)],
+    explain_only: bool,
+    natural_language: bool,
+    max_tools: Option<usize>,
     verbose: bool,
     quiet: bool,
+    trace: Option<&Path>,
 ) -> Result<String, Box<dyn std::error::Error>> {
     let mut spinner = ProgressSpinner::new(quiet);
 
@@ -646,8 +1436,32 @@ fn run_direct(
     let model = LlamaModel::load_from_file(&backend, &model_path, &model_params)
         .map_err(|e| format!("Failed to load model: {}", e))?;
 
-    spinner.set_message("Generating correction...");
-    let result = run_inference(&model, &backend, command, shell_str, verbose)?;
+    spinner.set_message(if explain_only {
+        "Generating explanation..."
+    } else if natural_language {
+        "Generating command..."
+    } else {
+        "Generating correction..."
+    });
+    let result = match trace {
+        Some(trace) => run_traced(
+            &model, &backend, command, shell_str, error, config, sampling, trace,
+        )?,
+        None => run_inference(
+            &model,
+            &backend,
+            command,
+            shell_str,
+            error,
+            config,
+            sampling,
+            extra_tool_results,
+            explain_only,
+            natural_language,
+            max_tools,
+            verbose,
+        )?,
+    };
 
     spinner.finish_with_message("✓");
 
@@ -659,17 +1473,69 @@ fn run_direct(
     Ok(result)
 }
 
+/// Print the correction, or exit non-zero if there is none or it is unsafe
+fn print_correction(command: &str, correction: &str, config: &Config) {
+    if correction.is_empty() {
+        eprintln!("Could not correct command");
+        std::process::exit(1);
+    }
+
+    if let Some(pattern) =
+        safety::introduced_destructive_pattern(command, correction, &config.destructive_patterns)
+    {
+        eprintln!(
+            "Refusing correction '{}': it adds '{}', which the original command didn't use",
+            correction, pattern
+        );
+        std::process::exit(2);
+    }
+
+    println!("{}", correction);
+}
+
+/// Print the `--explain-only` description, or exit non-zero if there is none
+fn print_explanation(explanation: &str) {
+    if explanation.is_empty() {
+        eprintln!("Could not explain command");
+        std::process::exit(1);
+    }
+
+    println!("{}", explanation);
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    if let Some(shell) = args.completions {
+        clap_complete::generate(shell, &mut Args::command(), "wit", &mut std::io::stdout());
+        return Ok(());
+    }
+
+    let interactive = is_interactive();
+    progress::set_color(interactive_default(args.color.explicit(), interactive));
+    // Spinners and progress bars are for people; --quiet turns them off anyway
+    let quiet = !interactive_default(args.quiet.then_some(false), interactive);
+
     let mut config = load_config();
+    if config.use_utf8_console(args.no_utf8_console) {
+        enable_utf8_console();
+    }
+    let model_dir = model_dir(args.model_dir.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    init_proxy(args.proxy.as_deref());
+    if let Ok(path) = config_path() {
+        log_debug!("Config file: {}", path.display());
+    }
 
     // Handle daemon mode (internal) - Unix only
     #[cfg(unix)]
     if args.daemon {
         let model_path = args
             .model
-            .unwrap_or_else(|| get_model_path(WIT_DEFAULT_MODEL));
-        return run_daemon(model_path, args.gpu_layers);
+            .unwrap_or_else(|| get_model_path(&model_dir, WIT_DEFAULT_MODEL));
+        return run_daemon(model_path, args.gpu_layers, &config);
     }
 
     // Handle --stop flag - Unix only (daemon mode)
@@ -690,7 +1556,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(unix)]
     if args.status {
         if is_daemon_running() {
-            println!("Daemon: running");
+            match daemon::ping(&socket_path()) {
+                Ok(()) => println!("Daemon: running"),
+                Err(e) => println!("Daemon: running, not responding ({})", e),
+            }
             println!("Socket: {}", socket_path().display());
             println!("PID file: {}", pid_path().display());
         } else {
@@ -705,21 +1574,68 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    #[cfg(unix)]
+    if args.ping {
+        if let Err(e) = daemon::ping(&socket_path()) {
+            eprintln!("Daemon: not responding ({})", e);
+            std::process::exit(1);
+        }
+        println!("Daemon: responding");
+        return Ok(());
+    }
+
+    #[cfg(not(unix))]
+    if args.ping {
+        println!("Daemon: not available on Windows (direct mode only)");
+        return Ok(());
+    }
+
     // Handle --refresh-tools flag
     if args.refresh_tools {
         eprintln!("Refreshing tool discovery cache...");
-        let new_cache = discovery::discover_tools();
+        // Save partial progress as we go so an interrupted scan isn't lost
+        let save_partial = |partial: &cache::ToolsCache| {
+            let _ = cache::save_cache(partial);
+        };
+        let bar = progress::count_bar(quiet, "Scanning tools");
+        let report = |scanned: usize, total: usize| {
+            bar.set_length(total as u64);
+            bar.set_position(scanned as u64);
+        };
+        let new_cache = if args.append_only {
+            let existing = cache::load_or_create_cache();
+            discovery::discover_tools_append_only(
+                &existing,
+                discovery::STREAMING_SAVE_INTERVAL,
+                save_partial,
+                report,
+            )
+        } else {
+            discovery::discover_tools_streaming(
+                discovery::STREAMING_SAVE_INTERVAL,
+                save_partial,
+                report,
+            )
+        };
+        bar.finish_and_clear();
         cache::save_cache(&new_cache)?;
         eprintln!("✓ Cache refreshed successfully");
         eprintln!("  Discovered {} tools", new_cache.tools.len());
         return Ok(());
     }
 
+    if args.list_tools {
+        let tools_cache =
+            cache::load_cache().map_err(|e| format!("{} (run wit --refresh-tools first)", e))?;
+        print!("{}", format_tool_list(&tools_cache));
+        return Ok(());
+    }
+
     if args.show_config {
-        let model_path = get_model_path(WIT_DEFAULT_MODEL);
+        let model_path = get_model_path(&model_dir, WIT_DEFAULT_MODEL);
         println!("Configuration:");
         println!("  Wit model: {}", WIT_DEFAULT_MODEL);
-        println!("  Config path: {}", config_path().display());
+        println!("  Config path: {}", config_path()?.display());
         if model_path.exists() {
             println!("  Model path: {}", model_path.display());
         } else {
@@ -727,6 +1643,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         #[cfg(unix)]
         {
+            println!("  Daemon enabled: {}", config.use_daemon);
             println!("  Daemon running: {}", is_daemon_running());
             println!("  Socket: {}", socket_path().display());
         }
@@ -735,21 +1652,52 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("  Daemon: not available on Windows (direct mode only)");
         }
 
-        let cache_path = cache::cache_path();
+        let cache_path = cache::cache_path()?;
         println!("  Cache path: {}", cache_path.display());
 
         if let Ok(tools_cache) = cache::load_cache() {
-            println!("  Cached tools: {}", tools_cache.tools.len());
+            let stats = cache::stats(&tools_cache);
+            println!("  Cached tools: {}", stats.total);
+            println!("    Missing on disk: {}", stats.dead_paths);
+            println!(
+                "    Priority tools: {}/{}",
+                stats.priority_present,
+                discovery::PRIORITY_TOOLS.len()
+            );
+            match stats.age {
+                Some(age) => println!("    Updated: {} ago", format_age(age)),
+                None => println!("    Updated: unknown"),
+            }
         }
+        println!("  Tool cache TTL: {}s", config.tool_cache_ttl_secs);
 
         return Ok(());
     }
 
+    // Handle --set-daemon flag
+    if let Some(ref state) = args.set_daemon {
+        config.use_daemon = state == "on";
+        save_config(&config)?;
+
+        #[cfg(unix)]
+        if !config.use_daemon && is_daemon_running() {
+            stop_daemon()?;
+        }
+
+        if config.use_daemon {
+            eprintln!("✓ Daemon enabled");
+        } else {
+            eprintln!("✓ Daemon disabled, models will load on each run");
+        }
+        return Ok(());
+    }
+
     // Handle --use-model flag
     if let Some(ref model_name) = args.use_model {
         eprintln!("Checking model availability...");
-        validate_model_exists(model_name)?;
-        download_model(model_name)?;
+        let revision = config.model_revision(args.revision.as_deref()).to_string();
+        validate_model_exists(model_name, &revision)?;
+        download_model(&model_dir, model_name, &revision, args.keep_partial)?;
         config.default_model = model_name.clone();
         save_config(&config)?;
         eprintln!("✓ Default model set to: {}", model_name);
@@ -763,6 +1711,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // Handle --update: re-download the model, and stop the daemon so the next
+    // correction starts one that loads the new file
+    if args.update {
+        let model_path = download_wit_model(
+            &model_dir,
+            args.model_url.as_deref(),
+            config.model_revision(args.revision.as_deref()),
+            true,
+            false,
+            args.keep_partial,
+        )?;
+
+        #[cfg(unix)]
+        if is_daemon_running() {
+            stop_daemon()?;
+            eprintln!("✓ Daemon stopped; the next correction loads the updated model");
+        }
+
+        if args.command.is_empty() {
+            eprintln!("✓ Model updated: {}", model_path.display());
+            return Ok(());
+        }
+    }
+
     // For inference, command is required
     if args.command.is_empty() {
         eprintln!("Usage: wit <command>");
@@ -770,47 +1742,113 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         eprintln!("       wit --refresh-tools");
         eprintln!("       wit --stop          # Unload model from memory");
         eprintln!("       wit --status        # Show daemon status");
+        eprintln!("       wit --update        # Re-download the model");
         eprintln!("       wit --direct <cmd>  # Run without daemon");
+        eprintln!("       wit --dry-run <cmd> # Print the prompt and exit");
+        eprintln!("       wit --explain-only <cmd> # Describe the command");
         std::process::exit(1);
     }
 
     let command = args.command.join(" ");
-    let shell_str = args.shell.unwrap_or_else(detect_shell);
+    if let Some(pattern) = safety::denied_input(&command, &config.input_denylist)? {
+        eprintln!(
+            "Not processing input matching input_denylist pattern '{}'",
+            pattern
+        );
+        std::process::exit(1);
+    }
+    let shell_str = args.shell.take().unwrap_or_else(detect_shell);
 
     if args.verbose {
         eprintln!("Shell: {}", shell_str);
         eprintln!("Command: {}", command);
     }
 
+    // Gathered here rather than in the daemon, whose working directory may
+    // be a different repository
+    if wants_git_context(&command, args.git_context) {
+        let shell = Shell::parse(&shell_str).unwrap_or(Shell::Bash);
+        let git_context = ToolExecutor::from_config(shell, &config).git_context();
+        args.extra_tool_results.extend(git_context);
+    }
+
+    // Show the prompt without loading the model
+    if args.dry_run {
+        let prepared = prepare_request(
+            &command,
+            &shell_str,
+            args.error.as_deref(),
+            &config,
+            config.prompt_format.unwrap_or_default(),
+            &args.extra_tool_results,
+            args.explain_only,
+            args.nl,
+            args.max_tools,
+            false,
+        );
+        print!("{}", prepared.prompt);
+        return Ok(());
+    }
+
     // Find or download model
-    let model_path = if let Some(ref path) = args.model {
-        path.clone()
-    } else {
-        find_or_download_model(WIT_DEFAULT_MODEL, false)?
+    let model_path = match args.model {
+        Some(ref path) => {
+            check_model_file(path)?;
+            path.clone()
+        }
+        None => download_wit_model(
+            &model_dir,
+            args.model_url.as_deref(),
+            config.model_revision(args.revision.as_deref()),
+            false,
+            should_confirm_download(is_interactive(), args.yes),
+            args.keep_partial,
+        )?,
     };
 
-    // On Windows, always use direct mode. On Unix, use direct mode if --direct flag is set.
+    // On first use there's no tool cache yet: describe the common tools now
+    if config.first_run_discovery_ms > 0 && cache::load_or_create_cache().tools.is_empty() {
+        first_run_discovery(
+            &config,
+            Duration::from_millis(config.first_run_discovery_ms),
+        );
+    }
+
+    // On Windows, always use direct mode. On Unix, use direct mode if --direct flag is set,
+    // or for --trace, whose conversation only this process sees.
     #[cfg(not(unix))]
     let use_direct = true;
     #[cfg(unix)]
-    let use_direct = args.direct;
+    let use_direct = config.use_direct_mode(args.direct) || args.trace.is_some();
+    log_info!(
+        "Model {}, {} mode",
+        model_path.display(),
+        if use_direct { "direct" } else { "daemon" }
+    );
 
     // Direct mode - no daemon
     if use_direct {
         let result = run_direct(
             &command,
             &shell_str,
+            args.error.as_deref(),
             model_path,
             args.gpu_layers,
+            &config,
+            args.sampling,
+            &args.extra_tool_results,
+            args.explain_only,
+            args.nl,
+            args.max_tools,
             args.verbose,
-            args.quiet,
+            quiet,
+            args.trace.as_deref(),
         )?;
 
-        if !result.is_empty() {
-            println!("{}", result);
+        if args.explain_only {
+            print_explanation(&result);
         } else {
-            eprintln!("Could not correct command");
-            std::process::exit(1);
+            print_correction(&command, &result, &config);
         }
         return Ok(());
     }
@@ -818,34 +1856,50 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Daemon mode (default on Unix)
     #[cfg(unix)]
     {
-        let mut spinner = ProgressSpinner::new(args.quiet);
+        let mut spinner = ProgressSpinner::new(quiet);
 
         // Ensure daemon is running
         if !is_daemon_running() {
+            log_debug!("No daemon running, starting one");
             spinner.set_message("Starting daemon...");
-            start_daemon(&model_path, args.gpu_layers)?;
+            start_daemon(&model_path, args.gpu_layers, &config)?;
         }
 
-        spinner.set_message("Correcting...");
+        spinner.set_message(if args.explain_only {
+            "Explaining..."
+        } else if args.nl {
+            "Writing command..."
+        } else {
+            "Correcting..."
+        });
 
         // Send request to daemon
         let request = DaemonRequest {
+            protocol_version: DAEMON_PROTOCOL_VERSION,
             command: command.clone(),
             shell: shell_str,
+            error: args.error,
+            sampling: args.sampling,
+            extra_tool_results: args.extra_tool_results,
+            explain_only: args.explain_only,
+            natural_language: args.nl,
+            max_tools: args.max_tools,
             verbose: args.verbose,
         };
 
-        let response = send_to_daemon(&request)?;
+        let response: DaemonResponse =
+            send_to_daemon(&socket_path(), &request, DAEMON_READ_TIMEOUT, || {
+                spinner.set_message("Restarting outdated daemon...");
+                stop_daemon()?;
+                start_daemon(&model_path, args.gpu_layers, &config)
+            })?;
 
         spinner.finish_with_message("✓");
 
-        if response.success {
-            if !response.output.is_empty() {
-                println!("{}", response.output);
-            } else {
-                eprintln!("Could not correct command");
-                std::process::exit(1);
-            }
+        if response.success && args.explain_only {
+            print_explanation(&response.output);
+        } else if response.success {
+            print_correction(&command, &response.output, &config);
         } else {
             eprintln!(
                 "Error: {}",
@@ -859,3 +1913,586 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_format_tool_list_sorted_and_aligned() {
+        let mut tools_cache = cache::ToolsCache::new();
+        for (name, desc) in [
+            ("git", "version control"),
+            ("cargo", "Rust builds"),
+            ("ls", ""),
+        ] {
+            tools_cache.tools.insert(
+                name.to_string(),
+                cache::ToolInfo {
+                    path: format!("/usr/bin/{}", name),
+                    desc: desc.to_string(),
+                },
+            );
+        }
+
+        let listing = format_tool_list(&tools_cache);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "Cached tools (3, updated 0s ago):");
+        assert_eq!(lines[1], "  cargo  Rust builds");
+        assert_eq!(lines[2], "  git    version control");
+        assert_eq!(lines[3], "  ls");
+    }
+
+    #[test]
+    fn test_repeats_invalid_input() {
+        let missing = |cmd: &str| cmd != "git";
+        assert!(repeats_invalid_input("gti status", "gti status", missing));
+        assert!(!repeats_invalid_input("gti status", "git status", missing));
+        // Unchanged is right for a command that exists
+        assert!(!repeats_invalid_input("git status", "git status", missing));
+    }
+
+    #[test]
+    fn test_builtins_answered_unchanged_are_not_retried() {
+        for (shell, input) in [
+            (Shell::Fish, "cd /tmp"),
+            (Shell::Cmd, "set X=1"),
+            (Shell::Cmd, "dir"),
+        ] {
+            let executor = ToolExecutor::new(shell);
+            let missing = |cmd: &str| confirmed_missing(&executor, cmd);
+            assert!(!repeats_invalid_input(input, input, missing), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_failed_retry_keeps_first_answer() {
+        let mut calls = 0;
+        let answer = answer_with_retry(
+            "gti status",
+            "prompt",
+            || "longer prompt".to_string(),
+            |_| true,
+            |_| {
+                calls += 1;
+                if calls == 1 {
+                    Ok("gti status".to_string())
+                } else {
+                    Err("Prompt is too long".to_string())
+                }
+            },
+        );
+        assert_eq!(answer, Ok("gti status".to_string()));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn test_unchanged_invalid_answer_is_retried() {
+        let exists = |cmd: &str| cmd == "git";
+        let retry_prompt = || {
+            let notes = with_invalid_command_note(&[], "gti status");
+            build_wit_prompt(PromptFormat::ChatMl, "bash", "gti status", None, &notes)
+        };
+
+        let mut prompts = Vec::new();
+        let missing = |cmd: &str| !exists(cmd);
+        let answer = answer_with_retry("gti status", "first prompt", retry_prompt, missing, |p| {
+            prompts.push(p.to_string());
+            Ok(if prompts.len() == 1 {
+                "gti status"
+            } else {
+                "git status"
+            }
+            .to_string())
+        });
+
+        assert_eq!(answer, Ok("git status".to_string()));
+        assert_eq!(prompts.len(), 2);
+        assert!(
+            prompts[1].contains("'gti' is not an installed command"),
+            "{}",
+            prompts[1]
+        );
+
+        // A valid command answered unchanged isn't retried
+        let mut calls = 0;
+        let answer = answer_with_retry(
+            "git status",
+            "prompt",
+            || unreachable!(),
+            missing,
+            |_| {
+                calls += 1;
+                Ok("git status".to_string())
+            },
+        );
+        assert_eq!(answer, Ok("git status".to_string()));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_explain_only_prompt_describes_instead_of_correcting() {
+        let tool_results = vec![(
+            "help_output(tar)".to_string(),
+            "-x extract files".to_string(),
+        )];
+        let prompt = build_explain_only_prompt(
+            PromptFormat::ChatMl,
+            "bash",
+            "tar -xzf a.tgz",
+            &tool_results,
+        );
+
+        assert!(prompt.contains("Describe what it does in one line"));
+        assert!(prompt.contains("do not correct or rewrite it"));
+        assert!(!prompt.contains("generate the correct command"));
+        assert!(prompt.contains("Input: tar -xzf a.tgz\n"));
+        assert!(prompt.contains("- help_output(tar): -x extract files\n"));
+    }
+
+    #[test]
+    fn test_git_context_only_with_flag_for_git() {
+        assert!(wants_git_context("git chekout mian", true));
+        assert!(wants_git_context("GIT_PAGER=cat git lg", true));
+        assert!(!wants_git_context("git chekout mian", false));
+        assert!(!wants_git_context("ls -la", true));
+        assert!(!wants_git_context("gitk --all", true));
+    }
+
+    #[test]
+    fn test_git_context_in_prompt() {
+        // As returned by `ToolExecutor::git_context` in a repo with two remotes
+        let git_context = vec![
+            (
+                "git_branches".to_string(),
+                "feature/login, main".to_string(),
+            ),
+            ("git_remotes".to_string(), "origin, upstream".to_string()),
+        ];
+        let prompt = build_wit_prompt(
+            PromptFormat::ChatMl,
+            "bash",
+            "git push orgin mian",
+            None,
+            &git_context,
+        );
+
+        assert!(prompt.contains(
+            "Tool results:\n\
+             - git_branches: feature/login, main\n\
+             - git_remotes: origin, upstream\n"
+        ));
+    }
+
+    #[test]
+    fn test_wit_prompt_includes_shell_hint() {
+        let prompt = build_wit_prompt(PromptFormat::ChatMl, "cmd", "dri", None, &[]);
+        assert!(prompt.contains(&format!(
+            "unchanged. {} /no_think",
+            Shell::Cmd.prompt_hint()
+        )));
+
+        let prompt = build_wit_prompt(PromptFormat::ChatMl, "bash", "gti", None, &[]);
+        assert!(prompt.contains("unchanged. /no_think"));
+    }
+
+    #[test]
+    fn test_wit_prompt_bash_system_unchanged() {
+        // Byte for byte the system message the models were trained on
+        let prompt = build_wit_prompt(PromptFormat::ChatMl, "bash", "gti", None, &[]);
+        assert!(prompt.starts_with(
+            "<|im_start|>system\n\
+             You are a shell command assistant for bash. Use the provided tool results \
+             to generate the correct command. If the input is already correct, output it \
+             unchanged. /no_think<|im_end|>\n<|im_start|>user\n"
+        ));
+    }
+
+    #[test]
+    fn test_format_age_units() {
+        assert_eq!(format_age(Duration::from_secs(42)), "42s");
+        assert_eq!(format_age(Duration::from_secs(125)), "2m");
+        assert_eq!(format_age(Duration::from_secs(3 * 3600 + 5)), "3h");
+        assert_eq!(format_age(Duration::from_secs(2 * 86400)), "2d");
+    }
+
+    /// Serve one canned response per connection, returning how many requests arrived
+    #[cfg(unix)]
+    fn mock_daemon(
+        socket: &Path,
+        responses: Vec<DaemonResponse>,
+    ) -> std::thread::JoinHandle<usize> {
+        let lines = responses
+            .iter()
+            .map(|response| serde_json::to_string(response).unwrap())
+            .collect();
+        mock_daemon_lines(socket, lines)
+    }
+
+    /// Like `mock_daemon`, with the raw response lines
+    #[cfg(unix)]
+    fn mock_daemon_lines(socket: &Path, responses: Vec<String>) -> std::thread::JoinHandle<usize> {
+        let _ = fs::remove_file(socket);
+        let listener = UnixListener::bind(socket).unwrap();
+
+        std::thread::spawn(move || {
+            let mut served = 0;
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let _: DaemonRequest = serde_json::from_str(&line).unwrap();
+
+                writeln!(&stream, "{}", response).unwrap();
+                served += 1;
+            }
+            served
+        })
+    }
+
+    #[cfg(unix)]
+    fn test_request() -> DaemonRequest {
+        DaemonRequest {
+            protocol_version: DAEMON_PROTOCOL_VERSION,
+            command: "gti status".to_string(),
+            shell: "bash".to_string(),
+            error: None,
+            sampling: SamplingPreset::default(),
+            extra_tool_results: Vec::new(),
+            explain_only: false,
+            natural_language: false,
+            max_tools: None,
+            verbose: false,
+        }
+    }
+
+    #[cfg(unix)]
+    fn no_restart() -> Result<(), String> {
+        panic!("daemon should not be restarted");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_to_daemon_restarts_outdated_daemon() {
+        let socket = std::env::temp_dir().join(format!("wit-outdated-{}.sock", std::process::id()));
+        // A daemon from before protocol versions existed
+        let outdated = mock_daemon_lines(
+            &socket,
+            vec![r#"{"success":true,"output":"stale","error":null}"#.to_string()],
+        );
+
+        let mut restarted = None;
+        let response: DaemonResponse =
+            send_to_daemon(&socket, &test_request(), DAEMON_READ_TIMEOUT, || {
+                assert_eq!(outdated.join().unwrap(), 1);
+                restarted = Some(mock_daemon(
+                    &socket,
+                    vec![DaemonResponse {
+                        protocol_version: DAEMON_PROTOCOL_VERSION,
+                        success: true,
+                        output: "git status".to_string(),
+                        error: None,
+                    }],
+                ));
+                Ok(())
+            })
+            .unwrap();
+        let _ = fs::remove_file(&socket);
+
+        assert_eq!(response.output, "git status");
+        assert_eq!(restarted.unwrap().join().unwrap(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_to_daemon_retries_transient_failure() {
+        let socket = std::env::temp_dir().join(format!("wit-retry-{}.sock", std::process::id()));
+        let daemon = mock_daemon(
+            &socket,
+            vec![
+                DaemonResponse {
+                    protocol_version: DAEMON_PROTOCOL_VERSION,
+                    success: false,
+                    output: String::new(),
+                    error: Some("Decode failed: NoKvCacheSlot".to_string()),
+                },
+                DaemonResponse {
+                    protocol_version: DAEMON_PROTOCOL_VERSION,
+                    success: true,
+                    output: "git status".to_string(),
+                    error: None,
+                },
+            ],
+        );
+
+        let response: DaemonResponse =
+            send_to_daemon(&socket, &test_request(), DAEMON_READ_TIMEOUT, no_restart).unwrap();
+        let _ = fs::remove_file(&socket);
+
+        assert!(response.success);
+        assert_eq!(response.output, "git status");
+        assert_eq!(daemon.join().unwrap(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_to_daemon_reports_permanent_failure() {
+        let socket = std::env::temp_dir().join(format!("wit-no-retry-{}.sock", std::process::id()));
+        let daemon = mock_daemon(
+            &socket,
+            vec![DaemonResponse {
+                protocol_version: DAEMON_PROTOCOL_VERSION,
+                success: false,
+                output: String::new(),
+                error: Some("Tokenization failed: bad utf8".to_string()),
+            }],
+        );
+
+        let response: DaemonResponse =
+            send_to_daemon(&socket, &test_request(), DAEMON_READ_TIMEOUT, no_restart).unwrap();
+        let _ = fs::remove_file(&socket);
+
+        assert!(!response.success);
+        assert_eq!(daemon.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_tool_result() {
+        assert_eq!(
+            parse_tool_result("hook=command not found: gti"),
+            Ok(("hook".to_string(), "command not found: gti".to_string()))
+        );
+        assert_eq!(
+            parse_tool_result("env=PATH=/usr/bin"),
+            Ok(("env".to_string(), "PATH=/usr/bin".to_string()))
+        );
+        assert!(parse_tool_result("no separator").is_err());
+        assert!(parse_tool_result("=output").is_err());
+    }
+
+    #[test]
+    fn test_select_tools_valid_command_with_flag_typo_adds_help() {
+        let tools =
+            select_tools_for_input("git comit -m 'msg'", Shell::Bash, None, |cmd| cmd == "git");
+
+        assert!(tools.contains(&Tool::HelpOutput {
+            command: "git".to_string()
+        }));
+    }
+
+    #[test]
+    fn test_select_tools_checks_build_tool_project_file() {
+        let tools = select_tools_for_input("cargo build", Shell::Bash, None, |_| true);
+        assert!(tools.contains(&Tool::FileExists {
+            path: "Cargo.toml".to_string()
+        }));
+
+        let tools = select_tools_for_input("git status", Shell::Bash, None, |_| true);
+        assert!(!tools
+            .iter()
+            .any(|tool| matches!(tool, Tool::FileExists { .. })));
+    }
+
+    #[test]
+    fn test_select_tools_command_not_found_error() {
+        // `g` is too short for a binary lookup unless the shell says it's missing
+        let without = select_tools_for_input("g status", Shell::Bash, None, |_| true);
+        let with = select_tools_for_input(
+            "g status",
+            Shell::Bash,
+            Some("bash: g: command not found"),
+            |_| true,
+        );
+        let which = Tool::WhichBinary {
+            command: "g".to_string(),
+        };
+        let help = Tool::HelpOutput {
+            command: "g".to_string(),
+        };
+
+        assert!(!without.contains(&which));
+        assert!(with.contains(&which));
+        assert!(with.contains(&Tool::ListSimilar {
+            prefix: "g".to_string()
+        }));
+        // No point reading the help of a command that doesn't exist
+        assert!(without.contains(&help));
+        assert!(!with.contains(&help));
+    }
+
+    #[test]
+    fn test_obviously_valid_with_fixture_cache() {
+        let mut tools_cache = cache::ToolsCache::new();
+        for name in ["git", "ls", "tar"] {
+            tools_cache.tools.insert(
+                name.to_string(),
+                cache::ToolInfo {
+                    path: format!("/usr/bin/{}", name),
+                    desc: String::new(),
+                },
+            );
+        }
+        let exists = |cmd: &str| tools_cache.tools.contains_key(cmd);
+
+        assert!(obviously_valid("ls", exists));
+        assert!(obviously_valid("ls ~/src", exists));
+        assert!(obviously_valid("tar 'my archive.tgz'", exists));
+
+        // Flags could be mistyped
+        assert!(!obviously_valid("ls --colr", exists));
+        assert!(!obviously_valid("git --verison", exists));
+        assert!(!obviously_valid("ls -la ~/src", exists));
+        assert!(!obviously_valid("tar --file=a.tgz", exists));
+
+        // Unknown command
+        assert!(!obviously_valid("gti --version", exists));
+        // Possible subcommand typo
+        assert!(!obviously_valid("git stauts", exists));
+        // Typo markers
+        assert!(!obviously_valid("ls -la 'src", exists));
+        assert!(!obviously_valid("ls -la |", exists));
+        assert!(!obviously_valid("ls --alll", exists));
+        assert!(!obviously_valid("", exists));
+    }
+
+    #[test]
+    fn test_is_command_not_found() {
+        assert!(is_command_not_found("zsh: command not found: gti"));
+        assert!(is_command_not_found("fish: Unknown command: gti"));
+        assert!(is_command_not_found(
+            "'gti' is not recognized as an internal or external command"
+        ));
+        assert!(!is_command_not_found("error: pathspec 'x' did not match"));
+    }
+
+    #[test]
+    fn test_select_tools_unknown_command_skips_exact_help() {
+        let tools = select_tools_for_input("frobnicate --all", Shell::Bash, None, |_| false);
+
+        assert!(!tools
+            .iter()
+            .any(|tool| matches!(tool, Tool::HelpOutput { .. })));
+    }
+
+    const GIT_HELP: &str = "usage: git [-v | --version] [-h | --help] <command> [<args>]
+
+These are common Git commands used in various situations:
+
+start a working area (see also: git help tutorial)
+   clone     Clone a repository into a new directory
+   init      Create an empty Git repository or reinitialize an existing one
+
+grow, mark and tweak your common history
+   branch    List, create, or delete branches
+   commit    Record changes to the repository
+   merge     Join two or more development histories together
+";
+
+    #[test]
+    fn test_select_tools_subcommand_typo_adds_help_for_command() {
+        let tools =
+            select_tools_for_input("git commmit -m x", Shell::Bash, None, |cmd| cmd == "git");
+
+        assert!(tools.contains(&Tool::HelpOutput {
+            command: "git".to_string()
+        }));
+        // The first word is fine, so no fuzzy lookup of another program
+        assert!(!tools.contains(&Tool::WhichBinary {
+            command: "docker".to_string()
+        }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_daemon_prompts_follow_request_shell() {
+        let config = Config::default();
+        let mut prompts = Vec::new();
+
+        // Both requests go through the same handler, as with one daemon
+        for shell in ["fish", "zsh"] {
+            let line = serde_json::json!({
+                "protocol_version": DAEMON_PROTOCOL_VERSION,
+                "command": "gti status",
+                "shell": shell,
+                "max_tools": 0,
+                "verbose": false,
+            })
+            .to_string();
+            let response =
+                handle_daemon_request(&line, &config, PromptFormat::ChatMl, |_, prepared| {
+                    prompts.push(prepared.prompt);
+                    Ok("git status".to_string())
+                });
+            let response: DaemonResponse = serde_json::from_str(&response).unwrap();
+            assert!(response.success);
+        }
+
+        assert_eq!(prompts.len(), 2);
+        assert!(prompts[0].contains("shell command assistant for fish"));
+        assert!(prompts[1].contains("shell command assistant for zsh"));
+        assert!(!prompts[1].contains("fish"));
+    }
+
+    #[test]
+    fn test_limit_tools_keeps_highest_priority() {
+        let tools = vec![
+            Tool::ManPage {
+                command: "git".to_string(),
+            },
+            Tool::HelpOutput {
+                command: "git".to_string(),
+            },
+            Tool::WhichBinary {
+                command: "gti".to_string(),
+            },
+            Tool::GetEnvVar {
+                name: "PATH".to_string(),
+            },
+            Tool::ListSimilar {
+                prefix: "gti".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            limit_tools(tools.clone(), Some(2)),
+            vec![tools[2].clone(), tools[4].clone()]
+        );
+        assert_eq!(limit_tools(tools.clone(), Some(10)), tools);
+        assert_eq!(limit_tools(tools.clone(), None), tools);
+        assert!(limit_tools(tools, Some(0)).is_empty());
+    }
+
+    #[test]
+    fn test_subcommands_from_help() {
+        let names = subcommands_from_help(GIT_HELP);
+        assert_eq!(names, vec!["clone", "init", "branch", "commit", "merge"]);
+
+        let cargo = "Commands:\n    build, b    Compile the current package\n    run, r      Run a binary\n";
+        assert_eq!(subcommands_from_help(cargo), vec!["build", "run"]);
+    }
+
+    #[test]
+    fn test_similar_subcommands_for_typo() {
+        assert_eq!(similar_subcommands("commmit", GIT_HELP), vec!["commit"]);
+        assert_eq!(similar_subcommands("brnach", GIT_HELP), vec!["branch"]);
+    }
+
+    #[test]
+    fn test_similar_subcommands_valid_or_unrelated() {
+        assert!(similar_subcommands("commit", GIT_HELP).is_empty());
+        assert!(similar_subcommands("frobnicate", GIT_HELP).is_empty());
+    }
+
+    #[test]
+    fn test_select_tools_bare_command_skips_help() {
+        let tools = select_tools_for_input("ls", Shell::Bash, None, |_| true);
+
+        assert!(!tools.contains(&Tool::HelpOutput {
+            command: "ls".to_string()
+        }));
+    }
+}
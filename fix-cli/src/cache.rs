@@ -6,7 +6,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 /// Cache file name in the config directory
@@ -15,6 +15,12 @@ const CACHE_FILE: &str = "tools_cache.json";
 /// Cache refresh interval (24 hours)
 pub const CACHE_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
+/// Current cache schema version
+///
+/// Bump this whenever the on-disk format changes, and teach `migrate` how
+/// to upgrade the previous version.
+pub const CACHE_VERSION: u32 = 1;
+
 /// Information about a discovered tool
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ToolInfo {
@@ -27,6 +33,9 @@ pub struct ToolInfo {
 /// Cache structure for discovered tools
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolsCache {
+    /// Schema version (missing in caches written before versioning, read as 0)
+    #[serde(default)]
+    pub version: u32,
     /// Last update timestamp (ISO 8601)
     pub last_updated: String,
     /// Map of tool names to their info
@@ -37,6 +46,7 @@ impl ToolsCache {
     /// Create a new empty cache
     pub fn new() -> Self {
         Self {
+            version: CACHE_VERSION,
             last_updated: chrono::Utc::now().to_rfc3339(),
             tools: HashMap::new(),
         }
@@ -72,14 +82,44 @@ impl Default for ToolsCache {
     }
 }
 
+/// Health of a tools cache, for `wit --show-config`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Tools in the cache
+    pub total: usize,
+    /// Tools whose recorded path no longer exists (uninstalled or moved)
+    pub dead_paths: usize,
+    /// How many of `discovery::PRIORITY_TOOLS` the cache has
+    pub priority_present: usize,
+    /// Time since the last refresh, if the timestamp can be read
+    pub age: Option<Duration>,
+}
+
+/// Count the tools in `cache`, checking each recorded path on disk
+pub fn stats(cache: &ToolsCache) -> CacheStats {
+    CacheStats {
+        total: cache.tools.len(),
+        dead_paths: cache
+            .tools
+            .values()
+            .filter(|info| !Path::new(&info.path).exists())
+            .count(),
+        priority_present: crate::discovery::PRIORITY_TOOLS
+            .iter()
+            .filter(|name| cache.tools.contains_key(**name))
+            .count(),
+        age: cache.age().ok(),
+    }
+}
+
 /// Get the path to the tools cache file
-pub fn cache_path() -> PathBuf {
-    crate::config_dir().join(CACHE_FILE)
+pub fn cache_path() -> Result<PathBuf, String> {
+    crate::config_dir().map(|dir| dir.join(CACHE_FILE))
 }
 
 /// Load the tools cache from disk
 pub fn load_cache() -> Result<ToolsCache, String> {
-    let path = cache_path();
+    let path = cache_path()?;
 
     if !path.exists() {
         return Err("Cache file does not exist".to_string());
@@ -87,19 +127,48 @@ pub fn load_cache() -> Result<ToolsCache, String> {
 
     let content = fs::read_to_string(&path).map_err(|e| format!("Failed to read cache: {}", e))?;
 
-    serde_json::from_str(&content).map_err(|e| format!("Failed to parse cache: {}", e))
+    parse_cache(&content)
+}
+
+/// Parse cache JSON, migrating older schema versions to the current one
+///
+/// Caches from a newer version of fix are rejected rather than guessed at.
+pub fn parse_cache(content: &str) -> Result<ToolsCache, String> {
+    let cache: ToolsCache =
+        serde_json::from_str(content).map_err(|e| format!("Failed to parse cache: {}", e))?;
+
+    if cache.version > CACHE_VERSION {
+        return Err(format!(
+            "Unsupported cache version {} (expected {})",
+            cache.version, CACHE_VERSION
+        ));
+    }
+
+    Ok(migrate(cache))
+}
+
+/// Upgrade a cache to `CACHE_VERSION`
+fn migrate(mut cache: ToolsCache) -> ToolsCache {
+    if cache.version == 0 {
+        // Pre-versioning caches share the current layout but may hold entries
+        // from older discovery logic. Keep them usable, but mark the cache
+        // stale so the next run rebuilds it.
+        cache.last_updated = chrono::DateTime::UNIX_EPOCH.to_rfc3339();
+        cache.version = 1;
+    }
+
+    cache
 }
 
 /// Save the tools cache to disk
 pub fn save_cache(cache: &ToolsCache) -> Result<(), String> {
-    let dir = crate::config_dir();
+    let dir = crate::config_dir()?;
     fs::create_dir_all(&dir).map_err(|e| format!("Failed to create config directory: {}", e))?;
 
     let content = serde_json::to_string_pretty(cache)
         .map_err(|e| format!("Failed to serialize cache: {}", e))?;
 
-    let path = cache_path();
-    fs::write(&path, content).map_err(|e| format!("Failed to write cache: {}", e))
+    fs::write(dir.join(CACHE_FILE), content).map_err(|e| format!("Failed to write cache: {}", e))
 }
 
 /// Load cache or create a new one if it doesn't exist
@@ -173,6 +242,36 @@ mod tests {
         assert_ne!(cache.last_updated, old_timestamp);
     }
 
+    #[test]
+    fn test_stats_counts_dead_paths_and_priority_tools() {
+        let dir = std::env::temp_dir().join(format!("fix-cache-stats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let live = dir.join("git");
+        fs::write(&live, "").unwrap();
+
+        let mut cache = ToolsCache::new();
+        let mut add = |name: &str, path: &Path| {
+            cache.tools.insert(
+                name.to_string(),
+                ToolInfo {
+                    path: path.to_string_lossy().to_string(),
+                    desc: String::new(),
+                },
+            );
+        };
+        add("git", &live);
+        add("docker", &dir.join("docker"));
+        add("fake-tool", &dir.join("fake-tool"));
+
+        let stats = stats(&cache);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.dead_paths, 2);
+        assert_eq!(stats.priority_present, 2);
+        assert!(stats.age.unwrap() < Duration::from_secs(60));
+    }
+
     #[test]
     fn test_cache_serialization() {
         let mut cache = ToolsCache::new();
@@ -193,12 +292,56 @@ mod tests {
         assert_eq!(deserialized.tools.get("git").unwrap().path, "/usr/bin/git");
     }
 
+    #[test]
+    fn test_parse_cache_legacy_without_version() {
+        let json = r#"{
+            "last_updated": "2099-01-01T00:00:00+00:00",
+            "tools": {"git": {"path": "/usr/bin/git", "desc": "vcs"}}
+        }"#;
+
+        let cache = parse_cache(json).unwrap();
+
+        assert_eq!(cache.version, CACHE_VERSION);
+        assert!(cache.needs_refresh());
+        assert_eq!(cache.tools.get("git").unwrap().path, "/usr/bin/git");
+    }
+
+    #[test]
+    fn test_parse_cache_current_version() {
+        let mut cache = ToolsCache::new();
+        cache.tools.insert(
+            "git".to_string(),
+            ToolInfo {
+                path: "/usr/bin/git".to_string(),
+                desc: "vcs".to_string(),
+            },
+        );
+        let json = serde_json::to_string(&cache).unwrap();
+
+        let loaded = parse_cache(&json).unwrap();
+
+        assert_eq!(loaded.version, CACHE_VERSION);
+        assert_eq!(loaded.last_updated, cache.last_updated);
+        assert_eq!(loaded.tools, cache.tools);
+        assert!(!loaded.needs_refresh());
+    }
+
+    #[test]
+    fn test_parse_cache_rejects_newer_version() {
+        let json = format!(
+            r#"{{"version": {}, "last_updated": "2099-01-01T00:00:00+00:00", "tools": {{}}}}"#,
+            CACHE_VERSION + 1
+        );
+
+        assert!(parse_cache(&json).is_err());
+    }
+
     #[test]
     fn test_cache_path_returns_correct_location() {
-        let path = cache_path();
+        let path = cache_path().unwrap();
 
         assert!(path.ends_with("tools_cache.json"));
-        assert_eq!(path.parent().unwrap(), crate::config_dir());
+        assert_eq!(path.parent().unwrap(), crate::config_dir().unwrap());
     }
 
     #[test]
@@ -0,0 +1,145 @@
+//! Reusing one inference context across daemon requests
+//!
+//! Creating a `LlamaContext` allocates its KV cache, which adds latency to
+//! every correction. `ReusableContext` keeps a single context alive instead,
+//! clearing it before each request and throwing it away after a failure so a
+//! half-finished request can't leak into the next one.
+
+use llama_cpp_2::context::LlamaContext;
+
+/// A context whose cached state can be cleared between requests
+pub trait ResettableContext {
+    /// Forget everything decoded so far
+    fn reset(&mut self);
+}
+
+impl ResettableContext for LlamaContext<'_> {
+    fn reset(&mut self) {
+        self.clear_kv_cache();
+    }
+}
+
+/// Holds at most one context, created on first use
+pub struct ReusableContext<C> {
+    context: Option<C>,
+}
+
+impl<C: ResettableContext> ReusableContext<C> {
+    /// Start without a context; the first request creates it
+    pub fn new() -> Self {
+        Self { context: None }
+    }
+
+    /// Run `request` with the kept context, creating it with `create` if needed
+    ///
+    /// The context is reset before every request. When `request` fails the
+    /// context is dropped, and the next request starts from a new one.
+    pub fn run<T, E, N, R>(&mut self, create: N, request: R) -> Result<T, E>
+    where
+        N: FnOnce() -> Result<C, E>,
+        R: FnOnce(&mut C) -> Result<T, E>,
+    {
+        let context = match self.context.as_mut() {
+            Some(context) => {
+                context.reset();
+                context
+            }
+            None => self.context.insert(create()?),
+        };
+
+        let result = request(context);
+        if result.is_err() {
+            self.context = None;
+        }
+        result
+    }
+}
+
+impl<C: ResettableContext> Default for ReusableContext<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stand-in context recording decoded tokens
+    struct MockContext {
+        id: usize,
+        tokens: Vec<u32>,
+    }
+
+    impl ResettableContext for MockContext {
+        fn reset(&mut self) {
+            self.tokens.clear();
+        }
+    }
+
+    #[test]
+    fn test_context_is_reused_and_reset() {
+        let mut created = 0;
+        let mut contexts = ReusableContext::new();
+        let mut create = || -> Result<MockContext, String> {
+            created += 1;
+            Ok(MockContext {
+                id: created,
+                tokens: Vec::new(),
+            })
+        };
+
+        let first = contexts
+            .run(&mut create, |ctx| {
+                ctx.tokens.extend([1, 2, 3]);
+                Ok::<_, String>((ctx.id, ctx.tokens.len()))
+            })
+            .unwrap();
+        assert_eq!(first, (1, 3));
+
+        // Same context, but nothing left over from the first request
+        let second = contexts
+            .run(&mut create, |ctx| {
+                let leftover = ctx.tokens.len();
+                ctx.tokens.push(4);
+                Ok::<_, String>((ctx.id, leftover))
+            })
+            .unwrap();
+        assert_eq!(second, (1, 0));
+    }
+
+    #[test]
+    fn test_failed_request_discards_context() {
+        let mut created = 0;
+        let mut contexts = ReusableContext::new();
+        let mut create = || -> Result<MockContext, String> {
+            created += 1;
+            Ok(MockContext {
+                id: created,
+                tokens: Vec::new(),
+            })
+        };
+
+        let failed: Result<(), String> = contexts.run(&mut create, |ctx| {
+            ctx.tokens.push(7);
+            Err("Decode failed".to_string())
+        });
+        assert!(failed.is_err());
+
+        let next = contexts
+            .run(&mut create, |ctx| {
+                Ok::<_, String>((ctx.id, ctx.tokens.len()))
+            })
+            .unwrap();
+        assert_eq!(next, (2, 0));
+    }
+
+    #[test]
+    fn test_create_failure_is_returned() {
+        let mut contexts: ReusableContext<MockContext> = ReusableContext::new();
+        let result = contexts.run(|| Err("Failed to create context".to_string()), |_| Ok(()));
+        assert_eq!(result, Err("Failed to create context".to_string()));
+    }
+}
@@ -0,0 +1,541 @@
+//! Connection loop shared by the fix and wit daemons (Unix only)
+//!
+//! Each connection carries one JSON line. `{"stop": true}` and
+//! `{"ping": true}` are handled here; anything else is passed to the
+//! binary's request handler. The loop exits on a stop message, once no
+//! connection has counted as activity for the idle timeout, or when the
+//! model file is replaced so the next client starts a daemon with the new one.
+//!
+//! A client sending many requests, such as an editor, can instead open with
+//! `{"stream": true}` and keep the connection: every following line gets a
+//! response line until it sends `{"close": true}` or closes the socket.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long to sleep when no connection is waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Longest one read waits on a connection before the loop moves on
+///
+/// Kept short so a quiet streaming client can't hold up other clients, the
+/// idle timeout or the model check.
+const READ_POLL_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// How often the model file is checked for replacement
+const MODEL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest a client waits for a ping reply
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Control messages the daemon answers without running inference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Stop,
+    Ping,
+    /// Keep the connection open for more requests
+    Stream,
+    /// End a streaming connection
+    Close,
+}
+
+/// Why `serve` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// A client sent `{"stop": true}`
+    Stopped,
+    /// Nothing counted as activity for the idle timeout
+    IdleTimeout,
+    /// The model file changed on disk (e.g. `--update` from another terminal)
+    ModelChanged,
+}
+
+/// An accepted client and the part of its next line read so far
+///
+/// The line is kept as raw bytes until it is complete, so a multi-byte
+/// character split across reads isn't lost.
+struct Connection {
+    reader: BufReader<UnixStream>,
+    line: Vec<u8>,
+    streaming: bool,
+    /// When the client last sent anything
+    last_read: Instant,
+}
+
+/// What one poll of a connection produced
+enum Poll {
+    /// A full request line is in `Connection::line`
+    Line,
+    /// Nothing complete yet; try again on the next pass
+    Pending,
+    /// The client hung up or the socket failed
+    Closed,
+}
+
+impl Connection {
+    fn new(stream: UnixStream) -> Self {
+        // Accepted sockets inherit non-blocking mode on some platforms
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(READ_POLL_TIMEOUT));
+        Self {
+            reader: BufReader::new(stream),
+            line: Vec::new(),
+            streaming: false,
+            last_read: Instant::now(),
+        }
+    }
+
+    /// Read towards the next line, waiting at most `READ_POLL_TIMEOUT`
+    ///
+    /// A read that times out keeps what it got in `line`, so a request
+    /// arriving in pieces is completed on a later pass.
+    fn poll(&mut self) -> Poll {
+        let read_before = self.line.len();
+        let result = self.reader.read_until(b'\n', &mut self.line);
+        if self.line.len() > read_before {
+            self.last_read = Instant::now();
+        }
+
+        match result {
+            Ok(0) if self.line.is_empty() => Poll::Closed,
+            Ok(_) => Poll::Line,
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                Poll::Pending
+            }
+            Err(_) => Poll::Closed,
+        }
+    }
+
+    /// Take the complete line read by `poll`
+    fn take_line(&mut self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned()
+    }
+
+    fn reply(&self, response: impl std::fmt::Display) {
+        let _ = writeln!(self.reader.get_ref(), "{}", response);
+    }
+}
+
+/// Recognize a control message; None means a regular request
+pub fn control_message(line: &str) -> Option<Control> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    let is_set = |key: &str| value.get(key).and_then(|v| v.as_bool()) == Some(true);
+
+    if is_set("stop") {
+        Some(Control::Stop)
+    } else if is_set("ping") {
+        Some(Control::Ping)
+    } else if is_set("stream") {
+        Some(Control::Stream)
+    } else if is_set("close") {
+        Some(Control::Close)
+    } else {
+        None
+    }
+}
+
+/// Modification time of `path`, or None if it can't be read
+pub fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Whether the model file was replaced since it was loaded
+///
+/// A file that is missing right now doesn't count yet, since a download
+/// briefly removes it before renaming the new one into place.
+pub fn model_changed(loaded: Option<SystemTime>, current: Option<SystemTime>) -> bool {
+    current.is_some() && current != loaded
+}
+
+/// Accept connections until stopped or idle, answering requests with `handle`
+///
+/// `handle` receives the request line and returns the response line. Requests
+/// always reset the idle timer; pings only do when `ping_keeps_alive` is set,
+/// so a monitoring loop can be told not to hold the model in memory. When
+/// `model` is given, its modification time is checked every
+/// `MODEL_CHECK_INTERVAL` and the loop exits once it changes.
+///
+/// Connections are polled in turn with a short read timeout, so a streaming
+/// client that goes quiet doesn't hold up anyone else. A connection that
+/// sends nothing for the idle timeout is closed.
+pub fn serve<F>(
+    listener: &UnixListener,
+    idle_timeout: Duration,
+    ping_keeps_alive: bool,
+    model: Option<&Path>,
+    mut handle: F,
+) -> std::io::Result<Shutdown>
+where
+    F: FnMut(&str) -> String,
+{
+    listener.set_nonblocking(true)?;
+    let mut last_activity = Instant::now();
+    let loaded_model = model.map(modified_time);
+    let mut last_model_check = Instant::now();
+    let mut connections: Vec<Connection> = Vec::new();
+
+    loop {
+        if last_activity.elapsed() > idle_timeout {
+            return Ok(Shutdown::IdleTimeout);
+        }
+
+        if let (Some(path), Some(loaded)) = (model, loaded_model) {
+            if last_model_check.elapsed() >= MODEL_CHECK_INTERVAL {
+                last_model_check = Instant::now();
+                if model_changed(loaded, modified_time(path)) {
+                    return Ok(Shutdown::ModelChanged);
+                }
+            }
+        }
+
+        match listener.accept() {
+            Ok((stream, _)) => connections.push(Connection::new(stream)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                // Open connections already wait in their reads
+                if connections.is_empty() {
+                    std::thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            }
+            Err(_) => {
+                std::thread::sleep(POLL_INTERVAL * 2);
+                continue;
+            }
+        }
+
+        let mut open = Vec::with_capacity(connections.len());
+        for mut connection in connections.drain(..) {
+            match connection.poll() {
+                Poll::Line => {}
+                Poll::Pending => {
+                    if connection.last_read.elapsed() <= idle_timeout {
+                        open.push(connection);
+                    }
+                    continue;
+                }
+                Poll::Closed => continue,
+            }
+
+            let line = connection.take_line();
+            match control_message(&line) {
+                Some(Control::Stop) => {
+                    connection.reply(status_response("Daemon stopping"));
+                    return Ok(Shutdown::Stopped);
+                }
+                Some(Control::Ping) => {
+                    if ping_keeps_alive {
+                        last_activity = Instant::now();
+                    }
+                    connection.reply(status_response("pong"));
+                }
+                Some(Control::Stream) => {
+                    connection.streaming = true;
+                    connection.reply(status_response("streaming"));
+                }
+                Some(Control::Close) => continue,
+                None => {
+                    connection.reply(handle(&line));
+                    // Measured from the reply so a long inference can't eat into the timeout
+                    last_activity = Instant::now();
+                }
+            }
+
+            if connection.streaming {
+                open.push(connection);
+            }
+        }
+        connections = open;
+    }
+}
+
+/// Ask the daemon listening on `socket` whether it is alive
+pub fn ping(socket: &Path) -> Result<(), String> {
+    let mut stream =
+        UnixStream::connect(socket).map_err(|e| format!("Failed to connect: {}", e))?;
+    stream
+        .set_read_timeout(Some(PING_TIMEOUT))
+        .map_err(|e| format!("Failed to set timeout: {}", e))?;
+
+    writeln!(stream, "{}", serde_json::json!({"ping": true}))
+        .map_err(|e| format!("Failed to send: {}", e))?;
+
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(|e| format!("No reply: {}", e))?;
+
+    if response.contains("pong") {
+        Ok(())
+    } else {
+        Err(format!("Unexpected reply: {}", response.trim()))
+    }
+}
+
+/// A successful response line in the shape both daemons use
+fn status_response(output: &str) -> serde_json::Value {
+    serde_json::json!({
+        "protocol_version": crate::DAEMON_PROTOCOL_VERSION,
+        "success": true,
+        "output": output,
+        "error": null,
+    })
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bind a listener on a fresh socket path in the temp directory
+    fn bind(name: &str) -> (std::path::PathBuf, UnixListener) {
+        let socket =
+            std::env::temp_dir().join(format!("fix-daemon-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        (socket, listener)
+    }
+
+    #[test]
+    fn test_control_message() {
+        assert_eq!(control_message(r#"{"stop": true}"#), Some(Control::Stop));
+        assert_eq!(control_message(r#"{"ping": true}"#), Some(Control::Ping));
+        assert_eq!(
+            control_message(r#"{"command": "echo \"stop\"", "shell": "bash"}"#),
+            None
+        );
+        assert_eq!(
+            control_message(r#"{"stream": true}"#),
+            Some(Control::Stream)
+        );
+        assert_eq!(control_message(r#"{"close": true}"#), Some(Control::Close));
+        assert_eq!(control_message("not json"), None);
+    }
+
+    #[test]
+    fn test_serve_answers_requests_and_stops() {
+        let (socket, listener) = bind("serve");
+        let server = std::thread::spawn(move || {
+            serve(&listener, Duration::from_secs(10), true, None, |line| {
+                format!("echo {}", line.trim())
+            })
+        });
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        writeln!(stream, "hello").unwrap();
+        let mut response = String::new();
+        BufReader::new(&stream).read_line(&mut response).unwrap();
+        assert_eq!(response.trim(), "echo hello");
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        writeln!(stream, "{}", serde_json::json!({"stop": true})).unwrap();
+
+        assert_eq!(server.join().unwrap().unwrap(), Shutdown::Stopped);
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn test_serve_streams_requests_over_one_connection() {
+        let (socket, listener) = bind("stream");
+        let server = std::thread::spawn(move || {
+            serve(&listener, Duration::from_secs(10), true, None, |line| {
+                format!("echo {}", line.trim())
+            })
+        });
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut read_response = || {
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+
+        writeln!(stream, "{}", serde_json::json!({"stream": true})).unwrap();
+        assert!(read_response().contains("streaming"));
+        writeln!(stream, "first").unwrap();
+        assert_eq!(read_response().trim(), "echo first");
+        writeln!(stream, "second").unwrap();
+        assert_eq!(read_response().trim(), "echo second");
+
+        // After closing, the daemon takes new connections again
+        writeln!(stream, "{}", serde_json::json!({"close": true})).unwrap();
+        assert_eq!(read_response(), "");
+        ping(&socket).unwrap();
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        writeln!(stream, "{}", serde_json::json!({"stop": true})).unwrap();
+
+        assert_eq!(server.join().unwrap().unwrap(), Shutdown::Stopped);
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn test_quiet_streaming_client_does_not_block_others() {
+        let (socket, listener) = bind("stream-quiet");
+        let server = std::thread::spawn(move || {
+            serve(&listener, Duration::from_secs(10), true, None, |line| {
+                format!("echo {}", line.trim())
+            })
+        });
+
+        let mut quiet = UnixStream::connect(&socket).unwrap();
+        let mut quiet_reader = BufReader::new(quiet.try_clone().unwrap());
+        writeln!(quiet, "{}", serde_json::json!({"stream": true})).unwrap();
+        let mut response = String::new();
+        quiet_reader.read_line(&mut response).unwrap();
+        assert!(response.contains("streaming"));
+
+        // The streaming client says nothing more, yet others are answered
+        let mut other = UnixStream::connect(&socket).unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        writeln!(other, "hello").unwrap();
+        let mut response = String::new();
+        BufReader::new(&other).read_line(&mut response).unwrap();
+        assert_eq!(response.trim(), "echo hello");
+
+        // A request split across reads still arrives whole
+        write!(quiet, "la").unwrap();
+        std::thread::sleep(READ_POLL_TIMEOUT * 3);
+        writeln!(quiet, "ter").unwrap();
+        let mut response = String::new();
+        quiet_reader.read_line(&mut response).unwrap();
+        assert_eq!(response.trim(), "echo later");
+
+        // So does a character split between its bytes
+        quiet.write_all(&[b'c', b'a', b'f', 0xc3]).unwrap();
+        std::thread::sleep(READ_POLL_TIMEOUT * 3);
+        quiet.write_all(&[0xa9, b'\n']).unwrap();
+        let mut response = String::new();
+        quiet_reader.read_line(&mut response).unwrap();
+        assert_eq!(response.trim(), "echo café");
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        writeln!(stream, "{}", serde_json::json!({"stop": true})).unwrap();
+
+        assert_eq!(server.join().unwrap().unwrap(), Shutdown::Stopped);
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn test_quiet_streaming_client_is_closed_after_idle_timeout() {
+        let (socket, listener) = bind("stream-idle");
+        let idle_timeout = Duration::from_millis(300);
+        let server = std::thread::spawn(move || {
+            serve(&listener, idle_timeout, true, None, |_| String::new())
+        });
+
+        let mut quiet = UnixStream::connect(&socket).unwrap();
+        quiet
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut quiet_reader = BufReader::new(quiet.try_clone().unwrap());
+        writeln!(quiet, "{}", serde_json::json!({"stream": true})).unwrap();
+        let mut response = String::new();
+        quiet_reader.read_line(&mut response).unwrap();
+        assert!(response.contains("streaming"));
+
+        // Pings keep the daemon up while the streaming client stays silent
+        for _ in 0..4 {
+            std::thread::sleep(Duration::from_millis(150));
+            ping(&socket).unwrap();
+        }
+        let mut response = String::new();
+        assert_eq!(quiet_reader.read_line(&mut response).unwrap(), 0);
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        writeln!(stream, "{}", serde_json::json!({"stop": true})).unwrap();
+
+        assert_eq!(server.join().unwrap().unwrap(), Shutdown::Stopped);
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn test_model_changed() {
+        let loaded = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let later = loaded + Duration::from_secs(60);
+
+        assert!(!model_changed(Some(loaded), Some(loaded)));
+        assert!(model_changed(Some(loaded), Some(later)));
+        // Mid-download the file is briefly missing
+        assert!(!model_changed(Some(loaded), None));
+        assert!(model_changed(None, Some(later)));
+    }
+
+    #[test]
+    fn test_serve_exits_when_model_changes() {
+        let (socket, listener) = bind("model-changed");
+        let model = socket.with_extension("gguf");
+        std::fs::write(&model, b"GGUF").unwrap();
+
+        let watched = model.clone();
+        let server = std::thread::spawn(move || {
+            serve(
+                &listener,
+                Duration::from_secs(10),
+                true,
+                Some(&watched),
+                |_| String::new(),
+            )
+        });
+
+        // Still serving while the file is untouched
+        std::thread::sleep(Duration::from_millis(200));
+        ping(&socket).unwrap();
+
+        let file = std::fs::File::options().write(true).open(&model).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(server.join().unwrap().unwrap(), Shutdown::ModelChanged);
+        let _ = std::fs::remove_file(&socket);
+        let _ = std::fs::remove_file(&model);
+    }
+
+    #[test]
+    fn test_ping_resets_idle_timer() {
+        let (socket, listener) = bind("ping-alive");
+        let idle_timeout = Duration::from_millis(400);
+        let started = Instant::now();
+        let server = std::thread::spawn(move || {
+            serve(&listener, idle_timeout, true, None, |_| String::new())
+        });
+
+        // Ping well inside each timeout window, outliving the timeout overall
+        for _ in 0..4 {
+            std::thread::sleep(Duration::from_millis(200));
+            ping(&socket).unwrap();
+        }
+        assert!(started.elapsed() > idle_timeout);
+
+        assert_eq!(server.join().unwrap().unwrap(), Shutdown::IdleTimeout);
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn test_ping_without_keep_alive_lets_daemon_idle_out() {
+        let (socket, listener) = bind("ping-idle");
+        let idle_timeout = Duration::from_millis(400);
+        let server = std::thread::spawn(move || {
+            serve(&listener, idle_timeout, false, None, |_| String::new())
+        });
+
+        std::thread::sleep(Duration::from_millis(200));
+        ping(&socket).unwrap();
+
+        assert_eq!(server.join().unwrap().unwrap(), Shutdown::IdleTimeout);
+        let _ = std::fs::remove_file(&socket);
+
+        // The daemon has exited, so nobody answers
+        assert!(ping(&socket).is_err());
+    }
+}
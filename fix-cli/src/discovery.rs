@@ -13,7 +13,7 @@ use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// Timeout for running --help or --version commands (200ms)
 const HELP_TIMEOUT_MS: u64 = 200;
@@ -24,24 +24,43 @@ const MAX_HELP_LINES: usize = 5;
 /// Maximum number of non-priority tools to process
 const MAX_TOOLS_TO_PROCESS: usize = 50;
 
+/// Number of newly described tools between incremental cache saves
+pub const STREAMING_SAVE_INTERVAL: usize = 10;
+
+/// How long wit describes priority tools on first use, before correcting (2 seconds)
+pub const DEFAULT_FIRST_RUN_DISCOVERY_MS: u64 = 2000;
+
 /// Priority tools to scan first (common CLIs)
-const PRIORITY_TOOLS: &[&str] = &[
+pub const PRIORITY_TOOLS: &[&str] = &[
     "git", "docker", "kubectl", "npm", "pip", "python", "node", "cargo", "rustc", "go", "java",
     "mvn", "gradle", "make", "gcc", "clang", "curl", "wget",
 ];
 
 /// Scan PATH for all executable files
 pub fn scan_path() -> Vec<PathBuf> {
-    let path_env = match env::var("PATH") {
-        Ok(p) => p,
-        Err(_) => return Vec::new(),
-    };
+    match env::var("PATH") {
+        Ok(path_env) => scan_path_dirs(&path_env),
+        Err(_) => Vec::new(),
+    }
+}
 
+/// Directories listed in a PATH value, skipping empty entries
+///
+/// POSIX shells treat an empty entry (a leading, trailing or doubled `:`) as
+/// the current directory. That is deliberately not honored here: scanning
+/// whatever directory the user happens to be in would run its executables
+/// with `--help` during discovery.
+pub fn path_dirs(path_env: &str) -> impl Iterator<Item = &str> {
     let separator = if cfg!(windows) { ';' } else { ':' };
+    path_env.split(separator).filter(|dir| !dir.is_empty())
+}
+
+/// Collect the executables in the directories of a PATH value
+fn scan_path_dirs(path_env: &str) -> Vec<PathBuf> {
     let mut executables = Vec::new();
     let mut seen = HashSet::new();
 
-    for dir in path_env.split(separator) {
+    for dir in path_dirs(path_env) {
         if let Ok(entries) = fs::read_dir(dir) {
             for entry in entries.flatten() {
                 let path = entry.path();
@@ -165,35 +184,134 @@ fn extract_from_flag(tool_path: &Path, args: &[&str]) -> Option<String> {
 
 /// Discover tools and build a cache
 pub fn discover_tools() -> ToolsCache {
-    let executables = scan_path();
+    discover_tools_in(&scan_path(), usize::MAX, |_| {}, |_, _| {})
+}
+
+/// Discover tools, handing a snapshot of the partial cache to `save` every
+/// `save_interval` tools so an interrupted scan keeps its progress
+///
+/// Partial snapshots carry a stale timestamp, so a cache left behind by an
+/// interrupted scan still reports `needs_refresh()` and gets completed later.
+/// `progress` is called with `(scanned, total)` after each executable; the
+/// scan may stop short of `total` once enough tools are described.
+pub fn discover_tools_streaming<F, P>(save_interval: usize, save: F, progress: P) -> ToolsCache
+where
+    F: FnMut(&ToolsCache),
+    P: FnMut(usize, usize),
+{
+    discover_tools_in(&scan_path(), save_interval, save, progress)
+}
+
+/// Like `discover_tools_streaming`, but keep entries from `existing` that the
+/// scan doesn't find
+///
+/// For systems where PATH differs between shells, so a single scan misses
+/// tools that were found before. Snapshots passed to `save` are merged too.
+pub fn discover_tools_append_only<F, P>(
+    existing: &ToolsCache,
+    save_interval: usize,
+    save: F,
+    progress: P,
+) -> ToolsCache
+where
+    F: FnMut(&ToolsCache),
+    P: FnMut(usize, usize),
+{
+    discover_tools_append_only_in(existing, &scan_path(), save_interval, save, progress)
+}
+
+/// Merge a fresh scan into an existing cache
+///
+/// Tools found by the scan win; tools only in `existing` are kept. The
+/// timestamp comes from the scan.
+pub fn merge_caches(existing: &ToolsCache, scanned: ToolsCache) -> ToolsCache {
+    let mut merged = scanned;
+    for (name, info) in &existing.tools {
+        merged
+            .tools
+            .entry(name.clone())
+            .or_insert_with(|| info.clone());
+    }
+    merged
+}
+
+fn discover_tools_append_only_in<F, P>(
+    existing: &ToolsCache,
+    executables: &[PathBuf],
+    save_interval: usize,
+    mut save: F,
+    progress: P,
+) -> ToolsCache
+where
+    F: FnMut(&ToolsCache),
+    P: FnMut(usize, usize),
+{
+    let scanned = discover_tools_in(
+        executables,
+        save_interval,
+        |partial| save(&merge_caches(existing, partial.clone())),
+        progress,
+    );
+    merge_caches(existing, scanned)
+}
+
+/// Build a cache from the given executables, saving every `save_interval` tools
+/// and reporting `(scanned, total)` to `progress` after each executable
+fn discover_tools_in<F, P>(
+    executables: &[PathBuf],
+    save_interval: usize,
+    mut save: F,
+    mut progress: P,
+) -> ToolsCache
+where
+    F: FnMut(&ToolsCache),
+    P: FnMut(usize, usize),
+{
     let mut cache = ToolsCache::new();
+    cache.last_updated = chrono::DateTime::UNIX_EPOCH.to_rfc3339();
+
+    let save_interval = save_interval.max(1);
+    let mut discovered = 0;
+    let mut record = |cache: &ToolsCache| {
+        discovered += 1;
+        if discovered % save_interval == 0 {
+            save(cache);
+        }
+    };
 
     // Process priority tools first
     let priority_set: HashSet<&str> = PRIORITY_TOOLS.iter().copied().collect();
+    let is_priority = |path: &PathBuf| {
+        get_tool_name(path).is_some_and(|name| priority_set.contains(name.as_str()))
+    };
+    let total = executables.len();
+    let mut scanned = 0;
 
-    for path in &executables {
+    for path in executables.iter().filter(|path| is_priority(path)) {
         if let Some(name) = get_tool_name(path) {
-            if priority_set.contains(name.as_str()) {
-                if let Some(desc) = extract_description(path) {
-                    cache.tools.insert(
-                        name,
-                        ToolInfo {
-                            path: path.to_string_lossy().to_string(),
-                            desc,
-                        },
-                    );
-                }
+            if let Some(desc) = extract_description(path) {
+                cache.tools.insert(
+                    name,
+                    ToolInfo {
+                        path: path.to_string_lossy().to_string(),
+                        desc,
+                    },
+                );
+                record(&cache);
             }
         }
+        scanned += 1;
+        progress(scanned, total);
     }
 
     // Process remaining tools (limited to avoid long scan times)
     let mut processed_count = 0;
-    for path in &executables {
+    for path in executables.iter().filter(|path| !is_priority(path)) {
         if processed_count >= MAX_TOOLS_TO_PROCESS {
             break;
         }
 
+        scanned += 1;
         if let Some(name) = get_tool_name(path) {
             if let Entry::Vacant(e) = cache.tools.entry(name) {
                 if let Some(desc) = extract_description(path) {
@@ -202,19 +320,68 @@ pub fn discover_tools() -> ToolsCache {
                         desc,
                     });
                     processed_count += 1;
+                    record(&cache);
                 }
             }
         }
+        progress(scanned, total);
     }
 
     cache.update_timestamp();
     cache
 }
 
+/// Describe only the priority tools on PATH, stopping once `timeout` has passed
+///
+/// For the first run, when there is no cache yet: the common tools are known
+/// before the correction and a full scan can follow later. The cache carries
+/// a stale timestamp, so it still reports `needs_refresh()`.
+pub fn discover_priority_tools(timeout: Duration) -> ToolsCache {
+    discover_priority_tools_in(&scan_path(), timeout)
+}
+
+fn discover_priority_tools_in(executables: &[PathBuf], timeout: Duration) -> ToolsCache {
+    let started = Instant::now();
+    let mut cache = ToolsCache::new();
+    cache.last_updated = chrono::DateTime::UNIX_EPOCH.to_rfc3339();
+
+    for path in executables {
+        if started.elapsed() >= timeout {
+            break;
+        }
+        let Some(name) = get_tool_name(path) else {
+            continue;
+        };
+        // The first match on PATH is the one the shell runs
+        if !PRIORITY_TOOLS.contains(&name.as_str()) || cache.tools.contains_key(&name) {
+            continue;
+        }
+        if let Some(desc) = extract_description(path) {
+            cache.tools.insert(
+                name,
+                ToolInfo {
+                    path: path.to_string_lossy().to_string(),
+                    desc,
+                },
+            );
+        }
+    }
+
+    cache
+}
+
 /// Spawn a background thread to refresh the cache
 pub fn refresh_cache_background(cache_arc: Arc<Mutex<ToolsCache>>) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let new_cache = discover_tools();
+        let new_cache = discover_tools_streaming(
+            STREAMING_SAVE_INTERVAL,
+            |partial| {
+                if let Err(e) = crate::cache::save_cache(partial) {
+                    eprintln!("Warning: Failed to save tools cache: {}", e);
+                }
+            },
+            |_, _| {},
+        );
 
         // Save to disk
         if let Err(e) = crate::cache::save_cache(&new_cache) {
@@ -243,6 +410,38 @@ mod tests {
         assert!(executables.is_empty() || !executables.is_empty());
     }
 
+    #[test]
+    fn test_path_dirs_skips_empty_entries() {
+        let sep = if cfg!(windows) { ';' } else { ':' };
+        let path_env = format!("{sep}/usr/bin{sep}{sep}/bin{sep}");
+        assert_eq!(
+            path_dirs(&path_env).collect::<Vec<_>>(),
+            ["/usr/bin", "/bin"]
+        );
+        assert_eq!(path_dirs("").count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_path_ignores_empty_entries() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("fix-discovery-empty-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("fake-path-tool");
+        fs::write(&tool, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let only_empty = scan_path_dirs("::");
+        let mixed = scan_path_dirs(&format!(":{}::", dir.display()));
+
+        let _ = fs::remove_dir_all(&dir);
+
+        // Empty entries would mean the cwd; nothing may come from there
+        assert!(only_empty.is_empty());
+        assert_eq!(mixed, vec![tool]);
+    }
+
     #[test]
     fn test_get_tool_name_unix() {
         #[cfg(unix)]
@@ -323,6 +522,165 @@ mod tests {
         // We'll just check it doesn't panic
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_discover_tools_streaming_saves_periodically() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("fix-discovery-stream-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut executables = Vec::new();
+        for i in 0..5 {
+            let path = dir.join(format!("fake-tool-{}", i));
+            fs::write(&path, format!("#!/bin/sh\necho \"fake tool {}\"\n", i)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            executables.push(path);
+        }
+
+        let mut snapshots = Vec::new();
+        let cache = discover_tools_in(
+            &executables,
+            2,
+            |partial| {
+                snapshots.push(partial.tools.len());
+                assert!(partial.needs_refresh(), "Partial snapshots must look stale");
+            },
+            |_, _| {},
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(cache.tools.len(), 5);
+        assert_eq!(snapshots, vec![2, 4]);
+        assert!(!cache.needs_refresh());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_discover_priority_tools_within_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("fix-discovery-priority-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut executables = Vec::new();
+        for name in ["fake-other-tool", "git", "docker"] {
+            let path = dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\necho \"{} help\"\n", name)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            executables.push(path);
+        }
+
+        let cache = discover_priority_tools_in(&executables, Duration::from_secs(5));
+        let expired = discover_priority_tools_in(&executables, Duration::ZERO);
+
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut names: Vec<&str> = cache.tools.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["docker", "git"]);
+        assert_eq!(cache.tools["git"].desc, "git help");
+        // Still stale, so the full scan isn't skipped
+        assert!(cache.needs_refresh());
+        assert!(expired.tools.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_discover_tools_reports_progress() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("fix-discovery-progress-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut executables = Vec::new();
+        for i in 0..4 {
+            let path = dir.join(format!("fake-tool-{}", i));
+            fs::write(&path, format!("#!/bin/sh\necho \"fake tool {}\"\n", i)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            executables.push(path);
+        }
+
+        let mut reports = Vec::new();
+        discover_tools_in(
+            &executables,
+            usize::MAX,
+            |_| {},
+            |scanned, total| {
+                reports.push((scanned, total));
+            },
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(reports, vec![(1, 4), (2, 4), (3, 4), (4, 4)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_append_only_keeps_entries_missing_from_scan() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("fix-discovery-append-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("fake-scanned-tool");
+        fs::write(&path, "#!/bin/sh\necho \"fake scanned tool\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let executables = vec![path];
+
+        let mut existing = ToolsCache::new();
+        existing.tools.insert(
+            "login-shell-only".to_string(),
+            ToolInfo {
+                path: "/opt/login/bin/login-shell-only".to_string(),
+                desc: "only on the login PATH".to_string(),
+            },
+        );
+
+        let appended = discover_tools_append_only_in(
+            &existing,
+            &executables,
+            1,
+            |partial| {
+                assert!(partial.tools.contains_key("login-shell-only"));
+            },
+            |_, _| {},
+        );
+        let full = discover_tools_in(&executables, usize::MAX, |_| {}, |_, _| {});
+
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(appended.tools.contains_key("login-shell-only"));
+        assert!(appended.tools.contains_key("fake-scanned-tool"));
+        assert!(!full.tools.contains_key("login-shell-only"));
+        assert!(full.tools.contains_key("fake-scanned-tool"));
+    }
+
+    #[test]
+    fn test_merge_caches_prefers_scanned_entry() {
+        let mut existing = ToolsCache::new();
+        existing.tools.insert(
+            "git".to_string(),
+            ToolInfo {
+                path: "/old/git".to_string(),
+                desc: "old".to_string(),
+            },
+        );
+        let mut scanned = ToolsCache::new();
+        scanned.tools.insert(
+            "git".to_string(),
+            ToolInfo {
+                path: "/usr/bin/git".to_string(),
+                desc: "new".to_string(),
+            },
+        );
+
+        let merged = merge_caches(&existing, scanned);
+        assert_eq!(merged.tools.get("git").unwrap().path, "/usr/bin/git");
+    }
+
     #[test]
     fn test_priority_tools_list_not_empty() {
         assert!(!PRIORITY_TOOLS.is_empty());
@@ -0,0 +1,152 @@
+//! Install diagnostics for `fix --doctor`
+//!
+//! This module runs a list of named checks and prints a pass/fail line for
+//! each, so users can see which part of their install is broken.
+
+use std::io::Write;
+use std::path::Path;
+
+/// Outcome of a single check: a detail message on pass, or the reason it failed
+pub type CheckOutcome = Result<String, String>;
+
+/// A named diagnostic check
+pub struct Check<'a> {
+    /// Short description shown in the report
+    pub name: &'static str,
+    run: Box<dyn Fn() -> CheckOutcome + 'a>,
+}
+
+impl<'a> Check<'a> {
+    /// Create a check from a name and the function that runs it
+    pub fn new<F>(name: &'static str, run: F) -> Self
+    where
+        F: Fn() -> CheckOutcome + 'a,
+    {
+        Self {
+            name,
+            run: Box::new(run),
+        }
+    }
+
+    /// Run the check
+    pub fn run(&self) -> CheckOutcome {
+        (self.run)()
+    }
+}
+
+/// Run every check, writing one result line per check to `out`
+///
+/// All checks run even after a failure. Returns true if every check passed.
+pub fn run_checks<W: Write>(checks: &[Check], out: &mut W) -> bool {
+    let mut all_passed = true;
+
+    for check in checks {
+        let line = match check.run() {
+            Ok(detail) => format!("✓ {}: {}", check.name, detail),
+            Err(reason) => {
+                all_passed = false;
+                format!("✗ {}: {}", check.name, reason)
+            }
+        };
+        let _ = writeln!(out, "{}", line);
+    }
+
+    all_passed
+}
+
+/// Checks that don't need a loaded model
+///
+/// The model-dependent checks (GPU, a known correction) live in the binary.
+pub fn standard_checks<'a>(
+    model_dir: &'a Path,
+    model_name: &'a str,
+    revision: &'a str,
+) -> Vec<Check<'a>> {
+    let mut checks = vec![
+        Check::new("Config directory writable", check_config_dir_writable),
+        Check::new("Model available", move || {
+            check_model_available(model_dir, model_name, revision)
+        }),
+    ];
+
+    #[cfg(target_os = "linux")]
+    checks.push(Check::new("libgomp present", check_libgomp));
+
+    checks
+}
+
+/// Verify the config directory can be created and written to
+pub fn check_config_dir_writable() -> CheckOutcome {
+    crate::config_dir().map(|dir| dir.display().to_string())
+}
+
+/// Verify the model is on disk, or at least downloadable from HuggingFace
+pub fn check_model_available(model_dir: &Path, model_name: &str, revision: &str) -> CheckOutcome {
+    let path = crate::get_model_path(model_dir, model_name);
+    if path.exists() {
+        return Ok(path.display().to_string());
+    }
+
+    crate::validate_model_exists(model_name, revision)
+        .map(|_| format!("{} not downloaded, available on HuggingFace", model_name))
+}
+
+/// Verify the OpenMP runtime needed by llama.cpp is installed
+#[cfg(target_os = "linux")]
+pub fn check_libgomp() -> CheckOutcome {
+    if crate::check_library_exists("libgomp.so.1") {
+        Ok("libgomp.so.1 found".to_string())
+    } else {
+        Err(format!(
+            "libgomp.so.1 missing, install with: {}",
+            crate::detect_package_manager_command()
+        ))
+    }
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_checks_prints_line_per_check() {
+        let checks = vec![
+            Check::new("First", || Ok("fine".to_string())),
+            Check::new("Second", || Err("broken".to_string())),
+            Check::new("Third", || Ok("also fine".to_string())),
+        ];
+
+        let mut out = Vec::new();
+        let passed = run_checks(&checks, &mut out);
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert!(!passed);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "✓ First: fine");
+        assert_eq!(lines[1], "✗ Second: broken");
+        assert_eq!(lines[2], "✓ Third: also fine");
+    }
+
+    #[test]
+    fn test_run_checks_all_pass() {
+        let checks = vec![Check::new("Only", || Ok("ok".to_string()))];
+
+        let mut out = Vec::new();
+        assert!(run_checks(&checks, &mut out));
+    }
+
+    #[test]
+    fn test_standard_checks_names() {
+        let model_dir = crate::config_dir().unwrap();
+        let checks = standard_checks(&model_dir, "test-model", crate::DEFAULT_REVISION);
+        let names: Vec<&str> = checks.iter().map(|c| c.name).collect();
+
+        assert!(names.contains(&"Config directory writable"));
+        assert!(names.contains(&"Model available"));
+        #[cfg(target_os = "linux")]
+        assert!(names.contains(&"libgomp present"));
+    }
+}
@@ -0,0 +1,170 @@
+//! Reading commands back out of shell history files
+//!
+//! `fix --history-file` accepts the formats the common shells write:
+//! - bash: one command per line, with `#<timestamp>` lines when
+//!   `HISTTIMEFORMAT` is set
+//! - zsh: plain lines, or `: <start>:<elapsed>;<command>` with
+//!   `EXTENDED_HISTORY`; multi-line commands continue with a trailing `\`
+//! - fish: `- cmd: <command>` entries followed by indented `when:` and
+//!   `paths:` lines
+//!
+//! Each line is recognized on its own, so a file mixing plain and extended
+//! zsh entries (after toggling the option) still parses.
+
+/// Extract the commands from a history file's contents, oldest first
+pub fn parse_history(contents: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut pending: Option<String> = None;
+
+    for line in contents.lines() {
+        // A zsh command whose previous line ended in `\` continues here
+        if let Some(mut command) = pending.take() {
+            command.push('\n');
+            push_line(&mut commands, &mut pending, command, line);
+            continue;
+        }
+
+        if let Some(command) = line.strip_prefix("- cmd: ") {
+            commands.push(unescape_fish(command));
+        } else if line.starts_with("  ") && is_fish_field(line.trim_start()) {
+            // fish metadata for the previous entry
+        } else if is_bash_timestamp(line) {
+            // HISTTIMEFORMAT timestamp for the next entry
+        } else {
+            let command = zsh_extended_command(line).unwrap_or(line);
+            push_line(&mut commands, &mut pending, String::new(), command);
+        }
+    }
+
+    if let Some(command) = pending {
+        commands.push(command);
+    }
+    commands.retain(|command| !command.trim().is_empty());
+    commands
+}
+
+/// Append `line` to `command`, holding it back in `pending` if it continues
+fn push_line(
+    commands: &mut Vec<String>,
+    pending: &mut Option<String>,
+    mut command: String,
+    line: &str,
+) {
+    match line.strip_suffix('\\') {
+        Some(start) => {
+            command.push_str(start);
+            command.push('\\');
+            *pending = Some(command);
+        }
+        None => {
+            command.push_str(line);
+            commands.push(command);
+        }
+    }
+}
+
+/// Command from a zsh `EXTENDED_HISTORY` line: `: 1700000000:0;git status`
+pub fn zsh_extended_command(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix(": ")?;
+    let (meta, command) = rest.split_once(';')?;
+    let (start, elapsed) = meta.split_once(':')?;
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    (is_number(start) && is_number(elapsed)).then_some(command)
+}
+
+/// bash writes `#1700000000` before each entry when `HISTTIMEFORMAT` is set
+fn is_bash_timestamp(line: &str) -> bool {
+    line.strip_prefix('#')
+        .is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Indented fields fish writes under each `- cmd:` entry
+fn is_fish_field(line: &str) -> bool {
+    line.starts_with("when: ") || line == "paths:" || line.starts_with("- ")
+}
+
+/// Undo fish's escaping of backslashes and newlines in `cmd:` values
+fn unescape_fish(command: &str) -> String {
+    let mut unescaped = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_bash_history() {
+        let contents = "ls -la\ngti status\n\ncd /tmp\n";
+        assert_eq!(
+            parse_history(contents),
+            vec!["ls -la", "gti status", "cd /tmp"]
+        );
+    }
+
+    #[test]
+    fn test_parse_bash_history_with_timestamps() {
+        let contents = "#1700000000\ngti status\n#1700000005\n# not a timestamp\n";
+        assert_eq!(
+            parse_history(contents),
+            vec!["gti status", "# not a timestamp"]
+        );
+    }
+
+    #[test]
+    fn test_zsh_extended_command() {
+        assert_eq!(
+            zsh_extended_command(": 1700000000:0;git status"),
+            Some("git status")
+        );
+        assert_eq!(
+            zsh_extended_command(": 1700000000:12;echo a;b"),
+            Some("echo a;b")
+        );
+        assert_eq!(zsh_extended_command(": not:a;timestamp"), None);
+        assert_eq!(zsh_extended_command("git status"), None);
+    }
+
+    #[test]
+    fn test_parse_zsh_extended_history() {
+        let contents = ": 1700000000:0;gti status\n\
+                        plain line\n\
+                        : 1700000010:3;docker run \\\n  -it ubuntu\n\
+                        : 1700000020:0;echo done\n";
+        assert_eq!(
+            parse_history(contents),
+            vec![
+                "gti status",
+                "plain line",
+                "docker run \\\n  -it ubuntu",
+                "echo done"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_fish_history() {
+        let contents = "- cmd: gti status\n  when: 1700000000\n\
+                        - cmd: printf 'a\\\\nb'\\nls\n  when: 1700000005\n  paths:\n    - /tmp\n";
+        assert_eq!(
+            parse_history(contents),
+            vec!["gti status", "printf 'a\\nb'\nls"]
+        );
+    }
+}
@@ -4,19 +4,32 @@
 //! including model management, shell detection, and prompt building.
 
 pub mod agent;
+pub mod benchmark;
 pub mod cache;
+pub mod context;
+#[cfg(unix)]
+pub mod daemon;
 pub mod discovery;
+pub mod doctor;
+pub mod history;
+pub mod logging;
 pub mod parser;
 pub mod progress;
+pub mod prompt_cache;
+pub mod safety;
+pub mod sampling;
 pub mod tools;
 
 use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 // ===== Constants =====
 
@@ -29,91 +42,582 @@ pub const DEFAULT_MODEL: &str = "qwen3-correct-0.6B";
 /// Default model for wit (agentic) CLI - larger model for complex corrections
 pub const WIT_DEFAULT_MODEL: &str = "qwen3-wit-1.7B";
 
+/// Revision of `HF_REPO` models are downloaded from unless one is pinned
+pub const DEFAULT_REVISION: &str = "main";
+
 // ===== Configuration =====
 
 /// Persistent configuration for the fix CLI
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub default_model: String,
+    /// Keep the model loaded in a background daemon (Unix only)
+    #[serde(default = "default_use_daemon")]
+    pub use_daemon: bool,
+    /// Whether `--ping` and `--status` reset the daemon's idle timer
+    #[serde(default = "default_ping_keeps_daemon_alive")]
+    pub ping_keeps_daemon_alive: bool,
+    /// How long wit reuses a tool result before running the tool again
+    #[serde(default = "default_tool_cache_ttl_secs")]
+    pub tool_cache_ttl_secs: u64,
+    /// How long wit describes common tools before its first correction, when
+    /// there is no tool cache yet; 0 turns this off
+    #[serde(default = "default_first_run_discovery_ms")]
+    pub first_run_discovery_ms: u64,
+    /// How much of each wit tool's output goes into the prompt, by tool name
+    #[serde(default = "tools::default_output_limits")]
+    pub tool_output_limits: BTreeMap<String, tools::OutputLimit>,
+    /// Directory containing the fix and wit binaries, used to start the
+    /// daemon when the running executable is a wrapper
+    #[serde(default)]
+    pub bin_dir: Option<PathBuf>,
+    /// Patterns a correction may not introduce (see `safety`)
+    #[serde(default = "safety::default_patterns")]
+    pub destructive_patterns: Vec<String>,
+    /// Regular expressions for inputs never sent to the model (see `safety`)
+    #[serde(default)]
+    pub input_denylist: Vec<String>,
+    /// Abbreviations wit expands when the first word isn't a real command,
+    /// such as `k` for `kubectl`
+    #[serde(default = "default_abbreviations")]
+    pub abbreviations: BTreeMap<String, String>,
+    /// Prompt format override; detected from the model's metadata when unset
+    #[serde(default)]
+    pub prompt_format: Option<PromptFormat>,
+    /// Custom system prompt template for fix; `{shell}`, `{shell_hint}` and
+    /// `{command}` are filled in
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Apply small corrections word by word, keeping the user's spacing and
+    /// casing (see `merge_minimal_edit`)
+    #[serde(default)]
+    pub minimal_edit: bool,
+    /// Read the user's shell aliases so wit treats them as valid commands
+    #[serde(default)]
+    pub shell_aliases: bool,
+    /// Keep one inference context in the fix daemon instead of one per request
+    #[serde(default)]
+    pub reuse_daemon_context: bool,
+    /// Corrections the fix daemon remembers for repeated prompts; 0 turns this off
+    #[serde(default = "default_prompt_cache_size")]
+    pub prompt_cache_size: usize,
+    /// Let fix's model think before answering; slower, and the thoughts are discarded
+    #[serde(default)]
+    pub thinking: bool,
+    /// HuggingFace branch, tag or commit to download models from (default `main`)
+    #[serde(default)]
+    pub model_revision: Option<String>,
+    /// Tag pairs around model reasoning to strip from output (see `sampling`)
+    #[serde(default = "sampling::default_thinking_tags")]
+    pub thinking_tags: Vec<sampling::ThinkingTags>,
+    /// Switch Windows consoles to UTF-8 output while fix and wit run
+    #[serde(default = "default_utf8_console")]
+    pub utf8_console: bool,
+}
+
+fn default_use_daemon() -> bool {
+    true
+}
+
+fn default_utf8_console() -> bool {
+    true
+}
+
+fn default_ping_keeps_daemon_alive() -> bool {
+    true
+}
+
+fn default_prompt_cache_size() -> usize {
+    prompt_cache::DEFAULT_PROMPT_CACHE_SIZE
+}
+
+fn default_first_run_discovery_ms() -> u64 {
+    discovery::DEFAULT_FIRST_RUN_DISCOVERY_MS
+}
+
+fn default_tool_cache_ttl_secs() -> u64 {
+    tools::DEFAULT_CACHE_TTL_SECS
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             default_model: DEFAULT_MODEL.to_string(),
+            use_daemon: default_use_daemon(),
+            ping_keeps_daemon_alive: default_ping_keeps_daemon_alive(),
+            tool_cache_ttl_secs: default_tool_cache_ttl_secs(),
+            first_run_discovery_ms: default_first_run_discovery_ms(),
+            tool_output_limits: tools::default_output_limits(),
+            bin_dir: None,
+            destructive_patterns: safety::default_patterns(),
+            input_denylist: Vec::new(),
+            abbreviations: default_abbreviations(),
+            prompt_format: None,
+            system_prompt: None,
+            minimal_edit: false,
+            shell_aliases: false,
+            reuse_daemon_context: false,
+            prompt_cache_size: default_prompt_cache_size(),
+            thinking: false,
+            model_revision: None,
+            thinking_tags: sampling::default_thinking_tags(),
+            utf8_console: default_utf8_console(),
         }
     }
 }
 
+impl Config {
+    /// Whether inference should bypass the daemon, given the `--direct` flag
+    pub fn use_direct_mode(&self, direct_flag: bool) -> bool {
+        direct_flag || !self.use_daemon
+    }
+
+    /// Whether to switch the console to UTF-8, given the `--no-utf8-console` flag
+    pub fn use_utf8_console(&self, no_utf8_flag: bool) -> bool {
+        self.utf8_console && !no_utf8_flag
+    }
+
+    /// Model revision to download, given the `--revision` flag
+    pub fn model_revision<'a>(&'a self, revision_flag: Option<&'a str>) -> &'a str {
+        revision_flag
+            .or(self.model_revision.as_deref())
+            .unwrap_or(DEFAULT_REVISION)
+    }
+
+    /// The system prompt template in effect: the configured one or the default
+    pub fn system_prompt_template(&self) -> &str {
+        self.system_prompt
+            .as_deref()
+            .unwrap_or(DEFAULT_SYSTEM_PROMPT_TEMPLATE)
+    }
+
+    /// Tool result cache TTL as a Duration
+    pub fn tool_cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.tool_cache_ttl_secs)
+    }
+}
+
 /// Represents an available model on HuggingFace
 pub struct AvailableModel {
     pub name: String,
     pub size: u64,
+    /// Date of the last commit touching the file, when the API reports it
+    pub updated: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 // ===== Path Functions =====
 
 /// Get the platform-specific configuration directory for the fix CLI
-pub fn config_dir() -> PathBuf {
-    dirs::config_dir()
-        .unwrap_or_else(|| env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
-        .join("fix")
+///
+/// Fails, explaining why, when no writable directory can be found. The
+/// result is computed once per process.
+pub fn config_dir() -> Result<PathBuf, String> {
+    static RESOLVED: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+    RESOLVED
+        .get_or_init(|| {
+            #[cfg(target_os = "linux")]
+            {
+                resolve_config_dir(env::var_os("XDG_CONFIG_HOME"), dirs::home_dir())
+            }
+
+            #[cfg(not(target_os = "linux"))]
+            {
+                let dir = dirs::config_dir()
+                    .ok_or_else(|| "Could not determine the config directory".to_string())?
+                    .join("fix");
+                ensure_writable_dir(&dir).map(|_| dir)
+            }
+        })
+        .clone()
+}
+
+/// Resolve `fix`'s config directory following the XDG base directory spec
+///
+/// Tries `$XDG_CONFIG_HOME/fix` (ignored unless absolute, as the spec
+/// requires) and then `$HOME/.config/fix`, returning the first that can be
+/// created and written to.
+pub fn resolve_config_dir(
+    xdg_config_home: Option<OsString>,
+    home: Option<PathBuf>,
+) -> Result<PathBuf, String> {
+    let mut candidates = Vec::new();
+    if let Some(xdg) = xdg_config_home.map(PathBuf::from) {
+        if xdg.is_absolute() {
+            candidates.push(("$XDG_CONFIG_HOME", xdg.join("fix")));
+        }
+    }
+    if let Some(home) = home {
+        candidates.push(("$HOME/.config", home.join(".config").join("fix")));
+    }
+
+    if candidates.is_empty() {
+        return Err("No config directory: neither $XDG_CONFIG_HOME nor $HOME is set".to_string());
+    }
+
+    let mut failures = Vec::new();
+    for (source, dir) in candidates {
+        match ensure_writable_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) => failures.push(format!("{} ({}): {}", source, dir.display(), e)),
+        }
+    }
+
+    Err(format!(
+        "No writable config directory: {}",
+        failures.join("; ")
+    ))
+}
+
+/// Create `dir` if needed and check that files can be written to it
+fn ensure_writable_dir(dir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+
+    let probe = dir.join(".write-test");
+    std::fs::write(&probe, b"ok").map_err(|e| e.to_string())?;
+    let _ = std::fs::remove_file(&probe);
+
+    Ok(())
 }
 
 /// Get the path to the configuration file
-pub fn config_path() -> PathBuf {
-    config_dir().join("config.json")
+pub fn config_path() -> Result<PathBuf, String> {
+    config_dir().map(|dir| dir.join("config.json"))
+}
+
+/// What is at the config file's path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigPathKind {
+    Missing,
+    File,
+    Directory,
+    /// A symlink whose target doesn't exist
+    DanglingSymlink,
+    /// Anything else that isn't a regular file (a FIFO, a socket)
+    Other,
+}
+
+impl ConfigPathKind {
+    /// Inspect `path`, following symlinks
+    pub fn of(path: &Path) -> Self {
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => ConfigPathKind::File,
+            Ok(metadata) if metadata.is_dir() => ConfigPathKind::Directory,
+            Ok(_) => ConfigPathKind::Other,
+            Err(_) if std::fs::symlink_metadata(path).is_ok() => ConfigPathKind::DanglingSymlink,
+            Err(_) => ConfigPathKind::Missing,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            ConfigPathKind::Missing => "missing",
+            ConfigPathKind::File => "a file",
+            ConfigPathKind::Directory => "a directory",
+            ConfigPathKind::DanglingSymlink => "a symlink to a missing file",
+            ConfigPathKind::Other => "not a regular file",
+        }
+    }
 }
 
 /// Load configuration from disk, returning default if not found
 pub fn load_config() -> Config {
-    let path = config_path();
-    if path.exists() {
-        if let Ok(content) = std::fs::read_to_string(&path) {
-            if let Ok(config) = serde_json::from_str(&content) {
-                return config;
+    match config_path() {
+        Ok(path) => load_config_from(&path, ConfigPathKind::of),
+        Err(_) => Config::default(),
+    }
+}
+
+/// Load the config at `path`, with `kind` telling what is there
+///
+/// Something other than a file at the path is most likely a mistake, so it
+/// gets a warning instead of silently falling back to the defaults.
+fn load_config_from(path: &Path, kind: impl Fn(&Path) -> ConfigPathKind) -> Config {
+    match kind(path) {
+        ConfigPathKind::Missing => {}
+        ConfigPathKind::File => {
+            if let Ok(content) = std::fs::read_to_string(path) {
+                if let Ok(config) = serde_json::from_str(&content) {
+                    return config;
+                }
             }
         }
+        other => eprintln!(
+            "Warning: Config path {} is {}, using the default config",
+            path.display(),
+            other.describe()
+        ),
     }
     Config::default()
 }
 
 /// Save configuration to disk
 pub fn save_config(config: &Config) -> Result<(), String> {
-    let dir = config_dir();
+    let dir = config_dir()?;
     std::fs::create_dir_all(&dir)
         .map_err(|e| format!("Failed to create config directory: {}", e))?;
+    save_config_to(&dir.join("config.json"), config, ConfigPathKind::of)
+}
+
+/// Write the config to `path`, refusing to replace a directory
+fn save_config_to(
+    path: &Path,
+    config: &Config,
+    kind: impl Fn(&Path) -> ConfigPathKind,
+) -> Result<(), String> {
+    if kind(path) == ConfigPathKind::Directory {
+        return Err(format!(
+            "Failed to save config: {} is a directory",
+            path.display()
+        ));
+    }
     let content = serde_json::to_string_pretty(config).map_err(|e| e.to_string())?;
-    std::fs::write(config_path(), content).map_err(|e| format!("Failed to save config: {}", e))
+    std::fs::write(path, content).map_err(|e| format!("Failed to save config: {}", e))
+}
+
+/// Check whether `path` is the binary called `name` (with `.exe` on Windows)
+pub fn is_expected_executable(path: &Path, name: &str) -> bool {
+    let expected = format!("{}{}", name, env::consts::EXE_SUFFIX);
+    path.file_name().and_then(|f| f.to_str()) == Some(expected.as_str())
+}
+
+/// Pick the executable to re-spawn with `--daemon`
+///
+/// `current` comes from `std::env::current_exe()`, which can be a wrapper
+/// script or interpreter when the binary is launched indirectly. Those don't
+/// accept `--daemon`, so fall back to `name` inside the configured `bin_dir`.
+pub fn resolve_daemon_executable(
+    current: &Path,
+    name: &str,
+    bin_dir: Option<&Path>,
+) -> Result<PathBuf, String> {
+    if is_expected_executable(current, name) {
+        return Ok(current.to_path_buf());
+    }
+
+    match bin_dir {
+        Some(dir) => {
+            let candidate = dir.join(format!("{}{}", name, env::consts::EXE_SUFFIX));
+            if candidate.is_file() {
+                Ok(candidate)
+            } else {
+                Err(format!(
+                    "Configured bin_dir has no {} binary: {}",
+                    name,
+                    candidate.display()
+                ))
+            }
+        }
+        None => Err(format!(
+            "Running executable {} is not {}; set \"bin_dir\" in {} to the directory containing it",
+            current.display(),
+            name,
+            config_path().map_or_else(
+                |_| "the config file".to_string(),
+                |p| p.display().to_string()
+            )
+        )),
+    }
 }
 
 // ===== Model Management =====
 
-/// Fetch available models from HuggingFace
-pub fn fetch_available_models() -> Result<Vec<AvailableModel>, String> {
-    let url = format!("https://huggingface.co/api/models/{}/tree/main", HF_REPO);
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
+/// Proxies used to reach HuggingFace and other model hosts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxySettings {
+    /// Proxy for `https://` URLs
+    pub https: Option<String>,
+    /// Proxy for `http://` URLs
+    pub http: Option<String>,
+    /// Hosts (and their subdomains) reached without a proxy
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    /// Read `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` (either case) through `var`
+    ///
+    /// `flag` (from `--proxy`) replaces both proxies but still honors
+    /// `NO_PROXY`. Empty values count as unset.
+    pub fn resolve<F: Fn(&str) -> Option<String>>(flag: Option<&str>, var: F) -> Self {
+        let read = |name: &str| {
+            let set = |value: &String| !value.trim().is_empty();
+            var(name)
+                .filter(set)
+                .or_else(|| var(&name.to_lowercase()).filter(set))
+        };
+        let flag = flag.map(str::to_string);
+
+        Self {
+            https: flag.clone().or_else(|| read("HTTPS_PROXY")),
+            http: flag.or_else(|| read("HTTP_PROXY")),
+            no_proxy: read("NO_PROXY")
+                .map(|list| {
+                    list.split(',')
+                        .map(|host| host.trim().to_lowercase())
+                        .filter(|host| !host.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Proxy URL to send a request for `url` through, if any
+    ///
+    /// A proxy given without a scheme (`proxy.corp:8080`) is taken as `http://`.
+    pub fn proxy_for(&self, url: &reqwest::Url) -> Option<String> {
+        let host = url.host_str()?.to_lowercase();
+        if self
+            .no_proxy
+            .iter()
+            .any(|entry| no_proxy_matches(entry, &host))
+        {
+            return None;
+        }
+
+        let proxy = match url.scheme() {
+            "https" => self.https.as_ref(),
+            "http" => self.http.as_ref(),
+            _ => None,
+        }?;
+        if proxy.contains("://") {
+            Some(proxy.clone())
+        } else {
+            Some(format!("http://{}", proxy))
+        }
+    }
+}
+
+/// Whether a `NO_PROXY` entry covers `host`
+///
+/// `*` matches every host; `example.com`, `.example.com` and `*.example.com`
+/// all match the domain and its subdomains.
+fn no_proxy_matches(entry: &str, host: &str) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    let domain = entry.trim_start_matches('*').trim_start_matches('.');
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+static PROXY: OnceLock<ProxySettings> = OnceLock::new();
+
+/// Apply `--proxy` for this process's downloads
+///
+/// Call it before the first request; without it, downloads use the proxy
+/// environment variables.
+pub fn init_proxy(flag: Option<&str>) {
+    let _ = PROXY.set(ProxySettings::resolve(flag, |name| env::var(name).ok()));
+}
+
+/// Proxy settings chosen by `init_proxy`, or read from the environment
+fn proxy_settings() -> &'static ProxySettings {
+    PROXY.get_or_init(|| ProxySettings::resolve(None, |name| env::var(name).ok()))
+}
+
+/// Build an HTTP client that routes requests through `proxy` where it applies
+pub fn http_client(timeout: std::time::Duration, proxy: &ProxySettings) -> Result<Client, String> {
+    let proxy = proxy.clone();
+    Client::builder()
+        .timeout(timeout)
+        .proxy(reqwest::Proxy::custom(move |url| {
+            proxy
+                .proxy_for(url)
+                .and_then(|p| reqwest::Url::parse(&p).ok())
+        }))
         .build()
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| e.to_string())
+}
 
-    let response = client.get(&url).send().map_err(|e| {
-        format!(
-            "Failed to connect to HuggingFace. Check your internet connection.\nError: {}",
-            e
-        )
-    })?;
+/// Fetch the models available at `revision` from HuggingFace
+pub fn fetch_available_models(revision: &str) -> Result<Vec<AvailableModel>, String> {
+    fetch_models(false, revision)
+}
 
-    if !response.status().is_success() {
+/// Fetch the model list, with last-commit dates when `expand` is set
+///
+/// Expanded listings are slower, so they're only requested for `--since`.
+fn fetch_models(expand: bool, revision: &str) -> Result<Vec<AvailableModel>, String> {
+    Ok(parse_model_listing(&fetch_model_files(expand, revision)?))
+}
+
+/// Fetch the tree listing of `HF_REPO` at `revision`, as the API returns it
+fn fetch_model_files(expand: bool, revision: &str) -> Result<Vec<serde_json::Value>, String> {
+    let mut url = format!(
+        "https://huggingface.co/api/models/{}/tree/{}",
+        HF_REPO,
+        encode_revision(revision)
+    );
+    if expand {
+        url.push_str("?expand=true");
+    }
+    let client = http_client(std::time::Duration::from_secs(30), proxy_settings())?;
+    fetch_listing(&client, url)
+}
+
+/// Most pages of a tree listing followed, in case a server keeps linking onward
+const MAX_LISTING_PAGES: usize = 100;
+
+/// Fetch a tree listing, following its `Link: <...>; rel="next"` pages
+///
+/// HuggingFace splits the listing of a large repository into pages; the
+/// entries of all of them are returned together.
+fn fetch_listing(client: &Client, url: String) -> Result<Vec<serde_json::Value>, String> {
+    let mut files = Vec::new();
+    let mut next = Some(url);
+
+    for _ in 0..MAX_LISTING_PAGES {
+        let Some(url) = next.take() else {
+            break;
+        };
+        log_info!("Fetching model list from {}", url);
+        let response = client.get(&url).send().map_err(|e| {
+            format!(
+                "Failed to connect to HuggingFace. Check your internet connection.\nError: {}",
+                e
+            )
+        })?;
+
+        if !response.status().is_success() {
+            return Err(format!(
+                "Failed to fetch models: HTTP {}",
+                response.status()
+            ));
+        }
+
+        next = response
+            .headers()
+            .get(reqwest::header::LINK)
+            .and_then(|link| link.to_str().ok())
+            .and_then(next_page_link);
+        let page: Vec<serde_json::Value> = response.json().map_err(|e| e.to_string())?;
+        files.extend(page);
+    }
+
+    if next.is_some() {
         return Err(format!(
-            "Failed to fetch models: HTTP {}",
-            response.status()
+            "Model list is longer than {} pages; refusing to show a partial list",
+            MAX_LISTING_PAGES
         ));
     }
 
-    let files: Vec<serde_json::Value> = response.json().map_err(|e| e.to_string())?;
+    Ok(files)
+}
+
+/// The `rel="next"` URL of a `Link` header, if there is one
+pub fn next_page_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        let is_next = params
+            .split(';')
+            .any(|param| matches!(param.trim(), "rel=\"next\"" | "rel=next"));
+        let url = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+        is_next.then(|| url.to_string())
+    })
+}
 
-    Ok(files
+/// Extract GGUF models from a HuggingFace tree listing
+pub fn parse_model_listing(files: &[serde_json::Value]) -> Vec<AvailableModel> {
+    files
         .iter()
         .filter_map(|f| {
             let path = f.get("path")?.as_str()?;
@@ -121,18 +625,70 @@ pub fn fetch_available_models() -> Result<Vec<AvailableModel>, String> {
                 Some(AvailableModel {
                     name: path.trim_end_matches(".gguf").to_string(),
                     size: f.get("size").and_then(|s| s.as_u64()).unwrap_or(0),
+                    updated: f
+                        .pointer("/lastCommit/date")
+                        .and_then(|d| d.as_str())
+                        .and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok())
+                        .map(|d| d.with_timezone(&chrono::Utc)),
                 })
             } else {
                 None
             }
         })
-        .collect())
+        .collect()
+}
+
+/// Parse a `--since` window such as `24h`, `7d` or `2w`
+pub fn parse_since(s: &str) -> Result<chrono::Duration, String> {
+    let s = s.trim();
+    let invalid = || format!("Invalid duration '{}', expected e.g. 24h, 7d or 2w", s);
+
+    let unit_start = s.char_indices().last().map_or(0, |(i, _)| i);
+    let (count, unit) = s.split_at(unit_start);
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    if count <= 0 {
+        return Err(invalid());
+    }
+
+    match unit {
+        "h" => Ok(chrono::Duration::hours(count)),
+        "d" => Ok(chrono::Duration::days(count)),
+        "w" => Ok(chrono::Duration::weeks(count)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Check whether a model was updated within `window` of `now`
+///
+/// Models without a timestamp never match.
+pub fn updated_within(
+    model: &AvailableModel,
+    window: chrono::Duration,
+    now: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    model.updated.is_some_and(|updated| updated >= now - window)
 }
 
 /// List available models and print to stdout
-pub fn list_models(config: &Config) -> Result<(), String> {
+///
+/// With `since`, only models updated within that window are shown. If the
+/// API returns no timestamps, every model is shown instead.
+pub fn list_models(
+    config: &Config,
+    revision: &str,
+    since: Option<chrono::Duration>,
+) -> Result<(), String> {
     eprintln!("Fetching available models...");
-    let models = fetch_available_models()?;
+    let mut models = fetch_models(since.is_some(), revision)?;
+
+    if let Some(window) = since {
+        if models.iter().any(|m| m.updated.is_some()) {
+            let now = chrono::Utc::now();
+            models.retain(|m| updated_within(m, window, now));
+        } else {
+            eprintln!("Model update times unavailable, showing all models");
+        }
+    }
 
     if models.is_empty() {
         println!("No models available in repository.");
@@ -153,44 +709,276 @@ pub fn list_models(config: &Config) -> Result<(), String> {
     Ok(())
 }
 
-/// Validate that a model exists on HuggingFace
-pub fn validate_model_exists(model_name: &str) -> Result<(), String> {
-    let models = fetch_available_models()?;
-    if models.iter().any(|m| m.name == model_name) {
-        Ok(())
-    } else {
-        let names: Vec<_> = models.iter().map(|m| m.name.as_str()).collect();
-        Err(format!(
-            "Model '{}' not found.\nAvailable models: {}",
-            model_name,
-            names.join(", ")
-        ))
+/// Validate that a model exists on HuggingFace at `revision`
+pub fn validate_model_exists(model_name: &str, revision: &str) -> Result<(), String> {
+    find_available_model(model_name, revision).map(|_| ())
+}
+
+/// Look up a model on HuggingFace by name
+pub fn find_available_model(model_name: &str, revision: &str) -> Result<AvailableModel, String> {
+    find_model_in(fetch_available_models(revision)?, model_name)
+}
+
+/// Size in bytes of the model file `model_name` at `revision`, before downloading it
+pub fn model_download_size(model_name: &str, revision: &str) -> Result<u64, String> {
+    model_size_in_listing(&fetch_model_files(false, revision)?, model_name)
+}
+
+/// Size in bytes of `model_name` in a HuggingFace tree listing
+///
+/// A listed model without a size is an error rather than 0 bytes.
+pub fn model_size_in_listing(files: &[serde_json::Value], model_name: &str) -> Result<u64, String> {
+    find_model_in(parse_model_listing(files), model_name)?;
+
+    let path = format!("{}.gguf", model_name);
+    files
+        .iter()
+        .find(|f| f.get("path").and_then(|p| p.as_str()) == Some(path.as_str()))
+        .and_then(|f| f.get("size")?.as_u64())
+        .ok_or_else(|| {
+            format!(
+                "HuggingFace didn't report the size of model '{}'",
+                model_name
+            )
+        })
+}
+
+/// Pick `model_name` out of a model list, naming the alternatives if it's missing
+fn find_model_in(models: Vec<AvailableModel>, model_name: &str) -> Result<AvailableModel, String> {
+    let names: Vec<_> = models.iter().map(|m| m.name.clone()).collect();
+    models
+        .into_iter()
+        .find(|m| m.name == model_name)
+        .ok_or_else(|| {
+            format!(
+                "Model '{}' not found.\nAvailable models: {}",
+                model_name,
+                names.join(", ")
+            )
+        })
+}
+
+/// Whether to ask before downloading a missing model
+///
+/// Only interactive runs ask; scripts and shell integrations download as
+/// before. `--yes` skips the question.
+pub fn should_confirm_download(interactive: bool, assume_yes: bool) -> bool {
+    interactive_default(assume_yes.then_some(false), interactive)
+}
+
+/// Whether a person is at the terminal: both stdin and stdout are a TTY
+///
+/// Scripts, pipes and shell integrations are non-interactive; prompts,
+/// spinners and color default off for them.
+pub fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
+}
+
+/// Switch console output to UTF-8 so corrections with non-ASCII characters print intact
+///
+/// Windows consoles start in a legacy code page; elsewhere the terminal
+/// already takes UTF-8 and this does nothing. The console is shared with the
+/// shell, so its original code page is put back when the process exits.
+/// Returns whether output is UTF-8.
+pub fn enable_utf8_console() -> bool {
+    #[cfg(windows)]
+    {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        const CP_UTF8: u32 = 65001;
+        /// Code page to restore on exit; 0 until the console is switched
+        static ORIGINAL_CODE_PAGE: AtomicU32 = AtomicU32::new(0);
+
+        #[link(name = "kernel32")]
+        extern "system" {
+            fn GetConsoleOutputCP() -> u32;
+            fn SetConsoleOutputCP(code_page: u32) -> i32;
+        }
+
+        extern "C" {
+            fn atexit(callback: extern "C" fn()) -> i32;
+        }
+
+        extern "C" fn restore_code_page() {
+            let original = ORIGINAL_CODE_PAGE.load(Ordering::SeqCst);
+            if original != 0 {
+                // SAFETY: SetConsoleOutputCP takes a plain integer and has no
+                // memory-safety preconditions; a failure only leaves UTF-8 on.
+                unsafe {
+                    SetConsoleOutputCP(original);
+                }
+            }
+        }
+
+        // SAFETY: both calls take and return plain integers and have no
+        // memory-safety preconditions; without a console they just fail.
+        let original = unsafe { GetConsoleOutputCP() };
+        if original == CP_UTF8 {
+            return true;
+        }
+        // SAFETY: as above.
+        if unsafe { SetConsoleOutputCP(CP_UTF8) } == 0 {
+            return false;
+        }
+        // Register the restore only for the first switch; `exit` runs it on
+        // both normal returns and `std::process::exit`
+        if ORIGINAL_CODE_PAGE.swap(original, Ordering::SeqCst) == 0 {
+            // SAFETY: `restore_code_page` is a plain `extern "C" fn` that
+            // doesn't unwind and only touches a static.
+            unsafe {
+                atexit(restore_code_page);
+            }
+        }
+        true
+    }
+
+    #[cfg(not(windows))]
+    {
+        true
     }
 }
 
-/// Download a model from HuggingFace
-pub fn download_model(model_name: &str) -> Result<PathBuf, String> {
-    let url = format!(
-        "https://huggingface.co/{}/resolve/main/{}.gguf",
-        HF_REPO, model_name
-    );
-    let dest = config_dir().join(format!("{}.gguf", model_name));
+/// Resolve a setting that defaults to on exactly when the run is interactive
+///
+/// `explicit` is the choice made with a flag, if any, and always wins.
+pub fn interactive_default(explicit: Option<bool>, interactive: bool) -> bool {
+    explicit.unwrap_or(interactive)
+}
 
-    // Create directory if needed
-    std::fs::create_dir_all(config_dir())
-        .map_err(|e| format!("Failed to create config directory: {}", e))?;
+/// Parse an answer to a `[Y/n]` question
+///
+/// An empty answer accepts the default (yes); None means the answer wasn't
+/// recognized and the question should be asked again.
+pub fn parse_confirmation(answer: &str) -> Option<bool> {
+    match answer.trim().to_lowercase().as_str() {
+        "" | "y" | "yes" => Some(true),
+        "n" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// The question asked before downloading `model_name`, `size` bytes large
+pub fn download_prompt(model_name: &str, size: u64) -> String {
+    let size_mb = size as f64 / (1024.0 * 1024.0);
+    format!(
+        "Model {} ({:.0} MB) is not downloaded. Download now? [Y/n] ",
+        model_name, size_mb
+    )
+}
+
+/// Ask on the terminal whether to download `model_name`; end of input declines
+fn confirm_download(model_name: &str, size: u64) -> bool {
+    confirm(&download_prompt(model_name, size))
+}
+
+/// Ask a `[Y/n]` question on stderr until it gets an answer; end of input declines
+pub fn confirm(prompt: &str) -> bool {
+    let stdin = std::io::stdin();
+    loop {
+        eprint!("{}", prompt);
+        let _ = std::io::stderr().flush();
+
+        let mut answer = String::new();
+        match stdin.lock().read_line(&mut answer) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) => {}
+        }
+        if let Some(accepted) = parse_confirmation(&answer) {
+            return accepted;
+        }
+    }
+}
+
+/// Download a model at `revision` from HuggingFace into `model_dir`
+///
+/// The revision is recorded next to the model (see `revision_path`).
+pub fn download_model(
+    model_dir: &Path,
+    model_name: &str,
+    revision: &str,
+    keep_partial: bool,
+) -> Result<PathBuf, String> {
+    let dest = get_model_path(model_dir, model_name);
 
     eprintln!("Downloading {}...", model_name);
+    download_file(
+        &hf_model_url(model_name, revision),
+        &dest,
+        "HuggingFace",
+        keep_partial,
+    )?;
+    std::fs::write(revision_path(&dest), revision)
+        .map_err(|e| format!("Failed to record model revision: {}", e))?;
 
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(3600)) // 1 hour timeout for large files
-        .build()
-        .map_err(|e| e.to_string())?;
+    eprintln!("✓ Downloaded to {}", dest.display());
+    Ok(dest)
+}
+
+/// Download URL for a model in `HF_REPO` at `revision`
+fn hf_model_url(model_name: &str, revision: &str) -> String {
+    format!(
+        "https://huggingface.co/{}/resolve/{}/{}.gguf",
+        HF_REPO,
+        encode_revision(revision),
+        model_name
+    )
+}
+
+/// `revision` as a single URL path segment
+///
+/// Branch names may contain `/`, `?`, `#` or spaces; everything but
+/// `[A-Za-z0-9._-]` is percent-encoded so the revision can't change the
+/// rest of the URL.
+fn encode_revision(revision: &str) -> String {
+    revision
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
 
-    let response = client.get(&url).send().map_err(|e| {
+/// File recording which revision a downloaded model came from: `<model>.gguf.revision`
+pub fn revision_path(model_path: &Path) -> PathBuf {
+    let mut path = model_path.as_os_str().to_owned();
+    path.push(".revision");
+    PathBuf::from(path)
+}
+
+/// Whether a model downloaded at `recorded` satisfies the wanted `revision`
+///
+/// Models downloaded before revisions were recorded count as `main`.
+pub fn revision_matches(recorded: Option<&str>, revision: &str) -> bool {
+    recorded.map(str::trim).unwrap_or(DEFAULT_REVISION) == revision
+}
+
+/// Stream `url` to `dest` with a progress bar
+///
+/// The data goes to a `.tmp` file that is renamed into place once complete,
+/// so an interrupted download never leaves a truncated model behind. The
+/// `.tmp` file is deleted on failure unless `keep_partial` is set, in which
+/// case its path and size are printed for debugging. `source` names the
+/// server in connection errors.
+pub fn download_file(
+    url: &str,
+    dest: &Path,
+    source: &str,
+    keep_partial: bool,
+) -> Result<(), String> {
+    if let Some(dir) = dest.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create config directory: {}", e))?;
+    }
+
+    // 1 hour timeout for large files
+    let client = http_client(std::time::Duration::from_secs(3600), proxy_settings())?;
+
+    log_info!("Downloading {} to {}", url, dest.display());
+    let response = client.get(url).send().map_err(|e| {
         format!(
-            "Failed to connect to HuggingFace. Check your internet connection.\nError: {}",
-            e
+            "Failed to connect to {}. Check your internet connection.\nError: {}",
+            source, e
         )
     })?;
 
@@ -199,6 +987,7 @@ pub fn download_model(model_name: &str) -> Result<PathBuf, String> {
     }
 
     let total = response.content_length().unwrap_or(0);
+    log_debug!("HTTP {}, {} bytes", response.status(), total);
 
     let pb = ProgressBar::new(total);
     pb.set_style(
@@ -210,10 +999,34 @@ pub fn download_model(model_name: &str) -> Result<PathBuf, String> {
 
     // Write to a temp file first, then rename (atomic operation)
     let temp_dest = dest.with_extension("gguf.tmp");
-    let mut file = File::create(&temp_dest).map_err(|e| format!("Failed to create file: {}", e))?;
+    let result = stream_to_file(response, &temp_dest, &pb).and_then(|_| {
+        std::fs::rename(&temp_dest, dest).map_err(|e| format!("Failed to finalize download: {}", e))
+    });
+
+    pb.finish_and_clear();
+
+    if let Err(e) = &result {
+        log_error!("Download of {} failed: {}", url, e);
+        if keep_partial {
+            let size = std::fs::metadata(&temp_dest).map_or(0, |m| m.len());
+            eprintln!(
+                "Partial download kept at {} ({} bytes)",
+                temp_dest.display(),
+                size
+            );
+        } else {
+            let _ = std::fs::remove_file(&temp_dest);
+        }
+    }
+
+    result
+}
+
+/// Copy `reader` into a new file at `path`, advancing `pb` as bytes arrive
+fn stream_to_file(mut reader: impl Read, path: &Path, pb: &ProgressBar) -> Result<(), String> {
+    let mut file = File::create(path).map_err(|e| format!("Failed to create file: {}", e))?;
 
     let mut downloaded = 0u64;
-    let mut reader = response;
     let mut buf = [0u8; 8192];
 
     loop {
@@ -221,36 +1034,137 @@ pub fn download_model(model_name: &str) -> Result<PathBuf, String> {
             .read(&mut buf)
             .map_err(|e| format!("Download error: {}", e))?;
         if n == 0 {
-            break;
+            return Ok(());
         }
         file.write_all(&buf[..n])
             .map_err(|e| format!("Write error: {}", e))?;
         downloaded += n as u64;
         pb.set_position(downloaded);
     }
+}
 
-    pb.finish_and_clear();
+/// Longest file stem kept from a model URL in its cache file name
+const MODEL_URL_STEM_MAX_LEN: usize = 40;
+
+/// Cache file name for a model downloaded from `url`
+///
+/// The name is keyed by a hash of the full URL, so different hosts serving
+/// the same file name don't collide, and carries the URL's file stem so the
+/// cache directory stays readable: `url-my-model-1f3a...gguf`.
+pub fn model_url_file_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let file = path.rsplit('/').next().unwrap_or_default();
+    let stem: String = file
+        .strip_suffix(".gguf")
+        .unwrap_or(file)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(MODEL_URL_STEM_MAX_LEN)
+        .collect();
+
+    if stem.is_empty() {
+        format!("url-{:016x}.gguf", fnv1a_hash(url))
+    } else {
+        format!("url-{}-{:016x}.gguf", stem, fnv1a_hash(url))
+    }
+}
+
+/// 64-bit FNV-1a hash, stable across Rust versions unlike `DefaultHasher`
+fn fnv1a_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Find a model downloaded from `url`, downloading it on first use
+pub fn find_or_download_model_url(
+    model_dir: &Path,
+    url: &str,
+    force_download: bool,
+    keep_partial: bool,
+) -> Result<PathBuf, String> {
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        return Err(format!(
+            "Model URL must start with http:// or https://: {}",
+            url
+        ));
+    }
+
+    let dest = model_dir.join(model_url_file_name(url));
+    if dest.exists() && !force_download {
+        return Ok(dest);
+    }
 
-    // Rename temp file to final destination
-    std::fs::rename(&temp_dest, &dest)
-        .map_err(|e| format!("Failed to finalize download: {}", e))?;
+    eprintln!("Downloading {}...", url);
+    download_file(url, &dest, url, keep_partial)?;
 
     eprintln!("✓ Downloaded to {}", dest.display());
     Ok(dest)
 }
 
+/// Environment variable that overrides where models are stored
+pub const MODEL_DIR_ENV: &str = "FIX_MODEL_DIR";
+
+/// Directory models are stored in
+///
+/// `--model-dir` wins, then `$FIX_MODEL_DIR`, then the config directory.
+pub fn model_dir(flag: Option<&Path>) -> Result<PathBuf, String> {
+    let dir = resolve_model_dir(flag, env::var_os(MODEL_DIR_ENV), config_dir)?;
+    log_debug!("Model directory: {}", dir.display());
+    Ok(dir)
+}
+
+/// Pick the model directory from the flag, the environment value, or `default`
+///
+/// An empty environment value counts as unset. `default` is only called
+/// when neither override is given, so only then can resolving fail.
+pub fn resolve_model_dir<F: FnOnce() -> Result<PathBuf, String>>(
+    flag: Option<&Path>,
+    env_value: Option<OsString>,
+    default: F,
+) -> Result<PathBuf, String> {
+    if let Some(dir) = flag {
+        return Ok(dir.to_path_buf());
+    }
+
+    match env_value {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => default(),
+    }
+}
+
 /// Get the expected path for a model by name
-pub fn get_model_path(model_name: &str) -> PathBuf {
-    config_dir().join(format!("{}.gguf", model_name))
+pub fn get_model_path(model_dir: &Path, model_name: &str) -> PathBuf {
+    model_dir.join(format!("{}.gguf", model_name))
 }
 
-/// Find or download a model by name
-pub fn find_or_download_model(model_name: &str, force_download: bool) -> Result<PathBuf, String> {
-    let model_path = get_model_path(model_name);
+/// Find or download a model by name at `revision`
+///
+/// A model downloaded from another revision is downloaded again. With
+/// `confirm` set, a missing model is only downloaded once the user agrees;
+/// forced re-downloads never ask.
+pub fn find_or_download_model(
+    model_dir: &Path,
+    model_name: &str,
+    revision: &str,
+    force_download: bool,
+    confirm: bool,
+    keep_partial: bool,
+) -> Result<PathBuf, String> {
+    let model_path = get_model_path(model_dir, model_name);
+    let recorded = std::fs::read_to_string(revision_path(&model_path)).ok();
+    let up_to_date = revision_matches(recorded.as_deref(), revision);
 
-    if model_path.exists() && !force_download {
+    if model_path.exists() && up_to_date && !force_download {
+        log_debug!("Using model {} at {}", model_name, model_path.display());
         return Ok(model_path);
     }
+    log_info!(
+        "Model {} at revision {} not found at {}",
+        model_name,
+        revision,
+        model_path.display()
+    );
 
     if force_download {
         eprintln!("Re-downloading {}...", model_name);
@@ -258,38 +1172,145 @@ pub fn find_or_download_model(model_name: &str, force_download: bool) -> Result<
 
     // Validate model exists in repo before downloading
     eprintln!("Checking model availability...");
-    validate_model_exists(model_name)?;
+    let size = model_download_size(model_name, revision)?;
+
+    if confirm && !force_download && !confirm_download(model_name, size) {
+        return Err(format!(
+            "Download declined. Run again with --yes to download {}, or pass --model <path>.",
+            model_name
+        ));
+    }
 
-    download_model(model_name)
+    download_model(model_dir, model_name, revision, keep_partial)
 }
 
-/// Find the model path to use, either from override, or configured default
-pub fn find_model_path(
-    override_path: Option<PathBuf>,
-    config: &Config,
-    force_update: bool,
-) -> Result<PathBuf, String> {
-    // If user specified a path, use it directly
-    if let Some(path) = override_path {
-        if path.exists() {
-            return Ok(path);
-        }
+/// Bytes of a model file read to tell its format
+const MODEL_HEADER_LEN: u64 = 9;
+
+/// File format of a model, told from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelFormat {
+    Gguf,
+    /// HuggingFace weights, which llama.cpp can't load without converting
+    Safetensors,
+    Unknown,
+}
+
+impl ModelFormat {
+    /// Detect the format from the start of a model file
+    ///
+    /// GGUF files start with the magic `GGUF`; safetensors files with the
+    /// little-endian length of their JSON header, then the header's `{`.
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(b"GGUF") {
+            ModelFormat::Gguf
+        } else if header.len() >= 9 && header[8] == b'{' {
+            ModelFormat::Safetensors
+        } else {
+            ModelFormat::Unknown
+        }
+    }
+}
+
+/// Check that `path` is a readable GGUF model before llama.cpp tries to load it
+///
+/// Loading a directory, a text file or safetensors weights fails with an
+/// error that doesn't say what was wrong with the path.
+pub fn check_model_file(path: &Path) -> Result<(), String> {
+    if !path.exists() {
         return Err(format!("Model not found at: {}", path.display()));
     }
+    if path.is_dir() {
+        return Err(format!(
+            "Model path {} is a directory, not a .gguf file",
+            path.display()
+        ));
+    }
+
+    let mut header = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(MODEL_HEADER_LEN).read_to_end(&mut header))
+        .map_err(|e| format!("Cannot read model at {}: {}", path.display(), e))?;
+
+    let has_extension = |expected: &str| {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(expected))
+    };
+    match ModelFormat::detect(&header) {
+        ModelFormat::Gguf => Ok(()),
+        ModelFormat::Safetensors => Err(unsupported_model_format(path)),
+        ModelFormat::Unknown if has_extension("safetensors") => Err(unsupported_model_format(path)),
+        ModelFormat::Unknown if has_extension("gguf") => Err(format!(
+            "Model {} doesn't start with the GGUF header; it may be incomplete or corrupt",
+            path.display()
+        )),
+        ModelFormat::Unknown => Err(format!("Model path {} is not a .gguf file", path.display())),
+    }
+}
+
+fn unsupported_model_format(path: &Path) -> String {
+    format!(
+        "Model {} is in safetensors format, but only GGUF models are supported; \
+         convert it with llama.cpp's convert_hf_to_gguf.py",
+        path.display()
+    )
+}
+
+/// Find the model path to use, either from override, or configured default
+pub fn find_model_path(
+    model_dir: &Path,
+    override_path: Option<PathBuf>,
+    config: &Config,
+    revision: &str,
+    force_update: bool,
+    confirm: bool,
+    keep_partial: bool,
+) -> Result<PathBuf, String> {
+    // If user specified a path, use it directly
+    if let Some(path) = override_path {
+        check_model_file(&path)?;
+        log_debug!("Using model from --model: {}", path.display());
+        return Ok(path);
+    }
 
     // Otherwise, find or download the configured default model
-    find_or_download_model(&config.default_model, force_update)
+    find_or_download_model(
+        model_dir,
+        &config.default_model,
+        revision,
+        force_update,
+        confirm,
+        keep_partial,
+    )
 }
 
 // ===== Shell Detection =====
 
+/// Executable name of a shell path: `/usr/local/bin/fish` → `fish`
+///
+/// Handles `/` and `\` separators, a `.exe` suffix, and the leading `-`
+/// login shells get in `$0` (`-bash`).
+fn shell_name_from_path(path: &str) -> &str {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let name = name.strip_prefix('-').unwrap_or(name);
+    name.strip_suffix(".exe")
+        .or_else(|| name.strip_suffix(".EXE"))
+        .unwrap_or(name)
+}
+
+/// Identify the shell from its executable path, e.g. `$SHELL`
+pub fn parse_shell_from_path(path: &str) -> Option<tools::Shell> {
+    tools::Shell::parse(shell_name_from_path(path))
+}
+
 /// Detect the current shell from environment variables
 pub fn detect_shell() -> String {
-    // Unix: check SHELL env var
+    // Unix: check SHELL env var, keeping unsupported shells (sh, dash) by name
     if let Ok(shell_path) = env::var("SHELL") {
-        if let Some(name) = shell_path.rsplit('/').next() {
-            return name.to_string();
-        }
+        return match parse_shell_from_path(&shell_path) {
+            Some(shell) => shell.to_string(),
+            None => shell_name_from_path(&shell_path).to_string(),
+        };
     }
 
     // PowerShell (works on all platforms)
@@ -308,19 +1329,756 @@ pub fn detect_shell() -> String {
 
 // ===== Prompt Building =====
 
-/// Build a ChatML-formatted prompt for the model
-pub fn build_prompt(shell: &str, command: &str, _error: Option<&str>) -> String {
-    // Match the exact format used in training data
-    format!(
-        "<|im_start|>system\n\
-         You are a shell command corrector for {}. Output only the corrected command.<|im_end|>\n\
-         <|im_start|>user\n\
-         {}<|im_end|>\n\
-         <|im_start|>assistant\n",
-        shell, command
+/// GGUF metadata key holding the model's chat template
+pub const CHAT_TEMPLATE_KEY: &str = "tokenizer.chat_template";
+
+/// Check that a prompt of `prompt_tokens` fits a context of `context_tokens`
+///
+/// The prompt is decoded in one batch sized to the context, and needs at least
+/// one token to spare for the answer. A longer one would otherwise fail
+/// inside llama.cpp with an error that doesn't mention the input's length.
+pub fn check_prompt_fits(prompt_tokens: usize, context_tokens: usize) -> Result<(), String> {
+    if prompt_tokens < context_tokens {
+        return Ok(());
+    }
+    Err(format!(
+        "Input is too long: the prompt is {} tokens, but the model's context holds {}. \
+         Shorten the command or error message.",
+        prompt_tokens, context_tokens
+    ))
+}
+
+/// Chat prompt format expected by a model family
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptFormat {
+    /// `<|im_start|>` turns, used by Qwen (the default models)
+    #[default]
+    ChatMl,
+    /// `<|start_header_id|>` turns, used by Llama 3
+    Llama3,
+    /// `<start_of_turn>` turns, used by Gemma
+    Gemma,
+}
+
+impl PromptFormat {
+    /// Detect the format from a GGUF chat template
+    pub fn from_chat_template(template: &str) -> Option<Self> {
+        if template.contains("<|im_start|>") {
+            Some(PromptFormat::ChatMl)
+        } else if template.contains("<|start_header_id|>") {
+            Some(PromptFormat::Llama3)
+        } else if template.contains("<start_of_turn>") {
+            Some(PromptFormat::Gemma)
+        } else {
+            None
+        }
+    }
+
+    /// Pick the format: the configured one, else detected, else ChatML
+    pub fn resolve(configured: Option<Self>, chat_template: Option<&str>) -> Self {
+        configured
+            .or_else(|| chat_template.and_then(Self::from_chat_template))
+            .unwrap_or_default()
+    }
+
+    /// Render a system and user message, leaving the assistant turn open
+    ///
+    /// The BOS token is left out; it is added during tokenization.
+    pub fn render(self, system: &str, user: &str) -> String {
+        match self {
+            PromptFormat::ChatMl => format!(
+                "<|im_start|>system\n{}<|im_end|>\n\
+                 <|im_start|>user\n{}<|im_end|>\n\
+                 <|im_start|>assistant\n",
+                system, user
+            ),
+            PromptFormat::Llama3 => format!(
+                "<|start_header_id|>system<|end_header_id|>\n\n{}<|eot_id|>\
+                 <|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>\
+                 <|start_header_id|>assistant<|end_header_id|>\n\n",
+                system, user
+            ),
+            // Gemma has no system role, so the instructions lead the user turn
+            PromptFormat::Gemma => format!(
+                "<start_of_turn>user\n{}\n\n{}<end_of_turn>\n\
+                 <start_of_turn>model\n",
+                system, user
+            ),
+        }
+    }
+
+    /// Tokens that mark the end of the model's turn
+    pub fn stop_markers(self) -> &'static [&'static str] {
+        match self {
+            PromptFormat::ChatMl => &["<|im_end|>", "<|im_start|>"],
+            PromptFormat::Llama3 => &["<|eot_id|>", "<|start_header_id|>"],
+            PromptFormat::Gemma => &["<end_of_turn>", "<start_of_turn>"],
+        }
+    }
+}
+
+/// Special tokens of the supported prompt formats
+///
+/// Typed literally into a command, these would end the user turn early, so
+/// user text goes through `escape_special_tokens` before it's put in a prompt.
+pub const SPECIAL_TOKENS: &[&str] = &[
+    "<|im_start|>",
+    "<|im_end|>",
+    "<|endoftext|>",
+    "<|begin_of_text|>",
+    "<|start_header_id|>",
+    "<|end_header_id|>",
+    "<|eot_id|>",
+    "<start_of_turn>",
+    "<end_of_turn>",
+];
+
+/// Replaces the leading `<` of special tokens in user text (a fullwidth `＜`)
+const ESCAPED_TOKEN_START: &str = "\u{FF1C}";
+
+/// Defuse special tokens in user text so the tokenizer reads them as plain text
+///
+/// The leading `<` becomes `ESCAPED_TOKEN_START`; `unescape_special_tokens`
+/// turns them back when the model repeats them in its correction.
+pub fn escape_special_tokens(text: &str) -> String {
+    SPECIAL_TOKENS.iter().fold(text.to_string(), |text, token| {
+        text.replace(token, &format!("{}{}", ESCAPED_TOKEN_START, &token[1..]))
+    })
+}
+
+/// Undo `escape_special_tokens` on model output
+pub fn unescape_special_tokens(text: &str) -> String {
+    SPECIAL_TOKENS.iter().fold(text.to_string(), |text, token| {
+        text.replace(&format!("{}{}", ESCAPED_TOKEN_START, &token[1..]), token)
+    })
+}
+
+/// Default system prompt for fix, matching the wording used in training data
+///
+/// `{shell_hint}` is empty for bash, so bash prompts are exactly the
+/// training wording.
+pub const DEFAULT_SYSTEM_PROMPT_TEMPLATE: &str =
+    "You are a shell command corrector for {shell}. Output only the corrected command. \
+     If the command is already correct, output it unchanged. {shell_hint}";
+
+/// Added to fix's system message by `--flags-only`
+pub const FLAGS_ONLY_INSTRUCTION: &str =
+    "Only correct the flags. Keep the command word and all other arguments exactly as typed.";
+
+/// Appended to the system message to stop Qwen3 models from emitting a
+/// `<think>` block, which costs tokens the correction never uses
+pub const NO_THINK_DIRECTIVE: &str = "/no_think";
+
+/// Build a prompt for the model in the given format using the default template
+pub fn build_prompt(
+    format: PromptFormat,
+    shell: &str,
+    command: &str,
+    error: Option<&str>,
+) -> String {
+    build_prompt_from_template(
+        format,
+        DEFAULT_SYSTEM_PROMPT_TEMPLATE,
+        shell,
+        command,
+        error,
+        false,
     )
 }
 
+/// Build a prompt whose system message comes from `template`
+///
+/// `{shell}`, `{shell_hint}` (see `Shell::prompt_hint`) and `{command}` in
+/// the template are replaced; the command is always sent as the user
+/// message, with special tokens escaped. Unless `thinking` is set, the
+/// system message ends with `NO_THINK_DIRECTIVE`.
+pub fn build_prompt_from_template(
+    format: PromptFormat,
+    template: &str,
+    shell: &str,
+    command: &str,
+    _error: Option<&str>,
+    thinking: bool,
+) -> String {
+    let command = &escape_special_tokens(command);
+    let hint = tools::Shell::parse(shell).map_or("", |shell| shell.prompt_hint());
+    // Without a hint, the space before the placeholder goes too
+    let template = if hint.is_empty() {
+        template.replace(" {shell_hint}", "")
+    } else {
+        template.to_string()
+    };
+    let mut system = template
+        .replace("{shell_hint}", hint)
+        .replace("{shell}", shell)
+        .replace("{command}", command);
+    if !thinking {
+        system.push(' ');
+        system.push_str(NO_THINK_DIRECTIVE);
+    }
+    format.render(&system, command)
+}
+
+// ===== Command Parsing =====
+
+/// Split leading `NAME=value` environment assignments from a command
+///
+/// Returns the assignments exactly as typed and the remaining command, so
+/// `FOO=bar pytohn script.py` yields `("FOO=bar", "pytohn script.py")`.
+/// Quoted values (`FOO="a b"`) and values containing `=` are kept whole.
+pub fn split_env_assignments(input: &str) -> (&str, &str) {
+    let input = input.trim();
+    let mut prefix_end = 0;
+    let mut rest = input;
+
+    while let Some(word_len) = env_assignment_len(rest) {
+        let consumed = input.len() - rest.len() + word_len;
+        prefix_end = consumed;
+        rest = input[consumed..].trim_start();
+    }
+
+    (input[..prefix_end].trim_end(), rest)
+}
+
+/// Length of the `NAME=value` word at the start of `s`, if it is one
+fn env_assignment_len(s: &str) -> Option<usize> {
+    let eq = s.find('=')?;
+    let name = &s[..eq];
+    let mut name_chars = name.chars();
+    let first = name_chars.next()?;
+    if !(first.is_ascii_alphabetic() || first == '_')
+        || !name_chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return None;
+    }
+
+    // Walk the value up to the first unquoted whitespace
+    let mut quote: Option<char> = None;
+    for (i, c) in s[eq + 1..].char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() => {
+                let end = eq + 1 + i;
+                // A bare assignment with nothing after it isn't a prefix
+                return (!s[end..].trim().is_empty()).then_some(end);
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Put environment assignments back in front of a corrected command
+pub fn prepend_env_assignments(assignments: &str, command: &str) -> String {
+    if assignments.is_empty() || command.is_empty() {
+        command.to_string()
+    } else {
+        format!("{} {}", assignments, command)
+    }
+}
+
+// ===== Typo Heuristics =====
+
+/// Maximum edit distance for a known command to count as a near-neighbor
+pub const TYPO_MAX_DISTANCE: usize = 2;
+
+/// Simple Levenshtein distance for typo detection
+pub fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let a_chars: Vec<char> = a.chars().collect();
+    let b_chars: Vec<char> = b.chars().collect();
+    let a_len = a_chars.len();
+    let b_len = b_chars.len();
+
+    if a_len == 0 {
+        return b_len;
+    }
+    if b_len == 0 {
+        return a_len;
+    }
+
+    let mut matrix = vec![vec![0usize; b_len + 1]; a_len + 1];
+
+    for (i, row) in matrix.iter_mut().enumerate().take(a_len + 1) {
+        row[0] = i;
+    }
+    for (j, cell) in matrix[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a_len {
+        for j in 1..=b_len {
+            let cost = if a_chars[i - 1] == b_chars[j - 1] {
+                0
+            } else {
+                1
+            };
+            matrix[i][j] = std::cmp::min(
+                std::cmp::min(matrix[i - 1][j] + 1, matrix[i][j - 1] + 1),
+                matrix[i - 1][j - 1] + cost,
+            );
+        }
+    }
+
+    matrix[a_len][b_len]
+}
+
+/// Cheap pre-filter for whether a command is likely a typo, without inference
+///
+/// Returns true when the first word is neither a cached tool nor on PATH,
+/// but is within `TYPO_MAX_DISTANCE` edits of a cached tool.
+pub fn looks_incorrect(input: &str, cache: &cache::ToolsCache) -> bool {
+    let first_word = match input.split_whitespace().next() {
+        Some(word) => word,
+        None => return false,
+    };
+
+    if cache.tools.contains_key(first_word) || command_in_path(first_word) {
+        return false;
+    }
+
+    cache
+        .tools
+        .keys()
+        .any(|name| levenshtein_distance(first_word, name) <= TYPO_MAX_DISTANCE)
+}
+
+/// Abbreviations commonly typed for long command names
+pub fn default_abbreviations() -> BTreeMap<String, String> {
+    [
+        ("d", "docker"),
+        ("g", "git"),
+        ("k", "kubectl"),
+        ("py", "python3"),
+        ("tf", "terraform"),
+    ]
+    .into_iter()
+    .map(|(short, long)| (short.to_string(), long.to_string()))
+    .collect()
+}
+
+/// The command an abbreviated first word of `input` stands for
+///
+/// Returns None when the first word isn't in `abbreviations`, or when it is a
+/// real command (`command_exists`), so a user's own `g` binary is left alone.
+pub fn expand_abbreviation<'a>(
+    input: &str,
+    abbreviations: &'a BTreeMap<String, String>,
+    command_exists: impl Fn(&str) -> bool,
+) -> Option<&'a str> {
+    let first_word = input.split_whitespace().next()?;
+    let expansion = abbreviations.get(first_word)?;
+    (!command_exists(first_word)).then_some(expansion.as_str())
+}
+
+/// Version of the JSON line protocol between the CLIs and their daemons
+///
+/// Bump it whenever `DaemonRequest` or `DaemonResponse` change shape, so a
+/// daemon left running from before an upgrade gets replaced.
+pub const DAEMON_PROTOCOL_VERSION: u32 = 6;
+
+/// Protocol version a daemon wrote `response_line` with
+///
+/// Daemons from before versioning don't send one and count as version 0, as
+/// do replies that aren't JSON at all.
+pub fn response_protocol_version(response_line: &str) -> u32 {
+    serde_json::from_str::<serde_json::Value>(response_line)
+        .ok()
+        .and_then(|value| value.get("protocol_version")?.as_u64())
+        .and_then(|version| u32::try_from(version).ok())
+        .unwrap_or(0)
+}
+
+/// Whether the daemon that sent `response_line` must be restarted with this binary
+pub fn daemon_needs_restart(response_line: &str) -> bool {
+    response_protocol_version(response_line) != DAEMON_PROTOCOL_VERSION
+}
+
+/// Daemon error prefixes that may succeed on a second attempt
+///
+/// Context creation and decoding can fail transiently (e.g. a short-lived
+/// memory spike); tokenization failures depend only on the input and won't.
+const RETRYABLE_DAEMON_ERRORS: &[&str] = &["Failed to create context", "Decode failed"];
+
+/// Check whether a daemon error is worth resending the request for
+pub fn is_retryable_daemon_error(error: &str) -> bool {
+    RETRYABLE_DAEMON_ERRORS
+        .iter()
+        .any(|prefix| error.starts_with(prefix))
+}
+
+/// Whether `response_line` is a failure worth resending the request for
+#[cfg(unix)]
+fn response_is_retryable(response_line: &str) -> bool {
+    let Ok(response) = serde_json::from_str::<serde_json::Value>(response_line) else {
+        return false;
+    };
+    response.get("success").and_then(|v| v.as_bool()) == Some(false)
+        && response
+            .get("error")
+            .and_then(|v| v.as_str())
+            .is_some_and(is_retryable_daemon_error)
+}
+
+/// Send `request` to the daemon at `socket`, resending once on a retryable error
+///
+/// A daemon speaking another protocol version (left running across an
+/// upgrade) is replaced by calling `restart`, and the request is resent.
+#[cfg(unix)]
+pub fn send_to_daemon<Req, Resp, F>(
+    socket: &Path,
+    request: &Req,
+    timeout: std::time::Duration,
+    restart: F,
+) -> Result<Resp, String>
+where
+    Req: Serialize,
+    Resp: serde::de::DeserializeOwned,
+    F: FnOnce() -> Result<(), String>,
+{
+    let mut response_line = exchange_with_daemon(socket, request, timeout)?;
+    if daemon_needs_restart(&response_line) {
+        restart()?;
+        response_line = exchange_with_daemon(socket, request, timeout)?;
+    }
+    if response_is_retryable(&response_line) {
+        response_line = exchange_with_daemon(socket, request, timeout)?;
+    }
+
+    parse_daemon_response(&response_line)
+}
+
+/// Send a single request to the daemon and read its response line
+#[cfg(unix)]
+pub fn exchange_with_daemon<Req: Serialize>(
+    socket: &Path,
+    request: &Req,
+    timeout: std::time::Duration,
+) -> Result<String, String> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)
+        .map_err(|e| format!("Failed to connect: {}", e))?;
+
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| format!("Failed to set timeout: {}", e))?;
+
+    let request_json =
+        serde_json::to_string(request).map_err(|e| format!("Failed to serialize: {}", e))?;
+
+    writeln!(stream, "{}", request_json).map_err(|e| format!("Failed to send: {}", e))?;
+
+    let mut reader = std::io::BufReader::new(stream);
+    let mut response_line = String::new();
+    reader
+        .read_line(&mut response_line)
+        .map_err(|e| format!("Failed to read response: {}", e))?;
+
+    Ok(response_line)
+}
+
+/// Parse a daemon response line
+pub fn parse_daemon_response<Resp: serde::de::DeserializeOwned>(
+    response_line: &str,
+) -> Result<Resp, String> {
+    serde_json::from_str(response_line).map_err(|e| format!("Failed to parse response: {}", e))
+}
+
+/// Decide whether to keep the user's input instead of the model's correction
+///
+/// Models sometimes "fix" a command that was already valid by requoting it or
+/// shuffling whitespace. When the input's first word is a real command and
+/// the output differs only in that way, the original wins.
+pub fn prefer_original<F: Fn(&str) -> bool>(input: &str, output: &str, command_exists: F) -> bool {
+    let first_word = match input.split_whitespace().next() {
+        Some(word) => word,
+        None => return false,
+    };
+
+    input != output
+        && normalize_command(input) == normalize_command(output)
+        && command_exists(first_word)
+}
+
+/// Largest edit distance at which `merge_minimal_edit` keeps the user's formatting
+pub const MINIMAL_EDIT_MAX_DISTANCE: usize = 4;
+
+/// Apply a small correction to the input one word at a time
+///
+/// When `output` has as many words as `input` and is within
+/// `MINIMAL_EDIT_MAX_DISTANCE` edits of it, ignoring case, only the words
+/// that changed are swapped in, so the spacing the user typed survives. A
+/// word that differs only in case is kept as the user typed it. Larger
+/// rewrites are returned as-is.
+pub fn merge_minimal_edit(input: &str, output: &str) -> String {
+    let input_words: Vec<&str> = input.split_whitespace().collect();
+    let output_words: Vec<&str> = output.split_whitespace().collect();
+
+    if input_words.len() != output_words.len()
+        || levenshtein_distance(
+            &input_words.join(" ").to_lowercase(),
+            &output_words.join(" ").to_lowercase(),
+        ) > MINIMAL_EDIT_MAX_DISTANCE
+    {
+        return output.to_string();
+    }
+
+    let mut merged = String::with_capacity(input.len());
+    let mut rest = input.trim();
+    for (old_word, new_word) in input_words.iter().zip(output_words) {
+        let start = rest.find(old_word).unwrap_or(0);
+        merged.push_str(&rest[..start]);
+        if new_word.to_lowercase() == old_word.to_lowercase() {
+            merged.push_str(old_word);
+        } else {
+            merged.push_str(new_word);
+        }
+        rest = &rest[start + old_word.len()..];
+    }
+
+    merged
+}
+
+/// Split a command into its first word and the arguments after it
+///
+/// The arguments are returned as typed, so `gti push  origin` yields
+/// `("gti", "push  origin")`.
+pub fn split_command_args(command: &str) -> (&str, &str) {
+    let command = command.trim();
+    match command.find(char::is_whitespace) {
+        Some(end) => (&command[..end], command[end..].trim_start()),
+        None => (command, ""),
+    }
+}
+
+/// Whether `output` runs the same command as `input`, for `--flags-only`
+///
+/// Compares the first word after any leading `NAME=value` assignments.
+pub fn preserves_command_word(input: &str, output: &str) -> bool {
+    let (_, input) = split_env_assignments(input);
+    let (_, output) = split_env_assignments(output);
+    let (input_word, _) = split_command_args(input);
+    let (output_word, _) = split_command_args(output);
+    !output_word.is_empty() && output_word == input_word
+}
+
+/// Take only the command word from `output`, keeping the arguments of `input`
+///
+/// Used by `--preserve-args`: when the model drops, adds or reorders
+/// arguments, the user's own arguments are put back after the corrected
+/// command word. Leading `NAME=value` assignments are left as `output` has them.
+pub fn reattach_args(input: &str, output: &str) -> String {
+    let (_, input) = split_env_assignments(input);
+    let (assignments, output) = split_env_assignments(output);
+    let (_, input_args) = split_command_args(input);
+    let (word, output_args) = split_command_args(output);
+
+    if word.is_empty() || output_args == input_args {
+        return prepend_env_assignments(assignments, output);
+    }
+
+    let command = if input_args.is_empty() {
+        word.to_string()
+    } else {
+        format!("{} {}", word, input_args)
+    };
+    prepend_env_assignments(assignments, &command)
+}
+
+/// Words of a command with surrounding quotes removed, for loose comparison
+fn normalize_command(command: &str) -> Vec<&str> {
+    command
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c| c == '"' || c == '\''))
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Check whether a command name resolves to a file in a PATH directory
+pub fn command_in_path(name: &str) -> bool {
+    let path = match env::var_os("PATH") {
+        Some(path) => path,
+        None => return false,
+    };
+
+    let extensions: &[&str] = if cfg!(windows) {
+        &["", ".exe", ".cmd", ".bat", ".com"]
+    } else {
+        &[""]
+    };
+
+    env::split_paths(&path).any(|dir| {
+        extensions
+            .iter()
+            .any(|ext| dir.join(format!("{}{}", name, ext)).is_file())
+    })
+}
+
+/// Builtins of the POSIX shells, which `command_in_path` can't find
+const POSIX_BUILTINS: &[&str] = &[
+    ".", "alias", "cd", "eval", "exec", "exit", "export", "popd", "pushd", "read", "set", "shift",
+    "source", "type", "ulimit", "umask", "unset", "wait",
+];
+
+/// Whether `name` is a program in PATH or a common shell builtin such as `cd`
+pub fn command_or_builtin(name: &str) -> bool {
+    POSIX_BUILTINS.contains(&name) || command_in_path(name)
+}
+
+// ===== Batch Correction =====
+
+/// Turn the model's answer for `command` into the correction to show
+///
+/// Puts back special tokens the command contained, drops prefixes like
+/// `Command:`, chains separate lines with `&&` unless `multiline` is set, and
+/// keeps the user's formatting where the change is small. `command` is the
+/// input without its leading `NAME=value` assignments.
+pub fn clean_correction(command: &str, output: &str, multiline: bool, config: &Config) -> String {
+    let output = unescape_special_tokens(output);
+    let result = output.trim();
+    let result = result
+        .strip_prefix("command >")
+        .or_else(|| result.strip_prefix("command>"))
+        .or_else(|| result.strip_prefix("command 2>&1"))
+        .or_else(|| result.strip_prefix("Command:"))
+        .unwrap_or(result)
+        .trim();
+
+    let joined;
+    let result = if multiline {
+        parser::strip_prompt_marker(parser::command_lines(result))
+    } else {
+        joined = parser::join_command_lines(result, command_or_builtin);
+        &joined
+    };
+
+    let result = if prefer_original(command, result, command_in_path) {
+        command
+    } else {
+        result
+    };
+
+    if config.minimal_edit {
+        merge_minimal_edit(command, result)
+    } else {
+        result.to_string()
+    }
+}
+
+/// The answer in raw model output, with thinking blocks (by `tags`) removed
+///
+/// Falls back to a command found in the thinking when the model answered
+/// there and stopped, as the binaries do.
+fn answer_without_thinking(output: &str, tags: &[sampling::ThinkingTags]) -> String {
+    let mut state = sampling::GenerationState::with_tags(tags);
+    state.push(output);
+    let (answer, thinking) = state.into_parts();
+    if answer.trim().is_empty() {
+        parser::command_from_thinking(&thinking, command_in_path).unwrap_or_default()
+    } else {
+        answer
+    }
+}
+
+/// Correct many commands with one loaded model, for programs embedding fix
+///
+/// `generate` runs the model on a prompt and returns its raw answer. Keep the
+/// model, and one context reused between calls (see
+/// `context::ReusableContext`), in `generate`, so nothing is loaded per
+/// command. Results come back in the order of `inputs`; a failed generation
+/// only fails its own input.
+///
+/// The same safety checks as the binaries apply: inputs matching
+/// `Config::input_denylist` never reach `generate`, and a correction adding
+/// one of `Config::destructive_patterns` is refused. Both are errors.
+pub fn correct_batch<G>(
+    inputs: &[&str],
+    shell: &str,
+    format: PromptFormat,
+    config: &Config,
+    mut generate: G,
+) -> Vec<Result<String, String>>
+where
+    G: FnMut(&str) -> Result<String, String>,
+{
+    inputs
+        .iter()
+        .map(|input| {
+            if let Some(pattern) = safety::denied_input(input, &config.input_denylist)? {
+                return Err(format!(
+                    "Not processing input matching input_denylist pattern '{}'",
+                    pattern
+                ));
+            }
+
+            let (assignments, command) = split_env_assignments(input);
+            let prompt = build_prompt_from_template(
+                format,
+                config.system_prompt_template(),
+                shell,
+                command,
+                None,
+                config.thinking,
+            );
+            let output = answer_without_thinking(&generate(&prompt)?, &config.thinking_tags);
+            let correction = clean_correction(command, &output, false, config);
+            if let Some(pattern) = safety::introduced_destructive_pattern(
+                command,
+                &correction,
+                &config.destructive_patterns,
+            ) {
+                return Err(format!(
+                    "Refusing correction '{}': it adds '{}', which the original command didn't use",
+                    correction, pattern
+                ));
+            }
+            Ok(prepend_env_assignments(assignments, &correction))
+        })
+        .collect()
+}
+
+// ===== Correction Log =====
+
+/// One line of a `--save-output` file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CorrectionRecord {
+    /// When the correction was made, RFC 3339 in UTC
+    pub timestamp: String,
+    pub input: String,
+    pub correction: String,
+    pub shell: String,
+}
+
+impl CorrectionRecord {
+    /// Record a correction made now
+    pub fn new(input: &str, correction: &str, shell: &str) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            input: input.to_string(),
+            correction: correction.to_string(),
+            shell: shell.to_string(),
+        }
+    }
+}
+
+/// Append `record` to `path` as a JSON line, creating the file if needed
+///
+/// The line is written with a single `write` on a file opened for
+/// appending, so corrections saved by concurrent runs don't interleave.
+pub fn append_correction_record(path: &Path, record: &CorrectionRecord) -> Result<(), String> {
+    let mut line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+    line.push('\n');
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| format!("Failed to save correction to {}: {}", path.display(), e))
+}
+
 // ===== Logging =====
 
 /// Suppress llama.cpp log output
@@ -559,6 +2317,28 @@ mod tests {
 
     // ===== Shell Detection Tests =====
 
+    #[test]
+    fn test_parse_shell_from_path() {
+        use tools::Shell;
+
+        assert_eq!(parse_shell_from_path("/bin/bash"), Some(Shell::Bash));
+        assert_eq!(
+            parse_shell_from_path("/usr/local/bin/fish"),
+            Some(Shell::Fish)
+        );
+        assert_eq!(
+            parse_shell_from_path(r"C:\Program Files\PowerShell\7\pwsh.exe"),
+            Some(Shell::PowerShell)
+        );
+        assert_eq!(
+            parse_shell_from_path(r"C:\Windows\System32\cmd.exe"),
+            Some(Shell::Cmd)
+        );
+        assert_eq!(parse_shell_from_path("-bash"), Some(Shell::Bash));
+        assert_eq!(parse_shell_from_path("zsh"), Some(Shell::Zsh));
+        assert_eq!(parse_shell_from_path("/bin/dash"), None);
+    }
+
     #[test]
     fn test_detect_shell_from_shell_env_bash() {
         let original = env::var("SHELL").ok();
@@ -677,7 +2457,7 @@ mod tests {
 
     #[test]
     fn test_build_prompt_basic() {
-        let prompt = build_prompt("bash", "gti status", None);
+        let prompt = build_prompt(PromptFormat::ChatMl, "bash", "gti status", None);
 
         assert!(prompt.contains("<|im_start|>system"));
         assert!(prompt.contains("shell command corrector for bash"));
@@ -692,7 +2472,7 @@ mod tests {
         let shells = vec!["bash", "zsh", "fish", "powershell", "cmd", "tcsh"];
 
         for shell in shells {
-            let prompt = build_prompt(shell, "test command", None);
+            let prompt = build_prompt(PromptFormat::ChatMl, shell, "test command", None);
             assert!(
                 prompt.contains(&format!("corrector for {}", shell)),
                 "Prompt should contain shell name: {}",
@@ -703,14 +2483,19 @@ mod tests {
 
     #[test]
     fn test_build_prompt_special_characters() {
-        let prompt = build_prompt("bash", "echo \"hello world\" | grep 'test'", None);
+        let prompt = build_prompt(
+            PromptFormat::ChatMl,
+            "bash",
+            "echo \"hello world\" | grep 'test'",
+            None,
+        );
 
         assert!(prompt.contains("echo \"hello world\" | grep 'test'"));
     }
 
     #[test]
     fn test_build_prompt_empty_command() {
-        let prompt = build_prompt("bash", "", None);
+        let prompt = build_prompt(PromptFormat::ChatMl, "bash", "", None);
 
         // Should still produce valid ChatML structure
         assert!(prompt.contains("<|im_start|>system"));
@@ -721,78 +2506,1512 @@ mod tests {
     #[test]
     fn test_build_prompt_multiline_command() {
         let cmd = "echo hello && \\\necho world";
-        let prompt = build_prompt("bash", cmd, None);
+        let prompt = build_prompt(PromptFormat::ChatMl, "bash", cmd, None);
 
         assert!(prompt.contains(cmd));
     }
 
-    // ===== Path Function Tests =====
+    #[test]
+    fn test_build_prompt_chatml_delimiters() {
+        let prompt = build_prompt(PromptFormat::ChatMl, "zsh", "gti status", None);
+
+        assert!(
+            prompt.starts_with("<|im_start|>system\nYou are a shell command corrector for zsh.")
+        );
+        assert!(prompt.contains("<|im_start|>user\ngti status<|im_end|>\n"));
+        assert!(prompt.ends_with("<|im_start|>assistant\n"));
+    }
 
     #[test]
-    fn test_config_dir_returns_path() {
-        let dir = config_dir();
+    fn test_build_prompt_llama3_delimiters() {
+        let prompt = build_prompt(PromptFormat::Llama3, "zsh", "gti status", None);
+
+        assert!(prompt.starts_with("<|start_header_id|>system<|end_header_id|>\n\n"));
+        assert!(prompt.contains("corrector for zsh."));
+        assert!(prompt.contains("<|start_header_id|>user<|end_header_id|>\n\ngti status<|eot_id|>"));
+        assert!(prompt.ends_with("<|start_header_id|>assistant<|end_header_id|>\n\n"));
+        assert!(!prompt.contains("<|im_start|>"));
+    }
 
-        // Should end with "fix"
-        assert!(dir.ends_with("fix"));
+    #[test]
+    fn test_build_prompt_gemma_delimiters() {
+        let prompt = build_prompt(PromptFormat::Gemma, "zsh", "gti status", None);
+
+        assert!(
+            prompt.starts_with("<start_of_turn>user\nYou are a shell command corrector for zsh.")
+        );
+        assert!(prompt.contains("gti status<end_of_turn>\n"));
+        assert!(prompt.ends_with("<start_of_turn>model\n"));
+        assert!(!prompt.contains("<|im_start|>"));
+    }
 
-        // Should not be empty
-        assert!(!dir.as_os_str().is_empty());
+    #[test]
+    fn test_check_prompt_fits() {
+        assert!(check_prompt_fits(100, 512).is_ok());
+        assert!(check_prompt_fits(511, 512).is_ok());
+
+        // No room left for the answer
+        let err = check_prompt_fits(512, 512).unwrap_err();
+        assert!(err.contains("512 tokens"), "{}", err);
+        assert!(check_prompt_fits(900, 512).is_err());
     }
 
     #[test]
-    fn test_config_path_returns_json_file() {
-        let path = config_path();
+    fn test_build_prompt_bash_matches_training_wording() {
+        // Byte for byte the prompt the models were trained on
+        assert_eq!(
+            build_prompt(PromptFormat::ChatMl, "bash", "gti status", None),
+            "<|im_start|>system\n\
+             You are a shell command corrector for bash. Output only the corrected command. \
+             If the command is already correct, output it unchanged. /no_think<|im_end|>\n\
+             <|im_start|>user\ngti status<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
 
-        // Should end with "config.json"
-        assert!(path.ends_with("config.json"));
+    #[test]
+    fn test_build_prompt_includes_shell_hint() {
+        let system = |shell| {
+            let prompt = build_prompt(PromptFormat::ChatMl, shell, "gti status", None);
+            prompt[..prompt.find("<|im_end|>").unwrap()].to_string()
+        };
 
-        // Parent should be config_dir()
-        assert_eq!(path.parent().unwrap(), config_dir());
+        assert!(system("cmd").ends_with(&format!(
+            "unchanged. {} {}",
+            tools::Shell::Cmd.prompt_hint(),
+            NO_THINK_DIRECTIVE
+        )));
+        assert!(system("powershell").contains(tools::Shell::PowerShell.prompt_hint()));
+        assert!(system("bash").ends_with(&format!("unchanged. {}", NO_THINK_DIRECTIVE)));
+        assert!(system("tcsh").ends_with(&format!("unchanged. {}", NO_THINK_DIRECTIVE)));
+
+        // Text after the placeholder doesn't get a double space for bash
+        let template = format!(
+            "{} {}",
+            DEFAULT_SYSTEM_PROMPT_TEMPLATE, FLAGS_ONLY_INSTRUCTION
+        );
+        let prompt =
+            build_prompt_from_template(PromptFormat::ChatMl, &template, "bash", "ls", None, true);
+        assert!(prompt.contains(&format!("unchanged. {}<|im_end|>", FLAGS_ONLY_INSTRUCTION)));
     }
 
     #[test]
-    fn test_get_model_path_appends_gguf() {
-        let path = get_model_path("test-model");
+    fn test_special_tokens_in_command_are_escaped() {
+        let command = "sed 's/<|im_end|>//' chat.txt";
+        let prompt = build_prompt(PromptFormat::ChatMl, "bash", command, None);
+
+        // Only the template's own markers: after the system and user turns
+        assert_eq!(prompt.matches("<|im_end|>").count(), 2);
+        assert!(prompt.contains("<|im_start|>user\nsed 's/\u{FF1C}|im_end|>//' chat.txt<|im_end|>"));
+
+        // Nothing escaped trips a stop marker of any format
+        let escaped = escape_special_tokens(&SPECIAL_TOKENS.concat());
+        for format in [
+            PromptFormat::ChatMl,
+            PromptFormat::Llama3,
+            PromptFormat::Gemma,
+        ] {
+            assert!(!format.stop_markers().iter().any(|m| escaped.contains(m)));
+        }
+        assert_eq!(unescape_special_tokens(&escaped), SPECIAL_TOKENS.concat());
+    }
 
-        assert!(path.ends_with("test-model.gguf"));
-        assert_eq!(path.parent().unwrap(), config_dir());
+    #[test]
+    fn test_unescape_special_tokens_in_output() {
+        let output = "grep -v '\u{FF1C}|eot_id|>' log.txt";
+        assert_eq!(
+            unescape_special_tokens(output),
+            "grep -v '<|eot_id|>' log.txt"
+        );
+        assert_eq!(unescape_special_tokens("git status"), "git status");
     }
 
     #[test]
-    fn test_get_model_path_preserves_name() {
-        let model_names = vec![
-            "qwen3-correct-0.6B",
-            "llama-7b-q4",
-            "model_with_underscore",
-            "model-with-dash",
-        ];
+    fn test_system_prompt_template_default() {
+        let config = Config::default();
 
-        for name in model_names {
-            let path = get_model_path(name);
-            let filename = path.file_name().unwrap().to_str().unwrap();
-            assert_eq!(filename, format!("{}.gguf", name));
-        }
+        assert_eq!(
+            config.system_prompt_template(),
+            DEFAULT_SYSTEM_PROMPT_TEMPLATE
+        );
+        assert!(config.system_prompt_template().contains("{shell}"));
     }
 
-    // ===== Config Serialization Tests =====
-
     #[test]
-    fn test_config_serialization_roundtrip() {
+    fn test_system_prompt_template_override() {
         let config = Config {
-            default_model: "test-model".to_string(),
+            system_prompt: Some("Fix this {shell} command: {command}".to_string()),
+            ..Config::default()
         };
 
-        let json = serde_json::to_string(&config).unwrap();
-        let deserialized: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            config.system_prompt_template(),
+            "Fix this {shell} command: {command}"
+        );
+
+        let prompt = build_prompt_from_template(
+            PromptFormat::ChatMl,
+            config.system_prompt_template(),
+            "fish",
+            "gti status",
+            None,
+            true,
+        );
+        assert!(
+            prompt.starts_with("<|im_start|>system\nFix this fish command: gti status<|im_end|>")
+        );
+    }
 
-        assert_eq!(config.default_model, deserialized.default_model);
+    #[test]
+    fn test_no_think_directive_follows_thinking_setting() {
+        let build = |thinking| {
+            build_prompt_from_template(
+                PromptFormat::ChatMl,
+                DEFAULT_SYSTEM_PROMPT_TEMPLATE,
+                "bash",
+                "gti status",
+                None,
+                thinking,
+            )
+        };
+
+        assert!(build(false).contains(&format!("unchanged. {}<|im_end|>", NO_THINK_DIRECTIVE)));
+        assert!(!build(true).contains(NO_THINK_DIRECTIVE));
+
+        // Thinking is off unless configured
+        assert!(!Config::default().thinking);
+        assert!(
+            build_prompt(PromptFormat::ChatMl, "bash", "gti status", None)
+                .contains(NO_THINK_DIRECTIVE)
+        );
     }
 
     #[test]
-    fn test_config_deserialize_from_json() {
-        let json = r#"{"default_model": "custom-model"}"#;
-        let config: Config = serde_json::from_str(json).unwrap();
+    fn test_prompt_format_detection() {
+        assert_eq!(
+            PromptFormat::from_chat_template("{% for m in messages %}<|im_start|>{{ m.role }}"),
+            Some(PromptFormat::ChatMl)
+        );
+        assert_eq!(
+            PromptFormat::from_chat_template("<|start_header_id|>{{ role }}<|end_header_id|>"),
+            Some(PromptFormat::Llama3)
+        );
+        assert_eq!(
+            PromptFormat::from_chat_template("<start_of_turn>{{ role }}"),
+            Some(PromptFormat::Gemma)
+        );
+        assert_eq!(PromptFormat::from_chat_template("[INST]"), None);
+    }
 
-        assert_eq!(config.default_model, "custom-model");
+    #[test]
+    fn test_prompt_format_resolve() {
+        let gemma_template = Some("<start_of_turn>");
+
+        // Config wins over metadata
+        assert_eq!(
+            PromptFormat::resolve(Some(PromptFormat::Llama3), gemma_template),
+            PromptFormat::Llama3
+        );
+        assert_eq!(
+            PromptFormat::resolve(None, gemma_template),
+            PromptFormat::Gemma
+        );
+        assert_eq!(
+            PromptFormat::resolve(None, Some("[INST]")),
+            PromptFormat::ChatMl
+        );
+        assert_eq!(PromptFormat::resolve(None, None), PromptFormat::ChatMl);
+    }
+
+    // ===== Command Parsing Tests =====
+
+    #[test]
+    fn test_split_env_assignments_none() {
+        assert_eq!(split_env_assignments("git status"), ("", "git status"));
+        assert_eq!(split_env_assignments(""), ("", ""));
+    }
+
+    #[test]
+    fn test_split_env_assignments_single() {
+        assert_eq!(
+            split_env_assignments("FOO=bar pytohn script.py"),
+            ("FOO=bar", "pytohn script.py")
+        );
+    }
+
+    #[test]
+    fn test_split_env_assignments_multiple() {
+        assert_eq!(
+            split_env_assignments("RUST_LOG=debug  _X1=2 carg build"),
+            ("RUST_LOG=debug  _X1=2", "carg build")
+        );
+    }
+
+    #[test]
+    fn test_split_env_assignments_value_with_equals() {
+        assert_eq!(
+            split_env_assignments("OPTS=--level=3 mkae all"),
+            ("OPTS=--level=3", "mkae all")
+        );
+        assert_eq!(
+            split_env_assignments("MSG=\"a = b\" ehco $MSG"),
+            ("MSG=\"a = b\"", "ehco $MSG")
+        );
+    }
+
+    #[test]
+    fn test_split_env_assignments_not_an_assignment() {
+        // Arguments containing `=` after the command are left alone
+        assert_eq!(
+            split_env_assignments("dd if=/dev/zero of=out"),
+            ("", "dd if=/dev/zero of=out")
+        );
+        assert_eq!(split_env_assignments("1FOO=bar ls"), ("", "1FOO=bar ls"));
+        assert_eq!(split_env_assignments("FOO=bar"), ("", "FOO=bar"));
+    }
+
+    #[test]
+    fn test_prepend_env_assignments() {
+        assert_eq!(
+            prepend_env_assignments("FOO=bar", "python script.py"),
+            "FOO=bar python script.py"
+        );
+        assert_eq!(prepend_env_assignments("", "git status"), "git status");
+        assert_eq!(prepend_env_assignments("FOO=bar", ""), "");
+    }
+
+    // ===== Typo Heuristic Tests =====
+
+    fn cache_with_tools(names: &[&str]) -> cache::ToolsCache {
+        let mut tools_cache = cache::ToolsCache::new();
+        for name in names {
+            tools_cache.tools.insert(
+                name.to_string(),
+                cache::ToolInfo {
+                    path: format!("/usr/bin/{}", name),
+                    desc: String::new(),
+                },
+            );
+        }
+        tools_cache
+    }
+
+    #[test]
+    fn test_levenshtein_distance() {
+        assert_eq!(levenshtein_distance("gti", "git"), 2);
+        assert_eq!(levenshtein_distance("git", "git"), 0);
+        assert_eq!(levenshtein_distance("", "git"), 3);
+        assert_eq!(levenshtein_distance("dcoker", "docker"), 2);
+    }
+
+    #[test]
+    fn test_looks_incorrect_typo_of_cached_tool() {
+        let tools_cache = cache_with_tools(&["git", "docker"]);
+        assert!(looks_incorrect("gti status", &tools_cache));
+    }
+
+    #[test]
+    fn test_looks_incorrect_known_command() {
+        let tools_cache = cache_with_tools(&["git", "docker"]);
+        assert!(!looks_incorrect("git status", &tools_cache));
+    }
+
+    #[test]
+    fn test_looks_incorrect_novel_word_without_neighbor() {
+        let tools_cache = cache_with_tools(&["git", "docker"]);
+        assert!(!looks_incorrect("zqxwvkj --flag", &tools_cache));
+        assert!(!looks_incorrect("", &tools_cache));
+    }
+
+    #[test]
+    fn test_expand_abbreviation_only_when_not_a_real_binary() {
+        let abbreviations = default_abbreviations();
+        let tools_cache = cache_with_tools(&["git", "g"]);
+        let exists = |cmd: &str| tools_cache.tools.contains_key(cmd);
+
+        assert_eq!(
+            expand_abbreviation("k get pods", &abbreviations, exists),
+            Some("kubectl")
+        );
+        // `g` is installed here, so it's a command rather than an abbreviation
+        assert_eq!(
+            expand_abbreviation("g status", &abbreviations, exists),
+            None
+        );
+        assert_eq!(
+            expand_abbreviation("git status", &abbreviations, exists),
+            None
+        );
+        assert_eq!(expand_abbreviation("", &abbreviations, exists), None);
+    }
+
+    #[test]
+    fn test_is_retryable_daemon_error() {
+        assert!(is_retryable_daemon_error("Decode failed: NoKvCacheSlot"));
+        assert!(is_retryable_daemon_error(
+            "Failed to create context: null reference from llama.cpp"
+        ));
+        assert!(!is_retryable_daemon_error("Tokenization failed: bad utf8"));
+        assert!(!is_retryable_daemon_error("Unknown error"));
+    }
+
+    #[test]
+    fn test_parse_confirmation() {
+        assert_eq!(parse_confirmation("\n"), Some(true));
+        assert_eq!(parse_confirmation("y\n"), Some(true));
+        assert_eq!(parse_confirmation(" YES "), Some(true));
+        assert_eq!(parse_confirmation("n\n"), Some(false));
+        assert_eq!(parse_confirmation("No"), Some(false));
+        assert_eq!(parse_confirmation("maybe"), None);
+    }
+
+    #[test]
+    fn test_should_confirm_download_only_when_interactive() {
+        assert!(should_confirm_download(true, false));
+        assert!(!should_confirm_download(true, true));
+        assert!(!should_confirm_download(false, false));
+        assert!(!should_confirm_download(false, true));
+    }
+
+    #[test]
+    fn test_interactive_default_resolution() {
+        // Without a flag the setting follows the terminal
+        assert!(interactive_default(None, true));
+        assert!(!interactive_default(None, false));
+
+        // An explicit flag wins either way
+        assert!(interactive_default(Some(true), false));
+        assert!(interactive_default(Some(true), true));
+        assert!(!interactive_default(Some(false), true));
+        assert!(!interactive_default(Some(false), false));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_enable_utf8_console_is_noop_off_windows() {
+        assert!(enable_utf8_console());
+        assert!(enable_utf8_console());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_enable_utf8_console_prints_non_ascii() {
+        // CI runners may have no console, so whether it switched isn't checked
+        let correction = "cd café";
+        enable_utf8_console();
+        // Switching again is harmless
+        enable_utf8_console();
+        println!("{}", correction);
+    }
+
+    #[test]
+    fn test_use_utf8_console() {
+        let config = Config::default();
+        assert!(config.use_utf8_console(false));
+        assert!(!config.use_utf8_console(true));
+
+        let config = Config {
+            utf8_console: false,
+            ..Config::default()
+        };
+        assert!(!config.use_utf8_console(false));
+    }
+
+    #[test]
+    fn test_download_prompt_shows_size() {
+        assert_eq!(
+            download_prompt("qwen3-correct-0.6B", 450 * 1024 * 1024),
+            "Model qwen3-correct-0.6B (450 MB) is not downloaded. Download now? [Y/n] "
+        );
+    }
+
+    #[test]
+    fn test_daemon_needs_restart_on_version_mismatch() {
+        let current = format!(
+            r#"{{"protocol_version":{},"success":true,"output":"git status","error":null}}"#,
+            DAEMON_PROTOCOL_VERSION
+        );
+        assert_eq!(response_protocol_version(&current), DAEMON_PROTOCOL_VERSION);
+        assert!(!daemon_needs_restart(&current));
+
+        // A daemon from before versioning
+        let legacy = r#"{"success":true,"output":"git status","error":null}"#;
+        assert_eq!(response_protocol_version(legacy), 0);
+        assert!(daemon_needs_restart(legacy));
+
+        let newer = format!(
+            r#"{{"protocol_version":{},"success":true}}"#,
+            DAEMON_PROTOCOL_VERSION + 1
+        );
+        assert!(daemon_needs_restart(&newer));
+
+        assert!(daemon_needs_restart("garbage"));
+    }
+
+    #[test]
+    fn test_merge_minimal_edit_preserves_spacing() {
+        assert_eq!(
+            merge_minimal_edit("gti  status", "git status"),
+            "git  status"
+        );
+        assert_eq!(
+            merge_minimal_edit("gti commit -m  'x'", "git commit -m 'x'"),
+            "git commit -m  'x'"
+        );
+        assert_eq!(merge_minimal_edit("gti status", "git status"), "git status");
+    }
+
+    #[test]
+    fn test_merge_minimal_edit_preserves_casing() {
+        assert_eq!(merge_minimal_edit("GTI STATUS", "git status"), "git STATUS");
+        assert_eq!(
+            merge_minimal_edit("Docker  PS -A", "docker ps -a"),
+            "Docker  PS -A"
+        );
+        // A real correction still comes through in the model's casing
+        assert_eq!(merge_minimal_edit("gti Status", "git status"), "git Status");
+    }
+
+    #[test]
+    fn test_minimal_edit_is_off_by_default() {
+        assert!(!Config::default().minimal_edit);
+        let config: Config =
+            serde_json::from_str(r#"{"default_model": "qwen3-correct-0.6B"}"#).unwrap();
+        assert!(!config.minimal_edit);
+    }
+
+    #[test]
+    fn test_merge_minimal_edit_leaves_rewrites() {
+        // Different word count
+        assert_eq!(
+            merge_minimal_edit("gti  status", "git status --short"),
+            "git status --short"
+        );
+        // Too many edits to be a small fix
+        assert_eq!(merge_minimal_edit("list  files", "ls -la"), "ls -la");
+    }
+
+    #[test]
+    fn test_split_command_args() {
+        assert_eq!(split_command_args("gti"), ("gti", ""));
+        assert_eq!(
+            split_command_args("  gti push  origin main "),
+            ("gti", "push  origin main")
+        );
+        assert_eq!(
+            split_command_args("grpe 'a b' file"),
+            ("grpe", "'a b' file")
+        );
+        assert_eq!(split_command_args(""), ("", ""));
+    }
+
+    #[test]
+    fn test_correct_batch_one_result_per_input_in_order() {
+        let inputs = ["gti status", "FOO=1 pyhton3 app.py", "brkoen", "dokcer ps"];
+        let mut prompts = Vec::new();
+        let results = correct_batch(
+            &inputs,
+            "bash",
+            PromptFormat::ChatMl,
+            &Config::default(),
+            |prompt| {
+                prompts.push(prompt.to_string());
+                if prompt.contains("gti status") {
+                    Ok("Command: git status".to_string())
+                } else if prompt.contains("pyhton3 app.py") {
+                    Ok("python3 app.py".to_string())
+                } else if prompt.contains("dokcer ps") {
+                    Ok("docker ps".to_string())
+                } else {
+                    Err("Decode failed".to_string())
+                }
+            },
+        );
+
+        assert_eq!(
+            results,
+            vec![
+                Ok("git status".to_string()),
+                Ok("FOO=1 python3 app.py".to_string()),
+                Err("Decode failed".to_string()),
+                Ok("docker ps".to_string()),
+            ]
+        );
+        // Assignments are kept out of the prompt
+        assert_eq!(prompts.len(), 4);
+        assert!(!prompts[1].contains("FOO=1"));
+    }
+
+    #[test]
+    fn test_correct_batch_strips_thinking() {
+        let config = Config {
+            thinking: true,
+            thinking_tags: vec![sampling::ThinkingTags::new("<plan>", "</plan>")],
+            ..Config::default()
+        };
+        let results = correct_batch(
+            &["gti status", "dokcer ps"],
+            "bash",
+            PromptFormat::ChatMl,
+            &config,
+            |prompt| {
+                Ok(if prompt.contains("gti status") {
+                    "<plan>gti is a typo.\nThe user wants git.</plan>\ngit status".to_string()
+                } else {
+                    "<plan>Typo in docker, so: `docker ps`</plan>".to_string()
+                })
+            },
+        );
+
+        assert_eq!(
+            results,
+            vec![Ok("git status".to_string()), Ok("docker ps".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_correct_batch_applies_safety_checks() {
+        let config = Config {
+            input_denylist: vec![r"--password\b".to_string()],
+            ..Config::default()
+        };
+        let mut generated = Vec::new();
+        let results = correct_batch(
+            &["mysql --password hunter2", "list files"],
+            "bash",
+            PromptFormat::ChatMl,
+            &config,
+            |prompt| {
+                generated.push(prompt.to_string());
+                Ok("rm -rf /".to_string())
+            },
+        );
+
+        // The denied input never reaches the model
+        assert_eq!(generated.len(), 1);
+        assert!(results[0].as_ref().unwrap_err().contains("input_denylist"));
+        assert!(results[1].as_ref().unwrap_err().contains("rm -rf"));
+    }
+
+    #[test]
+    fn test_append_correction_records() {
+        let path =
+            std::env::temp_dir().join(format!("fix-save-output-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        append_correction_record(
+            &path,
+            &CorrectionRecord::new("gti status", "git status", "bash"),
+        )
+        .unwrap();
+        append_correction_record(&path, &CorrectionRecord::new("sl", "ls", "zsh")).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let records: Vec<CorrectionRecord> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].input, "gti status");
+        assert_eq!(records[0].correction, "git status");
+        assert_eq!(records[1].shell, "zsh");
+        assert!(chrono::DateTime::parse_from_rfc3339(&records[1].timestamp).is_ok());
+    }
+
+    #[test]
+    fn test_preserves_command_word() {
+        assert!(preserves_command_word("tar -xzvf a.tgz", "tar -xvzf a.tgz"));
+        assert!(preserves_command_word(
+            "FOO=1 grep -rnf x .",
+            "FOO=1 grep -rn x ."
+        ));
+        assert!(preserves_command_word("ls -la", "ls -l -a"));
+
+        // Changing the command itself is rejected
+        assert!(!preserves_command_word("tar -xzvf a.tgz", "unzip a.tgz"));
+        assert!(!preserves_command_word("gti --version", "git --version"));
+        assert!(!preserves_command_word("ls -la", ""));
+    }
+
+    #[test]
+    fn test_reattach_args() {
+        // Dropped and reordered arguments come back as typed
+        assert_eq!(
+            reattach_args("gti push origin main", "git push"),
+            "git push origin main"
+        );
+        assert_eq!(
+            reattach_args("gti push origin main", "git push main origin"),
+            "git push origin main"
+        );
+        // Quoting and spacing survive
+        assert_eq!(
+            reattach_args("grpe -r 'foo  bar' src", "grep -r \"foo bar\" src"),
+            "grep -r 'foo  bar' src"
+        );
+        // Arguments the user didn't type are stripped
+        assert_eq!(reattach_args("gti", "git status"), "git");
+        // Unchanged arguments leave the correction alone
+        assert_eq!(
+            reattach_args("pyhton -m http.server", "python -m http.server"),
+            "python -m http.server"
+        );
+        // Assignments stay as the correction has them
+        assert_eq!(
+            reattach_args("FOO=1 pytohn app.py --debug", "FOO=1 python app.py"),
+            "FOO=1 python app.py --debug"
+        );
+        assert_eq!(reattach_args("gti push", ""), "");
+    }
+
+    #[test]
+    fn test_prefer_original_when_valid_and_trivially_different() {
+        let exists = |cmd: &str| cmd == "git";
+
+        assert!(prefer_original(
+            "git commit -m 'wip'",
+            "git commit -m \"wip\"",
+            exists
+        ));
+        assert!(prefer_original("git  status", "git status", exists));
+    }
+
+    #[test]
+    fn test_prefer_original_rejects_real_corrections() {
+        let exists = |cmd: &str| cmd == "git";
+
+        // Flag typo on a valid command is a real fix
+        assert!(!prefer_original("git comit", "git commit", exists));
+        // Unknown first word: trust the model
+        assert!(!prefer_original("gti  status", "gti status", exists));
+        // Identical output needs no override
+        assert!(!prefer_original("git status", "git status", exists));
+        assert!(!prefer_original("", "git status", exists));
+    }
+
+    // ===== Path Function Tests =====
+
+    #[test]
+    fn test_config_dir_returns_path() {
+        let dir = config_dir().unwrap();
+
+        // Should end with "fix"
+        assert!(dir.ends_with("fix"));
+
+        // Should not be empty
+        assert!(!dir.as_os_str().is_empty());
+    }
+
+    #[test]
+    fn test_resolve_config_dir_uses_xdg_config_home() {
+        let xdg = env::temp_dir().join(format!("fix-xdg-test-{}", std::process::id()));
+
+        let dir = resolve_config_dir(Some(xdg.clone().into()), None).unwrap();
+        assert_eq!(dir, xdg.join("fix"));
+        assert!(dir.is_dir());
+
+        let _ = std::fs::remove_dir_all(&xdg);
+    }
+
+    #[test]
+    fn test_resolve_config_dir_ignores_relative_xdg() {
+        let home = env::temp_dir().join(format!("fix-xdg-relative-{}", std::process::id()));
+
+        let dir = resolve_config_dir(Some("relative/config".into()), Some(home.clone())).unwrap();
+        assert_eq!(dir, home.join(".config").join("fix"));
+
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn test_resolve_config_dir_falls_back_to_home() {
+        let base = env::temp_dir().join(format!("fix-xdg-fallback-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        // A directory can't be created underneath a regular file
+        let blocker = base.join("not-a-dir");
+        std::fs::write(&blocker, b"").unwrap();
+        let home = base.join("home");
+
+        let dir = resolve_config_dir(Some(blocker.join("xdg").into()), Some(home.clone())).unwrap();
+        assert_eq!(dir, home.join(".config").join("fix"));
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn test_resolve_config_dir_errors_when_nothing_writable() {
+        let base = env::temp_dir().join(format!("fix-xdg-unwritable-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        let blocker = base.join("not-a-dir");
+        std::fs::write(&blocker, b"").unwrap();
+
+        let err = resolve_config_dir(Some(blocker.join("xdg").into()), Some(blocker.join("home")))
+            .unwrap_err();
+        assert!(err.starts_with("No writable config directory"));
+        assert!(err.contains("$XDG_CONFIG_HOME"));
+        assert!(err.contains("$HOME/.config"));
+
+        assert!(resolve_config_dir(None, None).is_err());
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn test_config_path_returns_json_file() {
+        let path = config_path().unwrap();
+
+        // Should end with "config.json"
+        assert!(path.ends_with("config.json"));
+
+        // Parent should be config_dir()
+        assert_eq!(path.parent().unwrap(), config_dir().unwrap());
+    }
+
+    #[test]
+    fn test_model_url_file_name() {
+        let name = model_url_file_name("https://example.com/models/my-fix.gguf");
+        assert!(name.starts_with("url-my-fix-"), "{}", name);
+        assert!(name.ends_with(".gguf"));
+
+        // Same URL, same file; same file name on another host, different file
+        assert_eq!(
+            name,
+            model_url_file_name("https://example.com/models/my-fix.gguf")
+        );
+        assert_ne!(
+            name,
+            model_url_file_name("https://mirror.example.org/models/my-fix.gguf")
+        );
+
+        // Query strings and odd characters don't leak into the name
+        let name = model_url_file_name("https://example.com/dl/m%20odel.gguf?token=abc");
+        assert!(name.starts_with("url-m20odel-"), "{}", name);
+        assert!(!name.contains('?'));
+
+        let name = model_url_file_name("https://example.com/");
+        assert_eq!(name.len(), "url-.gguf".len() + 16);
+    }
+
+    #[test]
+    fn test_hf_model_url() {
+        assert_eq!(
+            hf_model_url("qwen3-correct-0.6B", DEFAULT_REVISION),
+            format!(
+                "https://huggingface.co/{}/resolve/main/qwen3-correct-0.6B.gguf",
+                HF_REPO
+            )
+        );
+    }
+
+    #[test]
+    fn test_hf_model_url_with_revision() {
+        assert_eq!(
+            hf_model_url("qwen3-correct-0.6B", "3f2a9c1"),
+            format!(
+                "https://huggingface.co/{}/resolve/3f2a9c1/qwen3-correct-0.6B.gguf",
+                HF_REPO
+            )
+        );
+        assert_eq!(
+            hf_model_url("qwen3-correct-0.6B", "v1.2"),
+            format!(
+                "https://huggingface.co/{}/resolve/v1.2/qwen3-correct-0.6B.gguf",
+                HF_REPO
+            )
+        );
+    }
+
+    #[test]
+    fn test_hf_model_url_encodes_revision() {
+        assert_eq!(
+            hf_model_url("qwen3-correct-0.6B", "refs/pr/1"),
+            format!(
+                "https://huggingface.co/{}/resolve/refs%2Fpr%2F1/qwen3-correct-0.6B.gguf",
+                HF_REPO
+            )
+        );
+        assert_eq!(encode_revision("a b?c#d"), "a%20b%3Fc%23d");
+        assert_eq!(encode_revision("café"), "caf%C3%A9");
+    }
+
+    #[test]
+    fn test_model_revision_defaults_to_main() {
+        let mut config = Config::default();
+        assert_eq!(config.model_revision(None), "main");
+
+        config.model_revision = Some("v1.2".to_string());
+        assert_eq!(config.model_revision(None), "v1.2");
+        assert_eq!(config.model_revision(Some("3f2a9c1")), "3f2a9c1");
+    }
+
+    #[test]
+    fn test_revision_record() {
+        assert_eq!(
+            revision_path(Path::new("/models/qwen3-correct-0.6B.gguf")),
+            PathBuf::from("/models/qwen3-correct-0.6B.gguf.revision")
+        );
+
+        assert!(revision_matches(Some("v1.2\n"), "v1.2"));
+        assert!(!revision_matches(Some("v1.1"), "v1.2"));
+        // Unrecorded downloads came from main
+        assert!(revision_matches(None, "main"));
+        assert!(!revision_matches(None, "v1.2"));
+    }
+
+    #[test]
+    fn test_next_page_link() {
+        assert_eq!(
+            next_page_link(
+                r#"<https://huggingface.co/api/models/x/tree/main?cursor=abc>; rel="next""#
+            ),
+            Some("https://huggingface.co/api/models/x/tree/main?cursor=abc".to_string())
+        );
+        assert_eq!(
+            next_page_link(r#"<https://a/1>; rel="prev", <https://a/3>; rel="next""#),
+            Some("https://a/3".to_string())
+        );
+        assert_eq!(next_page_link(r#"<https://a/1>; rel="prev""#), None);
+        assert_eq!(next_page_link(""), None);
+    }
+
+    /// Answer one HTTP request per response on a local port
+    ///
+    /// `responses` gets the server's address, for responses that link back to
+    /// it, and each is sent as-is once the request's headers are read. Returns
+    /// the address and a handle yielding the request lines received.
+    fn serve_http<F>(responses: F) -> (String, std::thread::JoinHandle<Vec<String>>)
+    where
+        F: FnOnce(&str) -> Vec<Vec<u8>>,
+    {
+        use std::io::BufRead;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let responses = responses(&addr);
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                // Drain headers before replying
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                stream.write_all(&response).unwrap();
+                requests.push(request_line);
+            }
+            requests
+        });
+        (addr, server)
+    }
+
+    /// A complete `200 OK` response with extra `headers` (each ending in CRLF)
+    fn http_ok(headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            headers,
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    fn test_fetch_listing_follows_next_pages() {
+        let (addr, server) = serve_http(|addr| {
+            let next = format!(
+                "Link: <http://{}/tree/main?cursor=2>; rel=\"next\"\r\n",
+                addr
+            );
+            vec![
+                http_ok(
+                    &next,
+                    br#"[{"path": "a.gguf", "size": 1}, {"path": "README.md", "size": 2}]"#,
+                ),
+                http_ok("", br#"[{"path": "b.gguf", "size": 3}]"#),
+            ]
+        });
+
+        let client = http_client(
+            std::time::Duration::from_secs(10),
+            &ProxySettings::default(),
+        )
+        .unwrap();
+        let files = fetch_listing(&client, format!("http://{}/tree/main", addr)).unwrap();
+        let names: Vec<String> = parse_model_listing(&files)
+            .into_iter()
+            .map(|model| model.name)
+            .collect();
+
+        assert_eq!(names, vec!["a", "b"]);
+        let requests = server.join().unwrap();
+        assert!(requests[1].starts_with("GET /tree/main?cursor=2 "));
+    }
+
+    #[test]
+    fn test_fetch_listing_rejects_endless_pages() {
+        // Every page links onward, past the page limit
+        let (addr, _server) = serve_http(|addr| {
+            let next = format!("Link: <http://{}/tree/main>; rel=\"next\"\r\n", addr);
+            let page = http_ok(&next, br#"[{"path": "a.gguf", "size": 1}]"#);
+            vec![page; MAX_LISTING_PAGES]
+        });
+
+        let client = http_client(
+            std::time::Duration::from_secs(10),
+            &ProxySettings::default(),
+        )
+        .unwrap();
+        let err = fetch_listing(&client, format!("http://{}/tree/main", addr)).unwrap_err();
+
+        assert!(err.contains("longer than 100 pages"), "{}", err);
+    }
+
+    #[test]
+    fn test_download_file_from_any_url() {
+        let (addr, server) = serve_http(|_| vec![http_ok("", b"GGUF")]);
+        let url = format!("http://{}/custom/model.gguf", addr);
+
+        let dir = env::temp_dir().join(format!("fix-download-test-{}", std::process::id()));
+        let dest = dir.join(model_url_file_name(&url));
+        download_file(&url, &dest, "test server", false).unwrap();
+
+        assert!(server.join().unwrap()[0].starts_with("GET /custom/model.gguf "));
+        assert_eq!(std::fs::read(&dest).unwrap(), b"GGUF");
+        assert!(!dest.with_extension("gguf.tmp").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_proxy_settings_from_env() {
+        let vars = |name: &str| match name {
+            "HTTPS_PROXY" => Some("".to_string()),
+            "https_proxy" => Some("proxy.corp:8443".to_string()),
+            "HTTP_PROXY" => Some("http://proxy.corp:8080".to_string()),
+            "NO_PROXY" => Some(" localhost, .Internal.corp ,".to_string()),
+            _ => None,
+        };
+
+        let settings = ProxySettings::resolve(None, vars);
+        assert_eq!(settings.https.as_deref(), Some("proxy.corp:8443"));
+        assert_eq!(settings.http.as_deref(), Some("http://proxy.corp:8080"));
+        assert_eq!(settings.no_proxy, vec!["localhost", ".internal.corp"]);
+
+        // --proxy replaces both proxies but keeps NO_PROXY
+        let settings = ProxySettings::resolve(Some("http://flag:3128"), vars);
+        assert_eq!(settings.https.as_deref(), Some("http://flag:3128"));
+        assert_eq!(settings.http.as_deref(), Some("http://flag:3128"));
+        assert_eq!(settings.no_proxy.len(), 2);
+
+        assert_eq!(
+            ProxySettings::resolve(None, |_| None),
+            ProxySettings::default()
+        );
+    }
+
+    #[test]
+    fn test_proxy_for_respects_no_proxy() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        let mut settings = ProxySettings {
+            https: Some("proxy.corp:8443".to_string()),
+            http: None,
+            no_proxy: Vec::new(),
+        };
+        let hf = url("https://huggingface.co/api/models/x/tree/main");
+        let lfs = url("https://cdn-lfs.huggingface.co/repos/model.gguf");
+
+        assert_eq!(
+            settings.proxy_for(&hf).as_deref(),
+            Some("http://proxy.corp:8443")
+        );
+        assert_eq!(settings.proxy_for(&url("http://huggingface.co/")), None);
+
+        for entry in ["huggingface.co", ".huggingface.co", "*.huggingface.co", "*"] {
+            settings.no_proxy = vec![entry.to_string()];
+            assert_eq!(settings.proxy_for(&hf), None, "{}", entry);
+            assert_eq!(settings.proxy_for(&lfs), None, "{}", entry);
+        }
+
+        settings.no_proxy = vec!["face.co".to_string()];
+        assert!(settings.proxy_for(&hf).is_some());
+    }
+
+    #[test]
+    fn test_http_client_sends_through_proxy() {
+        let (addr, server) = serve_http(|_| vec![http_ok("", b"ok")]);
+        let proxy = ProxySettings {
+            https: None,
+            http: Some(addr),
+            no_proxy: vec!["direct.invalid".to_string()],
+        };
+
+        let client = http_client(std::time::Duration::from_secs(10), &proxy).unwrap();
+        let body = client
+            .get("http://models.invalid/model.gguf")
+            .send()
+            .and_then(|r| r.text())
+            .unwrap();
+
+        assert_eq!(body, "ok");
+        // A proxy receives the absolute URL
+        assert!(server.join().unwrap()[0].starts_with("GET http://models.invalid/model.gguf "));
+    }
+
+    #[test]
+    fn test_download_file_keep_partial() {
+        for keep_partial in [true, false] {
+            // Promise 100 bytes, send 10, then hang up
+            let (addr, server) = serve_http(|_| {
+                vec![b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nGGUF012345".to_vec()]
+            });
+            let url = format!("http://{}/model.gguf", addr);
+
+            let dir = env::temp_dir().join(format!(
+                "fix-partial-test-{}-{}",
+                keep_partial,
+                std::process::id()
+            ));
+            let dest = dir.join("model.gguf");
+            let result = download_file(&url, &dest, "test server", keep_partial);
+            server.join().unwrap();
+
+            assert!(result.is_err());
+            assert!(!dest.exists());
+            let temp = dest.with_extension("gguf.tmp");
+            if keep_partial {
+                assert_eq!(std::fs::read(&temp).unwrap(), b"GGUF012345");
+            } else {
+                assert!(!temp.exists());
+            }
+
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn test_find_or_download_model_url_rejects_non_http() {
+        let err = find_or_download_model_url(
+            &config_dir().unwrap(),
+            "file:///tmp/model.gguf",
+            false,
+            false,
+        )
+        .unwrap_err();
+        assert!(err.contains("http"));
+    }
+
+    #[test]
+    fn test_get_model_path_appends_gguf() {
+        let path = get_model_path(&config_dir().unwrap(), "test-model");
+
+        assert!(path.ends_with("test-model.gguf"));
+        assert_eq!(path.parent().unwrap(), config_dir().unwrap());
+    }
+
+    #[test]
+    fn test_model_dir_flag_overrides_env_and_default() {
+        let flag = PathBuf::from("/flag/models");
+        let default = || Ok(PathBuf::from("/default/fix"));
+
+        let dir = resolve_model_dir(Some(&flag), Some("/env/models".into()), default).unwrap();
+        assert_eq!(dir, flag);
+        assert_eq!(
+            get_model_path(&dir, "test-model"),
+            PathBuf::from("/flag/models/test-model.gguf")
+        );
+
+        assert_eq!(
+            resolve_model_dir(None, Some("/env/models".into()), default),
+            Ok(PathBuf::from("/env/models"))
+        );
+
+        // An unusable config directory only matters when it's needed
+        let unusable = || Err("no writable config directory".to_string());
+        assert_eq!(
+            resolve_model_dir(Some(&flag), None, unusable),
+            Ok(flag.clone())
+        );
+        assert!(resolve_model_dir(None, None, unusable).is_err());
+    }
+
+    #[test]
+    fn test_model_dir_defaults_without_overrides() {
+        let default = || Ok(PathBuf::from("/default/fix"));
+
+        assert_eq!(
+            resolve_model_dir(None, None, default),
+            Ok(PathBuf::from("/default/fix"))
+        );
+        assert_eq!(
+            resolve_model_dir(None, Some("".into()), default),
+            Ok(PathBuf::from("/default/fix"))
+        );
+        assert_eq!(
+            get_model_path(&resolve_model_dir(None, None, default).unwrap(), "m"),
+            PathBuf::from("/default/fix/m.gguf")
+        );
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("24h"), Ok(chrono::Duration::hours(24)));
+        assert_eq!(parse_since("7d"), Ok(chrono::Duration::days(7)));
+        assert_eq!(parse_since("2w"), Ok(chrono::Duration::weeks(2)));
+
+        assert!(parse_since("").is_err());
+        assert!(parse_since("d").is_err());
+        assert!(parse_since("0d").is_err());
+        assert!(parse_since("-3d").is_err());
+        assert!(parse_since("7y").is_err());
+        assert!(parse_since("seven days").is_err());
+        assert!(parse_since("7é").is_err());
+    }
+
+    #[test]
+    fn test_updated_within_filters_fixture_listing() {
+        let listing = serde_json::json!([
+            {"type": "file", "path": "README.md", "size": 10},
+            {"type": "file", "path": "fresh.gguf", "size": 100,
+             "lastCommit": {"id": "a", "date": "2026-10-15T12:00:00.000Z"}},
+            {"type": "file", "path": "old.gguf", "size": 100,
+             "lastCommit": {"id": "b", "date": "2026-08-01T12:00:00.000Z"}},
+            {"type": "file", "path": "unknown.gguf", "size": 100}
+        ]);
+        let models = parse_model_listing(listing.as_array().unwrap());
+        assert_eq!(models.len(), 3);
+
+        let now = chrono::DateTime::parse_from_rfc3339("2026-10-17T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let recent: Vec<&str> = models
+            .iter()
+            .filter(|m| updated_within(m, chrono::Duration::days(7), now))
+            .map(|m| m.name.as_str())
+            .collect();
+
+        assert_eq!(recent, vec!["fresh"]);
+    }
+
+    #[test]
+    fn test_model_size_in_listing() {
+        let listing = serde_json::json!([
+            {"type": "file", "path": "README.md", "size": 10},
+            {"type": "file", "path": "qwen3-correct-0.6B.gguf", "size": 639_447_040u64},
+            {"type": "file", "path": "qwen3-wit-1.7B.gguf", "size": 1_834_426_016u64}
+        ]);
+        let files = listing.as_array().unwrap();
+
+        assert_eq!(
+            model_size_in_listing(files, "qwen3-wit-1.7B"),
+            Ok(1_834_426_016)
+        );
+
+        let err = model_size_in_listing(files, "missing-model").unwrap_err();
+        assert!(err.contains("Model 'missing-model' not found"), "{}", err);
+        assert!(
+            err.contains("qwen3-correct-0.6B, qwen3-wit-1.7B"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_model_size_in_listing_requires_size() {
+        let listing = serde_json::json!([{"type": "file", "path": "qwen3-wit-1.7B.gguf"}]);
+
+        let err = model_size_in_listing(listing.as_array().unwrap(), "qwen3-wit-1.7B").unwrap_err();
+        assert!(err.contains("size of model 'qwen3-wit-1.7B'"), "{}", err);
+    }
+
+    #[test]
+    fn test_get_model_path_preserves_name() {
+        let model_names = vec![
+            "qwen3-correct-0.6B",
+            "llama-7b-q4",
+            "model_with_underscore",
+            "model-with-dash",
+        ];
+
+        for name in model_names {
+            let path = get_model_path(&config_dir().unwrap(), name);
+            let filename = path.file_name().unwrap().to_str().unwrap();
+            assert_eq!(filename, format!("{}.gguf", name));
+        }
+    }
+
+    // ===== Config Serialization Tests =====
+
+    #[test]
+    fn test_config_serialization_roundtrip() {
+        let config = Config {
+            default_model: "test-model".to_string(),
+            use_daemon: false,
+            ping_keeps_daemon_alive: false,
+            tool_cache_ttl_secs: 300,
+            first_run_discovery_ms: 0,
+            tool_output_limits: BTreeMap::from([(
+                "help_output".to_string(),
+                tools::OutputLimit::Lines(5),
+            )]),
+            bin_dir: Some(PathBuf::from("/opt/fix/bin")),
+            destructive_patterns: vec!["rm -rf".to_string()],
+            input_denylist: vec!["--password".to_string()],
+            abbreviations: BTreeMap::from([("kc".to_string(), "kubectl".to_string())]),
+            prompt_format: Some(PromptFormat::Gemma),
+            system_prompt: Some("Fix {command}".to_string()),
+            minimal_edit: true,
+            shell_aliases: true,
+            reuse_daemon_context: true,
+            prompt_cache_size: 8,
+            thinking: true,
+            model_revision: Some("v1.2".to_string()),
+            thinking_tags: vec![sampling::ThinkingTags::new("<plan>", "</plan>")],
+            utf8_console: false,
+        };
+
+        let json = serde_json::to_string(&config).unwrap();
+        let deserialized: Config = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(config.default_model, deserialized.default_model);
+        assert_eq!(config.use_daemon, deserialized.use_daemon);
+        assert_eq!(
+            config.ping_keeps_daemon_alive,
+            deserialized.ping_keeps_daemon_alive
+        );
+        assert_eq!(config.minimal_edit, deserialized.minimal_edit);
+        assert_eq!(config.utf8_console, deserialized.utf8_console);
+        assert_eq!(config.shell_aliases, deserialized.shell_aliases);
+        assert_eq!(
+            config.reuse_daemon_context,
+            deserialized.reuse_daemon_context
+        );
+        assert_eq!(config.prompt_cache_size, deserialized.prompt_cache_size);
+        assert_eq!(config.thinking, deserialized.thinking);
+        assert_eq!(config.model_revision, deserialized.model_revision);
+        assert_eq!(config.thinking_tags, deserialized.thinking_tags);
+        assert_eq!(config.tool_cache_ttl_secs, deserialized.tool_cache_ttl_secs);
+        assert_eq!(
+            config.first_run_discovery_ms,
+            deserialized.first_run_discovery_ms
+        );
+        assert_eq!(config.tool_output_limits, deserialized.tool_output_limits);
+        assert_eq!(config.bin_dir, deserialized.bin_dir);
+        assert_eq!(
+            config.destructive_patterns,
+            deserialized.destructive_patterns
+        );
+        assert_eq!(config.input_denylist, deserialized.input_denylist);
+        assert_eq!(config.abbreviations, deserialized.abbreviations);
+        assert_eq!(config.prompt_format, deserialized.prompt_format);
+        assert_eq!(config.system_prompt, deserialized.system_prompt);
+    }
+
+    #[test]
+    fn test_config_path_kind() {
+        let dir = std::env::temp_dir().join(format!("fix-config-kind-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+        std::fs::write(&file, "{}").unwrap();
+
+        assert_eq!(ConfigPathKind::of(&file), ConfigPathKind::File);
+        assert_eq!(ConfigPathKind::of(&dir), ConfigPathKind::Directory);
+        assert_eq!(
+            ConfigPathKind::of(&dir.join("missing.json")),
+            ConfigPathKind::Missing
+        );
+        #[cfg(unix)]
+        {
+            let link = dir.join("dangling.json");
+            std::os::unix::fs::symlink(dir.join("nowhere.json"), &link).unwrap();
+            assert_eq!(ConfigPathKind::of(&link), ConfigPathKind::DanglingSymlink);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_model_format_from_header() {
+        // Start of a GGUF v3 file: magic, then the version
+        assert_eq!(
+            ModelFormat::detect(b"GGUF\x03\x00\x00\x00\x00"),
+            ModelFormat::Gguf
+        );
+
+        // A safetensors file: 8-byte header length, then the JSON header
+        let mut safetensors = 120u64.to_le_bytes().to_vec();
+        safetensors.extend_from_slice(br#"{"__metadata__":{}}"#);
+        assert_eq!(ModelFormat::detect(&safetensors), ModelFormat::Safetensors);
+
+        assert_eq!(
+            ModelFormat::detect(b"<!DOCTYPE html>"),
+            ModelFormat::Unknown
+        );
+        assert_eq!(ModelFormat::detect(b""), ModelFormat::Unknown);
+    }
+
+    #[test]
+    fn test_safetensors_model_is_rejected_with_advice() {
+        let dir = env::temp_dir().join(format!("fix-model-format-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut weights = 2u64.to_le_bytes().to_vec();
+        weights.extend_from_slice(b"{}");
+        let path = dir.join("model.safetensors");
+        std::fs::write(&path, &weights).unwrap();
+        let err = check_model_file(&path).unwrap_err();
+        assert!(err.contains("only GGUF models are supported"), "{}", err);
+
+        // An empty .gguf, say from an interrupted copy
+        let path = dir.join("model.gguf");
+        std::fs::write(&path, b"").unwrap();
+        let err = check_model_file(&path).unwrap_err();
+        assert!(err.contains("incomplete or corrupt"), "{}", err);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_find_model_path_checks_override() {
+        let dir = env::temp_dir().join(format!("fix-model-override-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let find = |path: &Path| {
+            find_model_path(
+                &dir,
+                Some(path.to_path_buf()),
+                &Config::default(),
+                DEFAULT_REVISION,
+                false,
+                false,
+                false,
+            )
+        };
+
+        let text = dir.join("notes.txt");
+        std::fs::write(&text, "not a model").unwrap();
+        let err = find(&text).unwrap_err();
+        assert!(err.contains("is not a .gguf file"), "{}", err);
+
+        let err = find(&dir).unwrap_err();
+        assert!(err.contains("is a directory"), "{}", err);
+
+        let err = find(&dir.join("missing.gguf")).unwrap_err();
+        assert!(err.contains("Model not found"), "{}", err);
+
+        let model = dir.join("model.GGUF");
+        std::fs::write(&model, b"GGUF").unwrap();
+        assert_eq!(find(&model), Ok(model.clone()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_directory_at_config_path() {
+        // Nothing exists at this path; the kind check says it's a directory
+        let path = std::env::temp_dir()
+            .join(format!("fix-config-dir-{}", std::process::id()))
+            .join("config.json");
+        let directory = |_: &Path| ConfigPathKind::Directory;
+
+        assert_eq!(
+            load_config_from(&path, directory).default_model,
+            DEFAULT_MODEL
+        );
+
+        let err = save_config_to(&path, &Config::default(), directory).unwrap_err();
+        assert!(err.contains("is a directory"), "{}", err);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_config_deserialize_from_json() {
+        let json = r#"{"default_model": "custom-model"}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+
+        assert_eq!(config.default_model, "custom-model");
+        // Older config files without the fields get the defaults
+        assert!(config.use_daemon);
+        assert_eq!(config.tool_cache_ttl_secs, tools::DEFAULT_CACHE_TTL_SECS);
+        assert!(config.bin_dir.is_none());
+        assert_eq!(config.destructive_patterns, safety::default_patterns());
+        assert!(config.prompt_format.is_none());
+        assert!(config.system_prompt.is_none());
+    }
+
+    // ===== Daemon Mode Tests =====
+
+    #[test]
+    fn test_is_expected_executable() {
+        let exe = format!("fix{}", env::consts::EXE_SUFFIX);
+
+        assert!(is_expected_executable(
+            &Path::new("/usr/local/bin").join(&exe),
+            "fix"
+        ));
+        assert!(!is_expected_executable(
+            Path::new("/usr/bin/python3"),
+            "fix"
+        ));
+        assert!(!is_expected_executable(
+            Path::new("/nix/store/x/.fix-wrapped"),
+            "fix"
+        ));
+        assert!(!is_expected_executable(
+            &Path::new("/usr/local/bin").join(&exe),
+            "wit"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_daemon_executable() {
+        let matching = Path::new("/usr/local/bin").join(format!("fix{}", env::consts::EXE_SUFFIX));
+        assert_eq!(
+            resolve_daemon_executable(&matching, "fix", None),
+            Ok(matching.clone())
+        );
+
+        let wrapper = Path::new("/usr/bin/wrapper.sh");
+        let err = resolve_daemon_executable(wrapper, "fix", None).unwrap_err();
+        assert!(err.contains("bin_dir"));
+
+        let dir = env::temp_dir().join(format!("fix-bin-dir-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let real = dir.join(format!("fix{}", env::consts::EXE_SUFFIX));
+        std::fs::write(&real, b"").unwrap();
+
+        assert_eq!(
+            resolve_daemon_executable(wrapper, "fix", Some(&dir)),
+            Ok(real)
+        );
+        assert!(resolve_daemon_executable(wrapper, "wit", Some(&dir)).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_use_direct_mode_defaults_to_daemon() {
+        let config = Config::default();
+
+        assert!(config.use_daemon);
+        assert!(!config.use_direct_mode(false));
+        assert!(config.use_direct_mode(true));
+    }
+
+    #[test]
+    fn test_use_direct_mode_when_daemon_disabled() {
+        let config = Config {
+            use_daemon: false,
+            ..Config::default()
+        };
+
+        // Direct mode is chosen even without the --direct flag
+        assert!(config.use_direct_mode(false));
+        assert!(config.use_direct_mode(true));
     }
 }
@@ -0,0 +1,143 @@
+//! Leveled diagnostics controlled by `FIX_LOG`
+//!
+//! `FIX_LOG=error|info|debug` turns on lines like `[debug] ...` on
+//! stderr; unset, empty, `off` or an unknown value keeps them off. Unlike
+//! `--verbose`, which prints one command's inference details, this covers
+//! the plumbing around it (model resolution, downloads, daemon choice).
+//!
+//! Log with the `log_error!`, `log_info!` and `log_debug!` macros; their
+//! arguments are only formatted when the level is enabled.
+
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Environment variable selecting the log level
+pub const LOG_ENV: &str = "FIX_LOG";
+
+/// How much to log, from nothing to everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Level {
+    #[default]
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// Parse a `FIX_LOG` value, ignoring case and surrounding whitespace
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "off" => Some(Self::Off),
+            "error" => Some(Self::Error),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    /// Whether a line at `self` is shown when logging up to `max`
+    pub fn enabled(self, max: Level) -> bool {
+        self != Level::Off && self <= max
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Level chosen by `FIX_LOG`, read once per process
+pub fn max_level() -> Level {
+    static LEVEL: OnceLock<Level> = OnceLock::new();
+
+    *LEVEL.get_or_init(|| {
+        std::env::var(LOG_ENV)
+            .ok()
+            .and_then(|value| Level::parse(&value))
+            .unwrap_or_default()
+    })
+}
+
+/// Whether lines at `level` are currently shown
+pub fn enabled(level: Level) -> bool {
+    level.enabled(max_level())
+}
+
+/// A log line as written to stderr
+pub fn format_line(level: Level, message: fmt::Arguments) -> String {
+    format!("[{}] {}", level.label(), message)
+}
+
+/// Write `message` to stderr if `level` is enabled; used by the macros
+pub fn log(level: Level, message: fmt::Arguments) {
+    if enabled(level) {
+        eprintln!("{}", format_line(level, message));
+    }
+}
+
+/// Log an error line
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+/// Log an info line
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
+
+/// Log a debug line
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_parse() {
+        assert_eq!(Level::parse("debug"), Some(Level::Debug));
+        assert_eq!(Level::parse(" INFO "), Some(Level::Info));
+        assert_eq!(Level::parse("error"), Some(Level::Error));
+        assert_eq!(Level::parse("off"), Some(Level::Off));
+        assert_eq!(Level::parse(""), Some(Level::Off));
+        assert_eq!(Level::parse("verbose"), None);
+    }
+
+    #[test]
+    fn test_info_suppresses_debug() {
+        assert!(Level::Error.enabled(Level::Info));
+        assert!(Level::Info.enabled(Level::Info));
+        assert!(!Level::Debug.enabled(Level::Info));
+        assert!(Level::Debug.enabled(Level::Debug));
+
+        for level in [Level::Error, Level::Info, Level::Debug] {
+            assert!(!level.enabled(Level::Off));
+        }
+        assert!(!Level::Off.enabled(Level::Debug));
+    }
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(
+            format_line(Level::Debug, format_args!("model at {}", "/tmp/m.gguf")),
+            "[debug] model at /tmp/m.gguf"
+        );
+    }
+}
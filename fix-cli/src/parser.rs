@@ -2,9 +2,11 @@
 //!
 //! This module handles parsing of model responses to detect:
 //! - Tool calls in `<tool_call>{...}</tool_call>` format
+//! - Tool calls as a fenced ```` ```json ```` block without tags
 //! - Final answers in `<answer>...</answer>` format
 //! - Raw text as final answers
 
+use crate::sampling::{default_thinking_tags, find_tag, ThinkingTags};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -20,11 +22,14 @@ pub enum ModelResponse {
     FinalAnswer(String),
 }
 
+/// Shell operators that make no sense dangling at the end of a command
+const TRAILING_OPERATORS: &[&str] = &["&&", "||", "|", ";", "\\"];
+
 /// Tool call structure for JSON deserialization
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct ToolCallJson {
     name: String,
-    #[serde(default)]
+    #[serde(default, alias = "arguments")]
     args: HashMap<String, serde_json::Value>,
 }
 
@@ -32,27 +37,59 @@ struct ToolCallJson {
 ///
 /// Looks for:
 /// 1. `<tool_call>{...}</tool_call>` - Tool request
-/// 2. `<answer>...</answer>` - Explicit final answer
-/// 3. Raw text - Treated as final answer
+/// 2. ```` ```json {...} ``` ```` with `name` and `args`/`arguments` - Tool request
+/// 3. `<answer>...</answer>` - Explicit final answer
+/// 4. Raw text - Treated as final answer
+///
+/// A tool call wins over an answer, unless a complete answer comes first:
+/// anything the model adds after finishing its answer is ignored.
 pub fn parse_response(output: &str) -> ModelResponse {
+    parse_response_with_tags(output, &default_thinking_tags())
+}
+
+/// Like `parse_response`, recognizing thinking blocks by `tags`
+pub fn parse_response_with_tags(output: &str, tags: &[ThinkingTags]) -> ModelResponse {
     let trimmed = output.trim();
 
+    if answer_precedes_tool_call(trimmed) {
+        if let Some(answer) = extract_answer(trimmed, tags) {
+            return ModelResponse::FinalAnswer(answer);
+        }
+    }
+
     // Try to extract tool call
     if let Some(tool_call) = extract_tool_call(trimmed) {
         return tool_call;
     }
 
     // Try to extract explicit answer
-    if let Some(answer) = extract_answer(trimmed) {
+    if let Some(answer) = extract_answer(trimmed, tags) {
         return ModelResponse::FinalAnswer(answer);
     }
 
     // Fallback: treat raw text as final answer
-    ModelResponse::FinalAnswer(clean_output(trimmed))
+    ModelResponse::FinalAnswer(clean_output_with_tags(trimmed, tags))
 }
 
-/// Extract tool call from `<tool_call>{...}</tool_call>` pattern
+/// Whether a closed `<answer>` ends before any tagged or fenced tool call starts
+fn answer_precedes_tool_call(output: &str) -> bool {
+    let Some(answer_end) = output.find("</answer>") else {
+        return false;
+    };
+    let tool_start = [output.find("<tool_call>"), output.find("```")]
+        .into_iter()
+        .flatten()
+        .min();
+    tool_start.is_none_or(|start| answer_end < start)
+}
+
+/// Extract a tool call, preferring tags over a fenced JSON block
 fn extract_tool_call(output: &str) -> Option<ModelResponse> {
+    extract_tagged_tool_call(output).or_else(|| extract_fenced_tool_call(output))
+}
+
+/// Extract tool call from `<tool_call>{...}</tool_call>` pattern
+fn extract_tagged_tool_call(output: &str) -> Option<ModelResponse> {
     // Find the tool_call tags
     let start_tag = "<tool_call>";
     let end_tag = "</tool_call>";
@@ -71,6 +108,43 @@ fn extract_tool_call(output: &str) -> Option<ModelResponse> {
     // Parse JSON
     let tool_call: ToolCallJson = serde_json::from_str(json_content).ok()?;
 
+    Some(tool_call_response(tool_call))
+}
+
+/// Extract tool call from a fenced ```` ```json ```` block without tags
+///
+/// Only objects with both a `name` and an `args`/`arguments` object count, so
+/// other JSON the model prints stays a final answer.
+fn extract_fenced_tool_call(output: &str) -> Option<ModelResponse> {
+    let fence = "```";
+
+    let open_idx = output.find(fence)?;
+    let after_open = &output[open_idx + fence.len()..];
+    // Skip the optional language tag on the opening fence line
+    let body_start = after_open.find('\n')? + 1;
+    let body = &after_open[body_start..];
+    let close_idx = body.find(fence)?;
+    let json_content = body[..close_idx].trim();
+
+    let value: serde_json::Value = serde_json::from_str(json_content).ok()?;
+    let object = value.as_object()?;
+    if !object.get("name")?.is_string() {
+        return None;
+    }
+    let has_args = ["args", "arguments"]
+        .iter()
+        .any(|key| object.get(*key).is_some_and(|v| v.is_object()));
+    if !has_args {
+        return None;
+    }
+
+    let tool_call: ToolCallJson = serde_json::from_value(value).ok()?;
+
+    Some(tool_call_response(tool_call))
+}
+
+/// Convert parsed tool call JSON into a response with string arguments
+fn tool_call_response(tool_call: ToolCallJson) -> ModelResponse {
     // Convert args to HashMap<String, String>
     let args: HashMap<String, String> = tool_call
         .args
@@ -84,14 +158,14 @@ fn extract_tool_call(output: &str) -> Option<ModelResponse> {
         })
         .collect();
 
-    Some(ModelResponse::ToolCall {
+    ModelResponse::ToolCall {
         name: tool_call.name,
         args,
-    })
+    }
 }
 
 /// Extract answer from `<answer>...</answer>` pattern
-fn extract_answer(output: &str) -> Option<String> {
+fn extract_answer(output: &str, tags: &[ThinkingTags]) -> Option<String> {
     let start_tag = "<answer>";
     let end_tag = "</answer>";
 
@@ -105,11 +179,18 @@ fn extract_answer(output: &str) -> Option<String> {
     let content_start = start_idx + start_tag.len();
     let answer = output[content_start..end_idx].trim();
 
-    Some(clean_output(answer))
+    Some(clean_output_with_tags(answer, tags))
 }
 
 /// Clean model output by removing common artifacts
+///
+/// Thinking blocks are recognized by `DEFAULT_THINKING_TAGS`.
 pub fn clean_output(output: &str) -> String {
+    clean_output_with_tags(output, &default_thinking_tags())
+}
+
+/// Like `clean_output`, recognizing thinking blocks by `tags`
+pub fn clean_output_with_tags(output: &str, tags: &[ThinkingTags]) -> String {
     let mut result = output.trim();
 
     // Remove common ChatML artifacts
@@ -126,11 +207,18 @@ pub fn clean_output(output: &str) -> String {
     }
 
     // Remove thinking blocks
-    if let Some(start) = result.find("<think>") {
-        if let Some(end) = result.find("</think>") {
+    if let Some((start, block)) = find_tag(result, tags, |tags| &tags.open) {
+        let content_start = start + tags[block].open.len();
+        if let Some(end) = result[content_start..].find(tags[block].close.as_str()) {
+            let end = content_start + end;
             let before = &result[..start];
-            let after = &result[end + "</think>".len()..];
+            let after = &result[end + tags[block].close.len()..];
             result = if after.trim().is_empty() {
+                if before.trim().is_empty() {
+                    let thinking = &result[content_start..end];
+                    return command_from_thinking(thinking, crate::command_in_path)
+                        .unwrap_or_default();
+                }
                 before.trim()
             } else {
                 // Return the part after thinking
@@ -157,8 +245,249 @@ pub fn clean_output(output: &str) -> String {
 
     // Take only first line if multi-line
     result = result.lines().next().unwrap_or(result).trim();
+    result = strip_prompt_marker(result);
+
+    strip_trailing_operators(result).to_string()
+}
+
+/// Shell prompt markers models copy in front of a command
+const PROMPT_MARKERS: &[&str] = &["PS> ", "$ ", "# ", "> "];
+
+/// Remove a leading shell prompt marker such as `$ ` or `PS> `
+///
+/// A marker only counts when followed by a space, so a command that starts
+/// with a variable (`$EDITOR notes.txt`) is left alone.
+pub fn strip_prompt_marker(command: &str) -> &str {
+    PROMPT_MARKERS
+        .iter()
+        .find_map(|marker| command.strip_prefix(marker))
+        .map_or(command, str::trim_start)
+}
+
+/// Longest line from a think block still treated as a command
+const MAX_THINKING_COMMAND_WORDS: usize = 12;
+
+/// Last-resort command extraction from the contents of a `<think>` block
+///
+/// Used when the model puts its answer inside the think block and emits
+/// nothing after it. Only the last non-empty line is considered: a backtick
+/// span wins, otherwise the text after the last `": "` counts only when its
+/// first word passes `command_exists`, so prose isn't taken for a command.
+/// A closing sentence period is dropped, but not dots that are an argument
+/// (`cd ..`). Questions and long lines are rejected.
+pub fn command_from_thinking<F>(thinking: &str, command_exists: F) -> Option<String>
+where
+    F: Fn(&str) -> bool,
+{
+    let line = thinking.lines().map(str::trim).rfind(|l| !l.is_empty())?;
+
+    let candidate = match backtick_span(line) {
+        Some(span) => span.trim(),
+        None => {
+            let tail = line.rsplit_once(": ").map_or(line, |(_, tail)| tail);
+            let candidate = without_sentence_period(tail.trim());
+            let first_word = candidate.split_whitespace().next()?;
+            if !command_exists(first_word) {
+                return None;
+            }
+            candidate
+        }
+    };
+
+    let candidate = strip_trailing_operators(candidate);
+    if candidate.is_empty()
+        || candidate.ends_with('?')
+        || candidate.split_whitespace().count() > MAX_THINKING_COMMAND_WORDS
+    {
+        return None;
+    }
+
+    Some(candidate.to_string())
+}
+
+/// Drop a period ending a sentence, keeping a last word made only of dots
+fn without_sentence_period(text: &str) -> &str {
+    let last_word = text.rsplit(char::is_whitespace).next().unwrap_or(text);
+    if last_word.trim_matches('.').is_empty() {
+        text
+    } else {
+        text.strip_suffix('.').unwrap_or(text)
+    }
+}
+
+/// The last `` `...` `` span on a line, if any
+fn backtick_span(line: &str) -> Option<&str> {
+    let end = line.rfind('`')?;
+    let start = line[..end].rfind('`')?;
+    Some(&line[start + 1..end])
+}
+
+/// The leading lines of `output` that make up its first command
+///
+/// A line ending in `\` continues onto the next one, and a heredoc runs
+/// until its delimiter line. Anything after that is dropped.
+pub fn command_lines(output: &str) -> &str {
+    let mut end = 0;
+    let mut heredoc: Option<String> = None;
+
+    for line in output.split_inclusive('\n') {
+        end += line.len();
+        let content = line.trim_end();
+
+        match heredoc.take() {
+            Some(delimiter) => {
+                if content.trim() != delimiter {
+                    heredoc = Some(delimiter);
+                    continue;
+                }
+            }
+            None => {
+                heredoc = heredoc_delimiter(content);
+                if heredoc.is_some() {
+                    continue;
+                }
+            }
+        }
+
+        if !content.ends_with('\\') {
+            break;
+        }
+    }
+
+    output[..end].trim()
+}
+
+/// Most lines `join_command_lines` chains into one command
+pub const MAX_SEQUENCE_LINES: usize = 4;
+
+/// Join the commands on the leading lines of `output` into one `&&` chain
+///
+/// Used when `--multiline` isn't set, so a fix that takes two steps
+/// (`mkdir build` then `cd build`) survives as `mkdir build && cd build`.
+/// Lines ending in `\`, `|`, `&&` or `||` continue onto the next one as the
+/// shell would read them. Joining stops at a blank line, at a line of prose
+/// (`This creates the directory.`) or after `MAX_SEQUENCE_LINES` lines.
+///
+/// Every command after the first must start with a word `command_exists`
+/// accepts; otherwise the extra lines may be an explanation, and only the
+/// first command is kept.
+pub fn join_command_lines<F>(output: &str, command_exists: F) -> String
+where
+    F: Fn(&str) -> bool,
+{
+    let mut commands: Vec<String> = Vec::new();
+    let mut continued = false;
+
+    for line in sequence_lines(output) {
+        let line = strip_prompt_marker(line.trim());
+        let joined = match commands.last_mut() {
+            Some(last) if continued => {
+                last.push(' ');
+                last.push_str(line);
+                last
+            }
+            Some(_) if !starts_with_command(line, &command_exists) => {
+                commands.truncate(1);
+                break;
+            }
+            _ => {
+                commands.push(line.to_string());
+                commands.last_mut().unwrap()
+            }
+        };
+
+        continued = ["\\", "|", "&&", "||"]
+            .iter()
+            .any(|op| joined.ends_with(op) && !joined.ends_with("\\;"));
+        if let Some(stripped) = joined.strip_suffix('\\') {
+            *joined = stripped.trim_end().to_string();
+        }
+    }
+
+    commands
+        .iter()
+        .map(|command| strip_trailing_operators(command))
+        .filter(|command| !command.is_empty())
+        .collect::<Vec<_>>()
+        .join(" && ")
+}
+
+/// Whether the first word of `line`, after any `NAME=value` assignments, is a command
+fn starts_with_command<F: Fn(&str) -> bool>(line: &str, command_exists: F) -> bool {
+    let (_, command) = crate::split_env_assignments(line);
+    let (word, _) = crate::split_command_args(command);
+    !word.is_empty() && command_exists(word)
+}
+
+/// Whether generation can stop because `join_command_lines` won't use more
+///
+/// True once `output` has a blank line after its first content, a finished
+/// line of prose, or more than `MAX_SEQUENCE_LINES` lines.
+pub fn sequence_complete(output: &str) -> bool {
+    let output = output.trim_start();
+    let finished_lines = output.matches('\n').count();
+    finished_lines > 0
+        && sequence_lines(output).count() < finished_lines.min(MAX_SEQUENCE_LINES + 1)
+}
+
+/// The lines `join_command_lines` considers: up to the first blank or prose line
+fn sequence_lines(output: &str) -> impl Iterator<Item = &str> {
+    output
+        .trim_start()
+        .lines()
+        .take_while(|line| !line.trim().is_empty() && !looks_like_prose(line.trim()))
+        .take(MAX_SEQUENCE_LINES)
+}
 
-    result.to_string()
+/// A sentence rather than a command: capitalized, several words, ending in `.` or `:`
+fn looks_like_prose(line: &str) -> bool {
+    line.starts_with(|c: char| c.is_ascii_uppercase())
+        && (line.ends_with('.') || line.ends_with(':'))
+        && line.split_whitespace().count() >= 3
+}
+
+/// The delimiter word of a heredoc started on `line` (`<<EOF`, `<<-'EOF'`)
+fn heredoc_delimiter(line: &str) -> Option<String> {
+    let start = line.find("<<")?;
+    let rest = &line[start + 2..];
+    // `<<<` is a here-string, which stays on one line
+    if rest.starts_with('<') {
+        return None;
+    }
+
+    let word = rest
+        .trim_start_matches('-')
+        .split_whitespace()
+        .next()?
+        .trim_matches(|c| c == '\'' || c == '"');
+    if word.is_empty() {
+        None
+    } else {
+        Some(word.to_string())
+    }
+}
+
+/// Remove dangling `&&`, `||`, `|`, `;` or `\` from the end of a command
+///
+/// Operators in the middle of a pipeline are left alone, as is the escaped
+/// `\;` terminating `find -exec`.
+fn strip_trailing_operators(command: &str) -> &str {
+    let mut result = command.trim_end();
+
+    'strip: loop {
+        if result.ends_with("\\;") {
+            break;
+        }
+        for op in TRAILING_OPERATORS {
+            if let Some(stripped) = result.strip_suffix(op) {
+                result = stripped.trim_end();
+                continue 'strip;
+            }
+        }
+        break;
+    }
+
+    result
 }
 
 // ========== Tests ==========
@@ -245,6 +574,57 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_fenced_json_tool_call() {
+        let output =
+            "```json\n{\"name\": \"which_binary\", \"arguments\": {\"command\": \"git\"}}\n```";
+        let result = parse_response(output);
+
+        match result {
+            ModelResponse::ToolCall { name, args } => {
+                assert_eq!(name, "which_binary");
+                assert_eq!(args.get("command"), Some(&"git".to_string()));
+            }
+            _ => panic!("Expected ToolCall, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_fenced_json_tool_call_with_args_key() {
+        let output = "```\n{\"name\": \"list_similar\", \"args\": {\"prefix\": \"gi\"}}\n```";
+        let result = parse_response(output);
+
+        match result {
+            ModelResponse::ToolCall { name, args } => {
+                assert_eq!(name, "list_similar");
+                assert_eq!(args.get("prefix"), Some(&"gi".to_string()));
+            }
+            _ => panic!("Expected ToolCall, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_fenced_json_without_name_is_answer() {
+        let output = "```json\n{\"command\": \"git status\"}\n```";
+        let result = parse_response(output);
+
+        match result {
+            ModelResponse::FinalAnswer(_) => {}
+            _ => panic!("Expected FinalAnswer for non-tool JSON, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_tagged_tool_call_preferred_over_fenced() {
+        let output = "<tool_call>{\"name\": \"man_page\", \"args\": {\"command\": \"ls\"}}</tool_call>\n```json\n{\"name\": \"which_binary\", \"args\": {\"command\": \"git\"}}\n```";
+        let result = parse_response(output);
+
+        match result {
+            ModelResponse::ToolCall { name, .. } => assert_eq!(name, "man_page"),
+            _ => panic!("Expected ToolCall, got {:?}", result),
+        }
+    }
+
     // ===== Answer Extraction Tests =====
 
     #[test]
@@ -278,6 +658,115 @@ mod tests {
         );
     }
 
+    // ===== Multi-line Command Tests =====
+
+    #[test]
+    fn test_command_lines_single_line() {
+        assert_eq!(command_lines("npm install\nnpm start"), "npm install");
+    }
+
+    #[test]
+    fn test_command_lines_backslash_continuation() {
+        let output = "docker run \\\n  -it ubuntu \\\n  bash\nThis runs a shell.";
+
+        assert_eq!(
+            command_lines(output),
+            "docker run \\\n  -it ubuntu \\\n  bash"
+        );
+    }
+
+    #[test]
+    fn test_command_lines_heredoc() {
+        let output = "cat <<'EOF' > notes.txt\nhello\nworld\nEOF\nDone.";
+
+        assert_eq!(
+            command_lines(output),
+            "cat <<'EOF' > notes.txt\nhello\nworld\nEOF"
+        );
+    }
+
+    #[test]
+    fn test_command_lines_here_string_is_one_line() {
+        assert_eq!(
+            command_lines("grep foo <<< \"$x\"\nextra"),
+            "grep foo <<< \"$x\""
+        );
+    }
+
+    #[test]
+    fn test_join_command_lines_two_commands() {
+        assert_eq!(
+            join_command_lines("mkdir build\ncd build", known),
+            "mkdir build && cd build"
+        );
+        assert_eq!(
+            join_command_lines("$ mkdir build;\n$ cd build\n", known),
+            "mkdir build && cd build"
+        );
+        assert_eq!(join_command_lines("git status", known), "git status");
+    }
+
+    #[test]
+    fn test_join_command_lines_continuations() {
+        assert_eq!(
+            join_command_lines("docker run \\\n  -it ubuntu", known),
+            "docker run -it ubuntu"
+        );
+        assert_eq!(
+            join_command_lines("ls |\ngrep foo\nmake &&\nmake install", known),
+            "ls | grep foo && make && make install"
+        );
+        assert_eq!(
+            join_command_lines("find . -exec rm {} \\;\nls", known),
+            "find . -exec rm {} \\; && ls"
+        );
+    }
+
+    #[test]
+    fn test_join_command_lines_stops_at_prose() {
+        assert_eq!(
+            join_command_lines("mkdir build\ncd build\n\nThen run cmake.", known),
+            "mkdir build && cd build"
+        );
+        assert_eq!(
+            join_command_lines("git status\nThis fixes the typo in git.", known),
+            "git status"
+        );
+        assert_eq!(
+            join_command_lines("ls a\nls b\nls c\nls d\nls e", known),
+            "ls a && ls b && ls c && ls d"
+        );
+    }
+
+    #[test]
+    fn test_join_command_lines_keeps_first_line_before_lowercase_prose() {
+        assert_eq!(
+            join_command_lines("git status\nthis fixes the typo", known),
+            "git status"
+        );
+        assert_eq!(join_command_lines("git status\nDone.", known), "git status");
+        // A later line that isn't a command drops every extra line
+        assert_eq!(
+            join_command_lines("mkdir build\ncd build\nnow build it", known),
+            "mkdir build"
+        );
+        // Assignments before the command word are allowed
+        assert_eq!(
+            join_command_lines("cd app\nFOO=1 make", known),
+            "cd app && FOO=1 make"
+        );
+    }
+
+    #[test]
+    fn test_sequence_complete() {
+        assert!(!sequence_complete("mkdir build"));
+        assert!(!sequence_complete("mkdir build\ncd bu"));
+        assert!(!sequence_complete("mkdir build\ncd build\n"));
+        assert!(sequence_complete("mkdir build\n\n"));
+        assert!(sequence_complete("git status\nThis fixes the typo.\n"));
+        assert!(sequence_complete("a\nb\nc\nd\ne\n"));
+    }
+
     // ===== Raw Text as Answer Tests =====
 
     #[test]
@@ -331,12 +820,168 @@ mod tests {
         assert_eq!(clean_output(">>> npm install"), "npm install".to_string());
     }
 
+    #[test]
+    fn test_clean_output_prompt_markers() {
+        assert_eq!(clean_output("$ git status"), "git status".to_string());
+        assert_eq!(clean_output("PS> Get-Process"), "Get-Process".to_string());
+        assert_eq!(clean_output("# apt update"), "apt update".to_string());
+        assert_eq!(clean_output("> ls -la"), "ls -la".to_string());
+    }
+
+    #[test]
+    fn test_clean_output_keeps_leading_variable() {
+        assert_eq!(
+            clean_output("$EDITOR notes.txt"),
+            "$EDITOR notes.txt".to_string()
+        );
+        assert_eq!(strip_prompt_marker("$HOME/bin/tool"), "$HOME/bin/tool");
+        assert_eq!(strip_prompt_marker("git status"), "git status");
+    }
+
     #[test]
     fn test_clean_output_thinking_block() {
         let output = "<think>Let me think about this...</think>git status";
         assert_eq!(clean_output(output), "git status".to_string());
     }
 
+    #[test]
+    fn test_clean_output_reasoning_block() {
+        let output = "<reasoning>gti looks like git</reasoning>\ngit status";
+        assert_eq!(clean_output(output), "git status".to_string());
+    }
+
+    #[test]
+    fn test_clean_output_with_configured_tags() {
+        let tags = [ThinkingTags::new("[plan]", "[/plan]")];
+        assert_eq!(
+            clean_output_with_tags("[plan]typo in git[/plan] git status", &tags),
+            "git status".to_string()
+        );
+        // Only the configured pairs count
+        assert_eq!(
+            clean_output_with_tags("<think>x</think>ls", &tags),
+            "<think>x</think>ls".to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_response_with_configured_tags() {
+        let tags = [ThinkingTags::new("[plan]", "[/plan]")];
+        assert_eq!(
+            parse_response_with_tags("[plan]typo in git[/plan] git status", &tags),
+            ModelResponse::FinalAnswer("git status".to_string())
+        );
+        assert_eq!(
+            parse_response_with_tags("<answer>[plan]x[/plan] ls -la</answer>", &tags),
+            ModelResponse::FinalAnswer("ls -la".to_string())
+        );
+    }
+
+    #[test]
+    fn test_clean_output_answer_inside_thinking() {
+        let output = "<think>The user typo'd git.\nThe command is `git status`</think>";
+        assert_eq!(clean_output(output), "git status".to_string());
+    }
+
+    /// Commands the thinking tests treat as installed
+    fn known(command: &str) -> bool {
+        ["cd", "docker", "git", "grep", "ls", "make", "mkdir"].contains(&command)
+    }
+
+    #[test]
+    fn test_command_from_thinking_backticks() {
+        let thinking = "User typed gti.\nSo the answer is `git status`.\n";
+        assert_eq!(
+            command_from_thinking(thinking, known),
+            Some("git status".to_string())
+        );
+        // Backticks are trusted without checking the command
+        assert_eq!(
+            command_from_thinking("Use `kubectl get pods`", known),
+            Some("kubectl get pods".to_string())
+        );
+    }
+
+    #[test]
+    fn test_command_from_thinking_after_colon() {
+        assert_eq!(
+            command_from_thinking("Corrected command: docker ps -a.", known),
+            Some("docker ps -a".to_string())
+        );
+        assert_eq!(
+            command_from_thinking("ls -la", known),
+            Some("ls -la".to_string())
+        );
+    }
+
+    #[test]
+    fn test_command_from_thinking_keeps_dot_arguments() {
+        assert_eq!(
+            command_from_thinking("The fix: cd ..", known),
+            Some("cd ..".to_string())
+        );
+        assert_eq!(
+            command_from_thinking("ls .", known),
+            Some("ls .".to_string())
+        );
+        assert_eq!(
+            command_from_thinking("`cd ..`", known),
+            Some("cd ..".to_string())
+        );
+    }
+
+    #[test]
+    fn test_command_from_thinking_rejects_prose() {
+        assert_eq!(command_from_thinking("", known), None);
+        assert_eq!(command_from_thinking("  \n \n", known), None);
+        assert_eq!(command_from_thinking("Is this git or gitk?", known), None);
+        assert_eq!(
+            command_from_thinking(
+                "I need to think more carefully about what the user actually wanted to run here",
+                known
+            ),
+            None
+        );
+        // Short prose whose first word isn't a command
+        assert_eq!(command_from_thinking("Seems fine.", known), None);
+        assert_eq!(command_from_thinking("So: probably a typo", known), None);
+    }
+
+    #[test]
+    fn test_clean_output_trailing_and() {
+        assert_eq!(clean_output("git status &&"), "git status".to_string());
+        assert_eq!(clean_output("git status ||"), "git status".to_string());
+        assert_eq!(clean_output("git status ;"), "git status".to_string());
+    }
+
+    #[test]
+    fn test_clean_output_preserves_mid_command_pipe() {
+        assert_eq!(
+            clean_output("ps aux | grep node"),
+            "ps aux | grep node".to_string()
+        );
+        assert_eq!(
+            clean_output("ps aux | grep node |"),
+            "ps aux | grep node".to_string()
+        );
+    }
+
+    #[test]
+    fn test_clean_output_trailing_backslash() {
+        assert_eq!(
+            clean_output("docker run -it ubuntu \\\nbash"),
+            "docker run -it ubuntu".to_string()
+        );
+    }
+
+    #[test]
+    fn test_clean_output_preserves_escaped_semicolon() {
+        assert_eq!(
+            clean_output("find . -name '*.tmp' -exec rm {} \\;"),
+            "find . -name '*.tmp' -exec rm {} \\;".to_string()
+        );
+    }
+
     #[test]
     fn test_clean_output_empty() {
         assert_eq!(clean_output(""), "".to_string());
@@ -405,4 +1050,43 @@ mod tests {
             _ => panic!("Expected ToolCall to take priority, got {:?}", result),
         }
     }
+
+    #[test]
+    fn test_parse_answer_before_tool_call_is_final() {
+        let output = r#"<answer>git status</answer>
+<tool_call>{"name": "which_binary", "args": {"command": "git"}}</tool_call>"#;
+        assert_eq!(
+            parse_response(output),
+            ModelResponse::FinalAnswer("git status".to_string())
+        );
+
+        let fenced = "<answer>git status</answer>\n```json\n{\"name\": \"which_binary\", \"args\": {\"command\": \"git\"}}\n```";
+        assert_eq!(
+            parse_response(fenced),
+            ModelResponse::FinalAnswer("git status".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_call_before_answer_wins() {
+        let output = r#"<tool_call>{"name": "which_binary", "args": {"command": "git"}}</tool_call>
+<answer>git status</answer>"#;
+        match parse_response(output) {
+            ModelResponse::ToolCall { name, args } => {
+                assert_eq!(name, "which_binary");
+                assert_eq!(args.get("command"), Some(&"git".to_string()));
+            }
+            other => panic!("Expected ToolCall, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_unclosed_answer_before_tool_call() {
+        // Not a well-formed answer, so the tool call still wins
+        let output = r#"<answer>git status <tool_call>{"name": "which_binary", "args": {"command": "git"}}</tool_call>"#;
+        assert!(matches!(
+            parse_response(output),
+            ModelResponse::ToolCall { .. }
+        ));
+    }
 }
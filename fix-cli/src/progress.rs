@@ -8,6 +8,33 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// When to color progress output, set with `--color`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ColorMode {
+    /// Color when the run is interactive
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// The choice the flag forces, or None to follow the terminal
+    pub fn explicit(self) -> Option<bool> {
+        match self {
+            ColorMode::Auto => None,
+            ColorMode::Always => Some(true),
+            ColorMode::Never => Some(false),
+        }
+    }
+}
+
+/// Turn colored spinners and progress bars on or off for the rest of the run
+pub fn set_color(enabled: bool) {
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
 /// A progress spinner that can be shown or hidden based on operation duration
 pub struct ProgressSpinner {
     pb: Option<ProgressBar>,
@@ -87,6 +114,24 @@ impl ProgressSpinner {
     }
 }
 
+/// A progress bar counting scanned items toward a total, hidden when `quiet`
+///
+/// The length starts at zero; set it with `set_length` once the total is known.
+pub fn count_bar(quiet: bool, msg: &str) -> ProgressBar {
+    if quiet {
+        return ProgressBar::hidden();
+    }
+    let pb = ProgressBar::new(0);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg} [{bar:40.cyan/blue}] {pos}/{len}")
+            .expect("Invalid progress bar template format")
+            .progress_chars("=>-"),
+    );
+    pb.set_message(msg.to_string());
+    pb
+}
+
 impl Drop for ProgressSpinner {
     fn drop(&mut self) {
         if let Some(ref pb) = self.pb {
@@ -100,6 +145,14 @@ mod tests {
     use super::*;
     use std::thread;
 
+    #[test]
+    fn test_color_mode_explicit() {
+        assert_eq!(ColorMode::default(), ColorMode::Auto);
+        assert_eq!(ColorMode::Auto.explicit(), None);
+        assert_eq!(ColorMode::Always.explicit(), Some(true));
+        assert_eq!(ColorMode::Never.explicit(), Some(false));
+    }
+
     #[test]
     fn test_spinner_created_not_quiet() {
         let spinner = ProgressSpinner::new(false);
@@ -0,0 +1,101 @@
+//! Remembering corrections for prompts the fix daemon has already answered
+//!
+//! Re-running the same failed command is common, and decoding the prompt
+//! again gives the same correction. `PromptCache` maps each prompt to its
+//! correction and keeps only the most recently used entries. Tool results
+//! are cached separately, in `tools`.
+
+use std::collections::VecDeque;
+
+/// Default number of corrections the daemon remembers
+pub const DEFAULT_PROMPT_CACHE_SIZE: usize = 64;
+
+/// Least-recently-used map from prompt to correction
+#[derive(Debug, Clone)]
+pub struct PromptCache<V> {
+    capacity: usize,
+    /// Most recently used first
+    entries: VecDeque<(String, V)>,
+}
+
+impl<V> PromptCache<V> {
+    /// Cache holding at most `capacity` entries; zero disables it
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Look up `prompt`, marking it as the most recently used
+    pub fn get(&mut self, prompt: &str) -> Option<&V> {
+        let position = self.entries.iter().position(|(key, _)| key == prompt)?;
+        let entry = self.entries.remove(position)?;
+        self.entries.push_front(entry);
+        self.entries.front().map(|(_, value)| value)
+    }
+
+    /// Remember `value` for `prompt`, evicting the least recently used entry when full
+    pub fn insert(&mut self, prompt: String, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.retain(|(key, _)| *key != prompt);
+        self.entries.push_front((prompt, value));
+        self.entries.truncate(self.capacity);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_hit() {
+        let mut cache = PromptCache::new(2);
+        assert_eq!(cache.get("gti status"), None);
+
+        cache.insert("gti status".to_string(), "git status".to_string());
+        assert_eq!(cache.get("gti status"), Some(&"git status".to_string()));
+        assert_eq!(cache.len(), 1);
+
+        // Inserting the same prompt again replaces the entry
+        cache.insert("gti status".to_string(), "git stash".to_string());
+        assert_eq!(cache.get("gti status"), Some(&"git stash".to_string()));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = PromptCache::new(2);
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+
+        // Using "a" makes "b" the oldest
+        assert_eq!(cache.get("a"), Some(&1));
+        cache.insert("c".to_string(), 3);
+
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(&1));
+        assert_eq!(cache.get("c"), Some(&3));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_zero_capacity_disables_cache() {
+        let mut cache = PromptCache::new(0);
+        cache.insert("a".to_string(), 1);
+        assert!(cache.is_empty());
+        assert_eq!(cache.get("a"), None);
+    }
+}
@@ -0,0 +1,243 @@
+//! Safety filter for generated corrections
+//!
+//! A correction should never turn a harmless command into a destructive one.
+//! This module checks the model's output against a denylist of patterns and
+//! flags any pattern the user's input didn't already contain.
+//!
+//! Inputs can be screened too: `Config::input_denylist` holds regular
+//! expressions for commands that must never reach the model at all, such as
+//! ones carrying an inline password.
+
+use regex::Regex;
+
+/// Destructive command patterns blocked by default
+///
+/// Each pattern is a sequence of words that must appear in order. Commands,
+/// the first word and any word after `|`, must match exactly (`mkfs` also
+/// matches `mkfs.ext4`, but `rm` doesn't match `rmdir`); other words match
+/// any output word that starts with them, so `dd if=` matches `dd if=/dev/zero`.
+pub const DEFAULT_DESTRUCTIVE_PATTERNS: &[&str] = &[
+    "rm -rf",
+    "rm -fr",
+    "rm -r",
+    "mkfs",
+    "dd if=",
+    "chmod -R 777",
+    "curl | sh",
+    "curl | bash",
+    "wget | sh",
+    "wget | bash",
+    ":(){",
+];
+
+/// Default value for `Config::destructive_patterns`
+pub fn default_patterns() -> Vec<String> {
+    DEFAULT_DESTRUCTIVE_PATTERNS
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// Find a destructive pattern the correction introduced
+///
+/// A pattern counts as introduced when it matches `output` but not `input`,
+/// so `rm foo` corrected to `rm -rf /` is caught even though `rm` was typed.
+/// Returns the first such pattern.
+pub fn introduced_destructive_pattern<'a>(
+    input: &str,
+    output: &str,
+    patterns: &'a [String],
+) -> Option<&'a str> {
+    let input_words: Vec<&str> = input.split_whitespace().collect();
+    let output_words: Vec<&str> = output.split_whitespace().collect();
+
+    patterns.iter().map(String::as_str).find(|pattern| {
+        matches_pattern(&output_words, pattern) && !matches_pattern(&input_words, pattern)
+    })
+}
+
+/// Check whether the pattern's words appear in order among `words`
+fn matches_pattern(words: &[&str], pattern: &str) -> bool {
+    let mut words = words.iter();
+    let mut command_position = true;
+    pattern.split_whitespace().all(|part| {
+        let is_command = command_position;
+        command_position = part == "|";
+        words.any(|word| {
+            if is_command {
+                is_command_word(word, part)
+            } else {
+                word.starts_with(part)
+            }
+        })
+    })
+}
+
+/// Check whether `word` runs `command`, by path or with a `.variant` suffix
+fn is_command_word(word: &str, command: &str) -> bool {
+    let name = word.rsplit('/').next().unwrap_or(word);
+    name == command
+        || name
+            .strip_prefix(command)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Find the first denylist pattern matching `input`
+///
+/// Patterns are regular expressions. An invalid one is an error rather than
+/// being skipped, so a typo in the config can't quietly turn the check off.
+pub fn denied_input<'a>(input: &str, patterns: &'a [String]) -> Result<Option<&'a str>, String> {
+    for pattern in patterns {
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("Invalid input_denylist pattern '{}': {}", pattern, e))?;
+        if regex.is_match(input) {
+            return Ok(Some(pattern));
+        }
+    }
+    Ok(None)
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denied_input() {
+        let patterns = vec![r"--password\b".to_string(), r"(?i)token=\S+".to_string()];
+
+        assert_eq!(
+            denied_input("mysql -u root --password hunter2", &patterns),
+            Ok(Some(r"--password\b"))
+        );
+        assert_eq!(
+            denied_input("curl example.com?TOKEN=abc", &patterns),
+            Ok(Some(r"(?i)token=\S+"))
+        );
+        assert_eq!(denied_input("mysql -u root -p", &patterns), Ok(None));
+        assert_eq!(denied_input("anything", &[]), Ok(None));
+    }
+
+    #[test]
+    fn test_invalid_denylist_pattern_is_an_error() {
+        let patterns = vec!["(unclosed".to_string()];
+        let err = denied_input("ls", &patterns).unwrap_err();
+        assert!(err.contains("(unclosed"));
+    }
+
+    #[test]
+    fn test_blocks_introduced_rm() {
+        let patterns = default_patterns();
+
+        assert_eq!(
+            introduced_destructive_pattern("list files", "rm -rf /", &patterns),
+            Some("rm -rf")
+        );
+    }
+
+    #[test]
+    fn test_allows_rm_already_in_input() {
+        let patterns = default_patterns();
+
+        assert_eq!(
+            introduced_destructive_pattern("rm -rf buidl", "rm -rf build", &patterns),
+            None
+        );
+        assert_eq!(
+            introduced_destructive_pattern("rm -r tmp", "rm -r tmp/", &patterns),
+            None
+        );
+    }
+
+    #[test]
+    fn test_blocks_escalation_of_typed_command() {
+        let patterns = default_patterns();
+
+        assert_eq!(
+            introduced_destructive_pattern("rm foo", "rm -rf /", &patterns),
+            Some("rm -rf")
+        );
+        assert_eq!(
+            introduced_destructive_pattern("curl x", "curl x | sh", &patterns),
+            Some("curl | sh")
+        );
+        assert_eq!(
+            introduced_destructive_pattern("chmod 644 f", "chmod -R 777 /", &patterns),
+            Some("chmod -R 777")
+        );
+        // Adding force to a recursive delete is an escalation too
+        assert_eq!(
+            introduced_destructive_pattern("rm -r tmp", "rm -rf tmp", &patterns),
+            Some("rm -rf")
+        );
+    }
+
+    #[test]
+    fn test_commands_match_whole_words() {
+        let patterns = default_patterns();
+
+        assert_eq!(
+            introduced_destructive_pattern("rmdri -r build", "rmdir -r build", &patterns),
+            None
+        );
+        assert_eq!(
+            introduced_destructive_pattern("curl x | shasm", "curl x | shasum", &patterns),
+            None
+        );
+        assert_eq!(
+            introduced_destructive_pattern("mkfs", "mkfs.ext4 /dev/sdb1", &patterns),
+            None
+        );
+        assert_eq!(
+            introduced_destructive_pattern("format disk", "mkfs.ext4 /dev/sdb1", &patterns),
+            Some("mkfs")
+        );
+        assert_eq!(
+            introduced_destructive_pattern("delete all", "/bin/rm -rf /", &patterns),
+            Some("rm -rf")
+        );
+    }
+
+    #[test]
+    fn test_blocks_pipe_to_shell() {
+        let patterns = default_patterns();
+
+        assert_eq!(
+            introduced_destructive_pattern(
+                "instal.sh",
+                "curl https://example.com/install.sh | sh",
+                &patterns
+            ),
+            Some("curl | sh")
+        );
+    }
+
+    #[test]
+    fn test_allows_benign_correction() {
+        let patterns = default_patterns();
+
+        assert_eq!(
+            introduced_destructive_pattern("gti status", "git status", &patterns),
+            None
+        );
+        assert_eq!(
+            introduced_destructive_pattern("curl example.com", "curl example.com", &patterns),
+            None
+        );
+    }
+
+    #[test]
+    fn test_custom_patterns() {
+        let patterns = vec!["git push --force".to_string()];
+
+        assert_eq!(
+            introduced_destructive_pattern("gti psuh", "git push --force", &patterns),
+            Some("git push --force")
+        );
+        assert_eq!(
+            introduced_destructive_pattern("gti psuh", "git push", &patterns),
+            None
+        );
+    }
+}
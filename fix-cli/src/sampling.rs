@@ -0,0 +1,666 @@
+//! Sampling presets for token generation
+//!
+//! Users pick a named preset with `--sampling` instead of hand-tuning
+//! temperature and top-p. Each preset resolves to concrete sampler
+//! parameters in `SamplingPreset::params`.
+//!
+//! It also drives the generation loop, scores how confident the model
+//! was in what it generated, and ranks alternative corrections.
+
+use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::data::LlamaTokenData;
+use llama_cpp_2::token::LlamaToken;
+use serde::{Deserialize, Serialize};
+
+/// Seed value that tells llama.cpp to pick a random seed (`LLAMA_DEFAULT_SEED`)
+const RANDOM_SEED: u32 = u32::MAX;
+
+/// Named sampling preset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplingPreset {
+    /// Always pick the most likely token (deterministic)
+    #[default]
+    Greedy,
+    /// The model's recommended defaults for non-thinking output
+    Balanced,
+    /// Higher temperature and a wider candidate pool
+    Creative,
+}
+
+/// Concrete sampler parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerParams {
+    /// Softmax temperature; 0 means greedy
+    pub temperature: f32,
+    /// Nucleus sampling cutoff
+    pub top_p: f32,
+    /// Number of candidates kept before top-p; 0 keeps all
+    pub top_k: i32,
+}
+
+impl SamplingPreset {
+    /// Resolve the preset into sampler parameters
+    pub fn params(self) -> SamplerParams {
+        match self {
+            SamplingPreset::Greedy => SamplerParams {
+                temperature: 0.0,
+                top_p: 1.0,
+                top_k: 0,
+            },
+            SamplingPreset::Balanced => SamplerParams {
+                temperature: 0.7,
+                top_p: 0.8,
+                top_k: 20,
+            },
+            SamplingPreset::Creative => SamplerParams {
+                temperature: 1.0,
+                top_p: 0.95,
+                top_k: 40,
+            },
+        }
+    }
+
+    /// Preset for extra suggestions: greedy would repeat the same output
+    pub fn for_alternatives(self) -> SamplingPreset {
+        match self {
+            SamplingPreset::Greedy => SamplingPreset::Balanced,
+            preset => preset,
+        }
+    }
+
+    /// Presets for `count` suggestions: this one first, then alternatives
+    pub fn for_suggestions(self, count: usize) -> Vec<SamplingPreset> {
+        (0..count)
+            .map(|i| {
+                if i == 0 {
+                    self
+                } else {
+                    self.for_alternatives()
+                }
+            })
+            .collect()
+    }
+
+    /// Build a llama.cpp sampler for this preset
+    pub fn sampler(self) -> LlamaSampler {
+        let params = self.params();
+        if params.temperature <= 0.0 {
+            return LlamaSampler::greedy();
+        }
+
+        LlamaSampler::chain_simple([
+            LlamaSampler::top_k(params.top_k),
+            LlamaSampler::top_p(params.top_p, 1),
+            LlamaSampler::temp(params.temperature),
+            LlamaSampler::dist(RANDOM_SEED),
+        ])
+    }
+}
+
+/// Outcome of one generation step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The token was accepted and decoded; keep generating
+    Continue,
+    /// Generation is finished (end of sequence, stop marker, or enough output)
+    Stop,
+}
+
+/// Why a generation loop ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A step returned `Step::Stop`
+    Finished,
+    /// Every step continued until `max_tokens` ran out
+    MaxTokens,
+}
+
+/// Result of a generation loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Generation {
+    /// Number of tokens decoded, which `--verbose` reports
+    pub decoded: usize,
+    /// Why generation ended
+    pub stop: StopReason,
+}
+
+/// Run generation steps until one returns `Step::Stop` or `max_tokens` is hit
+///
+/// `step` receives the index of the token being generated and samples,
+/// handles and decodes it.
+pub fn generate<F>(max_tokens: usize, mut step: F) -> Result<Generation, String>
+where
+    F: FnMut(usize) -> Result<Step, String>,
+{
+    let mut decoded = 0;
+    for i in 0..max_tokens {
+        if step(i)? == Step::Stop {
+            return Ok(Generation {
+                decoded,
+                stop: StopReason::Finished,
+            });
+        }
+        decoded += 1;
+    }
+    Ok(Generation {
+        decoded,
+        stop: StopReason::MaxTokens,
+    })
+}
+
+/// Whether `output` was likely cut off mid-command
+///
+/// Only output that ran into `max_tokens` counts; output that ends on a
+/// newline had finished its last line anyway.
+pub fn is_truncated(stop: StopReason, output: &str) -> bool {
+    stop == StopReason::MaxTokens && !output.ends_with('\n')
+}
+
+/// Run `attempt` with `max_tokens`, retrying once with `retry_max_tokens` if truncated
+///
+/// `attempt` returns its result along with whether it was truncated (see
+/// `is_truncated`). The retry's result is used even if it is truncated too.
+pub fn retry_if_truncated<T, F>(
+    max_tokens: usize,
+    retry_max_tokens: usize,
+    mut attempt: F,
+) -> Result<T, String>
+where
+    F: FnMut(usize) -> Result<(T, bool), String>,
+{
+    let (result, truncated) = attempt(max_tokens)?;
+    if !truncated || retry_max_tokens <= max_tokens {
+        return Ok(result);
+    }
+    attempt(retry_max_tokens).map(|(result, _)| result)
+}
+
+/// Tag pairs models wrap their reasoning in: Qwen3's `<think>` and the
+/// names other fine-tunes use
+pub const DEFAULT_THINKING_TAGS: &[(&str, &str)] = &[
+    ("<think>", "</think>"),
+    ("<reasoning>", "</reasoning>"),
+    ("<scratchpad>", "</scratchpad>"),
+];
+
+/// Opening and closing tag around a block of model reasoning
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThinkingTags {
+    pub open: String,
+    pub close: String,
+}
+
+impl ThinkingTags {
+    pub fn new(open: &str, close: &str) -> Self {
+        Self {
+            open: open.to_string(),
+            close: close.to_string(),
+        }
+    }
+}
+
+/// Default value for `Config::thinking_tags`
+pub fn default_thinking_tags() -> Vec<ThinkingTags> {
+    DEFAULT_THINKING_TAGS
+        .iter()
+        .map(|(open, close)| ThinkingTags::new(open, close))
+        .collect()
+}
+
+/// Earliest tag in `text` picked from `tags` by `tag`, as (position, index into `tags`)
+///
+/// Empty tags never match.
+pub fn find_tag(
+    text: &str,
+    tags: &[ThinkingTags],
+    tag: fn(&ThinkingTags) -> &str,
+) -> Option<(usize, usize)> {
+    tags.iter()
+        .enumerate()
+        .filter(|(_, tags)| !tag(tags).is_empty())
+        .filter_map(|(i, tags)| Some((text.find(tag(tags))?, i)))
+        .min()
+}
+
+/// Sorts generated pieces into the model's reasoning and its answer
+///
+/// Qwen3 models may reason inside `<think>...</think>` before answering;
+/// other fine-tunes use other tags (see `DEFAULT_THINKING_TAGS`). Text
+/// inside the tags goes to `thinking` and the rest to `output`, minus any
+/// whitespace before the answer's first real content (the newlines that
+/// follow `</think>`). Tags are found even when a piece has text on either
+/// side of them, and a closing tag without an opening one (when the chat
+/// template opened it) moves what came before it into `thinking`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationState {
+    tags: Vec<ThinkingTags>,
+    /// Index into `tags` of the block we're inside, if any
+    open_block: Option<usize>,
+    thinking: String,
+    output: String,
+}
+
+impl GenerationState {
+    /// Start before any piece has been generated, with the default tags
+    pub fn new() -> Self {
+        Self::with_tags(&default_thinking_tags())
+    }
+
+    /// Start before any piece has been generated, recognizing `tags`
+    pub fn with_tags(tags: &[ThinkingTags]) -> Self {
+        Self {
+            tags: tags.to_vec(),
+            open_block: None,
+            thinking: String::new(),
+            output: String::new(),
+        }
+    }
+
+    /// Take in one decoded piece, returning whether any of it reached `output`
+    pub fn push(&mut self, piece: &str) -> bool {
+        let mut rest = piece;
+        let mut added = false;
+
+        loop {
+            if let Some(block) = self.open_block {
+                let close = self.tags[block].close.as_str();
+                match rest.find(close) {
+                    Some(end) => {
+                        self.thinking.push_str(&rest[..end]);
+                        self.open_block = None;
+                        rest = &rest[end + close.len()..];
+                    }
+                    None => {
+                        self.thinking.push_str(rest);
+                        return added;
+                    }
+                }
+                continue;
+            }
+
+            let start = find_tag(rest, &self.tags, |tags| &tags.open);
+            match (start, find_tag(rest, &self.tags, |tags| &tags.close)) {
+                (Some((start, block)), end) if end.is_none_or(|(end, _)| start < end) => {
+                    added |= self.push_output(&rest[..start]);
+                    self.open_block = Some(block);
+                    rest = &rest[start + self.tags[block].open.len()..];
+                }
+                (_, Some((end, block))) => {
+                    let output = std::mem::take(&mut self.output);
+                    self.thinking.push_str(&output);
+                    self.thinking.push_str(&rest[..end]);
+                    added = false;
+                    rest = &rest[end + self.tags[block].close.len()..];
+                }
+                _ => return self.push_output(rest) || added,
+            }
+        }
+    }
+
+    /// Append answer text, dropping whitespace before the first content
+    fn push_output(&mut self, text: &str) -> bool {
+        let text = if self.output.is_empty() {
+            text.trim_start()
+        } else {
+            text
+        };
+        self.output.push_str(text);
+        !text.is_empty()
+    }
+
+    /// The answer generated so far
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Split into the answer and the reasoning
+    pub fn into_parts(self) -> (String, String) {
+        (self.output, self.thinking)
+    }
+}
+
+impl Default for GenerationState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Probability the model assigned to `token`, from a softmax over the raw logits
+///
+/// Pass the candidates as read from the context, before any sampler has
+/// rescaled or truncated them. Returns None if `token` isn't a candidate.
+pub fn token_probability(candidates: &[LlamaTokenData], token: LlamaToken) -> Option<f32> {
+    let chosen = candidates.iter().find(|c| c.id() == token)?.logit();
+    let max = candidates
+        .iter()
+        .map(LlamaTokenData::logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let total: f32 = candidates.iter().map(|c| (c.logit() - max).exp()).sum();
+
+    Some((chosen - max).exp() / total)
+}
+
+/// Combine per-token probabilities into a single confidence in [0, 1]
+///
+/// This is the geometric mean, i.e. the exponent of the average log-prob, so
+/// long outputs aren't penalized for their length but a single unlikely token
+/// still pulls the score down. Returns None for an empty sequence.
+pub fn sequence_confidence(probabilities: &[f32]) -> Option<f32> {
+    if probabilities.is_empty() {
+        return None;
+    }
+
+    let total_log_prob: f32 = probabilities
+        .iter()
+        .map(|p| p.clamp(f32::MIN_POSITIVE, 1.0).ln())
+        .sum();
+    let average = total_log_prob / probabilities.len() as f32;
+
+    Some(average.exp().clamp(0.0, 1.0))
+}
+
+/// One suggested correction and the model's confidence in it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Candidate {
+    pub correction: String,
+    pub confidence: Option<f32>,
+}
+
+/// Sort candidates by confidence, highest first, dropping empty and repeated ones
+///
+/// Candidates without a confidence go last. When the same correction was
+/// generated more than once, its most confident copy is kept.
+pub fn rank_candidates(mut candidates: Vec<Candidate>) -> Vec<Candidate> {
+    candidates.retain(|c| !c.correction.is_empty());
+    candidates.sort_by(|a, b| {
+        let score = |c: &Candidate| c.confidence.unwrap_or(f32::NEG_INFINITY);
+        score(b).total_cmp(&score(a))
+    });
+
+    let mut seen = std::collections::HashSet::new();
+    candidates.retain(|c| seen.insert(c.correction.clone()));
+    candidates
+}
+
+// ===== Tests =====
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preset_params() {
+        let tuple = |p: SamplingPreset| {
+            let params = p.params();
+            (params.temperature, params.top_p, params.top_k)
+        };
+
+        assert_eq!(tuple(SamplingPreset::Greedy), (0.0, 1.0, 0));
+        assert_eq!(tuple(SamplingPreset::Balanced), (0.7, 0.8, 20));
+        assert_eq!(tuple(SamplingPreset::Creative), (1.0, 0.95, 40));
+    }
+
+    #[test]
+    fn test_greedy_is_default_and_zero_temperature() {
+        assert_eq!(SamplingPreset::default(), SamplingPreset::Greedy);
+        assert_eq!(SamplingPreset::Greedy.params().temperature, 0.0);
+    }
+
+    #[test]
+    fn test_generate_counts_decode_iterations() {
+        // A mock model that decodes five tokens, then emits end of sequence
+        let mut decodes = 0;
+        let generated = generate(128, |i| {
+            if i == 5 {
+                return Ok(Step::Stop);
+            }
+            decodes += 1;
+            Ok(Step::Continue)
+        })
+        .unwrap();
+
+        assert_eq!(generated.decoded, 5);
+        assert_eq!(generated.decoded, decodes);
+        assert_eq!(generated.stop, StopReason::Finished);
+    }
+
+    #[test]
+    fn test_generate_stops_at_max_tokens() {
+        let mut calls = 0;
+        let generated = generate(3, |_| {
+            calls += 1;
+            Ok(Step::Continue)
+        })
+        .unwrap();
+
+        assert_eq!(generated.decoded, 3);
+        assert_eq!(generated.stop, StopReason::MaxTokens);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_is_truncated() {
+        assert!(is_truncated(StopReason::MaxTokens, "git commit --"));
+        assert!(!is_truncated(StopReason::MaxTokens, "git status\n"));
+        assert!(!is_truncated(StopReason::Finished, "git commit --"));
+    }
+
+    #[test]
+    fn test_retry_if_truncated_uses_larger_budget() {
+        // A mock loop that needs six tokens to finish "git commit --amend"
+        let words = ["git", " commit", " --", "am", "end", "\n"];
+        let mut budgets = Vec::new();
+        let output = retry_if_truncated(3, 8, |max_tokens| {
+            budgets.push(max_tokens);
+            let mut output = String::new();
+            let generated = generate(max_tokens, |i| match words.get(i) {
+                Some(word) => {
+                    output.push_str(word);
+                    Ok(Step::Continue)
+                }
+                None => Ok(Step::Stop),
+            })?;
+            let truncated = is_truncated(generated.stop, &output);
+            Ok((output, truncated))
+        })
+        .unwrap();
+
+        assert_eq!(budgets, vec![3, 8]);
+        assert_eq!(output, "git commit --amend\n");
+    }
+
+    #[test]
+    fn test_retry_if_truncated_keeps_complete_result() {
+        let mut attempts = 0;
+        let output = retry_if_truncated(3, 8, |_| {
+            attempts += 1;
+            Ok(("git status", false))
+        })
+        .unwrap();
+
+        assert_eq!(output, "git status");
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_generate_propagates_errors() {
+        let result = generate(10, |i| {
+            if i == 2 {
+                Err("Decode failed: test".to_string())
+            } else {
+                Ok(Step::Continue)
+            }
+        });
+
+        assert_eq!(result, Err("Decode failed: test".to_string()));
+    }
+
+    /// Feed `pieces` through a fresh state, returning what `push` reported for each
+    fn feed(pieces: &[&str]) -> (GenerationState, Vec<bool>) {
+        let mut state = GenerationState::new();
+        let added = pieces.iter().map(|piece| state.push(piece)).collect();
+        (state, added)
+    }
+
+    #[test]
+    fn test_generation_state_skips_whitespace_after_thinking() {
+        let (state, added) = feed(&[
+            "<think>", "\n", "typo", "</think>", "\n\n", " git", " status",
+        ]);
+
+        assert_eq!(added, vec![false, false, false, false, false, true, true]);
+        assert_eq!(state.output(), "git status");
+        assert_eq!(state.into_parts().1, "\ntypo");
+    }
+
+    #[test]
+    fn test_generation_state_keeps_content_sharing_a_piece_with_tags() {
+        let (state, added) = feed(&["<think>\n\n</think>\n\ngit", " status"]);
+        assert_eq!(added, vec![true, true]);
+        assert_eq!(state.output(), "git status");
+
+        let (state, _) = feed(&["git", " status<think>why", "</think>"]);
+        assert_eq!(
+            state.into_parts(),
+            ("git status".to_string(), "why".to_string())
+        );
+    }
+
+    #[test]
+    fn test_generation_state_keeps_newlines_inside_content() {
+        let (state, added) = feed(&["\n", "cd build", "\n", "make"]);
+        assert_eq!(added, vec![false, true, true, true]);
+        assert_eq!(state.output(), "cd build\nmake");
+    }
+
+    #[test]
+    fn test_generation_state_unopened_think_end() {
+        // The chat template opened the think block, so only its end is generated
+        let (state, _) = feed(&["user typed", " gti", "</think>", "\n", "git"]);
+        assert_eq!(
+            state.into_parts(),
+            ("git".to_string(), "user typed gti".to_string())
+        );
+    }
+
+    #[test]
+    fn test_generation_state_default_tags() {
+        let (state, _) = feed(&["<reasoning>", "gti is git", "</reasoning>", "\n", "git"]);
+        assert_eq!(
+            state.into_parts(),
+            ("git".to_string(), "gti is git".to_string())
+        );
+
+        // Another pair's closing tag doesn't end the open block
+        let (state, _) = feed(&["<think>see </reasoning>", "</think>ls"]);
+        assert_eq!(
+            state.into_parts(),
+            ("ls".to_string(), "see </reasoning>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_generation_state_configured_tags() {
+        let tags = [ThinkingTags::new("[plan]", "[/plan]")];
+        let mut state = GenerationState::with_tags(&tags);
+        for piece in ["[plan]fix typo[/plan]", " <think>x</think>"] {
+            state.push(piece);
+        }
+        assert_eq!(
+            state.into_parts(),
+            ("<think>x</think>".to_string(), "fix typo".to_string())
+        );
+    }
+
+    #[test]
+    fn test_token_probability_softmax() {
+        let candidates = [
+            LlamaTokenData::new(LlamaToken::new(0), 2.0, 0.0),
+            LlamaTokenData::new(LlamaToken::new(1), 2.0, 0.0),
+            LlamaTokenData::new(LlamaToken::new(2), f32::NEG_INFINITY, 0.0),
+        ];
+
+        let p = token_probability(&candidates, LlamaToken::new(0)).unwrap();
+        assert!((p - 0.5).abs() < 1e-6);
+        assert_eq!(
+            token_probability(&candidates, LlamaToken::new(2)),
+            Some(0.0)
+        );
+        assert_eq!(token_probability(&candidates, LlamaToken::new(9)), None);
+    }
+
+    #[test]
+    fn test_sequence_confidence_geometric_mean() {
+        assert_eq!(sequence_confidence(&[]), None);
+        assert_eq!(sequence_confidence(&[1.0, 1.0, 1.0]), Some(1.0));
+
+        let confidence = sequence_confidence(&[0.9, 0.4]).unwrap();
+        assert!((confidence - 0.6).abs() < 1e-5);
+
+        // A single near-impossible token dominates, but stays in range
+        let confidence = sequence_confidence(&[1.0, 0.0, 1.0]).unwrap();
+        assert!((0.0..0.01).contains(&confidence));
+    }
+
+    #[test]
+    fn test_suggestion_presets_avoid_repeating_greedy() {
+        assert_eq!(
+            SamplingPreset::Greedy.for_suggestions(3),
+            vec![
+                SamplingPreset::Greedy,
+                SamplingPreset::Balanced,
+                SamplingPreset::Balanced
+            ]
+        );
+        assert_eq!(
+            SamplingPreset::Creative.for_suggestions(2),
+            vec![SamplingPreset::Creative, SamplingPreset::Creative]
+        );
+        assert!(SamplingPreset::Greedy.for_suggestions(0).is_empty());
+    }
+
+    #[test]
+    fn test_rank_candidates_sorts_by_confidence() {
+        let candidate = |correction: &str, confidence: Option<f32>| Candidate {
+            correction: correction.to_string(),
+            confidence,
+        };
+        let ranked = rank_candidates(vec![
+            candidate("git stash", Some(0.4)),
+            candidate("git status", Some(0.9)),
+            candidate("", Some(1.0)),
+            candidate("git stat", None),
+            candidate("git status", Some(0.6)),
+            candidate("git st", Some(0.5)),
+        ]);
+
+        assert_eq!(
+            ranked,
+            vec![
+                candidate("git status", Some(0.9)),
+                candidate("git st", Some(0.5)),
+                candidate("git stash", Some(0.4)),
+                candidate("git stat", None),
+            ]
+        );
+        assert!(ranked
+            .windows(2)
+            .all(|pair| pair[0].confidence.unwrap_or(0.0) >= pair[1].confidence.unwrap_or(0.0)));
+
+        let json = serde_json::to_string(&ranked[..2]).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"correction":"git status","confidence":0.9},{"correction":"git st","confidence":0.5}]"#
+        );
+    }
+
+    #[test]
+    fn test_preset_serde_lowercase() {
+        let json = serde_json::to_string(&SamplingPreset::Balanced).unwrap();
+        assert_eq!(json, "\"balanced\"");
+
+        let preset: SamplingPreset = serde_json::from_str("\"creative\"").unwrap();
+        assert_eq!(preset, SamplingPreset::Creative);
+    }
+}
@@ -1,25 +1,260 @@
 //! Cross-platform tool executor for wit CLI
 //!
-//! This module provides 5 tools with cross-platform support for shell command correction:
+//! This module provides 6 tools with cross-platform support for shell command correction:
 //! - `help_output`: Get --help output (first 30 lines)
 //! - `which_binary`: Check if command exists
 //! - `list_similar`: List commands with similar prefix
-//! - `get_env_var`: Get environment variable value
+//! - `get_env_var`: Get environment variable value (secrets are redacted)
 //! - `man_page`: Get man page synopsis (Unix only)
+//! - `file_exists`: Check for a file such as `Cargo.toml` in the current directory
+//!
+//! With alias detection on, `which_binary` and `list_similar` also report the
+//! user's shell aliases (`alias: g=git`) so valid shorthands aren't "fixed".
 
+use crate::log_debug;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::{BufRead, BufReader};
+use std::path::{Component, Path};
 use std::process::{Command, Stdio};
-use std::sync::Mutex;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 
 /// Default timeout for tool execution (500ms)
 pub const DEFAULT_TIMEOUT_MS: u64 = 500;
 
+/// Shorter timeout for man_page, the least useful tool and the slowest on a cold mandb
+pub const MAN_PAGE_TIMEOUT_MS: u64 = 200;
+
+/// Longest a tool may run when retried after timing out (2 seconds)
+pub const MAX_RETRY_TIMEOUT_MS: u64 = 2000;
+
+thread_local! {
+    /// Deadline of the `execute_all` batch the current worker thread runs tools for
+    static BATCH_DEADLINE: std::cell::Cell<Option<Instant>> = const { std::cell::Cell::new(None) };
+}
+
+/// Error returned for a tool command killed at its timeout
+const TIMED_OUT: &str = "Command timed out";
+
+/// Start of the error returned for a tool command that failed without output
+const EXITED_WITH_STATUS: &str = "Command exited with status";
+
+/// Fish builtins, which `type -P` doesn't report since it only searches PATH
+const FISH_BUILTINS: &[&str] = &[
+    "abbr",
+    "alias",
+    "bg",
+    "bind",
+    "builtin",
+    "cd",
+    "command",
+    "complete",
+    "contains",
+    "count",
+    "dirh",
+    "dirs",
+    "echo",
+    "eval",
+    "exec",
+    "exit",
+    "fg",
+    "functions",
+    "history",
+    "jobs",
+    "math",
+    "nextd",
+    "popd",
+    "prevd",
+    "printf",
+    "pushd",
+    "pwd",
+    "read",
+    "set",
+    "set_color",
+    "source",
+    "status",
+    "string",
+    "test",
+    "type",
+    "ulimit",
+    "wait",
+];
+
+/// PowerShell aliases and cmdlet names users type that `Get-Command` gives no path for
+const POWERSHELL_BUILTINS: &[&str] = &[
+    "cd", "chdir", "cls", "copy", "cp", "cat", "del", "dir", "echo", "erase", "gc", "gci", "gl",
+    "ls", "md", "move", "mv", "popd", "pushd", "pwd", "rd", "ren", "ri", "rm", "rmdir", "set",
+    "sl", "type",
+];
+
+/// cmd builtins, which `where` doesn't report since it only searches PATH
+const CMD_BUILTINS: &[&str] = &[
+    "assoc", "call", "cd", "chdir", "cls", "color", "copy", "date", "del", "dir", "echo",
+    "endlocal", "erase", "exit", "for", "ftype", "goto", "if", "md", "mkdir", "mklink", "move",
+    "path", "pause", "popd", "prompt", "pushd", "rd", "ren", "rename", "rmdir", "set", "setlocal",
+    "shift", "start", "time", "title", "type", "ver", "vol",
+];
+
+/// Error `which_binary` returns when the lookup ran and found no `command`
+///
+/// Other failures, such as a timeout, say nothing about whether it exists.
+pub fn not_found_error(command: &str) -> String {
+    format!("Command '{}' not found", command)
+}
+
+/// How long `ToolExecutor::execute_all` waits before giving up on unfinished tools
+pub const DEFAULT_TOOLS_DEADLINE_MS: u64 = 800;
+
+/// Default time a cached tool result stays valid (1 minute)
+pub const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+
+/// Cache hits after which an entry counts as hot and keeps a longer TTL
+pub const HOT_ENTRY_MIN_HITS: u32 = 3;
+
+/// How many times the base TTL a hot cache entry stays valid
+pub const HOT_ENTRY_TTL_MULTIPLIER: u32 = 5;
+
+/// Hard limit on the age of any cached tool result (10 minutes)
+pub const MAX_CACHE_ENTRY_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum tools run at the same time by `ToolExecutor::execute_all`
+pub const MAX_CONCURRENT_TOOLS: usize = 4;
+
 /// Maximum lines to return from help output
 pub const MAX_HELP_LINES: usize = 30;
 
+/// Characters of a tool's output kept in wit's prompt unless configured otherwise
+pub const DEFAULT_PROMPT_OUTPUT_CHARS: usize = 200;
+
+/// Lines of `list_similar` output kept in wit's prompt by default
+pub const DEFAULT_PROMPT_OUTPUT_LINES: usize = 15;
+
+/// Most paths `which_binary` reports when a command resolves to several files
+const MAX_WHICH_MATCHES: usize = 5;
+
+/// Read-only git commands run for `wit --git-context`, with the name of each result
+const GIT_CONTEXT_COMMANDS: &[(&str, &[&str])] = &[
+    ("git_branches", &["branch", "--no-color"]),
+    ("git_remotes", &["remote"]),
+];
+
+/// Most names listed in one git context result
+const MAX_GIT_CONTEXT_NAMES: usize = 20;
+
+/// Name fragments marking an environment variable as a secret
+const SECRET_NAME_PATTERNS: &[&str] = &["TOKEN", "SECRET", "PASSWORD", "PASSWD"];
+
+/// Check whether an environment variable name looks like it holds a secret
+///
+/// Matches `_`-separated segments containing one of `SECRET_NAME_PATTERNS`
+/// or ending in `KEY` (`API_KEY`, `APIKEY`, `AWS_SECRET_ACCESS_KEY`).
+pub fn is_secret_env_name(name: &str) -> bool {
+    name.to_uppercase().split(['_', '-']).any(|segment| {
+        segment.ends_with("KEY")
+            || SECRET_NAME_PATTERNS
+                .iter()
+                .any(|pattern| segment.contains(pattern))
+    })
+}
+
+/// Parse the alias listing printed by bash, zsh or fish into name → expansion
+///
+/// Accepts bash's `alias g='git'`, zsh's `g=git` and fish's `alias g git`
+/// (fish quotes expansions containing spaces: `alias gs 'git status'`).
+pub fn parse_aliases(output: &str) -> HashMap<String, String> {
+    let mut aliases = HashMap::new();
+
+    for line in output.lines() {
+        let line = line.trim();
+        let rest = line.strip_prefix("alias ").unwrap_or(line).trim_start();
+
+        // bash/zsh separate with '=', fish with a space
+        let split_at = match (rest.find('='), rest.find(char::is_whitespace)) {
+            (Some(eq), Some(space)) if space < eq => space,
+            (Some(eq), _) => eq,
+            (None, Some(space)) if line.starts_with("alias ") => space,
+            _ => continue,
+        };
+
+        let name = unquote_alias_word(&rest[..split_at]);
+        let value = unquote_alias_word(rest[split_at + 1..].trim());
+        if !name.is_empty() && !value.is_empty() {
+            aliases.insert(name, value);
+        }
+    }
+
+    aliases
+}
+
+/// Strip the shell quoting alias listings put around names and expansions
+fn unquote_alias_word(word: &str) -> String {
+    if word.len() >= 2 && word.starts_with('\'') && word.ends_with('\'') {
+        // Single quotes can't be escaped inside, so shells print '\''
+        word[1..word.len() - 1].replace("'\\''", "'")
+    } else if word.len() >= 2 && word.starts_with('"') && word.ends_with('"') {
+        word[1..word.len() - 1].to_string()
+    } else {
+        word.to_string()
+    }
+}
+
+/// Run `f` over `items` on at most `max_concurrent` threads, waiting only
+/// until `deadline`
+///
+/// Workers pull the next item from a shared queue, so a slow item doesn't
+/// hold up the rest. Results keep the order of `items`. Items still running
+/// or not yet started at the deadline come back as None; their threads are
+/// left to finish in the background. Needs owned items and a `'static`
+/// closure for that reason.
+pub fn run_bounded_until<T, R, F>(
+    items: Vec<T>,
+    max_concurrent: usize,
+    deadline: Instant,
+    f: F,
+) -> Vec<Option<R>>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    let count = items.len();
+    let queue = Arc::new(Mutex::new(
+        items.into_iter().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let f = Arc::new(f);
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..max_concurrent.clamp(1, count.max(1)) {
+        let queue = Arc::clone(&queue);
+        let f = Arc::clone(&f);
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            // Don't start anything the caller will no longer wait for
+            while Instant::now() < deadline {
+                let Some((i, item)) = queue.lock().ok().and_then(|mut q| q.pop_front()) else {
+                    break;
+                };
+                if sender.send((i, f(item))).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut results: Vec<Option<R>> = (0..count).map(|_| None).collect();
+    for _ in 0..count {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok((i, result)) => results[i] = Some(result),
+            Err(_) => break,
+        }
+    }
+    results
+}
+
 /// Supported shell types
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -53,6 +288,54 @@ impl Shell {
     pub fn is_windows_native(&self) -> bool {
         matches!(self, Shell::Cmd | Shell::PowerShell)
     }
+
+    /// Whether `command` is run by the shell itself rather than found on PATH
+    ///
+    /// Only covers shells whose `which_binary` lookup misses builtins; bash
+    /// and zsh report them through `command -v`. Windows shells ignore case.
+    pub fn is_builtin(&self, command: &str) -> bool {
+        let builtins = match self {
+            Shell::Bash | Shell::Zsh => return false,
+            Shell::Fish => return FISH_BUILTINS.contains(&command),
+            Shell::PowerShell => POWERSHELL_BUILTINS,
+            Shell::Cmd => CMD_BUILTINS,
+        };
+        builtins.iter().any(|b| b.eq_ignore_ascii_case(command))
+    }
+
+    /// Program and arguments that run `command` in this shell
+    ///
+    /// `powershell` picks the executable for `Shell::PowerShell` and is
+    /// ignored by the other shells.
+    pub fn invocation(&self, command: &str, powershell: PowerShellFlavor) -> Vec<String> {
+        let argv: &[&str] = match self {
+            Shell::Bash => &["bash", "-c"],
+            Shell::Zsh => &["zsh", "-c"],
+            Shell::Fish => &["fish", "-c"],
+            Shell::PowerShell => &[powershell.executable(), "-NoProfile", "-Command"],
+            Shell::Cmd => &["cmd", "/c"],
+        };
+
+        argv.iter()
+            .copied()
+            .chain([command])
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Extra guidance for the model about this shell's syntax
+    ///
+    /// Added to the system message by `build_prompt` and wit's prompt. Empty
+    /// for bash, which the models assume anyway.
+    pub fn prompt_hint(&self) -> &'static str {
+        match self {
+            Shell::Bash => "",
+            Shell::Zsh => "Quote arguments containing *, ? or [ ] since zsh rejects globs that match nothing.",
+            Shell::Fish => "Use fish syntax: set -x VAR value instead of export, and (cmd) for command substitution.",
+            Shell::PowerShell => "Prefer PowerShell cmdlets such as Get-ChildItem and Remove-Item, and $env:VAR for environment variables.",
+            Shell::Cmd => "Use cmd.exe syntax: %VAR% for environment variables, and dir, copy, del or type instead of Unix tools.",
+        }
+    }
 }
 
 impl std::fmt::Display for Shell {
@@ -67,6 +350,70 @@ impl std::fmt::Display for Shell {
     }
 }
 
+/// Which PowerShell implementation is installed
+///
+/// Windows PowerShell 5.1 (`powershell`) and PowerShell Core 6+ (`pwsh`) format
+/// help and command listings differently, so tool scripts vary by flavor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerShellFlavor {
+    /// PowerShell Core (`pwsh`), `$PSVersionTable.PSEdition` is "Core"
+    Core,
+    /// Windows PowerShell (`powershell`), `$PSVersionTable.PSEdition` is "Desktop"
+    Windows,
+}
+
+impl PowerShellFlavor {
+    /// Parse the value of `$PSVersionTable.PSEdition`
+    pub fn from_edition(edition: &str) -> Option<Self> {
+        match edition.trim().to_lowercase().as_str() {
+            "core" => Some(PowerShellFlavor::Core),
+            "desktop" => Some(PowerShellFlavor::Windows),
+            _ => None,
+        }
+    }
+
+    /// Executable name for this flavor
+    pub fn executable(&self) -> &'static str {
+        match self {
+            PowerShellFlavor::Core => "pwsh",
+            PowerShellFlavor::Windows => "powershell",
+        }
+    }
+
+    /// Script returning the first lines of help for a command
+    pub fn help_script(&self, command: &str) -> String {
+        match self {
+            PowerShellFlavor::Core => {
+                format!(
+                    "Get-Help {} | Select-Object -First {}",
+                    command, MAX_HELP_LINES
+                )
+            }
+            // Windows PowerShell returns a single help object, so it must be
+            // streamed as text before lines can be selected
+            PowerShellFlavor::Windows => format!(
+                "Get-Help {} | Out-String -Stream | Select-Object -First {}",
+                command, MAX_HELP_LINES
+            ),
+        }
+    }
+
+    /// Script listing command names starting with a prefix
+    pub fn list_similar_script(&self, prefix: &str) -> String {
+        match self {
+            PowerShellFlavor::Core => format!(
+                "Get-Command '{}*' -ErrorAction SilentlyContinue | Select-Object -ExpandProperty Name",
+                prefix
+            ),
+            // Windows PowerShell reports applications with their `.exe` suffix
+            PowerShellFlavor::Windows => format!(
+                "Get-Command '{}*' -ErrorAction SilentlyContinue | ForEach-Object {{ $_.Name -replace '\\.exe$', '' }}",
+                prefix
+            ),
+        }
+    }
+}
+
 /// Available tools for the wit CLI
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -81,6 +428,8 @@ pub enum Tool {
     GetEnvVar { name: String },
     /// Get man page synopsis (Unix only)
     ManPage { command: String },
+    /// Check whether a file exists in the current directory
+    FileExists { path: String },
 }
 
 impl Tool {
@@ -92,6 +441,7 @@ impl Tool {
             Tool::ListSimilar { .. } => "list_similar",
             Tool::GetEnvVar { .. } => "get_env_var",
             Tool::ManPage { .. } => "man_page",
+            Tool::FileExists { .. } => "file_exists",
         }
     }
 }
@@ -127,11 +477,45 @@ impl ToolResult {
     }
 }
 
-/// Cache entry with timestamp
+/// Cache entry with timestamp and access statistics
 #[derive(Debug, Clone)]
 struct CacheEntry {
     result: ToolResult,
     timestamp: Instant,
+    /// Number of times the entry was served from the cache
+    hits: u32,
+    last_access: Instant,
+}
+
+impl CacheEntry {
+    fn new(result: ToolResult, now: Instant) -> Self {
+        Self {
+            result,
+            timestamp: now,
+            hits: 0,
+            last_access: now,
+        }
+    }
+
+    /// Whether the entry can still be served at `now`
+    ///
+    /// Entries hit at least `HOT_ENTRY_MIN_HITS` times, and used within the
+    /// last `base_ttl`, live `HOT_ENTRY_TTL_MULTIPLIER` times longer. Nothing
+    /// outlives `MAX_CACHE_ENTRY_AGE` (or `base_ttl`, if that is longer).
+    fn is_fresh(&self, base_ttl: Duration, now: Instant) -> bool {
+        let age = now.saturating_duration_since(self.timestamp);
+        let recently_used = now.saturating_duration_since(self.last_access) < base_ttl;
+
+        let ttl = if self.hits >= HOT_ENTRY_MIN_HITS && recently_used {
+            (base_ttl * HOT_ENTRY_TTL_MULTIPLIER)
+                .min(MAX_CACHE_ENTRY_AGE)
+                .max(base_ttl)
+        } else {
+            base_ttl
+        };
+
+        age < ttl
+    }
 }
 
 /// Tool executor with caching support
@@ -140,10 +524,18 @@ pub struct ToolExecutor {
     shell: Shell,
     /// Timeout for command execution
     timeout: Duration,
+    /// How long `execute_all` waits for the slowest tools
+    deadline: Duration,
     /// Cache for tool results
     cache: Mutex<HashMap<String, CacheEntry>>,
     /// Cache TTL (time-to-live)
     cache_ttl: Duration,
+    /// Detected PowerShell flavor (None until a detection succeeds)
+    powershell_flavor: Mutex<Option<PowerShellFlavor>>,
+    /// Whether to read the user's shell aliases
+    detect_aliases: bool,
+    /// Shell aliases, loaded on first use
+    aliases: OnceLock<HashMap<String, String>>,
 }
 
 impl ToolExecutor {
@@ -152,23 +544,56 @@ impl ToolExecutor {
         Self {
             shell,
             timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            deadline: Duration::from_millis(DEFAULT_TOOLS_DEADLINE_MS),
             cache: Mutex::new(HashMap::new()),
-            cache_ttl: Duration::from_secs(60), // 1 minute cache
+            cache_ttl: Duration::from_secs(DEFAULT_CACHE_TTL_SECS),
+            powershell_flavor: Mutex::new(None),
+            detect_aliases: false,
+            aliases: OnceLock::new(),
         }
     }
 
+    /// Create a new tool executor using settings from the config
+    pub fn from_config(shell: Shell, config: &crate::Config) -> Self {
+        Self::new(shell)
+            .with_cache_ttl(config.tool_cache_ttl())
+            .with_aliases(config.shell_aliases)
+    }
+
     /// Create a new tool executor with custom timeout
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
         self.timeout = timeout;
         self
     }
 
+    /// Create a new tool executor with a custom deadline for `execute_all`
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Timeout for each command `tool` runs
+    ///
+    /// man_page gets a shorter one, never longer than the general timeout.
+    pub fn tool_timeout(&self, tool: &Tool) -> Duration {
+        match tool {
+            Tool::ManPage { .. } => self.timeout.min(Duration::from_millis(MAN_PAGE_TIMEOUT_MS)),
+            _ => self.timeout,
+        }
+    }
+
     /// Create a new tool executor with custom cache TTL
     pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
         self.cache_ttl = ttl;
         self
     }
 
+    /// Create a new tool executor that reports the user's shell aliases
+    pub fn with_aliases(mut self, enabled: bool) -> Self {
+        self.detect_aliases = enabled;
+        self
+    }
+
     /// Get the current shell
     pub fn shell(&self) -> Shell {
         self.shell
@@ -180,9 +605,12 @@ impl ToolExecutor {
         let cache_key = format!("{:?}:{:?}", self.shell, tool);
 
         // Check cache
-        if let Ok(cache) = self.cache.lock() {
-            if let Some(entry) = cache.get(&cache_key) {
-                if entry.timestamp.elapsed() < self.cache_ttl {
+        if let Ok(mut cache) = self.cache.lock() {
+            if let Some(entry) = cache.get_mut(&cache_key) {
+                let now = Instant::now();
+                if entry.is_fresh(self.cache_ttl, now) {
+                    entry.hits = entry.hits.saturating_add(1);
+                    entry.last_access = now;
                     return entry.result.clone();
                 }
             }
@@ -195,22 +623,57 @@ impl ToolExecutor {
             Tool::ListSimilar { prefix } => self.execute_list_similar(prefix),
             Tool::GetEnvVar { name } => self.execute_get_env_var(name),
             Tool::ManPage { command } => self.execute_man_page(command),
+            Tool::FileExists { path } => match std::env::current_dir() {
+                Ok(dir) => file_exists_in(&dir, path),
+                Err(e) => ToolResult::failure(format!("No current directory: {}", e)),
+            },
         };
 
         // Store in cache
         if let Ok(mut cache) = self.cache.lock() {
-            cache.insert(
-                cache_key,
-                CacheEntry {
-                    result: result.clone(),
-                    timestamp: Instant::now(),
-                },
-            );
+            cache.insert(cache_key, CacheEntry::new(result.clone(), Instant::now()));
         }
 
         result
     }
 
+    /// Execute several tools in parallel, at most `MAX_CONCURRENT_TOOLS` at once
+    ///
+    /// Results are returned in the same order as `tools`. A tool listed more
+    /// than once runs once and shares its result, and tools go through the
+    /// same cache as `execute`. Tools that haven't finished by the executor's
+    /// deadline are reported as timed out, so one slow tool can't hold up the
+    /// correction.
+    pub fn execute_all(self: &Arc<Self>, tools: &[Tool]) -> Vec<ToolResult> {
+        let mut unique: Vec<Tool> = Vec::new();
+        let positions: Vec<usize> = tools
+            .iter()
+            .map(|tool| match unique.iter().position(|t| t == tool) {
+                Some(position) => position,
+                None => {
+                    unique.push(tool.clone());
+                    unique.len() - 1
+                }
+            })
+            .collect();
+
+        let executor = Arc::clone(self);
+        let deadline = Instant::now() + self.deadline;
+        let results: Vec<ToolResult> =
+            run_bounded_until(unique, MAX_CONCURRENT_TOOLS, deadline, move |tool| {
+                // A retry after a timeout must still finish before the deadline
+                BATCH_DEADLINE.with(|d| d.set(Some(deadline)));
+                executor.execute(&tool)
+            })
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| ToolResult::failure("Tool timed out".to_string()))
+            })
+            .collect();
+
+        positions.into_iter().map(|i| results[i].clone()).collect()
+    }
+
     /// Clear the cache
     pub fn clear_cache(&self) {
         if let Ok(mut cache) = self.cache.lock() {
@@ -230,11 +693,8 @@ impl ToolExecutor {
             }
             Shell::PowerShell => {
                 // PowerShell: Get-Help or native --help
-                self.run_powershell_command(&format!(
-                    "Get-Help {} | Select-Object -First 30",
-                    command
-                ))
-                .or_else(|_| self.run_command_with_timeout(command, &["--help"]))
+                self.run_powershell_command(|flavor| flavor.help_script(command))
+                    .or_else(|_| self.run_command_with_timeout(command, &["--help"]))
             }
             Shell::Cmd => {
                 // CMD: Try /? first, then --help
@@ -255,6 +715,13 @@ impl ToolExecutor {
 
     /// Execute which_binary tool
     fn execute_which_binary(&self, command: &str) -> ToolResult {
+        if let Some(expansion) = self.aliases().get(command) {
+            return ToolResult::success(format!("alias: {}={}", command, expansion));
+        }
+        if self.shell.is_builtin(command) {
+            return ToolResult::success(format!("builtin: {}", command));
+        }
+
         let result = match self.shell {
             Shell::Bash | Shell::Zsh => {
                 // Use 'command -v' POSIX builtin (more portable than 'which')
@@ -266,10 +733,12 @@ impl ToolExecutor {
             }
             Shell::PowerShell => {
                 // PowerShell: (Get-Command).Source
-                self.run_powershell_command(&format!(
-                    "(Get-Command {} -ErrorAction SilentlyContinue).Source",
-                    command
-                ))
+                self.run_powershell_command(|_| {
+                    format!(
+                        "(Get-Command {} -ErrorAction SilentlyContinue).Source",
+                        command
+                    )
+                })
             }
             Shell::Cmd => {
                 // CMD: where command
@@ -279,13 +748,17 @@ impl ToolExecutor {
 
         match result {
             Ok(output) => {
-                let path = output.lines().next().unwrap_or("").trim().to_string();
-                if path.is_empty() {
-                    ToolResult::failure(format!("Command '{}' not found", command))
+                let paths = which_matches(&output);
+                if paths.is_empty() {
+                    ToolResult::failure(not_found_error(command))
                 } else {
-                    ToolResult::success(path)
+                    ToolResult::success(paths)
                 }
             }
+            // The lookup ran and failed, which is how it reports a missing command
+            Err(e) if e.starts_with(EXITED_WITH_STATUS) => {
+                ToolResult::failure(not_found_error(command))
+            }
             Err(e) => ToolResult::failure(e),
         }
     }
@@ -309,10 +782,7 @@ impl ToolExecutor {
             }
             Shell::PowerShell => {
                 // PowerShell: Get-Command prefix*
-                self.run_powershell_command(&format!(
-                    "Get-Command '{}*' -ErrorAction SilentlyContinue | Select-Object -ExpandProperty Name",
-                    prefix
-                ))
+                self.run_powershell_command(|flavor| flavor.list_similar_script(prefix))
             }
             Shell::Cmd => {
                 // CMD: No native equivalent, scan PATH
@@ -320,6 +790,15 @@ impl ToolExecutor {
             }
         };
 
+        // Aliases come first so they survive the result limit
+        let mut aliases: Vec<String> = self
+            .aliases()
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, expansion)| format!("alias: {}={}", name, expansion))
+            .collect();
+        aliases.sort();
+
         match result {
             Ok(output) => {
                 // Deduplicate and limit results
@@ -331,19 +810,42 @@ impl ToolExecutor {
                 commands.sort();
                 commands.dedup();
 
+                aliases.extend(commands);
+
                 // Limit to 20 results
-                commands.truncate(20);
-                ToolResult::success(commands.join("\n"))
+                aliases.truncate(20);
+                ToolResult::success(aliases.join("\n"))
             }
+            Err(_) if !aliases.is_empty() => ToolResult::success(aliases.join("\n")),
             Err(e) => ToolResult::failure(e),
         }
     }
 
+    /// Branch and remote names of the git repository in the working directory
+    ///
+    /// Returns `(name, result)` pairs in the form of tool results, for the
+    /// model to correct misspelled branches and remotes. Commands that fail
+    /// (outside a repository, git not installed) or list nothing are left out.
+    pub fn git_context(&self) -> Vec<(String, String)> {
+        GIT_CONTEXT_COMMANDS
+            .iter()
+            .filter_map(|(name, args)| {
+                let output = self.run_command_with_timeout("git", args).ok()?;
+                let names = git_names(&output);
+                (!names.is_empty()).then(|| (name.to_string(), names))
+            })
+            .collect()
+    }
+
     /// Execute get_env_var tool
     fn execute_get_env_var(&self, name: &str) -> ToolResult {
         // Environment variables can be accessed directly in Rust,
         // regardless of shell type
         match std::env::var(name) {
+            // Keep secrets out of the prompt and the daemon's logs
+            Ok(value) if is_secret_env_name(name) => {
+                ToolResult::success(format!("set (redacted, {} chars)", value.chars().count()))
+            }
             Ok(value) => ToolResult::success(value),
             Err(_) => ToolResult::failure(format!("Environment variable '{}' not set", name)),
         }
@@ -356,8 +858,13 @@ impl ToolExecutor {
             return ToolResult::failure("man pages not available on this platform".to_string());
         }
 
+        let timeout = self.tool_timeout(&Tool::ManPage {
+            command: command.to_string(),
+        });
+
         // Get the synopsis section from man page
-        let result = self.run_command_with_timeout(
+        let result = self.run_command_within(
+            timeout,
             "man",
             &["-f", command], // whatis gives a brief description
         );
@@ -373,7 +880,7 @@ impl ToolExecutor {
             }
             Err(_) => {
                 // Try getting SYNOPSIS section from full man page
-                let result = self.run_command_with_timeout("man", &[command]);
+                let result = self.run_command_within(timeout, "man", &[command]);
                 match result {
                     Ok(output) => {
                         // Extract SYNOPSIS section
@@ -394,55 +901,88 @@ impl ToolExecutor {
 
     /// Run a command with timeout
     fn run_command_with_timeout(&self, cmd: &str, args: &[&str]) -> Result<String, String> {
+        self.run_command_within(self.timeout, cmd, args)
+    }
+
+    /// Run a command, killing it after `timeout`
+    ///
+    /// A command that times out is run once more with a longer timeout (see
+    /// `retry_timeout`), since the first run of a tool (a cold `--help` or
+    /// mandb) is often the slow one. Other failures aren't retried.
+    fn run_command_within(
+        &self,
+        timeout: Duration,
+        cmd: &str,
+        args: &[&str],
+    ) -> Result<String, String> {
+        match self.run_command_once(timeout, cmd, args) {
+            Err(e) if e == TIMED_OUT => {
+                let remaining = BATCH_DEADLINE
+                    .with(|d| d.get())
+                    .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                let Some(retry_timeout) = retry_timeout(timeout, remaining) else {
+                    return Err(e);
+                };
+                log_debug!(
+                    "{} timed out after {:?}, retrying with {:?}",
+                    cmd,
+                    timeout,
+                    retry_timeout
+                );
+                self.run_command_once(retry_timeout, cmd, args)
+            }
+            result => result,
+        }
+    }
+
+    /// Run a command once, killing it after `timeout`
+    fn run_command_once(
+        &self,
+        timeout: Duration,
+        cmd: &str,
+        args: &[&str],
+    ) -> Result<String, String> {
         let start = Instant::now();
 
+        // No tool reads input, and an interactive shell must not grab the terminal
         let mut child = Command::new(cmd)
             .args(args)
+            .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
             .map_err(|e| format!("Failed to spawn command: {}", e))?;
 
         // Wait with timeout
-        let timeout_remaining = self.timeout.saturating_sub(start.elapsed());
+        let timeout_remaining = timeout.saturating_sub(start.elapsed());
         if timeout_remaining.is_zero() {
             let _ = child.kill();
-            return Err("Command timed out".to_string());
+            return Err(TIMED_OUT.to_string());
         }
 
         match child.wait_timeout(timeout_remaining) {
             Ok(Some(status)) => {
                 if status.success() {
-                    let mut output = String::new();
-                    if let Some(stdout) = child.stdout.as_mut() {
-                        let reader = BufReader::new(stdout);
-                        for line in reader.lines().map_while(Result::ok) {
-                            output.push_str(&line);
-                            output.push('\n');
-                        }
-                    }
-                    Ok(output)
+                    let output = child.stdout.as_mut().map(read_lines_lossy);
+                    Ok(output.unwrap_or_default())
                 } else {
                     // Try to get output even on non-zero exit
-                    let mut output = String::new();
-                    if let Some(stdout) = child.stdout.as_mut() {
-                        let reader = BufReader::new(stdout);
-                        for line in reader.lines().map_while(Result::ok) {
-                            output.push_str(&line);
-                            output.push('\n');
-                        }
-                    }
+                    let output = child
+                        .stdout
+                        .as_mut()
+                        .map(read_lines_lossy)
+                        .unwrap_or_default();
                     if !output.is_empty() {
                         Ok(output)
                     } else {
-                        Err(format!("Command exited with status: {}", status))
+                        Err(format!("{}: {}", EXITED_WITH_STATUS, status))
                     }
                 }
             }
             Ok(None) => {
                 // Timeout - kill the process
                 let _ = child.kill();
-                Err("Command timed out".to_string())
+                Err(TIMED_OUT.to_string())
             }
             Err(e) => Err(format!("Failed to wait for command: {}", e)),
         }
@@ -453,26 +993,81 @@ impl ToolExecutor {
         self.run_command_with_timeout("bash", &["-c", script])
     }
 
-    /// Run a PowerShell command
-    fn run_powershell_command(&self, script: &str) -> Result<String, String> {
-        // Try pwsh (PowerShell Core) first, then powershell (Windows PowerShell)
-        self.run_command_with_timeout("pwsh", &["-NoProfile", "-Command", script])
-            .or_else(|_| {
-                self.run_command_with_timeout("powershell", &["-NoProfile", "-Command", script])
-            })
+    /// Run a PowerShell script built for the installed PowerShell flavor
+    fn run_powershell_command<F>(&self, script: F) -> Result<String, String>
+    where
+        F: FnOnce(PowerShellFlavor) -> String,
+    {
+        let flavor = self
+            .powershell_flavor()
+            .ok_or_else(|| "PowerShell not found".to_string())?;
+        self.run_command_with_timeout(
+            flavor.executable(),
+            &["-NoProfile", "-Command", &script(flavor)],
+        )
+    }
+
+    /// Detect which PowerShell is installed, caching the answer
+    ///
+    /// Only a successful detection is cached: a PowerShell that timed out
+    /// while starting is asked again next time.
+    pub fn powershell_flavor(&self) -> Option<PowerShellFlavor> {
+        // Held during detection, so concurrent tools don't each start PowerShell
+        let mut cached = self
+            .powershell_flavor
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if cached.is_none() {
+            // Try pwsh (PowerShell Core) first, then powershell (Windows PowerShell),
+            // trusting the reported edition over the executable name
+            *cached = ["pwsh", "powershell"].iter().find_map(|exe| {
+                self.run_command_with_timeout(
+                    exe,
+                    &["-NoProfile", "-Command", "$PSVersionTable.PSEdition"],
+                )
+                .ok()
+                .and_then(|edition| PowerShellFlavor::from_edition(&edition))
+            });
+        }
+        *cached
+    }
+
+    /// The user's shell aliases, read once from an interactive shell
+    ///
+    /// Empty when alias detection is off, for shells without aliases, or if the
+    /// shell's startup files don't finish within the timeout.
+    fn aliases(&self) -> &HashMap<String, String> {
+        self.aliases.get_or_init(|| {
+            if !self.detect_aliases {
+                return HashMap::new();
+            }
+
+            // Aliases live in the startup files, so the shell must be interactive;
+            // fish defines aliases as functions and `alias` lists those
+            let shell = match self.shell {
+                Shell::Bash => "bash",
+                Shell::Zsh => "zsh",
+                Shell::Fish => "fish",
+                Shell::PowerShell | Shell::Cmd => return HashMap::new(),
+            };
+
+            self.run_command_with_timeout(shell, &["-i", "-c", "alias"])
+                .map(|output| parse_aliases(&output))
+                .unwrap_or_default()
+        })
     }
 
     /// Scan PATH directories for executables matching prefix (used for CMD)
+    ///
+    /// Empty PATH entries are skipped rather than read as the current
+    /// directory (see `discovery::path_dirs`).
     fn scan_path_for_prefix(&self, prefix: &str) -> Result<String, String> {
         let path = std::env::var("PATH").map_err(|_| "PATH not set")?;
 
-        // Determine path separator based on platform
-        let separator = if cfg!(windows) { ';' } else { ':' };
-
         let mut matches = Vec::new();
         let prefix_lower = prefix.to_lowercase();
 
-        for dir in path.split(separator) {
+        for dir in crate::discovery::path_dirs(&path) {
             if let Ok(entries) = std::fs::read_dir(dir) {
                 for entry in entries.flatten() {
                     let name = entry.file_name().to_string_lossy().to_string();
@@ -506,6 +1101,82 @@ impl ToolExecutor {
     }
 }
 
+/// Names from `git branch` or `git remote` output, comma separated
+///
+/// Drops the `*` marking the current branch and entries like
+/// `(HEAD detached at 1a2b3c)`, keeping up to `MAX_GIT_CONTEXT_NAMES`.
+fn git_names(output: &str) -> String {
+    output
+        .lines()
+        .map(|line| line.trim().trim_start_matches("* ").trim())
+        .filter(|name| !name.is_empty() && !name.starts_with('('))
+        .take(MAX_GIT_CONTEXT_NAMES)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Timeout for running a command again after it timed out at `timeout`
+///
+/// Double the first, up to `MAX_RETRY_TIMEOUT_MS` and to the time
+/// `remaining` before the batch deadline, if there is one: a result that
+/// arrives after it is thrown away. None when that leaves no more time than
+/// the first run had.
+fn retry_timeout(timeout: Duration, remaining: Option<Duration>) -> Option<Duration> {
+    let retry = (timeout * 2).min(Duration::from_millis(MAX_RETRY_TIMEOUT_MS));
+    let retry = remaining.map_or(retry, |remaining| retry.min(remaining));
+    (retry > timeout).then_some(retry)
+}
+
+/// Paths from a binary lookup, one per line, primary first
+///
+/// `where` on Windows lists every match in PATH order (say `git.cmd` before
+/// `git.exe`). All of them are kept, up to `MAX_WHICH_MATCHES`, so the model
+/// can see shadowing, but the first `.exe` is moved to the front since
+/// that's usually the real binary.
+fn which_matches(output: &str) -> String {
+    let mut paths: Vec<&str> = Vec::new();
+    for path in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    if let Some(exe) = paths
+        .iter()
+        .position(|path| path.to_lowercase().ends_with(".exe"))
+    {
+        let exe = paths.remove(exe);
+        paths.insert(0, exe);
+    }
+
+    paths.truncate(MAX_WHICH_MATCHES);
+    paths.join("\n")
+}
+
+/// Read output line by line, keeping lines that aren't valid UTF-8
+///
+/// `BufRead::lines` fails on invalid UTF-8, which dropped the rest of some
+/// tools' help output; invalid bytes become replacement characters instead.
+/// Lines come back `\n`-terminated, with any `\r` before it removed.
+fn read_lines_lossy(reader: impl std::io::Read) -> String {
+    let mut reader = BufReader::new(reader);
+    let mut output = String::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let text = String::from_utf8_lossy(&line);
+                let text = text.strip_suffix('\n').unwrap_or(&text);
+                output.push_str(text.strip_suffix('\r').unwrap_or(text));
+                output.push('\n');
+            }
+        }
+    }
+    output
+}
+
 /// Check if a path is executable
 fn is_executable(path: &std::path::Path) -> bool {
     if !path.is_file() {
@@ -599,11 +1270,193 @@ impl WaitTimeoutExt for std::process::Child {
     }
 }
 
+/// Execute file_exists for `path` relative to `dir`
+///
+/// Only paths inside `dir` may be checked, so the model can learn whether
+/// this is a Rust or Node project but not probe the rest of the system.
+fn file_exists_in(dir: &Path, path: &str) -> ToolResult {
+    let relative = Path::new(path);
+    let inside = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || !inside {
+        return ToolResult::failure(format!(
+            "Only paths in the current directory can be checked: {}",
+            path
+        ));
+    }
+
+    if dir.join(relative).exists() {
+        ToolResult::success(format!("{} exists in the current directory", path))
+    } else {
+        ToolResult::success(format!("{} does not exist in the current directory", path))
+    }
+}
+
+// ========== Prompt Output Limits ==========
+
+/// How much of a tool's output goes into wit's prompt
+///
+/// List-style output reads best as whole lines; a blob like `--help` output
+/// is cut at a character count. In the config, `{"list_similar": {"lines": 10}}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLimit {
+    /// Keep the first N characters
+    Chars(usize),
+    /// Keep the first N lines
+    Lines(usize),
+}
+
+impl OutputLimit {
+    /// `output` cut to the limit, with `...` marking anything left out
+    pub fn apply(self, output: &str) -> String {
+        match self {
+            OutputLimit::Chars(max) => match output.char_indices().nth(max) {
+                Some((end, _)) => format!("{}...", &output[..end]),
+                None => output.to_string(),
+            },
+            OutputLimit::Lines(max) => {
+                let lines: Vec<&str> = output.lines().collect();
+                if lines.len() > max {
+                    format!("{}\n...", lines[..max].join("\n"))
+                } else {
+                    output.to_string()
+                }
+            }
+        }
+    }
+}
+
+/// Per-tool limits used unless the config sets its own
+pub fn default_output_limits() -> BTreeMap<String, OutputLimit> {
+    BTreeMap::from([(
+        "list_similar".to_string(),
+        OutputLimit::Lines(DEFAULT_PROMPT_OUTPUT_LINES),
+    )])
+}
+
+/// The limit for the tool named `tool`; tools without one are cut by characters
+pub fn output_limit(limits: &BTreeMap<String, OutputLimit>, tool: &str) -> OutputLimit {
+    limits
+        .get(tool)
+        .copied()
+        .unwrap_or(OutputLimit::Chars(DEFAULT_PROMPT_OUTPUT_CHARS))
+}
+
+// ========== Tool Sources ==========
+
+/// Something that can answer tool calls
+///
+/// `ToolExecutor` runs the real commands; `MockToolSource` replays recorded
+/// results so agent behavior can be tested the same on every machine.
+pub trait ToolSource {
+    /// Run `tool` and return its result
+    fn execute(&self, tool: &Tool) -> ToolResult;
+}
+
+impl ToolSource for ToolExecutor {
+    fn execute(&self, tool: &Tool) -> ToolResult {
+        ToolExecutor::execute(self, tool)
+    }
+}
+
+/// A recorded tool call and what it returned, as stored in fixtures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedTool {
+    pub tool: Tool,
+    pub result: ToolResult,
+}
+
+/// Replays recorded tool results instead of running anything
+///
+/// Tools without a recording fail with "No recorded result".
+#[derive(Debug, Clone, Default)]
+pub struct MockToolSource {
+    results: HashMap<Tool, ToolResult>,
+}
+
+impl MockToolSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load recordings from a JSON array of `RecordedTool`
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let recorded: Vec<RecordedTool> =
+            serde_json::from_str(json).map_err(|e| format!("Invalid tool fixture: {}", e))?;
+        let mut source = Self::new();
+        for RecordedTool { tool, result } in recorded {
+            source.record(tool, result);
+        }
+        Ok(source)
+    }
+
+    /// Serialize the recordings in the format `from_json` reads
+    pub fn to_json(&self) -> Result<String, String> {
+        let mut recorded: Vec<RecordedTool> = self
+            .results
+            .iter()
+            .map(|(tool, result)| RecordedTool {
+                tool: tool.clone(),
+                result: result.clone(),
+            })
+            .collect();
+        // Stable output for checked-in fixtures
+        recorded.sort_by_key(|r| format!("{:?}", r.tool));
+        serde_json::to_string_pretty(&recorded)
+            .map_err(|e| format!("Failed to serialize tool fixture: {}", e))
+    }
+
+    /// Answer `tool` with `result` from now on
+    pub fn record(&mut self, tool: Tool, result: ToolResult) {
+        self.results.insert(tool, result);
+    }
+}
+
+impl ToolSource for MockToolSource {
+    fn execute(&self, tool: &Tool) -> ToolResult {
+        self.results.get(tool).cloned().unwrap_or_else(|| {
+            ToolResult::failure(format!("No recorded result for {}", tool.name()))
+        })
+    }
+}
+
 // ========== Tests ==========
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // ===== Prompt Output Limit Tests =====
+
+    #[test]
+    fn test_output_limits_by_lines_and_chars() {
+        let limits = BTreeMap::from([("list_similar".to_string(), OutputLimit::Lines(3))]);
+
+        // Whole command names survive, however long they are
+        let similar = "kubectl\nkubectl-convert\nkubeadm-with-a-very-long-name\nkubelet";
+        assert_eq!(
+            output_limit(&limits, "list_similar").apply(similar),
+            "kubectl\nkubectl-convert\nkubeadm-with-a-very-long-name\n..."
+        );
+
+        let help = "x".repeat(DEFAULT_PROMPT_OUTPUT_CHARS + 50);
+        let cut = output_limit(&limits, "help_output").apply(&help);
+        assert_eq!(
+            cut,
+            format!("{}...", "x".repeat(DEFAULT_PROMPT_OUTPUT_CHARS))
+        );
+
+        // Short output is left alone either way
+        assert_eq!(OutputLimit::Lines(3).apply("a\nb"), "a\nb");
+        assert_eq!(OutputLimit::Chars(10).apply("café"), "café");
+        assert_eq!(
+            output_limit(&default_output_limits(), "list_similar"),
+            OutputLimit::Lines(DEFAULT_PROMPT_OUTPUT_LINES)
+        );
+    }
 
     // ===== Shell Tests =====
 
@@ -647,6 +1500,90 @@ mod tests {
         assert_eq!(format!("{}", Shell::Cmd), "cmd");
     }
 
+    #[test]
+    fn test_shell_prompt_hint() {
+        assert_eq!(Shell::Bash.prompt_hint(), "");
+
+        let hints = [
+            Shell::Zsh.prompt_hint(),
+            Shell::Fish.prompt_hint(),
+            Shell::PowerShell.prompt_hint(),
+            Shell::Cmd.prompt_hint(),
+        ];
+        for (i, hint) in hints.iter().enumerate() {
+            assert!(!hint.is_empty());
+            assert!(!hints[i + 1..].contains(hint), "Duplicate hint: {}", hint);
+        }
+        assert!(Shell::Cmd.prompt_hint().contains("%VAR%"));
+        assert!(Shell::PowerShell.prompt_hint().contains("$env:"));
+    }
+
+    // ===== PowerShell Flavor Tests =====
+
+    #[test]
+    fn test_shell_invocation() {
+        let command = "git status";
+        let core = PowerShellFlavor::Core;
+        assert_eq!(
+            Shell::Bash.invocation(command, core),
+            ["bash", "-c", "git status"]
+        );
+        assert_eq!(
+            Shell::Zsh.invocation(command, core),
+            ["zsh", "-c", "git status"]
+        );
+        assert_eq!(
+            Shell::Fish.invocation(command, core),
+            ["fish", "-c", "git status"]
+        );
+        assert_eq!(
+            Shell::PowerShell.invocation(command, core),
+            ["pwsh", "-NoProfile", "-Command", "git status"]
+        );
+        assert_eq!(
+            Shell::PowerShell.invocation(command, PowerShellFlavor::Windows),
+            ["powershell", "-NoProfile", "-Command", "git status"]
+        );
+        assert_eq!(
+            Shell::Cmd.invocation(command, core),
+            ["cmd", "/c", "git status"]
+        );
+    }
+
+    #[test]
+    fn test_powershell_flavor_from_edition() {
+        assert_eq!(
+            PowerShellFlavor::from_edition("Core\n"),
+            Some(PowerShellFlavor::Core)
+        );
+        assert_eq!(
+            PowerShellFlavor::from_edition("Desktop"),
+            Some(PowerShellFlavor::Windows)
+        );
+        assert_eq!(PowerShellFlavor::from_edition(""), None);
+    }
+
+    #[test]
+    fn test_powershell_flavor_executable() {
+        assert_eq!(PowerShellFlavor::Core.executable(), "pwsh");
+        assert_eq!(PowerShellFlavor::Windows.executable(), "powershell");
+    }
+
+    #[test]
+    fn test_powershell_flavor_script_variants() {
+        let core_help = PowerShellFlavor::Core.help_script("Get-Process");
+        let windows_help = PowerShellFlavor::Windows.help_script("Get-Process");
+        assert!(core_help.starts_with("Get-Help Get-Process"));
+        assert!(!core_help.contains("Out-String"));
+        assert!(windows_help.contains("Out-String -Stream"));
+        assert!(windows_help.contains("-First 30"));
+
+        let core_list = PowerShellFlavor::Core.list_similar_script("Get-");
+        let windows_list = PowerShellFlavor::Windows.list_similar_script("Get-");
+        assert!(core_list.contains("-ExpandProperty Name"));
+        assert!(windows_list.contains("-replace '\\.exe$'"));
+    }
+
     // ===== Tool Tests =====
 
     #[test]
@@ -720,12 +1657,281 @@ mod tests {
         assert_eq!(executor.timeout, Duration::from_secs(1));
     }
 
+    #[test]
+    fn test_retry_timeout() {
+        let ms = Duration::from_millis;
+
+        // Doubled, up to the cap
+        assert_eq!(retry_timeout(ms(200), None), Some(ms(400)));
+        assert_eq!(
+            retry_timeout(ms(1500), None),
+            Some(ms(MAX_RETRY_TIMEOUT_MS))
+        );
+        assert_eq!(retry_timeout(ms(MAX_RETRY_TIMEOUT_MS), None), None);
+
+        // Never past the batch deadline
+        assert_eq!(
+            retry_timeout(ms(DEFAULT_TIMEOUT_MS), Some(ms(700))),
+            Some(ms(700))
+        );
+        assert_eq!(retry_timeout(ms(DEFAULT_TIMEOUT_MS), Some(ms(300))), None);
+        assert_eq!(retry_timeout(ms(200), Some(ms(0))), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timed_out_command_is_retried_with_longer_timeout() {
+        let executor = ToolExecutor::new(Shell::Bash);
+        let timeout = Duration::from_millis(200);
+        // Outside `execute_all` there is no batch deadline to cap the retry
+        assert!(BATCH_DEADLINE.with(|d| d.get()).is_none());
+
+        // Too slow for 200ms, fast enough for the 400ms retry
+        let output = executor.run_command_within(timeout, "bash", &["-c", "sleep 0.3; echo done"]);
+        assert_eq!(output.as_deref().map(str::trim), Ok("done"));
+
+        // Still too slow for the retry
+        let started = Instant::now();
+        let output = executor.run_command_within(timeout, "sleep", &["5"]);
+        assert_eq!(output, Err(TIMED_OUT.to_string()));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_spawn_failure_is_not_retried() {
+        let executor = ToolExecutor::new(Shell::Bash);
+        let output =
+            executor.run_command_within(Duration::from_millis(200), "fix-no-such-command", &[]);
+        assert!(output.unwrap_err().starts_with("Failed to spawn"));
+    }
+
+    #[test]
+    fn test_cache_entry_hot_outlives_base_ttl() {
+        let base_ttl = Duration::from_secs(60);
+        let start = Instant::now();
+
+        let cold = CacheEntry::new(ToolResult::success("/usr/bin/git".to_string()), start);
+        let mut hot = cold.clone();
+        hot.hits = HOT_ENTRY_MIN_HITS;
+        hot.last_access = start + Duration::from_secs(50);
+
+        let later = start + Duration::from_secs(90);
+        assert!(!cold.is_fresh(base_ttl, later));
+        assert!(hot.is_fresh(base_ttl, later));
+
+        // Both are fresh inside the base TTL
+        assert!(cold.is_fresh(base_ttl, start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_cache_entry_hot_respects_max_age() {
+        let base_ttl = Duration::from_secs(60);
+        let start = Instant::now();
+
+        let mut hot = CacheEntry::new(ToolResult::success("ok".to_string()), start);
+        hot.hits = 100;
+
+        let past_max = start + MAX_CACHE_ENTRY_AGE;
+        hot.last_access = past_max - Duration::from_secs(1);
+        assert!(!hot.is_fresh(base_ttl, past_max));
+    }
+
+    #[test]
+    fn test_cache_entry_goes_cold_when_unused() {
+        let base_ttl = Duration::from_secs(60);
+        let start = Instant::now();
+
+        let mut entry = CacheEntry::new(ToolResult::success("ok".to_string()), start);
+        entry.hits = HOT_ENTRY_MIN_HITS;
+
+        // Hot by count, but not touched for longer than the base TTL
+        assert!(!entry.is_fresh(base_ttl, start + Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_run_bounded_until_limits_concurrency() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let items: Vec<usize> = (0..20).collect();
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        let (r, p) = (Arc::clone(&running), Arc::clone(&peak));
+        let results = run_bounded_until(items.clone(), 4, deadline, move |item| {
+            let now = r.fetch_add(1, Ordering::SeqCst) + 1;
+            p.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            r.fetch_sub(1, Ordering::SeqCst);
+            item * 2
+        });
+
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+        assert!(
+            peak.load(Ordering::SeqCst) > 1,
+            "Tools should still run in parallel"
+        );
+        assert_eq!(
+            results,
+            items.iter().map(|i| Some(i * 2)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_run_bounded_until_empty_and_zero_limit() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert!(run_bounded_until(Vec::<u32>::new(), 4, deadline, |i| i).is_empty());
+
+        // A zero limit still makes progress on one thread
+        assert_eq!(
+            run_bounded_until(vec![1, 2, 3], 0, deadline, |i| i + 1),
+            vec![Some(2), Some(3), Some(4)]
+        );
+    }
+
+    #[test]
+    fn test_run_bounded_until_abandons_slow_items() {
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(300);
+        let delays = vec![10, 2000, 20];
+
+        let results = run_bounded_until(delays, 4, deadline, |ms| {
+            std::thread::sleep(Duration::from_millis(ms));
+            ms
+        });
+
+        assert_eq!(results, vec![Some(10), None, Some(20)]);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_run_bounded_until_skips_items_queued_past_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(100);
+
+        // One worker: the first item outlives the deadline, the second never starts
+        let results = run_bounded_until(vec![500, 1], 1, deadline, |ms| {
+            std::thread::sleep(Duration::from_millis(ms));
+            ms
+        });
+
+        assert_eq!(results, vec![None, None]);
+    }
+
+    #[test]
+    fn test_man_page_has_shorter_timeout() {
+        let executor = ToolExecutor::new(Shell::Bash);
+        let man = Tool::ManPage {
+            command: "ls".to_string(),
+        };
+        let help = Tool::HelpOutput {
+            command: "ls".to_string(),
+        };
+
+        assert_eq!(
+            executor.tool_timeout(&man),
+            Duration::from_millis(MAN_PAGE_TIMEOUT_MS)
+        );
+        assert_eq!(
+            executor.tool_timeout(&help),
+            Duration::from_millis(DEFAULT_TIMEOUT_MS)
+        );
+
+        // A shorter general timeout still applies to man_page
+        let executor = executor.with_timeout(Duration::from_millis(50));
+        assert_eq!(executor.tool_timeout(&man), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_execute_all_reports_results_in_order() {
+        let executor = Arc::new(ToolExecutor::new(Shell::Bash));
+        let results = executor.execute_all(&[
+            Tool::GetEnvVar {
+                name: "PATH".to_string(),
+            },
+            Tool::GetEnvVar {
+                name: "FIX_TEST_UNSET_VAR_12345".to_string(),
+            },
+        ]);
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].success);
+        assert!(!results[1].success);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_execute_all_runs_duplicates_once_and_uses_cache() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A "command" whose --help output counts how often it has been run
+        let dir = std::env::temp_dir().join(format!("fix-execute-all-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runs = dir.join("runs");
+        let script = dir.join("counted");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho run >> '{}'\necho \"usage: counted\"\n",
+                runs.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let run_count = || {
+            std::fs::read_to_string(&runs)
+                .unwrap_or_default()
+                .lines()
+                .count()
+        };
+
+        let executor = Arc::new(ToolExecutor::new(Shell::Bash));
+        let help = Tool::HelpOutput {
+            command: script.display().to_string(),
+        };
+        let env = Tool::GetEnvVar {
+            name: "PATH".to_string(),
+        };
+
+        let results = executor.execute_all(&[help.clone(), env, help.clone()]);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].output.contains("usage: counted"));
+        assert_eq!(results[2].output, results[0].output);
+        assert_eq!(results[1].output, std::env::var("PATH").unwrap());
+        assert_eq!(run_count(), 1);
+
+        // A later batch is answered from the cache
+        let results = executor.execute_all(&[help]);
+        assert!(results[0].output.contains("usage: counted"));
+        assert_eq!(run_count(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn test_executor_with_cache_ttl() {
         let executor = ToolExecutor::new(Shell::Bash).with_cache_ttl(Duration::from_secs(120));
         assert_eq!(executor.cache_ttl, Duration::from_secs(120));
     }
 
+    #[test]
+    fn test_executor_from_config_uses_cache_ttl() {
+        let config = crate::Config {
+            tool_cache_ttl_secs: 900,
+            ..crate::Config::default()
+        };
+
+        let executor = ToolExecutor::from_config(Shell::Zsh, &config);
+        assert_eq!(executor.shell(), Shell::Zsh);
+        assert_eq!(executor.cache_ttl, Duration::from_secs(900));
+    }
+
+    #[test]
+    fn test_executor_default_cache_ttl() {
+        let executor = ToolExecutor::new(Shell::Bash);
+        assert_eq!(
+            executor.cache_ttl,
+            Duration::from_secs(DEFAULT_CACHE_TTL_SECS)
+        );
+    }
+
     #[test]
     fn test_get_env_var_path() {
         let executor = ToolExecutor::new(Shell::Bash);
@@ -738,6 +1944,42 @@ mod tests {
         assert!(!result.output.is_empty());
     }
 
+    #[test]
+    fn test_get_env_var_non_secret_returned_in_full() {
+        let executor = ToolExecutor::new(Shell::Bash);
+        let result = executor.execute(&Tool::GetEnvVar {
+            name: "PATH".to_string(),
+        });
+
+        assert_eq!(result.output, std::env::var("PATH").unwrap());
+    }
+
+    #[test]
+    fn test_get_env_var_redacts_secret() {
+        std::env::set_var("FIX_TEST_GITHUB_TOKEN", "ghp_0123456789");
+        let executor = ToolExecutor::new(Shell::Bash);
+        let result = executor.execute(&Tool::GetEnvVar {
+            name: "FIX_TEST_GITHUB_TOKEN".to_string(),
+        });
+
+        assert!(result.success);
+        assert_eq!(result.output, "set (redacted, 14 chars)");
+    }
+
+    #[test]
+    fn test_is_secret_env_name() {
+        assert!(is_secret_env_name("AWS_SECRET_ACCESS_KEY"));
+        assert!(is_secret_env_name("GITHUB_TOKEN"));
+        assert!(is_secret_env_name("DB_PASSWORD"));
+        assert!(is_secret_env_name("openai_api_key"));
+        assert!(is_secret_env_name("APIKEY"));
+
+        assert!(!is_secret_env_name("PATH"));
+        assert!(!is_secret_env_name("HOME"));
+        assert!(!is_secret_env_name("KEYBOARD_LAYOUT"));
+        assert!(!is_secret_env_name("EDITOR"));
+    }
+
     #[test]
     fn test_get_env_var_not_set() {
         let executor = ToolExecutor::new(Shell::Bash);
@@ -800,6 +2042,31 @@ mod tests {
         });
 
         assert!(!result.success);
+        // A clean miss is told apart from a lookup that failed to run
+        assert_eq!(
+            result.error,
+            Some(not_found_error("nonexistent_command_12345"))
+        );
+    }
+
+    #[test]
+    fn test_which_binary_reports_builtins() {
+        for (shell, builtin) in [
+            (Shell::Fish, "cd"),
+            (Shell::Cmd, "dir"),
+            (Shell::Cmd, "SET"),
+            (Shell::PowerShell, "cd"),
+        ] {
+            let result = ToolExecutor::new(shell).execute(&Tool::WhichBinary {
+                command: builtin.to_string(),
+            });
+            assert!(result.success, "{:?} {}", shell, builtin);
+            assert_eq!(result.output, format!("builtin: {}", builtin));
+        }
+
+        assert!(!Shell::Bash.is_builtin("cd"));
+        assert!(!Shell::Fish.is_builtin("CD"));
+        assert!(!Shell::Cmd.is_builtin("git"));
     }
 
     #[cfg(unix)]
@@ -821,6 +2088,72 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_aliases_bash() {
+        let output = "alias g='git'\nalias gs='git status'\nalias say='echo '\\''hi'\\'''\n";
+        let aliases = parse_aliases(output);
+
+        assert_eq!(aliases.len(), 3);
+        assert_eq!(aliases["g"], "git");
+        assert_eq!(aliases["gs"], "git status");
+        assert_eq!(aliases["say"], "echo 'hi'");
+    }
+
+    #[test]
+    fn test_parse_aliases_zsh() {
+        let aliases = parse_aliases("g=git\ngs='git status'\n'-'='cd -'\n");
+
+        assert_eq!(aliases["g"], "git");
+        assert_eq!(aliases["gs"], "git status");
+        assert_eq!(aliases["-"], "cd -");
+    }
+
+    #[test]
+    fn test_parse_aliases_fish() {
+        let aliases = parse_aliases("alias g git\nalias gs 'git status'\nalias ll 'ls -l'\n");
+
+        assert_eq!(aliases.len(), 3);
+        assert_eq!(aliases["g"], "git");
+        assert_eq!(aliases["gs"], "git status");
+        assert_eq!(aliases["ll"], "ls -l");
+    }
+
+    #[test]
+    fn test_parse_aliases_ignores_noise() {
+        let output = "bash: no job control in this shell\n\nalias g='git'\n";
+        let aliases = parse_aliases(output);
+
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases["g"], "git");
+    }
+
+    #[test]
+    fn test_aliases_disabled_by_default() {
+        let executor = ToolExecutor::new(Shell::Bash);
+        assert!(executor.aliases().is_empty());
+    }
+
+    #[test]
+    fn test_which_binary_reports_alias() {
+        let executor = ToolExecutor::new(Shell::Bash).with_aliases(true);
+        executor
+            .aliases
+            .set(parse_aliases("alias g='git'\n"))
+            .unwrap();
+
+        let result = executor.execute(&Tool::WhichBinary {
+            command: "g".to_string(),
+        });
+        assert!(result.success);
+        assert_eq!(result.output, "alias: g=git");
+
+        let result = executor.execute(&Tool::ListSimilar {
+            prefix: "g".to_string(),
+        });
+        assert!(result.success);
+        assert_eq!(result.output.lines().next(), Some("alias: g=git"));
+    }
+
     #[test]
     fn test_scan_path_for_prefix() {
         let executor = ToolExecutor::new(Shell::Cmd);
@@ -836,6 +2169,103 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mock_tool_source_roundtrip() {
+        let mut source = MockToolSource::new();
+        let which = Tool::WhichBinary {
+            command: "git".to_string(),
+        };
+        source.record(
+            which.clone(),
+            ToolResult::success("/usr/bin/git".to_string()),
+        );
+
+        let replayed = MockToolSource::from_json(&source.to_json().unwrap()).unwrap();
+        let result = replayed.execute(&which);
+        assert!(result.success);
+        assert_eq!(result.output, "/usr/bin/git");
+
+        let missing = replayed.execute(&Tool::GetEnvVar {
+            name: "HOME".to_string(),
+        });
+        assert!(!missing.success);
+        assert_eq!(
+            missing.error.as_deref(),
+            Some("No recorded result for get_env_var")
+        );
+        assert!(MockToolSource::from_json("{").is_err());
+    }
+
+    #[test]
+    fn test_file_exists_in_directory() {
+        let dir = std::env::temp_dir().join(format!("fix-file-exists-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let missing = file_exists_in(&dir, "Cargo.toml");
+        std::fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+        let present = file_exists_in(&dir, "Cargo.toml");
+        let outside = file_exists_in(&dir, "../Cargo.toml");
+        let absolute = file_exists_in(&dir, "/etc/passwd");
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(missing.success);
+        assert!(
+            missing.output.contains("does not exist"),
+            "{}",
+            missing.output
+        );
+        assert!(present.success);
+        assert_eq!(present.output, "Cargo.toml exists in the current directory");
+        assert!(!outside.success);
+        assert!(!absolute.success);
+    }
+
+    #[test]
+    fn test_git_names_from_branch_output() {
+        let branch_output = "  feature/login\n* main\n  (HEAD detached at 1a2b3c)\n  release-1.2\n";
+        assert_eq!(git_names(branch_output), "feature/login, main, release-1.2");
+        assert_eq!(git_names("origin\nupstream\n"), "origin, upstream");
+        assert_eq!(git_names(""), "");
+
+        let many: String = (0..30).map(|i| format!("  b{}\n", i)).collect();
+        assert_eq!(git_names(&many).split(", ").count(), MAX_GIT_CONTEXT_NAMES);
+    }
+
+    #[test]
+    fn test_which_matches_promotes_exe() {
+        let where_output = "C:\\Program Files\\Git\\cmd\\git.cmd\r\n\
+                            C:\\Program Files\\Git\\cmd\\git.exe\r\n\
+                            C:\\tools\\git.bat\r\n";
+        assert_eq!(
+            which_matches(where_output),
+            "C:\\Program Files\\Git\\cmd\\git.exe\n\
+             C:\\Program Files\\Git\\cmd\\git.cmd\n\
+             C:\\tools\\git.bat"
+        );
+    }
+
+    #[test]
+    fn test_which_matches_single_and_bounded() {
+        assert_eq!(which_matches("/usr/bin/git\n"), "/usr/bin/git");
+        assert_eq!(which_matches("\n  \n"), "");
+
+        let many: String = (0..8)
+            .map(|i| format!("C:\\bin{}\\tool.cmd\n", i))
+            .collect();
+        assert_eq!(which_matches(&many).lines().count(), MAX_WHICH_MATCHES);
+    }
+
+    #[test]
+    fn test_read_lines_lossy_keeps_invalid_utf8() {
+        let input: &[u8] = b"usage: tool [opts]\r\n\xff\xfeflags \xe2\x28\n--help\tshow help";
+        assert_eq!(
+            read_lines_lossy(input),
+            "usage: tool [opts]\n\u{FFFD}\u{FFFD}flags \u{FFFD}(\n--help\tshow help\n"
+        );
+        assert_eq!(read_lines_lossy(&b""[..]), "");
+    }
+
     #[test]
     fn test_extract_man_synopsis() {
         let man_output = r#"
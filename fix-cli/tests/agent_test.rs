@@ -2,9 +2,10 @@
 //!
 //! These tests verify the agentic loop works correctly with real tool execution.
 
-use fix_lib::agent::{agentic_correct, Context, MAX_ITERATIONS};
+use fix_lib::agent::{agentic_correct, agentic_correct_with, Context, MAX_ITERATIONS};
 use fix_lib::parser::{parse_response, ModelResponse};
-use fix_lib::tools::Shell;
+use fix_lib::sampling::{default_thinking_tags, ThinkingTags};
+use fix_lib::tools::{MockToolSource, Shell};
 
 // ===== Integration Tests =====
 
@@ -50,6 +51,55 @@ fn test_agent_loop_with_mock_model_tool_call_then_answer() {
     assert!(result.tools_used);
 }
 
+/// Tool results recorded for `gti stauts` on a machine with git installed
+const GTI_FIXTURE: &str = r#"[
+    {"tool": {"list_similar": {"prefix": "gti"}},
+     "result": {"success": true, "output": "git\ngitk", "error": null}},
+    {"tool": {"help_output": {"command": "git"}},
+     "result": {"success": true, "output": "usage: git <command>\n   status    Show the working tree status", "error": null}}
+]"#;
+
+#[test]
+fn test_agent_loop_replays_recorded_tools() {
+    let tools = MockToolSource::from_json(GTI_FIXTURE).unwrap();
+    let mut prompts = Vec::new();
+
+    // Answers from what the tools returned, so the result only holds if the
+    // recorded outputs reached the prompt
+    let tags = default_thinking_tags();
+    let result = agentic_correct_with("gti stauts", Shell::Bash, None, &tools, &tags, |prompt| {
+        prompts.push(prompt.to_string());
+        if !prompt.contains("[list_similar]") {
+            r#"<tool_call>{"name": "list_similar", "args": {"prefix": "gti"}}</tool_call>"#
+                .to_string()
+        } else if !prompt.contains("[help_output]") {
+            r#"<tool_call>{"name": "help_output", "args": {"command": "git"}}</tool_call>"#
+                .to_string()
+        } else if prompt.contains("[help_output]: usage: git <command>\n   status") {
+            "<answer>git status</answer>".to_string()
+        } else {
+            "<answer>gti stauts</answer>".to_string()
+        }
+    });
+
+    assert_eq!(result.command, "git status");
+    assert_eq!(result.iterations, 3);
+    assert!(result.tools_used);
+    assert!(prompts[1].contains("[list_similar]: git\ngitk"));
+}
+
+#[test]
+fn test_agent_loop_uses_configured_thinking_tags() {
+    let tools = MockToolSource::from_json(GTI_FIXTURE).unwrap();
+    let tags = [ThinkingTags::new("[plan]", "[/plan]")];
+
+    let result = agentic_correct_with("gti status", Shell::Bash, None, &tools, &tags, |_| {
+        "[plan]gti is a typo for git[/plan] git status".to_string()
+    });
+
+    assert_eq!(result.command, "git status");
+}
+
 #[test]
 fn test_agent_loop_respects_max_iterations() {
     // Model that always requests a tool (should be stopped at MAX_ITERATIONS)
@@ -105,13 +105,13 @@ fn test_save_and_load_cache() {
     );
 
     // Clean up
-    let _ = fs::remove_file(cache_path());
+    let _ = fs::remove_file(cache_path().unwrap());
 }
 
 #[test]
 fn test_load_or_create_cache_creates_if_missing() {
     // Remove cache file if it exists
-    let _ = fs::remove_file(cache_path());
+    let _ = fs::remove_file(cache_path().unwrap());
 
     let cache = load_or_create_cache();
 
@@ -121,7 +121,7 @@ fn test_load_or_create_cache_creates_if_missing() {
 
 #[test]
 fn test_cache_path_location() {
-    let path = cache_path();
+    let path = cache_path().unwrap();
 
     assert!(path.ends_with("tools_cache.json"));
     assert!(path.to_string_lossy().contains("fix"));
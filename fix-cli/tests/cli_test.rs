@@ -100,6 +100,178 @@ fn test_binary_verbose_flag() {
     assert!(output.status.success(), "Verbose + help should work");
 }
 
+#[test]
+fn test_binary_help_lists_doctor() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .arg("--help")
+        .output()
+        .expect("Failed to execute binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("--doctor"), "Help should list --doctor");
+}
+
+#[test]
+fn test_binary_bash_completions() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .args(["--completions", "bash"])
+        .output()
+        .expect("Failed to execute binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "Completions should exit successfully"
+    );
+    assert!(
+        stdout.contains("--direct"),
+        "Script should complete --direct"
+    );
+}
+
+/// Run `fix --print-prompt-template` with its config read from `config_home`
+#[cfg(target_os = "linux")]
+fn print_prompt_template(config_home: &std::path::Path) -> String {
+    let output = Command::new(get_binary_path())
+        .arg("--print-prompt-template")
+        .env("XDG_CONFIG_HOME", config_home)
+        .output()
+        .expect("Failed to execute binary");
+
+    assert!(output.status.success());
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_binary_print_prompt_template() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    let config_home =
+        std::env::temp_dir().join(format!("fix-prompt-template-test-{}", std::process::id()));
+    let config_dir = config_home.join("fix");
+    std::fs::create_dir_all(&config_dir).unwrap();
+
+    // No override: the default template, placeholders intact
+    let default = print_prompt_template(&config_home);
+    assert!(default.contains("shell command corrector for {shell}"));
+
+    std::fs::write(
+        config_dir.join("config.json"),
+        r#"{"default_model": "m", "system_prompt": "Custom {shell} fixer for {command}"}"#,
+    )
+    .unwrap();
+    let custom = print_prompt_template(&config_home);
+
+    let _ = std::fs::remove_dir_all(&config_home);
+
+    assert_eq!(custom.trim(), "Custom {shell} fixer for {command}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_binary_config_dir_follows_xdg_config_home() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    let config_home = std::env::temp_dir().join(format!("fix-xdg-cli-test-{}", std::process::id()));
+
+    let output = Command::new(get_binary_path())
+        .arg("--show-config")
+        .env("XDG_CONFIG_HOME", &config_home)
+        .output()
+        .expect("Failed to execute binary");
+
+    let _ = std::fs::remove_dir_all(&config_home);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let expected = config_home.join("fix").join("config.json");
+    assert!(
+        stdout.contains(&expected.display().to_string()),
+        "Config path should be under XDG_CONFIG_HOME, got: {}",
+        stdout
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_binary_unwritable_config_dir_errors() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    // Directories can't be created underneath a regular file, even as root
+    let blocker = std::env::temp_dir().join(format!("fix-xdg-blocker-test-{}", std::process::id()));
+    std::fs::write(&blocker, b"").unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg("--show-config")
+        .env("XDG_CONFIG_HOME", blocker.join("config"))
+        .env("HOME", blocker.join("home"))
+        .output()
+        .expect("Failed to execute binary");
+
+    let _ = std::fs::remove_file(&blocker);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("No writable config directory"),
+        "Should explain the missing config directory, got: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_binary_model_dir_flag_overrides_env() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    let root = std::env::temp_dir().join(format!("fix-model-dir-cli-test-{}", std::process::id()));
+    let flag_dir = root.join("flag");
+    std::fs::create_dir_all(&flag_dir).unwrap();
+    let model = flag_dir.join("qwen3-correct-0.6B.gguf");
+    std::fs::write(&model, b"").unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg("--model-dir")
+        .arg(&flag_dir)
+        .arg("--show-config")
+        .env("FIX_MODEL_DIR", root.join("env"))
+        .env("XDG_CONFIG_HOME", root.join("config"))
+        .output()
+        .expect("Failed to execute binary");
+
+    let _ = std::fs::remove_dir_all(&root);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("Model path: {}", model.display())),
+        "Model should be found in --model-dir, got: {}",
+        stdout
+    );
+}
+
 #[test]
 fn test_binary_invalid_flag() {
     if !binary_exists() {
@@ -139,3 +311,91 @@ fn test_binary_no_args_without_model() {
     let _ = String::from_utf8_lossy(&output.stdout);
     let _ = String::from_utf8_lossy(&output.stderr);
 }
+
+#[test]
+fn test_binary_input_denylist_stops_before_inference() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    let root = std::env::temp_dir().join(format!("fix-denylist-cli-test-{}", std::process::id()));
+    let config_dir = root.join("fix");
+    std::fs::create_dir_all(&config_dir).unwrap();
+    std::fs::write(
+        config_dir.join("config.json"),
+        r#"{"default_model": "m", "input_denylist": ["--password\\b"]}"#,
+    )
+    .unwrap();
+
+    let run = |command: &[&str]| {
+        Command::new(get_binary_path())
+            .arg("--direct")
+            .arg("--model")
+            .arg(root.join("missing.gguf"))
+            .arg("--")
+            .args(command)
+            .env("XDG_CONFIG_HOME", &root)
+            .output()
+            .expect("Failed to execute binary")
+    };
+    let denied = run(&["mysql", "--password", "hunter2"]);
+    let allowed = run(&["mysql", "-p"]);
+
+    let _ = std::fs::remove_dir_all(&root);
+
+    // Refused before the model is even looked for
+    assert!(!denied.status.success());
+    let stderr = String::from_utf8_lossy(&denied.stderr);
+    assert!(
+        stderr.contains("input_denylist") && !stderr.contains("Model not found"),
+        "Denied input should stop before inference, got: {}",
+        stderr
+    );
+
+    let stderr = String::from_utf8_lossy(&allowed.stderr);
+    assert!(
+        !stderr.contains("input_denylist") && stderr.contains("Model not found"),
+        "Other input should go on to load the model, got: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_binary_filter_always_prints_one_line() {
+    if !binary_exists() {
+        eprintln!("Binary not found, skipping integration test");
+        return;
+    }
+
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let root = std::env::temp_dir().join(format!("fix-filter-cli-test-{}", std::process::id()));
+    let filter = |input: &[u8]| {
+        let mut child = Command::new(get_binary_path())
+            .args(["--filter", "--direct", "--model"])
+            .arg(root.join("missing.gguf"))
+            .env("XDG_CONFIG_HOME", &root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to execute binary");
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    // Nothing to correct
+    let empty = filter(b"  \n");
+    assert_eq!(empty.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&empty.stdout), "\n");
+
+    // A failure still prints the empty result, with the reason on stderr
+    let failed = filter(b"gti status\n");
+    assert_eq!(failed.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&failed.stdout), "\n");
+    assert!(String::from_utf8_lossy(&failed.stderr).contains("Model not found"));
+
+    let _ = std::fs::remove_dir_all(&root);
+}
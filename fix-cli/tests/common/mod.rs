@@ -0,0 +1,48 @@
+//! Helpers shared by the integration tests
+
+use std::io::{BufRead, BufReader, Write};
+use std::thread::JoinHandle;
+
+/// Answer one HTTP request per response on a local port
+///
+/// `responses` gets the server's address, for responses that link back to
+/// it, and each is sent as-is once the request's headers are read. Returns
+/// the address and a handle yielding the request lines received.
+pub fn serve_http<F>(responses: F) -> (String, JoinHandle<Vec<String>>)
+where
+    F: FnOnce(&str) -> Vec<Vec<u8>>,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let responses = responses(&addr);
+    let server = std::thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            // Drain headers before replying
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            stream.write_all(&response).unwrap();
+            requests.push(request_line);
+        }
+        requests
+    });
+    (addr, server)
+}
+
+/// A complete `200 OK` response with extra `headers` (each ending in CRLF)
+pub fn http_ok(headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        headers,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
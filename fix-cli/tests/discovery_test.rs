@@ -75,8 +75,7 @@ fn test_extract_description_ls() {
         // ls should have some description (might be from --help or --version)
         // We can't guarantee exact text, but it should return something
         // Note: This may fail on some systems, so we'll be lenient
-        if desc.is_some() {
-            let text = desc.unwrap();
+        if let Some(text) = desc {
             assert!(!text.is_empty());
             assert!(text.len() < 200); // Should be a brief description
         }
@@ -49,28 +49,6 @@ fn run_wit(args: &[&str]) -> std::process::Output {
         .expect("Failed to execute wit command")
 }
 
-/// Run wit inference and return None if it fails (for CI resilience)
-/// This allows tests to skip gracefully when inference is flaky on CI
-fn try_run_wit_inference(args: &[&str]) -> Option<String> {
-    let output = Command::new(get_wit_binary_path())
-        .args(args)
-        .output()
-        .ok()?;
-
-    if !output.status.success() {
-        eprintln!("wit inference failed with non-zero exit, skipping test assertions");
-        return None;
-    }
-
-    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    if stdout.is_empty() {
-        eprintln!("Empty wit inference output, skipping test assertions");
-        return None;
-    }
-
-    Some(stdout)
-}
-
 // ========== Basic Execution Tests ==========
 
 #[test]
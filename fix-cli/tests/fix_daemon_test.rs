@@ -345,3 +345,147 @@ fn test_fix_status_when_no_daemon() {
         stderr
     );
 }
+
+/// A stand-in for another session's fix daemon, in a private temp dir
+///
+/// This process plays the daemon: its pid is in the pid file, and a thread
+/// answers every request on the socket with `response`.
+#[cfg(unix)]
+struct FakeDaemon {
+    dir: std::path::PathBuf,
+    model: std::path::PathBuf,
+    socket: std::path::PathBuf,
+    pid_file: std::path::PathBuf,
+    stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(unix)]
+impl FakeDaemon {
+    fn start(name: &str, response: serde_json::Value) -> Self {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixListener;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        // A private temp dir keeps the fake daemon away from real ones and other tests
+        let dir = std::env::temp_dir().join(format!("fix-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let uid = users::get_current_uid();
+        let socket = dir.join(format!("fix-daemon-{}.sock", uid));
+        let pid_file = dir.join(format!("fix-daemon-{}.pid", uid));
+        let model = dir.join("model.gguf");
+        std::fs::write(&model, b"GGUF\x03\x00\x00\x00\x00").unwrap();
+
+        std::fs::write(&pid_file, std::process::id().to_string()).unwrap();
+        let listener = UnixListener::bind(&socket).unwrap();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let saw_stop = Arc::clone(&stopped);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut line = String::new();
+                if BufReader::new(&stream).read_line(&mut line).is_err() {
+                    continue;
+                }
+                if line.contains("\"stop\"") {
+                    saw_stop.store(true, Ordering::SeqCst);
+                }
+                let _ = writeln!(&stream, "{}", response);
+            }
+        });
+
+        FakeDaemon {
+            dir,
+            model,
+            socket,
+            pid_file,
+            stopped,
+        }
+    }
+
+    /// Run fix against this daemon with `args` before the command
+    fn run_fix(&self, args: &[&str], command: &str) -> std::process::Output {
+        Command::new(get_binary_path())
+            .env("TMPDIR", &self.dir)
+            .args(args)
+            .arg("--model")
+            .arg(&self.model)
+            .arg(command)
+            .output()
+            .expect("Failed to execute fix binary")
+    }
+
+    fn was_stopped(&self) -> bool {
+        self.stopped.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for FakeDaemon {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fix_once_keeps_daemon_it_did_not_start() {
+    if !binary_exists() {
+        eprintln!("fix binary not found, skipping test");
+        return;
+    }
+
+    let daemon = FakeDaemon::start(
+        "once",
+        serde_json::json!({
+            "protocol_version": fix_lib::DAEMON_PROTOCOL_VERSION,
+            "success": true,
+            "output": "git status",
+            "error": null,
+        }),
+    );
+    let output = daemon.run_fix(&["--once"], "gti status");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(
+        stdout.contains("git status"),
+        "--once should correct through the running daemon. stdout: '{}', stderr: '{}'",
+        stdout,
+        stderr
+    );
+    assert!(
+        !daemon.was_stopped(),
+        "--once must not stop a daemon it didn't start"
+    );
+    assert!(daemon.socket.exists() && daemon.pid_file.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fix_reports_daemon_error_plainly() {
+    if !binary_exists() {
+        eprintln!("fix binary not found, skipping test");
+        return;
+    }
+
+    let daemon = FakeDaemon::start(
+        "daemon-error",
+        serde_json::json!({
+            "protocol_version": fix_lib::DAEMON_PROTOCOL_VERSION,
+            "success": false,
+            "output": "",
+            "error": "Tokenization failed: bad input",
+        }),
+    );
+    let output = daemon.run_fix(&["--once"], "gti status");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr.contains("Error: Tokenization failed: bad input\n"),
+        "stderr: '{}'",
+        stderr
+    );
+    assert!(!stderr.contains('"'), "stderr: '{}'", stderr);
+}
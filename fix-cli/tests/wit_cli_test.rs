@@ -3,6 +3,8 @@
 //! These tests verify the wit CLI binary works correctly when executed as a subprocess.
 //! Mirrors the test patterns used in cli_test.rs for fix binary.
 
+mod common;
+
 use std::process::Command;
 use std::time::Duration;
 
@@ -48,6 +50,29 @@ fn test_wit_help_flag() {
     assert!(output.status.success(), "Help should exit successfully");
 }
 
+#[test]
+fn test_wit_bash_completions() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .args(["--completions", "bash"])
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "Completions should exit successfully"
+    );
+    assert!(
+        stdout.contains("--direct"),
+        "Script should complete --direct"
+    );
+}
+
 #[test]
 fn test_wit_version_flag() {
     if !binary_exists() {
@@ -363,8 +388,303 @@ fn test_wit_verbose_direct() {
     );
 }
 
+// ========== Prompt Tests ==========
+
+#[test]
+fn test_wit_dry_run_includes_extra_tool_result() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .args([
+            "--dry-run",
+            "--shell",
+            "bash",
+            "--extra-tool-result",
+            "hook=command not found: zzqxv",
+            "--extra-tool-result",
+            "exit_code=127",
+            "zzqxv",
+            "status",
+        ])
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "Dry run should exit successfully");
+    assert!(stdout.contains("Input: zzqxv status"), "Prompt: {}", stdout);
+
+    let tool_section = stdout
+        .split("Tool results:\n")
+        .nth(1)
+        .expect("Prompt should have a Tool results section");
+    assert!(
+        tool_section.contains("- hook: command not found: zzqxv\n"),
+        "Injected result missing: {}",
+        stdout
+    );
+    assert!(tool_section.contains("- exit_code: 127\n"));
+}
+
+#[test]
+fn test_wit_dry_run_keeps_multiline_input() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let input = "dcoker run \\\n  -it ubuntu bash";
+    let output = Command::new(get_binary_path())
+        .args(["--dry-run", "--shell", "bash", input])
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "Dry run should exit successfully");
+    assert!(
+        stdout.contains(&format!("Input: {}\n", input)),
+        "Continuation line should reach the prompt intact: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_wit_dry_run_nl_prompt_generates_from_description() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let description = "list all pdf files modified today";
+    let output = Command::new(get_binary_path())
+        .args(["--dry-run", "--nl", "--shell", "bash", description])
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "Dry run should exit successfully");
+    assert!(
+        stdout.contains(&format!("Task: {}\n", description)),
+        "Prompt: {}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Write the single command that does it"),
+        "Prompt should ask for a new command: {}",
+        stdout
+    );
+    assert!(
+        !stdout.contains("generate the correct command") && !stdout.contains("Tool results:"),
+        "Prompt should not ask for a typo correction: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_wit_dry_run_explain_only_prompt() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .args([
+            "--dry-run",
+            "--explain-only",
+            "--shell",
+            "bash",
+            "tar -xzf a.tgz",
+        ])
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "Dry run should exit successfully");
+    assert!(
+        stdout.contains("Input: tar -xzf a.tgz\n"),
+        "Prompt: {}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Describe what it does in one line"),
+        "Prompt should ask for a description: {}",
+        stdout
+    );
+    assert!(
+        !stdout.contains("generate the correct command"),
+        "Prompt should not ask for a correction: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_wit_dry_run_includes_error() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .args([
+            "--dry-run",
+            "--shell",
+            "bash",
+            "--error",
+            "bash: gti: command not found",
+            "gti status",
+        ])
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "Dry run should exit successfully");
+    assert!(
+        stdout.contains("Input: gti status\nError: bash: gti: command not found\n"),
+        "Prompt should carry the error: {}",
+        stdout
+    );
+}
+
+// ========== Tool Cache Tests ==========
+
+/// Priority tools from discovery that are likely to be installed
+#[cfg(unix)]
+const COMMON_PRIORITY_TOOLS: &[&str] = &["git", "cargo", "rustc", "make", "gcc", "curl"];
+
+#[cfg(unix)]
+#[test]
+fn test_wit_list_tools_after_refresh() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let base = std::env::temp_dir().join(format!("wit-list-tools-test-{}", std::process::id()));
+    let bin_dir = base.join("bin");
+    std::fs::create_dir_all(&bin_dir).unwrap();
+
+    // Expose only the priority tools found on this system, so the scan stays fast
+    let mut present = Vec::new();
+    let system_path = std::env::var_os("PATH").unwrap_or_default();
+    for tool in COMMON_PRIORITY_TOOLS {
+        if let Some(path) = std::env::split_paths(&system_path)
+            .map(|dir| dir.join(tool))
+            .find(|path| path.is_file())
+        {
+            std::os::unix::fs::symlink(&path, bin_dir.join(tool)).unwrap();
+            present.push(*tool);
+        }
+    }
+
+    let run_wit = |flag: &str| {
+        Command::new(get_binary_path())
+            .arg(flag)
+            .env("XDG_CONFIG_HOME", base.join("config"))
+            .env("PATH", &bin_dir)
+            .output()
+            .expect("Failed to execute wit binary")
+    };
+
+    let refresh = run_wit("--refresh-tools");
+    let listing = run_wit("--list-tools");
+    let _ = std::fs::remove_dir_all(&base);
+
+    assert!(refresh.status.success(), "Refresh should succeed");
+    assert!(listing.status.success(), "List should succeed");
+
+    let stdout = String::from_utf8_lossy(&listing.stdout);
+    assert!(stdout.starts_with("Cached tools ("), "Listing: {}", stdout);
+    for tool in present {
+        assert!(
+            stdout
+                .lines()
+                .any(|line| line.split_whitespace().next() == Some(tool)),
+            "{} missing from listing: {}",
+            tool,
+            stdout
+        );
+    }
+}
+
 // ========== Model Management Tests ==========
 
+#[test]
+fn test_wit_help_lists_update() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .arg("--help")
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("--update"), "Help should list --update");
+}
+
+#[test]
+fn test_wit_trace_conflicts_with_explain_only() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let output = Command::new(get_binary_path())
+        .args(["--trace", "/tmp/wit-trace.json", "--explain-only", "ls"])
+        .output()
+        .expect("Failed to execute wit binary");
+
+    // Rejected by argument parsing, before any model is needed
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--trace"), "stderr: {}", stderr);
+}
+
+#[test]
+fn test_wit_update_forces_download() {
+    if !binary_exists() {
+        eprintln!("wit binary not found, skipping integration test");
+        return;
+    }
+
+    let config_home = std::env::temp_dir().join(format!("wit-update-test-{}", std::process::id()));
+    let (addr, server) = common::serve_http(|_| vec![common::http_ok("", b"GGUF new")]);
+    let url = format!("http://{}/wit-test.gguf", addr);
+
+    // A cached copy exists, so only a forced download fetches the new one
+    let cached = config_home
+        .join("fix")
+        .join(fix_lib::model_url_file_name(&url));
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(&cached, b"GGUF old").unwrap();
+
+    let output = Command::new(get_binary_path())
+        .args(["--update", "--model-url", &url])
+        .env("XDG_CONFIG_HOME", &config_home)
+        .output()
+        .expect("Failed to execute wit binary");
+
+    let contents = std::fs::read(&cached).unwrap();
+    let _ = std::fs::remove_dir_all(&config_home);
+
+    assert!(
+        output.status.success(),
+        "Update should succeed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(server.join().unwrap()[0].starts_with("GET /wit-test.gguf "));
+    assert_eq!(contents, b"GGUF new");
+}
+
 #[test]
 fn test_wit_list_models() {
     if !binary_exists() {